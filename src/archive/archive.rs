@@ -0,0 +1,875 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rayon::prelude::*;
+
+use crate::compressor::append::{refuse_encrypted, refuse_volume_set, replace_file};
+use crate::compressor::compressor::{compress_record, decompress_record, read_full_block, write_record, Progress, Workers};
+use crate::compressor::{CompressionOptions, DecompressOptions};
+use crate::container::container::{invalid_data, to_usize};
+use crate::container::{BlockRecord, Header, FLAG_ARCHIVE, FLAG_SOLID};
+use crate::dictionary::Dictionary;
+use crate::encryption::encryption::BlockCipher;
+use crate::volume::VolumeReader;
+use crate::recovery::recovery::{create_output, finish_output, protected_len, recovery_percent};
+
+/// Marks the trailer at the very end of an archive.
+const INDEX_MAGIC: [u8; 4] = *b"BPIX";
+/// Trailer layout: index offset `u64`, `INDEX_MAGIC`.
+const TRAILER_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// One file or directory stored in an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    /// Relative path with `/` separators.
+    pub path: String,
+    pub kind: EntryKind,
+    /// Uncompressed size in bytes (0 for directories).
+    pub size: u64,
+    /// Modification time since the Unix epoch.
+    pub mtime_secs: i64,
+    pub mtime_nanos: u32,
+    /// Unix permission bits, e.g. `0o644`.
+    pub mode: u32,
+    /// Offset of the entry's first block record in the archive; in solid
+    /// archives, offset of its data in the concatenated stream.
+    data_offset: u64,
+    block_count: u64,
+}
+
+impl ArchiveEntry {
+    pub fn modified(&self) -> SystemTime {
+        let since_epoch = Duration::new(self.mtime_secs.unsigned_abs(), self.mtime_nanos);
+        if self.mtime_secs >= 0 {
+            UNIX_EPOCH + since_epoch
+        } else {
+            UNIX_EPOCH - since_epoch
+        }
+    }
+
+    fn from_metadata(path: String, metadata: &fs::Metadata) -> Self {
+        let (mtime_secs, mtime_nanos) = match metadata.modified() {
+            Ok(time) => match time.duration_since(UNIX_EPOCH) {
+                Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+                Err(e) => (-(e.duration().as_secs() as i64), e.duration().subsec_nanos()),
+            },
+            Err(_) => (0, 0),
+        };
+        let kind = if metadata.is_dir() { EntryKind::Directory } else { EntryKind::File };
+        ArchiveEntry {
+            path,
+            kind,
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            mtime_secs,
+            mtime_nanos,
+            mode: permission_bits(metadata),
+            data_offset: 0,
+            block_count: 0,
+        }
+    }
+}
+
+#[cfg(unix)]
+fn permission_bits(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permission_bits(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+/// Walks `inputs` and lists what would be archived, each entry paired with
+/// the source path of its contents. A directory is stored under its own
+/// name, like `tar`; symbolic links to directories are not followed. Fails
+/// with `AlreadyExists` if two inputs give the same entry path, as `a/x` and
+/// `b/x` do.
+fn collect_entries<P: AsRef<Path>>(inputs: &[P]) -> io::Result<Vec<(ArchiveEntry, Option<PathBuf>)>> {
+    fn walk(path: &Path, name: String, out: &mut Vec<(ArchiveEntry, Option<PathBuf>)>) -> io::Result<()> {
+        let metadata = fs::metadata(path)?;
+        if metadata.is_dir() {
+            if !name.is_empty() {
+                out.push((ArchiveEntry::from_metadata(name.clone(), &metadata), None));
+            }
+            let mut children: Vec<_> = fs::read_dir(path)?.collect::<io::Result<_>>()?;
+            children.sort_by_key(|child| child.file_name());
+            for child in children {
+                if child.file_type()?.is_symlink() && child.path().is_dir() {
+                    continue;
+                }
+                let child_name = utf8_name(&child.file_name())?;
+                let child_path = if name.is_empty() { child_name } else { format!("{}/{}", name, child_name) };
+                walk(&child.path(), child_path, out)?;
+            }
+        } else {
+            out.push((ArchiveEntry::from_metadata(name, &metadata), Some(path.to_path_buf())));
+        }
+        Ok(())
+    }
+
+    let mut out = Vec::new();
+    for input in inputs {
+        let input = input.as_ref();
+        let name = match input.file_name() {
+            Some(name) => utf8_name(name)?,
+            // `.`, `..` or `/`: store the contents without a prefix.
+            None => String::new(),
+        };
+        walk(input, name, &mut out)?;
+    }
+    let mut seen = HashSet::with_capacity(out.len());
+    if let Some((entry, _)) = out.iter().find(|(entry, _)| !seen.insert(entry.path.as_str())) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("more than one input is named {}", entry.path)));
+    }
+    Ok(out)
+}
+
+fn utf8_name(name: &std::ffi::OsStr) -> io::Result<String> {
+    name.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("path is not valid UTF-8: {:?}", name)))
+}
+
+/// A shared block of a solid archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SolidBlock {
+    /// Offset of the block record in the archive.
+    record_offset: u64,
+    /// Offset of the block's first byte in the concatenated stream.
+    stream_offset: u64,
+    len: u64,
+}
+
+/// A block waiting to be compressed. `owner` is the entry it belongs to,
+/// or `None` for a shared block in solid mode.
+struct PendingBlock {
+    owner: Option<usize>,
+    stream_offset: u64,
+    data: Vec<u8>,
+}
+
+/// Archives `inputs` (files and directories) into `output`.
+///
+/// With [`CompressionOptions::solid`] set, the contents of all files are
+/// concatenated and cut into shared blocks, so the grammar and context
+/// model of each block span many small files; the index then records each
+/// file's offset in that stream.
+pub fn create_archive<P: AsRef<Path>, Q: AsRef<Path>>(inputs: &[P], output: Q, options: &CompressionOptions) -> io::Result<Progress> {
+    create_archive_with_progress(inputs, output, options, |_| Ok(()))
+}
+
+/// Like [`create_archive`], calling `progress` after each batch of blocks.
+/// Returning an error from the callback aborts the job.
+pub fn create_archive_with_progress<P, Q, F>(inputs: &[P], output: Q, options: &CompressionOptions, progress: F) -> io::Result<Progress>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: FnMut(&Progress) -> io::Result<()>,
+{
+    options.validate()?;
+    let entries = collect_entries(inputs)?;
+    let (header, cipher) = options.seal(Header {
+        flags: if options.solid { FLAG_ARCHIVE | FLAG_SOLID } else { FLAG_ARCHIVE },
+        // Entries are extracted one at a time, so their blocks stay independent.
+        prime_group: None,
+        dedup_window: None,
+        block_size: options.block_size as u64,
+        ..options.header()
+    })?;
+
+    let mut writer = BufWriter::new(create_output(&output)?);
+    let mut stats = Progress {
+        total_in: Some(entries.iter().map(|(entry, _)| entry.size).sum()),
+        ..Progress::default()
+    };
+    stats.bytes_out += header.write(&mut writer)? as u64;
+    let mut archive = ArchiveWriter {
+        writer,
+        header,
+        cipher,
+        offset: stats.bytes_out,
+        entries: Vec::new(),
+        solid_blocks: Vec::new(),
+    };
+    archive.add_entries(entries, options, &mut stats, progress)?;
+    let writer = archive.finish(&mut stats)?;
+    stats.add_recovery(finish_output(writer, options.recovery)?.1);
+    stats.split_output(output.as_ref(), options.volume_size)?;
+    Ok(stats)
+}
+
+/// Adds `inputs` (files and directories) to the archive at `path`, after
+/// the entries already in it.
+///
+/// The existing blocks are kept as they are: only the new entries are
+/// compressed, with the archive's own stages, block size and solid mode,
+/// and then the index is written again. `options` supply the threads plus
+/// the dictionary the archive was written with. Paths that are already in
+/// the archive are refused. The file is replaced atomically, so a failed
+/// append leaves it as it was; this copies every existing block to a new
+/// file, so each append reads and writes the whole archive and needs as
+/// much free disk space again. Recovery records, if the archive has them or
+/// `options` ask for them, are computed again for the whole file. Volume
+/// sets and encrypted archives cannot be appended to. The returned totals
+/// count only the new entries.
+pub fn append_to_archive<P: AsRef<Path>, Q: AsRef<Path>>(path: P, inputs: &[Q], options: &CompressionOptions) -> io::Result<Progress> {
+    let path = path.as_ref();
+    refuse_volume_set(path, options)?;
+    refuse_encrypted(&Header::read(&mut BufReader::new(File::open(path)?))?)?;
+    let decode_options = DecompressOptions::new().with_dictionary(options.dictionary.clone()).with_memory_limit(options.memory_limit);
+    let ArchiveReader { mut reader, header, entries, solid_blocks, cipher, index_offset, .. } = ArchiveReader::open_with_options(path, &decode_options)?;
+    let recovery = match options.recovery {
+        Some(percent) => Some(percent),
+        None => recovery_percent(&mut reader)?,
+    };
+    drop(reader);
+    Dictionary::for_header(&header, options.dictionary.as_deref())?;
+    let options = options.clone().with_block_size(to_usize(header.block_size)?).with_solid(header.is_solid());
+    options.validate()?;
+
+    let new_entries = collect_entries(inputs)?;
+    if let Some((entry, _)) = new_entries.iter().find(|(entry, _)| entries.iter().any(|old| old.path == entry.path)) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("already in archive: {}", entry.path)));
+    }
+    let (mut stats, recovery_bytes) = replace_file(path, index_offset, recovery, |writer| {
+        let mut stats = Progress {
+            total_in: Some(new_entries.iter().map(|(entry, _)| entry.size).sum()),
+            ..Progress::default()
+        };
+        let mut archive = ArchiveWriter { writer, header, cipher, offset: index_offset, entries, solid_blocks };
+        archive.add_entries(new_entries, &options, &mut stats, |_| Ok(()))?;
+        archive.finish(&mut stats)?;
+        Ok(stats)
+    })?;
+    stats.add_recovery(recovery_bytes);
+    Ok(stats)
+}
+
+/// Writes the records of an archive from offset `offset` on and keeps track
+/// of the entries and shared blocks its index lists.
+struct ArchiveWriter<W: Write> {
+    writer: W,
+    header: Header,
+    cipher: Option<BlockCipher>,
+    /// Archive offset of the next record.
+    offset: u64,
+    entries: Vec<ArchiveEntry>,
+    solid_blocks: Vec<SolidBlock>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    /// Adds `new_entries`, compressing each file entry from its source path.
+    /// `stats` counts what is read and written.
+    fn add_entries<F>(&mut self, new_entries: Vec<(ArchiveEntry, Option<PathBuf>)>, options: &CompressionOptions, stats: &mut Progress, mut progress: F) -> io::Result<()>
+    where
+        F: FnMut(&Progress) -> io::Result<()>,
+    {
+        let workers = Workers::new(options.threads)?;
+        let batch_len = options.blocks_in_flight(workers.threads());
+        let dictionary = options.dictionary.as_deref();
+        let first = self.entries.len();
+        let (entries, sources): (Vec<ArchiveEntry>, Vec<Option<PathBuf>>) = new_entries.into_iter().unzip();
+        self.entries.extend(entries);
+
+        // Blocks from consecutive files share a batch, so many small files
+        // still keep every worker busy.
+        let mut pending: Vec<PendingBlock> = Vec::with_capacity(batch_len);
+        // Solid mode fills `shared` across file boundaries, carrying on the
+        // concatenated stream where it ended; otherwise every file starts
+        // its own blocks.
+        let mut shared: Vec<u8> = Vec::new();
+        let mut stream_offset = self.solid_blocks.last().map_or(0, |block| block.stream_offset + block.len);
+        for (index, source) in (first..).zip(&sources) {
+            let Some(source) = source else { continue };
+            let mut reader = BufReader::new(File::open(source)?);
+            let mut size = 0;
+            if options.solid {
+                self.entries[index].data_offset = stream_offset + shared.len() as u64;
+                loop {
+                    let want = options.block_size - shared.len();
+                    let n = reader.by_ref().take(want as u64).read_to_end(&mut shared)?;
+                    size += n as u64;
+                    if shared.len() == options.block_size {
+                        let data = std::mem::replace(&mut shared, Vec::with_capacity(options.block_size));
+                        pending.push(PendingBlock { owner: None, stream_offset, data });
+                        stream_offset += options.block_size as u64;
+                        if pending.len() == batch_len {
+                            self.flush(&mut pending, &workers, dictionary, stats)?;
+                            progress(stats)?;
+                        }
+                    }
+                    if n < want {
+                        break;
+                    }
+                }
+            } else {
+                loop {
+                    let block = read_full_block(&mut reader, options.block_size)?;
+                    if block.is_empty() {
+                        break;
+                    }
+                    size += block.len() as u64;
+                    pending.push(PendingBlock { owner: Some(index), stream_offset: 0, data: block });
+                    if pending.len() == batch_len {
+                        self.flush(&mut pending, &workers, dictionary, stats)?;
+                        progress(stats)?;
+                    }
+                }
+            }
+            // The file may have changed since it was listed; record what was read.
+            self.entries[index].size = size;
+        }
+        if !shared.is_empty() {
+            pending.push(PendingBlock { owner: None, stream_offset, data: shared });
+        }
+        if !pending.is_empty() {
+            self.flush(&mut pending, &workers, dictionary, stats)?;
+            progress(stats)?;
+        }
+        Ok(())
+    }
+
+    /// Compresses `pending` in parallel and writes the records in order.
+    fn flush(&mut self, pending: &mut Vec<PendingBlock>, workers: &Workers, dictionary: Option<&Dictionary>, stats: &mut Progress) -> io::Result<()> {
+        let header = &self.header;
+        let records: Vec<BlockRecord> = workers.install(|| {
+            pending.par_iter().map(|block| compress_record(&block.data, header, dictionary)).collect()
+        });
+        for (block, record) in pending.drain(..).zip(records) {
+            match block.owner {
+                Some(index) => {
+                    let entry = &mut self.entries[index];
+                    if entry.block_count == 0 {
+                        entry.data_offset = self.offset;
+                    }
+                    entry.block_count += 1;
+                }
+                None => self.solid_blocks.push(SolidBlock {
+                    record_offset: self.offset,
+                    stream_offset: block.stream_offset,
+                    len: block.data.len() as u64,
+                }),
+            }
+            stats.bytes_in += record.orig_len as u64;
+            let written = write_record(&mut self.writer, record, self.cipher.as_ref(), self.offset, None)? as u64;
+            self.offset += written;
+            stats.bytes_out += written;
+        }
+        Ok(())
+    }
+
+    /// Writes the index and the trailer that points at it.
+    fn finish(mut self, stats: &mut Progress) -> io::Result<W> {
+        let index_offset = self.offset;
+        // The index never uses the dictionary, so an archive can be listed
+        // without it.
+        let index = compress_record(&encode_index(&self.entries, &self.solid_blocks), &self.header, None);
+        stats.bytes_out += write_record(&mut self.writer, index, self.cipher.as_ref(), index_offset, None)? as u64;
+        self.writer.write_all(&index_offset.to_le_bytes())?;
+        self.writer.write_all(&INDEX_MAGIC)?;
+        stats.bytes_out += TRAILER_LEN as u64;
+        Ok(self.writer)
+    }
+}
+
+/// Index layout: entry count `u64`, the entries, then (solid archives only)
+/// block count `u64` and one `(record_offset u64, stream_offset u64,
+/// len u64)` per shared block.
+fn encode_index(entries: &[ArchiveEntry], solid_blocks: &[SolidBlock]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend(&(entries.len() as u64).to_le_bytes());
+    for entry in entries {
+        out.extend(&(entry.path.len() as u64).to_le_bytes());
+        out.extend(entry.path.as_bytes());
+        out.push(match entry.kind {
+            EntryKind::File => 0,
+            EntryKind::Directory => 1,
+        });
+        out.extend(&entry.mode.to_le_bytes());
+        out.extend(&entry.mtime_secs.to_le_bytes());
+        out.extend(&entry.mtime_nanos.to_le_bytes());
+        out.extend(&entry.size.to_le_bytes());
+        out.extend(&entry.data_offset.to_le_bytes());
+        out.extend(&entry.block_count.to_le_bytes());
+    }
+    if !solid_blocks.is_empty() {
+        out.extend(&(solid_blocks.len() as u64).to_le_bytes());
+        for block in solid_blocks {
+            out.extend(&block.record_offset.to_le_bytes());
+            out.extend(&block.stream_offset.to_le_bytes());
+            out.extend(&block.len.to_le_bytes());
+        }
+    }
+    out
+}
+
+/// Parses an index written by [`encode_index`]; `None` if it is malformed,
+/// including a solid block table that does not tile the shared stream from
+/// 0 or a file that does not lie inside it.
+fn decode_index(data: &[u8], solid: bool) -> Option<(Vec<ArchiveEntry>, Vec<SolidBlock>)> {
+    fn take<'a>(data: &'a [u8], pos: &mut usize, n: usize) -> Option<&'a [u8]> {
+        let bytes = data.get(*pos..pos.checked_add(n)?)?;
+        *pos += n;
+        Some(bytes)
+    }
+    fn take_array<const N: usize>(data: &[u8], pos: &mut usize) -> Option<[u8; N]> {
+        take(data, pos, N)?.try_into().ok()
+    }
+    let mut pos = 0;
+    let count = usize::try_from(u64::from_le_bytes(take_array(data, &mut pos)?)).ok()?;
+    let mut entries = Vec::with_capacity(count.min(data.len()));
+    for _ in 0..count {
+        let path_len = usize::try_from(u64::from_le_bytes(take_array(data, &mut pos)?)).ok()?;
+        let path = String::from_utf8(take(data, &mut pos, path_len)?.to_vec()).ok()?;
+        let kind = match take_array::<1>(data, &mut pos)?[0] {
+            0 => EntryKind::File,
+            1 => EntryKind::Directory,
+            _ => return None,
+        };
+        entries.push(ArchiveEntry {
+            path,
+            kind,
+            mode: u32::from_le_bytes(take_array(data, &mut pos)?),
+            mtime_secs: i64::from_le_bytes(take_array(data, &mut pos)?),
+            mtime_nanos: u32::from_le_bytes(take_array(data, &mut pos)?),
+            size: u64::from_le_bytes(take_array(data, &mut pos)?),
+            data_offset: u64::from_le_bytes(take_array(data, &mut pos)?),
+            block_count: u64::from_le_bytes(take_array(data, &mut pos)?),
+        });
+    }
+    let mut solid_blocks = Vec::new();
+    if solid && pos < data.len() {
+        let count = usize::try_from(u64::from_le_bytes(take_array(data, &mut pos)?)).ok()?;
+        solid_blocks.reserve(count.min(data.len()));
+        for _ in 0..count {
+            solid_blocks.push(SolidBlock {
+                record_offset: u64::from_le_bytes(take_array(data, &mut pos)?),
+                stream_offset: u64::from_le_bytes(take_array(data, &mut pos)?),
+                len: u64::from_le_bytes(take_array(data, &mut pos)?),
+            });
+        }
+    }
+    if solid {
+        // Readers index the shared stream by these offsets, so the blocks
+        // must tile it from 0 and every file must lie inside it.
+        let mut stream_len = 0u64;
+        for block in &solid_blocks {
+            if block.stream_offset != stream_len {
+                return None;
+            }
+            stream_len = stream_len.checked_add(block.len)?;
+        }
+        if entries.iter().any(|entry| entry.data_offset.checked_add(entry.size).is_none_or(|end| end > stream_len)) {
+            return None;
+        }
+    }
+    Some((entries, solid_blocks))
+}
+
+/// Turns a stored path into a relative filesystem path, refusing anything
+/// that could escape the extraction directory.
+fn safe_relative_path(path: &str) -> io::Result<PathBuf> {
+    let relative = Path::new(path);
+    let escapes = relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+    if path.is_empty() || escapes {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsafe path in archive: {}", path)));
+    }
+    Ok(relative.to_path_buf())
+}
+
+fn apply_metadata(path: &Path, entry: &ArchiveEntry) -> io::Result<()> {
+    if entry.kind == EntryKind::File {
+        File::options().write(true).open(path)?.set_modified(entry.modified())?;
+    } else if let Ok(dir) = File::open(path) {
+        // Not every platform can open a directory as a file; its time is
+        // best effort.
+        let _ = dir.set_modified(entry.modified());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Setuid, setgid and sticky bits are not restored: an archive from
+        // someone else must not hand out their privileges.
+        fs::set_permissions(path, fs::Permissions::from_mode(entry.mode & 0o777))?;
+    }
+    Ok(())
+}
+
+/// Reads the trailer of the archive with `header` in `reader` and returns
+/// the offset of its index record, which is also where its entry blocks end.
+pub(crate) fn index_offset<R: Read + Seek>(reader: &mut R, header: &Header) -> io::Result<u64> {
+    let end = protected_len(reader)?;
+    if end < (header.encoded_len() + TRAILER_LEN) as u64 {
+        return Err(invalid_data("archive is truncated"));
+    }
+    reader.seek(SeekFrom::Start(end - TRAILER_LEN as u64))?;
+    let mut trailer = [0u8; TRAILER_LEN];
+    reader.read_exact(&mut trailer)?;
+    if trailer[8..] != INDEX_MAGIC {
+        return Err(invalid_data("archive index trailer is missing"));
+    }
+    Ok(u64::from_le_bytes(trailer[..8].try_into().unwrap()))
+}
+
+/// Random-access reader over a multi-file archive.
+pub struct ArchiveReader<R: Read + Seek> {
+    reader: R,
+    header: Header,
+    entries: Vec<ArchiveEntry>,
+    solid_blocks: Vec<SolidBlock>,
+    /// Recently decoded shared blocks of a solid archive, by block number.
+    cache: Vec<(usize, Vec<u8>)>,
+    options: DecompressOptions,
+    cipher: Option<BlockCipher>,
+    /// Archive offset of the index record.
+    index_offset: u64,
+    /// Bytes decoded so far, checked against `options.max_output`.
+    produced: u64,
+}
+
+impl ArchiveReader<BufReader<VolumeReader>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_with_options(path, &DecompressOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: &DecompressOptions) -> io::Result<Self> {
+        Self::with_options(BufReader::new(VolumeReader::open(path)?), options)
+    }
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    /// Reads the header and the index; entry contents are decoded on demand.
+    pub fn new(reader: R) -> io::Result<Self> {
+        Self::with_options(reader, &DecompressOptions::default())
+    }
+
+    /// Like [`ArchiveReader::new`]. The index can always be read; a
+    /// dictionary from `options` is only checked when entry data is decoded.
+    /// The limits in `options` apply to the index as well as to entries.
+    pub fn with_options(mut reader: R, options: &DecompressOptions) -> io::Result<Self> {
+        let header = Header::read(&mut reader)?;
+        if !header.is_archive() {
+            return Err(invalid_data("not a multi-file archive"));
+        }
+        if header.prime_group.is_some() || header.dedup_window.is_some() {
+            return Err(invalid_data("archive blocks cannot be primed or deduplicated"));
+        }
+        let index_offset = index_offset(&mut reader, &header)?;
+        reader.seek(SeekFrom::Start(index_offset))?;
+        let cipher = options.cipher(&header)?;
+        let mut offset = index_offset;
+        let record = options
+            .read_record(&mut reader, &header, cipher.as_ref(), &mut offset)?
+            .ok_or_else(|| invalid_data("archive index is missing"))?;
+        let (entries, solid_blocks) = decode_index(&decompress_record(record, &header, None, options.max_rules())?, header.is_solid())
+            .ok_or_else(|| invalid_data("archive index is corrupt"))?;
+        Ok(ArchiveReader {
+            reader,
+            header,
+            entries,
+            solid_blocks,
+            cache: Vec::new(),
+            options: options.clone(),
+            cipher,
+            index_offset,
+            produced: 0,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn entries(&self) -> &[ArchiveEntry] {
+        &self.entries
+    }
+
+    /// Index of the entry stored under `path`.
+    pub fn find(&self, path: &str) -> Option<usize> {
+        let path = path.trim_end_matches('/');
+        self.entries.iter().position(|entry| entry.path == path)
+    }
+
+    /// Decompresses the contents of entry `index` into `writer`.
+    pub fn read_entry<W: Write>(&mut self, index: usize, writer: &mut W) -> io::Result<u64> {
+        let entry = &self.entries[index];
+        let (data_offset, block_count, size) = (entry.data_offset, entry.block_count, entry.size);
+        if self.header.is_solid() {
+            return self.read_solid_range(data_offset, size, writer);
+        }
+        if block_count == 0 {
+            return Ok(0);
+        }
+        self.reader.seek(SeekFrom::Start(data_offset))?;
+        let batch_len = rayon::current_num_threads().max(1);
+        let mut remaining = usize::try_from(block_count).unwrap_or(usize::MAX);
+        let mut written = 0u64;
+        let mut offset = data_offset;
+        while remaining > 0 {
+            let mut records = Vec::with_capacity(batch_len.min(remaining));
+            let mut batch_memory = 0;
+            while records.len() < batch_len && remaining > 0 {
+                let record_offset = offset;
+                let record = self
+                    .options
+                    .read_record(&mut self.reader, &self.header, self.cipher.as_ref(), &mut offset)?
+                    .ok_or_else(|| invalid_data("archive ends inside an entry"))?;
+                match self.options.fit_in_batch(&self.header, batch_memory, &record) {
+                    Some(total) => batch_memory = total,
+                    None => {
+                        // Leave it for the next batch.
+                        self.reader.seek(SeekFrom::Start(record_offset))?;
+                        offset = record_offset;
+                        break;
+                    }
+                }
+                self.produced += record.orig_len as u64;
+                records.push(record);
+                remaining -= 1;
+            }
+            self.options.check_output(self.produced)?;
+            let header = self.header;
+            let dictionary = Dictionary::for_header(&header, self.options.dictionary.as_deref())?;
+            let max_rules = self.options.max_rules();
+            let blocks: Vec<io::Result<Vec<u8>>> = records
+                .into_par_iter()
+                .map(|record| decompress_record(record, &header, dictionary, max_rules))
+                .collect();
+            for block in blocks {
+                let block = block?;
+                writer.write_all(&block)?;
+                written += block.len() as u64;
+            }
+        }
+        if written != size {
+            return Err(invalid_data("entry size does not match the index"));
+        }
+        Ok(written)
+    }
+
+    /// Copies `len` bytes starting at `start` of a solid archive's
+    /// concatenated stream into `writer`.
+    fn read_solid_range<W: Write>(&mut self, start: u64, len: u64, writer: &mut W) -> io::Result<u64> {
+        let end = start.checked_add(len).ok_or_else(|| invalid_data("entry lies outside the solid stream"))?;
+        let mut block_number = self
+            .solid_blocks
+            .partition_point(|block| block.stream_offset + block.len <= start);
+        let mut pos = start;
+        while pos < end {
+            let block = *self
+                .solid_blocks
+                .get(block_number)
+                .ok_or_else(|| invalid_data("entry lies outside the solid stream"))?;
+            let data = self.solid_block(block_number)?;
+            let from = (pos - block.stream_offset) as usize;
+            let to = (end.min(block.stream_offset + block.len) - block.stream_offset) as usize;
+            writer.write_all(&data[from..to])?;
+            pos += (to - from) as u64;
+            block_number += 1;
+        }
+        Ok(len)
+    }
+
+    /// Returns shared block `block_number`, decoding it together with the
+    /// following blocks in parallel when it is not cached. Extracting files
+    /// in archive order therefore decodes every block once.
+    fn solid_block(&mut self, block_number: usize) -> io::Result<&[u8]> {
+        if let Some(pos) = self.cache.iter().position(|(number, _)| *number == block_number) {
+            return Ok(&self.cache[pos].1);
+        }
+        let batch_len = rayon::current_num_threads().max(1);
+        let batch_end = (block_number + batch_len).min(self.solid_blocks.len());
+        let mut offset = self.solid_blocks[block_number].record_offset;
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut records = Vec::with_capacity(batch_end - block_number);
+        let mut batch_memory = 0;
+        for number in block_number..batch_end {
+            let record = self
+                .options
+                .read_record(&mut self.reader, &self.header, self.cipher.as_ref(), &mut offset)?
+                .ok_or_else(|| invalid_data("archive ends inside the solid stream"))?;
+            if record.orig_len as u64 != self.solid_blocks[number].len {
+                return Err(invalid_data("solid block size does not match the index"));
+            }
+            // Blocks past the memory budget are read again by a later call.
+            match self.options.fit_in_batch(&self.header, batch_memory, &record) {
+                Some(total) => batch_memory = total,
+                None => break,
+            }
+            self.produced += record.orig_len as u64;
+            records.push((number, record));
+        }
+        self.options.check_output(self.produced)?;
+        let header = self.header;
+        let dictionary = Dictionary::for_header(&header, self.options.dictionary.as_deref())?;
+        let max_rules = self.options.max_rules();
+        self.cache = records
+            .into_par_iter()
+            .map(|(number, record)| decompress_record(record, &header, dictionary, max_rules).map(|data| (number, data)))
+            .collect::<io::Result<_>>()?;
+        Ok(&self.cache[0].1)
+    }
+
+    /// Extracts entry `index` below `dest`, creating parent directories.
+    /// Returns the path written.
+    pub fn extract_entry<P: AsRef<Path>>(&mut self, index: usize, dest: P) -> io::Result<PathBuf> {
+        let entry = self.entries[index].clone();
+        let target = dest.as_ref().join(safe_relative_path(&entry.path)?);
+        match entry.kind {
+            EntryKind::Directory => fs::create_dir_all(&target)?,
+            EntryKind::File => {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut writer = BufWriter::new(File::create(&target)?);
+                self.read_entry(index, &mut writer)?;
+                writer.flush()?;
+            }
+        }
+        apply_metadata(&target, &entry)?;
+        Ok(target)
+    }
+
+    /// Extracts every entry below `dest`.
+    pub fn extract_all<P: AsRef<Path>>(&mut self, dest: P) -> io::Result<()> {
+        let dest = dest.as_ref();
+        for index in 0..self.entries.len() {
+            if self.entries[index].kind == EntryKind::File {
+                self.extract_entry(index, dest)?;
+            } else {
+                fs::create_dir_all(dest.join(safe_relative_path(&self.entries[index].path)?))?;
+            }
+        }
+        // Directory times and permissions go last, deepest first: writing
+        // files would bump the times, and a read-only directory would block
+        // its own contents.
+        for entry in self.entries.iter().rev().filter(|entry| entry.kind == EntryKind::Directory) {
+            apply_metadata(&dest.join(safe_relative_path(&entry.path)?), entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, size: u64, data_offset: u64) -> ArchiveEntry {
+        ArchiveEntry { path: path.to_string(), kind: EntryKind::File, size, mtime_secs: 0, mtime_nanos: 0, mode: 0o644, data_offset, block_count: 0 }
+    }
+
+    fn block(stream_offset: u64, len: u64) -> SolidBlock {
+        SolidBlock { record_offset: 64, stream_offset, len }
+    }
+
+    #[test]
+    fn solid_tables_must_tile_the_stream() {
+        let entries = [entry("a", 100, 0), entry("b", 50, 100)];
+        let good = [block(0, 120), block(120, 30)];
+        assert_eq!(decode_index(&encode_index(&entries, &good), true), Some((entries.to_vec(), good.to_vec())));
+
+        let gap = [block(0, 100), block(120, 30)];
+        let overlap = [block(0, 120), block(100, 50)];
+        let not_from_zero = [block(10, 140)];
+        let overflow = [block(0, u64::MAX), block(u64::MAX, 2)];
+        for blocks in [&gap[..], &overlap, &not_from_zero, &overflow] {
+            assert_eq!(decode_index(&encode_index(&entries, blocks), true), None);
+        }
+
+        let past_end = [entry("a", 100, 0), entry("b", 51, 100)];
+        let wrapping = [entry("a", 2, u64::MAX)];
+        for entries in [&past_end[..], &wrapping] {
+            assert_eq!(decode_index(&encode_index(entries, &good), true), None);
+        }
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockpiper-archive-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn inputs_with_the_same_name_are_refused() {
+        let dir = scratch_dir("duplicates");
+        for sub in ["a", "b"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+            fs::write(dir.join(sub).join("x"), sub).unwrap();
+        }
+        let output = dir.join("ar.bpc");
+        let error = create_archive(&[dir.join("a/x"), dir.join("b/x")], &output, &CompressionOptions::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(!output.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn setuid_bits_are_not_restored() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("setuid");
+        let input = dir.join("tool");
+        fs::write(&input, b"#!/bin/sh\n").unwrap();
+        fs::set_permissions(&input, fs::Permissions::from_mode(0o4755)).unwrap();
+        let output = dir.join("ar.bpc");
+        create_archive(&[&input], &output, &CompressionOptions::default()).unwrap();
+
+        let mut archive = ArchiveReader::open(&output).unwrap();
+        assert_eq!(archive.entries()[0].mode & 0o7777, fs::metadata(&input).unwrap().permissions().mode() & 0o7777);
+        let extracted = archive.extract_entry(0, dir.join("out")).unwrap();
+        assert_eq!(fs::metadata(extracted).unwrap().permissions().mode() & 0o7777, 0o755);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn solid_archives_of_small_files_round_trip_smaller() {
+        let dir = scratch_dir("solid");
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("nested")).unwrap();
+        let mut files = Vec::new();
+        for n in 0..80 {
+            let name = if n % 5 == 0 { format!("nested/file{}.txt", n) } else { format!("file{}.txt", n) };
+            let contents = format!("[section {}]\nname = \"item {}\"\nenabled = {}\nweight = {}\n", n % 7, n, n % 2 == 0, n * 13 % 100).into_bytes();
+            fs::write(src.join(&name), &contents).unwrap();
+            files.push((name, contents));
+        }
+
+        let mut sizes = Vec::new();
+        for solid in [false, true] {
+            let output = dir.join(format!("solid-{}.bpc", solid));
+            create_archive(&[&src], &output, &CompressionOptions::default().with_solid(solid)).unwrap();
+            sizes.push(fs::metadata(&output).unwrap().len());
+
+            let mut archive = ArchiveReader::open(&output).unwrap();
+            assert_eq!(archive.header().is_solid(), solid);
+            let extracted = dir.join(format!("out-{}", solid));
+            archive.extract_all(&extracted).unwrap();
+            for (name, contents) in &files {
+                assert_eq!(&fs::read(extracted.join("src").join(name)).unwrap(), contents, "{}", name);
+            }
+            // Single entries are cut out of the shared blocks.
+            let index = archive.find("src/file41.txt").unwrap();
+            let mut entry = Vec::new();
+            archive.read_entry(index, &mut entry).unwrap();
+            assert_eq!(entry, files[41].1);
+        }
+        assert!(sizes[1] < sizes[0], "solid {} bytes, separate {} bytes", sizes[1], sizes[0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,2 @@
+pub mod archive;
+pub use archive::{append_to_archive, create_archive, create_archive_with_progress, ArchiveEntry, ArchiveReader, EntryKind};
@@ -1,42 +1,164 @@
-use constriction::stream::{model::UniformModel, queue::{DefaultRangeEncoder, DefaultRangeDecoder}, Encode, Decode};
-use bytemuck;
-
-pub struct ArithmeticEncoder {
-    encoder: DefaultRangeEncoder,
-}
-
-impl ArithmeticEncoder {
-    pub fn new() -> Self {
-        ArithmeticEncoder {
-            encoder: DefaultRangeEncoder::new(),
-        }
-    }
-
-    pub fn encode_symbol(&mut self, symbol: u8, _prob: (u32, u32)) {
-        let model = UniformModel::<u8, 16>::new(255);
-        self.encoder.encode_symbol(symbol, &model).unwrap();
-    }
-
-    pub fn finish(self) -> Vec<u8> {
-        let compressed: Vec<u32> = self.encoder.into_compressed().unwrap();
-        bytemuck::cast_slice(&compressed).to_vec()
-    }
-}
-
-pub struct ArithmeticDecoder {
-    decoder: DefaultRangeDecoder,
-}
-
-impl ArithmeticDecoder {
-    pub fn new(encoded: Vec<u8>) -> Self {
-        let compressed: Vec<u32> = bytemuck::cast_slice(&encoded).to_vec();
-        ArithmeticDecoder {
-            decoder: DefaultRangeDecoder::from_compressed(compressed).unwrap(),
-        }
-    }
-
-    pub fn decode_symbol(&mut self, _prob: (u32, u32)) -> u8 {
-        let model = UniformModel::<u8, 16>::new(255);
-        self.decoder.decode_symbol(&model).unwrap()
-    }
-} 
\ No newline at end of file
+use constriction::stream::{model::{DecoderModel, EncoderModel, EntropyModel}, queue::{DefaultRangeEncoder, DefaultRangeDecoder}, Encode, Decode};
+use std::io;
+use std::num::NonZeroU32;
+
+/// Bits of probability resolution handed to the range coder.
+const PRECISION: usize = 24;
+
+/// Byte distribution quantized so that every symbol has a non-zero
+/// probability and the probabilities sum to exactly `1 << PRECISION`.
+struct FrequencyModel {
+    cumulative: [u32; 257],
+}
+
+impl FrequencyModel {
+    fn new(freqs: &[u32; 256]) -> Self {
+        let total: u64 = freqs.iter().map(|&f| f as u64).sum::<u64>().max(1);
+        // Reserve one unit per symbol, share the rest proportionally and
+        // give the rounding leftover to the most likely symbol.
+        let spare = (1u64 << PRECISION) - 256;
+        let mut probs = [1u32; 256];
+        let mut assigned = 256u64;
+        let mut most_likely = 0;
+        for (i, &f) in freqs.iter().enumerate() {
+            let share = f as u64 * spare / total;
+            probs[i] += share as u32;
+            assigned += share;
+            if f > freqs[most_likely] {
+                most_likely = i;
+            }
+        }
+        probs[most_likely] += ((1u64 << PRECISION) - assigned) as u32;
+
+        let mut cumulative = [0u32; 257];
+        for i in 0..256 {
+            cumulative[i + 1] = cumulative[i] + probs[i];
+        }
+        FrequencyModel { cumulative }
+    }
+}
+
+impl EntropyModel<PRECISION> for FrequencyModel {
+    type Symbol = u8;
+    type Probability = u32;
+}
+
+impl EncoderModel<PRECISION> for FrequencyModel {
+    fn left_cumulative_and_probability(&self, symbol: impl std::borrow::Borrow<u8>) -> Option<(u32, NonZeroU32)> {
+        let s = *symbol.borrow() as usize;
+        let left = self.cumulative[s];
+        NonZeroU32::new(self.cumulative[s + 1] - left).map(|p| (left, p))
+    }
+}
+
+impl DecoderModel<PRECISION> for FrequencyModel {
+    fn quantile_function(&self, quantile: u32) -> (u8, u32, NonZeroU32) {
+        // Last symbol whose left cumulative is <= quantile.
+        let s = self.cumulative[1..].partition_point(|&c| c <= quantile);
+        let left = self.cumulative[s];
+        let prob = NonZeroU32::new(self.cumulative[s + 1] - left).expect("every symbol has non-zero probability");
+        (s as u8, left, prob)
+    }
+}
+
+pub struct ArithmeticEncoder {
+    encoder: DefaultRangeEncoder,
+}
+
+impl Default for ArithmeticEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArithmeticEncoder {
+    pub fn new() -> Self {
+        ArithmeticEncoder {
+            encoder: DefaultRangeEncoder::new(),
+        }
+    }
+
+    /// Encodes `symbol` under the distribution given by `freqs` (any scale;
+    /// zero entries are allowed and get the smallest codable probability).
+    pub fn encode_symbol(&mut self, symbol: u8, freqs: &[u32; 256]) {
+        let model = FrequencyModel::new(freqs);
+        self.encoder.encode_symbol(symbol, model).unwrap();
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let compressed: Vec<u32> = self.encoder.into_compressed().unwrap();
+        compressed.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+}
+
+pub struct ArithmeticDecoder {
+    decoder: DefaultRangeDecoder,
+}
+
+impl ArithmeticDecoder {
+    /// Starts decoding `encoded`, which must be a whole number of the
+    /// coder's 32-bit words.
+    pub fn new(encoded: Vec<u8>) -> io::Result<Self> {
+        if !encoded.len().is_multiple_of(4) {
+            return Err(invalid_payload());
+        }
+        // The payload is a byte buffer with no alignment guarantee, so copy the
+        // words out instead of casting the slice in place.
+        let compressed: Vec<u32> = encoded
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let decoder = DefaultRangeDecoder::from_compressed(compressed).map_err(|_| invalid_payload())?;
+        Ok(ArithmeticDecoder { decoder })
+    }
+
+    /// Decodes one symbol; `freqs` must match what the encoder used. A
+    /// payload the encoder cannot have produced is `InvalidData`.
+    pub fn decode_symbol(&mut self, freqs: &[u32; 256]) -> io::Result<u8> {
+        let model = FrequencyModel::new(freqs);
+        self.decoder.decode_symbol(model).map_err(|_| invalid_payload())
+    }
+}
+
+fn invalid_payload() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt arithmetic-coded payload")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_every_byte_value() {
+        // 0xFF used to be outside the coder's alphabet and panicked.
+        let symbols: Vec<u8> = (0..=255).chain([0xff, 0, 0xff]).collect();
+        let mut encoder = ArithmeticEncoder::new();
+        for &symbol in &symbols {
+            encoder.encode_symbol(symbol, &[1; 256]);
+        }
+        let mut decoder = ArithmeticDecoder::new(encoder.finish()).unwrap();
+        let decoded: Vec<u8> = symbols.iter().map(|_| decoder.decode_symbol(&[1; 256]).unwrap()).collect();
+        assert_eq!(decoded, symbols);
+    }
+
+    #[test]
+    fn hostile_payloads_are_invalid_data() {
+        let mut encoder = ArithmeticEncoder::new();
+        for symbol in b"some ordinary block contents" {
+            encoder.encode_symbol(*symbol, &[1; 256]);
+        }
+        let mut payload = encoder.finish();
+        payload.push(0);
+        let error = ArithmeticDecoder::new(payload.clone()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Words of all ones lie outside every range the encoder can leave.
+        payload.truncate(payload.len() - 1);
+        payload[..8].fill(0xff);
+        let mut decoder = ArithmeticDecoder::new(payload).unwrap();
+        let mut freqs = [0; 256];
+        freqs[0] = 1_000_000;
+        let error = (0..64).find_map(|_| decoder.decode_symbol(&freqs).err()).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
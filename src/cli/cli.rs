@@ -0,0 +1,845 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::archive::{create_archive_with_progress, ArchiveReader, EntryKind};
+use crate::compressor::{append_files, salvage_file, compress_file_with_progress, decompress_file_with_progress, decompress_stream, ChunkSizes, CompressionOptions, DecompressOptions, Progress, RandomAccessReader, DEFAULT_BLOCK_SIZE, DEFAULT_DEDUP_WINDOW, DEFAULT_LEVEL};
+use crate::container::{ChecksumKind, Header};
+use crate::delta::{apply_patch_file, create_patch_file};
+use crate::grammar::{write_grammar, ExportFormat, Grammar, GrammarAnalysis, RuleInfo};
+use crate::dictionary::Dictionary;
+use crate::encryption::Credentials;
+use crate::recovery::{check_recovery, repair_file};
+use crate::volume::{volume_path, volume_set_path, VolumeReader};
+
+const USAGE: &str = "\
+usage: blockpiper <command> [options]
+
+commands:
+  compress <input> [-o <output>] [compression options]
+      Compress a file (default output: <input>.bpc)
+  decompress <input> [-o <output>] [decompression options]
+      Decompress a .bpc file (default output: <input> without .bpc); a
+      file split into volumes is read from <input>.001 on
+  archive <output.bpc> <paths>... [compression options]
+      Store files and directories (recursively) in one archive
+  append <file.bpc> <paths>... [compression options]
+      Add files to an archive, or their contents to the end of a stream
+  extract <archive.bpc> [paths]... [-C <dir>] [decompression options]
+      Extract everything, or only the listed entries, into <dir> (default .)
+  list <archive.bpc> [decompression options]
+      List the entries of an archive
+  delta --ref <old> <new> [-o <patch>] [compression options]
+      Write a patch that rebuilds <new> from <old> (default: <new>.patch.bpc)
+  patch <old> <patch.bpc> [-o <output>] [decompression options]
+      Rebuild a file from <old> and a patch made by `delta`
+  train <samples>... -o <dict> [compression options]
+      Build a preset dictionary from sample files
+  grammar <input> [--format text|dot|json] [--offset <n>] [--length <size>] [-o <output>]
+      Infer the grammar of a region (default: the first 256K) and export it
+  grammar <input> --stats [--top <n>] [--offset <n>] [--length <size>]
+      Report depth, repeats and a compression estimate for that grammar
+  grep <pattern> <files.bpc>... [--lines | --count] [decompression options]
+      Print the offset of every match without decompressing the files
+  test <files.bpc>... [decompression options]
+      Decode files without writing them; report damage and whether
+      recovery records can repair it
+  repair <file.bpc> [-o <output>]
+      Rebuild damaged parts of a file from its recovery records (default:
+      in place)
+  salvage <file.bpc> [-o <output>] [--fill <byte> | --skip-lost]
+      Decode every intact block of a damaged stream and list the byte
+      ranges that were lost; lost data becomes zero bytes, or <byte>, or
+      is left out (default output: <file> without .bpc)
+  help
+      Show this message
+
+compression options:
+  -1 .. -9, --level <n>    Preset from fastest (1) to strongest (9), default 6
+  --block-size <size>      Block size, e.g. 256K or 4M
+  --chunking <avg>         Cut blocks at content-defined boundaries averaging
+  --chunking <min,avg,max>   <avg> (default min avg/4, max avg*4)
+  --threads <n>            Worker threads (0 = one per core)
+  --depth <n>              Context model depth (0-6)
+  --grammar                Run grammar inference before the context model
+  --no-grammar             Skip grammar inference (the default)
+  --no-model               Code bytes without the context model
+  --checksum <kind>        Per-block checksum: crc32 or none
+  --memory-limit <size>    Cap on working memory, e.g. 512M
+  --solid                  Archives: pack files into shared blocks
+  --prime-group <n>        Carry the context model from block to block, in
+                           independent groups of n blocks
+  --dedup                  Store repeated blocks as references to the first copy
+  --dedup-window <size>    Same, looking back over this much data (default 256M)
+  --recovery <percent>     Add recovery records of this size (1-100%) that
+                           `repair` can rebuild damaged parts from
+  --volume-size <size>     Split the output into volumes of at most this size,
+                           <output>.001, <output>.002 and so on
+  --no-sync                Leave out the sync markers `salvage` resumes from
+                           after a damaged block
+  --dict <file>            Code against a dictionary made by `train`
+  --password <password>    Encrypt blocks with a key derived from a password
+  --key-file <file>        Encrypt blocks with a key derived from a file
+
+decompression options:
+  --dict <file>            Dictionary the input was compressed with
+  --max-block-size <size>  Reject blocks larger than this
+  --max-output <size>      Stop once the output would exceed this
+  --max-rules <n>          Reject blocks whose grammar has more rules
+  --memory-limit <size>    Reject blocks that need more working memory
+  --password <password>    Password the input was encrypted with
+  --key-file <file>        Key file the input was encrypted with
+
+A password given on the command line is visible to other users of the
+machine; prefer --key-file on shared systems.
+
+options:
+  -q, --quiet    Do not print a summary line";
+
+/// Options of the `compress` command that take a value.
+const COMPRESSION_VALUE_OPTIONS: &[&str] = &[
+    "--level",
+    "--block-size",
+    "--chunking",
+    "--threads",
+    "--depth",
+    "--checksum",
+    "--memory-limit",
+    "--dict",
+    "--prime-group",
+    "--dedup-window",
+    "--recovery",
+    "--volume-size",
+    "--password",
+    "--key-file",
+];
+
+/// Flags of the commands that take compression options.
+const COMPRESSION_FLAGS: &[&str] = &[
+    "-q",
+    "--quiet",
+    "-1",
+    "-2",
+    "-3",
+    "-4",
+    "-5",
+    "-6",
+    "-7",
+    "-8",
+    "-9",
+    "--grammar",
+    "--no-grammar",
+    "--no-model",
+    "--solid",
+    "--dedup",
+    "--no-sync",
+];
+
+/// Flags of the commands that print a summary unless told not to.
+const QUIET_FLAGS: &[&str] = &["-q", "--quiet"];
+
+/// Options of the `decompress` and `extract` commands that take a value.
+const DECOMPRESSION_VALUE_OPTIONS: &[&str] = &[
+    "--dict",
+    "--max-block-size",
+    "--max-output",
+    "--max-rules",
+    "--memory-limit",
+    "--password",
+    "--key-file",
+];
+
+/// Positional arguments plus `--name value` options and bare flags.
+pub(crate) struct ParsedArgs {
+    pub positional: Vec<String>,
+    pub options: HashMap<String, String>,
+    pub flags: HashSet<String>,
+}
+
+impl ParsedArgs {
+    /// Parses `args`; names listed in `with_value` consume the following
+    /// argument and names listed in `flags` stand alone. Any other name is a
+    /// usage error.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I, with_value: &[&str], flags: &[&str]) -> io::Result<Self> {
+        let mut parsed = ParsedArgs {
+            positional: Vec::new(),
+            options: HashMap::new(),
+            flags: HashSet::new(),
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg.len() > 1 && arg.starts_with('-') {
+                if with_value.contains(&arg.as_str()) {
+                    let value = args.next().ok_or_else(|| usage_error(format!("{} needs a value", arg)))?;
+                    parsed.options.insert(arg, value);
+                } else if flags.contains(&arg.as_str()) {
+                    parsed.flags.insert(arg);
+                } else {
+                    return Err(usage_error(format!("unknown option: {}", arg)));
+                }
+            } else {
+                parsed.positional.push(arg);
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Looks up an option given under any of `names`.
+    pub fn option(&self, names: &[&str]) -> Option<&str> {
+        names.iter().find_map(|name| self.options.get(*name)).map(String::as_str)
+    }
+
+    pub fn flag(&self, names: &[&str]) -> bool {
+        names.iter().any(|name| self.flags.contains(*name))
+    }
+
+    /// Parses an option value, naming the option in the error.
+    pub fn parsed_option<T: std::str::FromStr>(&self, names: &[&str]) -> io::Result<Option<T>> {
+        match self.option(names) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| usage_error(format!("invalid value for {}: {}", names[0], value))),
+            None => Ok(None),
+        }
+    }
+
+    /// Parses a size option such as `65536`, `64K`, `4M` or `1G`.
+    pub fn size_option(&self, names: &[&str]) -> io::Result<Option<usize>> {
+        match self.option(names) {
+            Some(value) => parse_size(value)
+                .map(Some)
+                .ok_or_else(|| usage_error(format!("invalid size for {}: {}", names[0], value))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn positional(&self, index: usize, what: &str) -> io::Result<&str> {
+        self.positional
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| usage_error(format!("missing {}", what)))
+    }
+}
+
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1usize << 10),
+        'M' => (&value[..value.len() - 1], 1 << 20),
+        'G' => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+pub(crate) fn usage_error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{}\n\n{}", msg, USAGE))
+}
+
+/// Runs the command line `args` (without the program name).
+pub fn run<I: IntoIterator<Item = String>>(args: I) -> io::Result<()> {
+    let mut args = args.into_iter();
+    let command = args.next().unwrap_or_else(|| "help".to_string());
+    match command.as_str() {
+        "compress" | "c" => {
+            let with_value = [&["-o", "--output"], COMPRESSION_VALUE_OPTIONS].concat();
+            compress(ParsedArgs::parse(args, &with_value, COMPRESSION_FLAGS)?)
+        }
+        "decompress" | "d" => {
+            let with_value = [&["-o", "--output"], DECOMPRESSION_VALUE_OPTIONS].concat();
+            decompress(ParsedArgs::parse(args, &with_value, QUIET_FLAGS)?)
+        }
+        "archive" | "a" => {
+            let with_value = [&["-o", "--output"], COMPRESSION_VALUE_OPTIONS].concat();
+            archive(ParsedArgs::parse(args, &with_value, COMPRESSION_FLAGS)?)
+        }
+        "append" => append(ParsedArgs::parse(args, COMPRESSION_VALUE_OPTIONS, COMPRESSION_FLAGS)?),
+        "extract" | "x" => {
+            let with_value = [&["-C", "--directory"], DECOMPRESSION_VALUE_OPTIONS].concat();
+            extract(ParsedArgs::parse(args, &with_value, QUIET_FLAGS)?)
+        }
+        "list" | "l" => list(ParsedArgs::parse(args, DECOMPRESSION_VALUE_OPTIONS, &[])?),
+        "train" => {
+            let with_value = [&["-o", "--output"], COMPRESSION_VALUE_OPTIONS].concat();
+            train(ParsedArgs::parse(args, &with_value, COMPRESSION_FLAGS)?)
+        }
+        "delta" => {
+            let with_value = [&["-o", "--output", "--ref"], COMPRESSION_VALUE_OPTIONS].concat();
+            delta(ParsedArgs::parse(args, &with_value, COMPRESSION_FLAGS)?)
+        }
+        "patch" => {
+            let with_value = [&["-o", "--output"], DECOMPRESSION_VALUE_OPTIONS].concat();
+            patch(ParsedArgs::parse(args, &with_value, QUIET_FLAGS)?)
+        }
+        "grep" => grep(ParsedArgs::parse(args, DECOMPRESSION_VALUE_OPTIONS, &["--lines", "--count"])?),
+        "test" | "t" => test(ParsedArgs::parse(args, DECOMPRESSION_VALUE_OPTIONS, QUIET_FLAGS)?),
+        "repair" => repair(ParsedArgs::parse(args, &["-o", "--output"], QUIET_FLAGS)?),
+        "salvage" => {
+            let with_value = [&["-o", "--output", "--fill"], DECOMPRESSION_VALUE_OPTIONS].concat();
+            salvage(ParsedArgs::parse(args, &with_value, &["-q", "--quiet", "--skip-lost"])?)
+        }
+        "grammar" | "g" => grammar(ParsedArgs::parse(args, &["-o", "--output", "--format", "--offset", "--length", "--top"], &["--stats"])?),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => Err(usage_error(format!("unknown command: {}", other))),
+    }
+}
+
+fn compress(args: ParsedArgs) -> io::Result<()> {
+    let input = args.positional(0, "input file")?;
+    let output = match args.option(&["-o", "--output"]) {
+        Some(output) => output.to_string(),
+        None => format!("{}.bpc", input),
+    };
+    let options = compression_options(&args)?;
+    let stats = compress_file_with_progress(input, &output, &options, |_| Ok(()))?;
+    if !args.flag(&["-q", "--quiet"]) {
+        print_summary(&output, &stats, stats.bytes_in, stats.bytes_out);
+    }
+    Ok(())
+}
+
+/// Builds [`CompressionOptions`] from a level preset plus any overrides.
+pub(crate) fn compression_options(args: &ParsedArgs) -> io::Result<CompressionOptions> {
+    let shorthand_level = (1..=9).rev().find(|level| args.flag(&[&format!("-{}", level)]));
+    let level = match args.parsed_option::<u32>(&["--level"])? {
+        Some(level) if (1..=9).contains(&level) => level,
+        Some(level) => return Err(usage_error(format!("level must be 1-9, got {}", level))),
+        None => shorthand_level.unwrap_or(DEFAULT_LEVEL),
+    };
+    let mut options = CompressionOptions::level(level);
+    if let Some(block_size) = args.size_option(&["--block-size"])? {
+        options = options.with_block_size(block_size);
+    }
+    if let Some(chunking) = chunking_option(args)? {
+        options = options.with_chunking(Some(chunking));
+    }
+    if let Some(threads) = args.parsed_option(&["--threads"])? {
+        options = options.with_threads(threads);
+    }
+    if let Some(depth) = args.parsed_option(&["--depth"])? {
+        options = options.with_model_depth(depth);
+    }
+    match (args.flag(&["--grammar"]), args.flag(&["--no-grammar"])) {
+        (true, true) => return Err(usage_error("--grammar and --no-grammar cannot be combined".to_string())),
+        (grammar, _) => options.stages.grammar = grammar,
+    }
+    if args.flag(&["--no-model"]) {
+        options.stages.context_model = false;
+    }
+    if let Some(checksum) = args.option(&["--checksum"]) {
+        options = options.with_checksum(checksum.parse::<ChecksumKind>().map_err(usage_error)?);
+    }
+    if let Some(limit) = args.size_option(&["--memory-limit"])? {
+        options = options.with_memory_limit(Some(limit));
+    }
+    if args.flag(&["--solid"]) {
+        options = options.with_solid(true);
+    }
+    if let Some(group) = args.parsed_option(&["--prime-group"])? {
+        options = options.with_prime_group(Some(group));
+    }
+    match args.size_option(&["--dedup-window"])? {
+        Some(window) => options = options.with_dedup_window(Some(window as u64)),
+        None if args.flag(&["--dedup"]) => options = options.with_dedup_window(Some(DEFAULT_DEDUP_WINDOW)),
+        None => {}
+    }
+    if let Some(value) = args.option(&["--recovery"]) {
+        let percent = value
+            .strip_suffix('%')
+            .unwrap_or(value)
+            .parse::<u8>()
+            .map_err(|_| usage_error(format!("invalid value for --recovery: {}", value)))?;
+        options = options.with_recovery(Some(percent));
+    }
+    if let Some(size) = args.size_option(&["--volume-size"])? {
+        options = options.with_volume_size(Some(size as u64));
+    }
+    if args.flag(&["--no-sync"]) {
+        options = options.with_sync_markers(false);
+    }
+    options = options.with_dictionary(dictionary_option(args)?);
+    options = options.with_encryption(credentials_option(args)?);
+    options.validate()?;
+    Ok(options)
+}
+
+/// Parses `--chunking <avg>` or `--chunking <min>,<avg>,<max>`.
+fn chunking_option(args: &ParsedArgs) -> io::Result<Option<ChunkSizes>> {
+    let Some(value) = args.option(&["--chunking"]) else {
+        return Ok(None);
+    };
+    let sizes: Option<Vec<usize>> = value.split(',').map(parse_size).collect();
+    match sizes.as_deref() {
+        Some(&[avg]) => Ok(Some(ChunkSizes::with_average(avg))),
+        Some(&[min, avg, max]) => Ok(Some(ChunkSizes::new(min, avg, max))),
+        _ => Err(usage_error(format!("invalid value for --chunking: {}", value))),
+    }
+}
+
+/// Loads the dictionary named by `--dict`, if any.
+fn dictionary_option(args: &ParsedArgs) -> io::Result<Option<Arc<Dictionary>>> {
+    match args.option(&["--dict"]) {
+        Some(path) => Dictionary::load(path)
+            .map(|dictionary| Some(Arc::new(dictionary)))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e))),
+        None => Ok(None),
+    }
+}
+
+/// Reads the credentials named by `--password` or `--key-file`, if any.
+fn credentials_option(args: &ParsedArgs) -> io::Result<Option<Arc<Credentials>>> {
+    match (args.option(&["--password"]), args.option(&["--key-file"])) {
+        (Some(_), Some(_)) => Err(usage_error("--password and --key-file cannot be combined".to_string())),
+        (Some(password), None) => Ok(Some(Arc::new(Credentials::password(password)))),
+        (None, Some(path)) => Credentials::key_file(path)
+            .map(|credentials| Some(Arc::new(credentials)))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Builds [`DecompressOptions`] from `--dict`, the credentials and the limit
+/// options.
+fn decompress_options(args: &ParsedArgs) -> io::Result<DecompressOptions> {
+    Ok(DecompressOptions::new()
+        .with_dictionary(dictionary_option(args)?)
+        .with_encryption(credentials_option(args)?)
+        .with_max_block_size(args.size_option(&["--max-block-size"])?)
+        .with_max_output(args.size_option(&["--max-output"])?.map(|size| size as u64))
+        .with_max_grammar_rules(args.parsed_option(&["--max-rules"])?)
+        .with_memory_limit(args.size_option(&["--memory-limit"])?))
+}
+
+fn decompress(args: ParsedArgs) -> io::Result<()> {
+    let input = args.positional(0, "input file")?;
+    let output = match args.option(&["-o", "--output"]) {
+        Some(output) => output.to_string(),
+        None => decoded_name(input)?,
+    };
+    let stats = decompress_file_with_progress(input, &output, &decompress_options(&args)?, |_| Ok(()))?;
+    if !args.flag(&["-q", "--quiet"]) {
+        print_summary(&output, &stats, stats.bytes_out, stats.bytes_in);
+    }
+    Ok(())
+}
+
+/// Default output name for decoding `input`: without `.bpc`, and without
+/// the volume number if it names a volume (`name.bpc.001`).
+fn decoded_name(input: &str) -> io::Result<String> {
+    let set = volume_set_path(input).map(|set| set.to_string_lossy().into_owned());
+    match set.as_deref().unwrap_or(input).strip_suffix(".bpc") {
+        Some(stem) if !stem.is_empty() => Ok(stem.to_string()),
+        _ => Err(usage_error("input does not end in .bpc; pass -o <output>".to_string())),
+    }
+}
+
+fn archive(args: ParsedArgs) -> io::Result<()> {
+    let output = args.positional(0, "archive name")?;
+    let inputs = &args.positional[1..];
+    if inputs.is_empty() {
+        return Err(usage_error("nothing to archive".to_string()));
+    }
+    let options = compression_options(&args)?;
+    let stats = create_archive_with_progress(inputs, output, &options, |_| Ok(()))?;
+    if !args.flag(&["-q", "--quiet"]) {
+        print_summary(output, &stats, stats.bytes_in, stats.bytes_out);
+    }
+    Ok(())
+}
+
+fn append(args: ParsedArgs) -> io::Result<()> {
+    let target = args.positional(0, "file to append to")?;
+    let inputs = &args.positional[1..];
+    if inputs.is_empty() {
+        return Err(usage_error("nothing to append".to_string()));
+    }
+    let options = compression_options(&args)?;
+    let stats = append_files(target, inputs, &options)?;
+    if !args.flag(&["-q", "--quiet"]) {
+        print_summary(target, &stats, stats.bytes_in, stats.bytes_out);
+    }
+    Ok(())
+}
+
+fn extract(args: ParsedArgs) -> io::Result<()> {
+    let input = args.positional(0, "archive")?;
+    let dest = args.option(&["-C", "--directory"]).unwrap_or(".");
+    let mut archive = ArchiveReader::open_with_options(input, &decompress_options(&args)?)?;
+    let wanted = &args.positional[1..];
+    if wanted.is_empty() {
+        return archive.extract_all(dest);
+    }
+    for path in wanted {
+        let index = archive
+            .find(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("not in archive: {}", path)))?;
+        let target = archive.extract_entry(index, dest)?;
+        if !args.flag(&["-q", "--quiet"]) {
+            println!("{}", target.display());
+        }
+    }
+    Ok(())
+}
+
+fn list(args: ParsedArgs) -> io::Result<()> {
+    let archive = ArchiveReader::open_with_options(args.positional(0, "archive")?, &decompress_options(&args)?)?;
+    for entry in archive.entries() {
+        let suffix = if entry.kind == EntryKind::Directory { "/" } else { "" };
+        println!("{:o} {:>12} {}{}", entry.mode, entry.size, entry.path, suffix);
+    }
+    Ok(())
+}
+
+fn delta(args: ParsedArgs) -> io::Result<()> {
+    let reference = args
+        .option(&["--ref"])
+        .ok_or_else(|| usage_error("delta needs --ref <reference file>".to_string()))?;
+    let input = args.positional(0, "input file")?;
+    let output = match args.option(&["-o", "--output"]) {
+        Some(output) => output.to_string(),
+        None => format!("{}.patch.bpc", input),
+    };
+    let options = compression_options(&args)?;
+    let stats = create_patch_file(reference, input, &output, &options)?;
+    if !args.flag(&["-q", "--quiet"]) {
+        let ratio = if stats.patch_len == 0 { 0.0 } else { stats.target_len as f64 / stats.patch_len as f64 };
+        println!(
+            "{}: {} -> {} bytes (ratio {:.3}), {} bytes copied from {}, {} new",
+            Path::new(&output).display(),
+            stats.target_len,
+            stats.patch_len,
+            ratio,
+            stats.copied,
+            reference,
+            stats.inserted
+        );
+    }
+    Ok(())
+}
+
+fn patch(args: ParsedArgs) -> io::Result<()> {
+    let reference = args.positional(0, "reference file")?;
+    let input = args.positional(1, "patch file")?;
+    let output = match args.option(&["-o", "--output"]) {
+        Some(output) => output.to_string(),
+        None => match input.strip_suffix(".patch.bpc") {
+            Some(stem) if !stem.is_empty() => stem.to_string(),
+            _ => return Err(usage_error("patch does not end in .patch.bpc; pass -o <output>".to_string())),
+        },
+    };
+    let written = apply_patch_file(reference, input, &output, &decompress_options(&args)?)?;
+    if !args.flag(&["-q", "--quiet"]) {
+        println!("{}: {} bytes, hash verified", Path::new(&output).display(), written);
+    }
+    Ok(())
+}
+
+fn test(args: ParsedArgs) -> io::Result<()> {
+    let inputs = &args.positional;
+    if inputs.is_empty() {
+        return Err(usage_error("nothing to test".to_string()));
+    }
+    let options = decompress_options(&args)?;
+    let mut failed = 0;
+    for input in inputs {
+        match test_file(input, &options) {
+            Ok(status) if !args.flag(&["-q", "--quiet"]) => println!("{}: {}", input, status),
+            Ok(_) => {}
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", input, e);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} of {} files failed the test", failed, inputs.len())));
+    }
+    Ok(())
+}
+
+/// Decodes every block of `input` and checks it against its recovery
+/// records, if it has any. The error for a damaged file says whether it
+/// can be repaired.
+fn test_file(input: &str, options: &DecompressOptions) -> io::Result<String> {
+    let recovery = check_recovery(input)?;
+    if let Some(report) = recovery.as_ref().filter(|report| !report.is_intact()) {
+        let damage = match report.damaged.len() {
+            0 => "recovery records damaged".to_string(),
+            ranges => format!("{} bytes damaged in {} places", report.damaged_bytes(), ranges),
+        };
+        let verdict = if report.recoverable { "repairable with `blockpiper repair`" } else { "too damaged to repair" };
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}; {}", damage, verdict)));
+    }
+    let decoded = (|| -> io::Result<()> {
+        let header = Header::read(&mut BufReader::new(VolumeReader::open(input)?))?;
+        if header.is_archive() {
+            let mut archive = ArchiveReader::open_with_options(input, options)?;
+            for index in 0..archive.entries().len() {
+                archive.read_entry(index, &mut io::sink())?;
+            }
+        } else {
+            decompress_stream(BufReader::new(VolumeReader::open(input)?), io::sink(), options)?;
+        }
+        Ok(())
+    })();
+    match (decoded, recovery) {
+        (Ok(()), Some(report)) => Ok(format!("ok ({}% recovery records)", report.percent)),
+        (Ok(()), None) => Ok("ok".to_string()),
+        (Err(e), None) if e.kind() == io::ErrorKind::InvalidData || e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(io::Error::new(e.kind(), format!("{}; no recovery records to repair it from", e)))
+        }
+        (Err(e), _) => Err(e),
+    }
+}
+
+fn repair(args: ParsedArgs) -> io::Result<()> {
+    let input = args.positional(0, "file to repair")?;
+    let output = args.option(&["-o", "--output"]).unwrap_or(input);
+    let report = repair_file(input, output)?;
+    if !args.flag(&["-q", "--quiet"]) {
+        if report.is_intact() {
+            println!("{}: no damage found", Path::new(output).display());
+        } else if report.damaged.is_empty() {
+            println!("{}: data intact, recovery records rewritten", Path::new(output).display());
+        } else {
+            println!(
+                "{}: rebuilt {} damaged bytes in {} places, recovery records rewritten",
+                Path::new(output).display(),
+                report.damaged_bytes(),
+                report.damaged.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+fn salvage(args: ParsedArgs) -> io::Result<()> {
+    let input = args.positional(0, "file to salvage")?;
+    let output = match args.option(&["-o", "--output"]) {
+        Some(output) => output.to_string(),
+        None => decoded_name(input)?,
+    };
+    let placeholder = match (args.option(&["--fill"]), args.flag(&["--skip-lost"])) {
+        (Some(_), true) => return Err(usage_error("--fill and --skip-lost cannot be combined".to_string())),
+        (Some(value), false) => Some(parse_byte(value).ok_or_else(|| usage_error(format!("invalid value for --fill: {}", value)))?),
+        (None, true) => None,
+        (None, false) => Some(0),
+    };
+    let report = salvage_file(input, &output, &decompress_options(&args)?, placeholder)?;
+    if !args.flag(&["-q", "--quiet"]) {
+        println!("{}: recovered {} bytes in {} blocks", Path::new(&output).display(), report.recovered_bytes, report.blocks);
+    }
+    if report.is_complete() {
+        return Ok(());
+    }
+    for range in &report.lost {
+        eprintln!("lost bytes {}..{} ({} bytes)", range.start, range.end, range.end - range.start);
+    }
+    if report.tail_lost {
+        eprintln!("lost everything from byte {} on", report.recovered_bytes + report.lost_bytes());
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "stream was damaged; not everything could be recovered"))
+}
+
+/// Parses a byte given as decimal or as `0x` hex.
+fn parse_byte(value: &str) -> Option<u8> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn train(args: ParsedArgs) -> io::Result<()> {
+    let output = args
+        .option(&["-o", "--output"])
+        .ok_or_else(|| usage_error("train needs -o <dictionary file>".to_string()))?;
+    if args.positional.is_empty() {
+        return Err(usage_error("no sample files given".to_string()));
+    }
+    for option in ["--dict", "--password", "--key-file"] {
+        if args.option(&[option]).is_some() {
+            return Err(usage_error(format!("{} cannot be used when training", option)));
+        }
+    }
+    let options = compression_options(&args)?;
+    let dictionary = Dictionary::train_files(&args.positional, &options)?;
+    dictionary.save(output)?;
+    if !args.flag(&["-q", "--quiet"]) {
+        println!(
+            "{}: dictionary {:016x}, {} rules, {} bytes",
+            Path::new(output).display(),
+            dictionary.id(),
+            dictionary.seed().rule_count(),
+            dictionary.to_bytes().len()
+        );
+    }
+    Ok(())
+}
+
+fn grammar(args: ParsedArgs) -> io::Result<()> {
+    let input = args.positional(0, "input file")?;
+    let format = match args.option(&["--format"]) {
+        Some(format) => format.parse::<ExportFormat>().map_err(usage_error)?,
+        None => ExportFormat::default(),
+    };
+    let offset = args.size_option(&["--offset"])?.unwrap_or(0);
+    let length = args.size_option(&["--length"])?.unwrap_or(DEFAULT_BLOCK_SIZE);
+
+    let mut file = File::open(input)?;
+    file.seek(SeekFrom::Start(offset as u64))?;
+    let mut data = Vec::with_capacity(length);
+    file.take(length as u64).read_to_end(&mut data)?;
+    let mut grammar = Grammar::new();
+    grammar.infer_grammar(&data);
+
+    if args.flag(&["--stats"]) {
+        let top = args.parsed_option(&["--top"])?.unwrap_or(10);
+        print_grammar_stats(&grammar, &data, top);
+        return Ok(());
+    }
+    match args.option(&["-o", "--output"]) {
+        Some(output) => {
+            let mut writer = BufWriter::new(File::create(output)?);
+            write_grammar(&grammar, format, &mut writer)?;
+            writer.flush()
+        }
+        None => write_grammar(&grammar, format, &mut io::stdout().lock()),
+    }
+}
+
+/// Longest stretch `grep --lines` looks at on either side of a match.
+const MAX_LINE_CONTEXT: u64 = 4096;
+
+fn grep(args: ParsedArgs) -> io::Result<()> {
+    let pattern = args.positional(0, "pattern")?.as_bytes();
+    let files = &args.positional[1..];
+    if files.is_empty() {
+        return Err(usage_error("no files given".to_string()));
+    }
+    if pattern.is_empty() {
+        return Err(usage_error("empty pattern".to_string()));
+    }
+    let options = decompress_options(&args)?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    for file in files {
+        let prefix = if files.len() > 1 { format!("{}:", file) } else { String::new() };
+        let mut reader = RandomAccessReader::open_with_options(file, &options).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file, e)))?;
+        let matches = reader.find(pattern)?;
+        if args.flag(&["--count"]) {
+            writeln!(out, "{}{}", prefix, matches.len())?;
+            continue;
+        }
+        if !args.flag(&["--lines"]) {
+            for offset in matches {
+                writeln!(out, "{}{}", prefix, offset)?;
+            }
+            continue;
+        }
+        // One line of output per matching line, at its first match.
+        let mut printed_up_to = 0;
+        for offset in matches {
+            if offset < printed_up_to {
+                continue;
+            }
+            let before = reader.read_range(offset.saturating_sub(MAX_LINE_CONTEXT).max(printed_up_to)..offset)?;
+            let line_start = offset - before.len() as u64 + before.iter().rposition(|&b| b == b'\n').map_or(0, |pos| pos as u64 + 1);
+            let match_end = offset + pattern.len() as u64;
+            let after = reader.read_range(match_end..match_end + MAX_LINE_CONTEXT)?;
+            let line_end = match_end + after.iter().position(|&b| b == b'\n').unwrap_or(after.len()) as u64;
+            let line = reader.read_range(line_start..line_end)?;
+            writeln!(out, "{}{}:{}", prefix, offset, String::from_utf8_lossy(&line))?;
+            printed_up_to = line_end + 1;
+        }
+    }
+    out.flush()
+}
+
+fn print_grammar_stats(grammar: &Grammar, data: &[u8], top: usize) {
+    let analysis = GrammarAnalysis::new(grammar);
+    let potential = analysis.compression_potential();
+    println!(
+        "{} bytes: {} rules, start sequence of {} symbols",
+        analysis.expanded_len(),
+        analysis.rule_count(),
+        grammar.sequence.len()
+    );
+    println!("derivation depth: max {}, average {:.2}", analysis.max_depth(), analysis.avg_depth());
+    println!(
+        "estimate: {} grammar symbols, ~{:.0} bytes ({:.3} bits/byte, ratio {:.3})",
+        potential.grammar_symbols,
+        potential.estimated_bits / 8.0,
+        potential.estimated_bits_per_byte(),
+        potential.estimated_ratio()
+    );
+    let print_rules = |title: &str, rules: Vec<&RuleInfo>| {
+        println!("{}:", title);
+        for rule in rules {
+            let first = rule.occurrences.first().copied().unwrap_or(0);
+            let preview = &data[first..(first + rule.length.min(40)).min(data.len())];
+            let escaped: String = preview.iter().flat_map(|&b| std::ascii::escape_default(b)).map(char::from).collect();
+            println!(
+                "  R{:<8} {:>8} bytes {:>8}x  depth {:<3} \"{}\"{}",
+                rule.id,
+                rule.length,
+                rule.occurrences.len(),
+                rule.depth,
+                escaped,
+                if rule.length > 40 { "…" } else { "" }
+            );
+        }
+    };
+    print_rules("longest repeats", analysis.longest_repeats(top));
+    print_rules("most frequent repeats", analysis.most_frequent_repeats(top));
+}
+
+fn print_summary(output: &str, stats: &Progress, original: u64, compressed: u64) {
+    let ratio = if compressed == 0 { 0.0 } else { original as f64 / compressed as f64 };
+    println!(
+        "{}: {} -> {} bytes (ratio {:.3})",
+        Path::new(output).display(),
+        original,
+        compressed,
+        ratio
+    );
+    if stats.duplicate_blocks > 0 {
+        println!("  {} duplicate blocks ({} bytes) stored as references", stats.duplicate_blocks, stats.duplicate_bytes);
+    }
+    if stats.recovery_bytes > 0 {
+        println!("  {} bytes of recovery records", stats.recovery_bytes);
+    }
+    if stats.volumes > 0 {
+        println!("  split into {} volumes: {} ...", stats.volumes, volume_path(output, 1).display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> io::Result<ParsedArgs> {
+        ParsedArgs::parse(args.iter().map(|arg| arg.to_string()), COMPRESSION_VALUE_OPTIONS, COMPRESSION_FLAGS)
+    }
+
+    #[test]
+    fn known_options_are_parsed() {
+        let args = parse(&["in", "--block-size", "4M", "-9", "--solid", "-"]).unwrap();
+        assert_eq!(args.positional, ["in", "-"]);
+        assert_eq!(args.option(&["--block-size"]), Some("4M"));
+        assert!(args.flag(&["-9"]) && args.flag(&["--solid"]));
+    }
+
+    #[test]
+    fn unknown_options_are_usage_errors() {
+        for arg in ["--blocksize", "-x", "--extract"] {
+            let error = parse(&["in", arg]).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(error.to_string().starts_with(&format!("unknown option: {}", arg)));
+        }
+    }
+}
@@ -0,0 +1,496 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::container::{BlockRecord, Header, LimitExceeded};
+use crate::ctw::Ctw;
+use crate::dictionary::Dictionary;
+use crate::encryption::encryption::BlockCipher;
+use crate::grammar::index::{find_bytes, PatternScan};
+use crate::grammar::GrammarIndex;
+use crate::volume::VolumeReader;
+use super::compressor::{decode_stages, map_primed, DecodedBlock};
+use super::dedup::StoredBlock;
+use super::options::DecompressOptions;
+
+/// Decoded blocks kept by a [`RandomAccessReader`].
+const CACHED_BLOCKS: usize = 8;
+
+/// Where one block sits in the stream and in the decompressed output.
+#[derive(Debug, Clone, Copy)]
+struct BlockLocation {
+    record_offset: u64,
+    output_offset: u64,
+    len: usize,
+}
+
+/// A decoded block, ready to answer range queries.
+enum BlockContent {
+    Grammar(GrammarIndex),
+    Bytes(Vec<u8>),
+}
+
+impl BlockContent {
+    /// Decodes `record` up to its grammar, if it has one, starting from the
+    /// context model `primer` in a primed stream. Also returns the model the
+    /// block leaves behind. A reference to another block is returned as it
+    /// is and leaves the model as it was.
+    fn decode(record: BlockRecord, header: &Header, options: &DecompressOptions, primer: Option<Ctw>) -> io::Result<(StoredBlock<Self>, Option<Ctw>)> {
+        let dictionary = options.dictionary.as_deref().filter(|_| header.dictionary_id.is_some());
+        let mut primer = Some(primer);
+        let stored = StoredBlock::from_record(record, header)?.try_map(|record| {
+            let (decoded, next) = decode_stages(record.payload, record.orig_len, header, dictionary, options.max_rules(), primer.take().flatten())?;
+            primer = Some(next);
+            Ok(match decoded {
+                DecodedBlock::Bytes(bytes) => BlockContent::Bytes(bytes),
+                DecodedBlock::Grammar(grammar) => {
+                    let index = GrammarIndex::new(grammar, record.orig_len).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    if index.len() != record.orig_len {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "block does not decode to its recorded length"));
+                    }
+                    BlockContent::Grammar(index)
+                }
+            })
+        })?;
+        Ok((stored, primer.flatten()))
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            BlockContent::Grammar(index) => index.len(),
+            BlockContent::Bytes(bytes) => bytes.len(),
+        }
+    }
+
+    fn read_into(&self, range: Range<usize>, out: &mut Vec<u8>) {
+        match self {
+            BlockContent::Grammar(index) => index.read_into(range, out),
+            BlockContent::Bytes(bytes) => out.extend_from_slice(&bytes[range.start.min(bytes.len())..range.end.min(bytes.len())]),
+        }
+    }
+
+    fn find(&self, pattern: &[u8]) -> Vec<usize> {
+        match self {
+            BlockContent::Grammar(index) => index.find(pattern),
+            BlockContent::Bytes(bytes) => find_bytes(bytes, pattern),
+        }
+    }
+}
+
+/// What [`RandomAccessReader::find`] needs from one block to stitch its
+/// matches to its neighbours'.
+struct BlockMatches {
+    len: usize,
+    offsets: Vec<usize>,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl BlockMatches {
+    /// Searches `content` for `pattern`, keeping `keep` bytes at each end.
+    fn new(content: &BlockContent, pattern: &[u8], keep: usize) -> Self {
+        let len = content.len();
+        let mut head = Vec::new();
+        content.read_into(0..keep, &mut head);
+        let mut tail = Vec::new();
+        content.read_into(len.saturating_sub(keep)..len, &mut tail);
+        BlockMatches { len, offsets: content.find(pattern), head, tail }
+    }
+}
+
+/// Reads arbitrary byte ranges of a `.bpc` stream's decompressed contents.
+///
+/// Opening scans the block headers to learn where every block starts in the
+/// output. A range query then decodes only the blocks it overlaps, and within
+/// each block walks its grammar with precomputed rule lengths instead of
+/// expanding the whole block. Decoded blocks are cached, so nearby queries
+/// are cheap.
+///
+/// In a primed stream a block can only be decoded after the blocks before
+/// it in its group, so the first read in a group costs up to a whole group;
+/// reading on from there continues from where the last block left off.
+/// A deduplicated block is read from the block it refers to.
+///
+/// Block checksums cover whole blocks and are therefore not verified; use
+/// [`decompress_file`](super::decompress_file) to check a stream's integrity.
+/// The reader also implements [`Read`] and [`Seek`] over the decompressed
+/// contents.
+pub struct RandomAccessReader<R: Read + Seek> {
+    reader: R,
+    header: Header,
+    options: DecompressOptions,
+    cipher: Option<BlockCipher>,
+    blocks: Vec<BlockLocation>,
+    len: u64,
+    /// Most recently used last.
+    cache: Vec<(usize, BlockContent)>,
+    /// Blocks found to be references, and the block each refers to.
+    references: HashMap<usize, usize>,
+    /// In a primed stream, the last block decoded and the model it left
+    /// behind.
+    primer: Option<(usize, Ctw)>,
+    pos: u64,
+}
+
+impl RandomAccessReader<BufReader<VolumeReader>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_with_options(path, &DecompressOptions::default())
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(path: P, options: &DecompressOptions) -> io::Result<Self> {
+        Self::with_options(BufReader::new(VolumeReader::open(path)?), options)
+    }
+}
+
+impl<R: Read + Seek> RandomAccessReader<R> {
+    /// Reads the header and the length of every block.
+    pub fn new(reader: R) -> io::Result<Self> {
+        Self::with_options(reader, &DecompressOptions::default())
+    }
+
+    pub fn with_options(mut reader: R, options: &DecompressOptions) -> io::Result<Self> {
+        let header = Header::read(&mut reader)?;
+        if header.is_archive() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "stream is a multi-file archive; extract it instead"));
+        }
+        Dictionary::for_header(&header, options.dictionary.as_deref())?;
+        let cipher = options.cipher(&header)?;
+        let mut record_offset = reader.stream_position()?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(record_offset))?;
+
+        let mut blocks = Vec::new();
+        let mut len = 0u64;
+        while let Some((orig_len, framed_len)) = BlockRecord::skip_in(&mut reader, &header)? {
+            if let Some(limit) = options.max_block_size.filter(|&limit| orig_len > limit) {
+                return Err(LimitExceeded::BlockSize { size: orig_len as u64, limit: limit as u64 }.into());
+            }
+            blocks.push(BlockLocation { record_offset, output_offset: len, len: orig_len });
+            record_offset += framed_len as u64;
+            len += orig_len as u64;
+        }
+        if record_offset > end {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block"));
+        }
+        header.check_decoded_len(len, true)?;
+        Ok(RandomAccessReader {
+            reader,
+            header,
+            options: options.clone(),
+            cipher,
+            blocks,
+            len,
+            cache: Vec::new(),
+            references: HashMap::new(),
+            primer: None,
+            pos: 0,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Length of the decompressed contents.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Offsets in the decompressed contents covered by block `block`.
+    pub fn block_range(&self, block: usize) -> Range<u64> {
+        let location = self.blocks[block];
+        location.output_offset..location.output_offset + location.len as u64
+    }
+
+    /// Bytes `range` of block `block`, clamped to the block's length.
+    pub fn read_block_range(&mut self, block: usize, range: Range<usize>) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.block(block)?.read_into(range, &mut out);
+        Ok(out)
+    }
+
+    /// Bytes `range` of the decompressed contents, clamped to its length.
+    pub fn read_range(&mut self, range: Range<u64>) -> io::Result<Vec<u8>> {
+        let (start, end) = (range.start, range.end.min(self.len));
+        let mut out = Vec::new();
+        if start >= end {
+            return Ok(out);
+        }
+        let mut block = self.blocks.partition_point(|location| location.output_offset + location.len as u64 <= start);
+        while block < self.blocks.len() && self.blocks[block].output_offset < end {
+            let location = self.blocks[block];
+            let from = start.saturating_sub(location.output_offset) as usize;
+            let to = (end - location.output_offset).min(location.len as u64) as usize;
+            self.block(block)?.read_into(from..to, &mut out);
+            block += 1;
+        }
+        Ok(out)
+    }
+
+    /// Offsets of every occurrence of `pattern` in the decompressed contents,
+    /// in increasing order and including overlapping ones.
+    ///
+    /// Blocks are decoded in parallel up to their grammars and searched with
+    /// [`GrammarIndex::find`], without being expanded. Matches that span
+    /// blocks are found from each block's first and last bytes.
+    pub fn find(&mut self, pattern: &[u8]) -> io::Result<Vec<u64>> {
+        let mut found = Vec::new();
+        if pattern.is_empty() {
+            return Ok(found);
+        }
+        let keep = pattern.len() - 1;
+        let mut scan = PatternScan::new(pattern, 0);
+        let batch_len = rayon::current_num_threads().max(1).saturating_mul(self.header.prime_group.unwrap_or(1) as usize);
+        let mut primer = None;
+        let mut next = 0;
+        while next < self.blocks.len() {
+            let first_block = next as u64;
+            let mut offset = self.blocks[next].record_offset;
+            self.reader.seek(SeekFrom::Start(offset))?;
+            let mut records = Vec::with_capacity(batch_len);
+            let mut batch_memory = 0;
+            while records.len() < batch_len && next < self.blocks.len() {
+                let record = self
+                    .options
+                    .read_record(&mut self.reader, &self.header, self.cipher.as_ref(), &mut offset)?
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block"))?;
+                match self.options.fit_in_batch(&self.header, batch_memory, &record) {
+                    Some(total) => batch_memory = total,
+                    None => break,
+                }
+                records.push(record);
+                next += 1;
+            }
+            let (header, options) = (&self.header, &self.options);
+            let batch = map_primed(records, first_block, header.prime_group, &mut primer, |record, primer| {
+                let (stored, primer) = BlockContent::decode(record, header, options, primer)?;
+                Ok((stored.try_map(|content| Ok(BlockMatches::new(&content, pattern, keep)))?, primer))
+            });
+            for (number, block) in (first_block as usize..).zip(batch) {
+                let block = match block? {
+                    StoredBlock::Coded(block) => block,
+                    StoredBlock::Reference { .. } => BlockMatches::new(self.block(number)?, pattern, keep),
+                };
+                let start = scan.pos;
+                scan.append(&block.head, &block.tail, block.len as u64, |offset| found.push(offset));
+                found.extend(block.offsets.iter().map(|&offset| start + offset as u64));
+            }
+        }
+        Ok(found)
+    }
+
+    /// Block `block`, decoded up to its grammar, from the cache if possible.
+    /// A reference is read from the block it refers to.
+    fn block(&mut self, block: usize) -> io::Result<&BlockContent> {
+        let block = self.references.get(&block).copied().unwrap_or(block);
+        if !self.touch_cached(block) {
+            let (number, content) = match self.decode_block(block)? {
+                StoredBlock::Coded(content) => (block, content),
+                StoredBlock::Reference { target, .. } => {
+                    let target = usize::try_from(target)
+                        .ok()
+                        .filter(|&target| target < block && self.blocks[target].len == self.blocks[block].len)
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid block reference"))?;
+                    self.references.insert(block, target);
+                    if self.touch_cached(target) {
+                        return Ok(&self.cache.last().unwrap().1);
+                    }
+                    match self.decode_block(target)? {
+                        StoredBlock::Coded(content) => (target, content),
+                        StoredBlock::Reference { .. } => {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, "block reference to another reference"));
+                        }
+                    }
+                }
+            };
+            if self.cache.len() == CACHED_BLOCKS {
+                self.cache.remove(0);
+            }
+            self.cache.push((number, content));
+        }
+        Ok(&self.cache.last().unwrap().1)
+    }
+
+    /// Moves block `block` to the most recently used end of the cache, if it
+    /// is there.
+    fn touch_cached(&mut self, block: usize) -> bool {
+        match self.cache.iter().position(|(number, _)| *number == block) {
+            Some(pos) => {
+                let entry = self.cache.remove(pos);
+                self.cache.push(entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Decodes block `block` up to its grammar, bypassing the cache.
+    fn decode_block(&mut self, block: usize) -> io::Result<StoredBlock<BlockContent>> {
+        // In a primed stream, decode from the start of the block's group, or
+        // from the last block decoded if that is on the way.
+        let (mut first, mut primer) = (block, None);
+        if let Some(group) = self.header.prime_group {
+            first = block - block % group as usize;
+            if let Some((last, model)) = self.primer.take().filter(|(last, _)| (first..block).contains(last)) {
+                (first, primer) = (last + 1, Some(model));
+            }
+        }
+        let mut offset = self.blocks[first].record_offset;
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let mut content = None;
+        for number in first..=block {
+            let record = self
+                .options
+                .read_record(&mut self.reader, &self.header, self.cipher.as_ref(), &mut offset)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block"))?;
+            let decoded = BlockContent::decode(record, &self.header, &self.options, primer)?;
+            primer = decoded.1;
+            if number == block {
+                content = Some(decoded.0);
+            }
+        }
+        self.primer = primer.map(|model| (block, model));
+        Ok(content.unwrap())
+    }
+}
+
+impl<R: Read + Seek> Read for RandomAccessReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.read_range(self.pos..self.pos.saturating_add(buf.len() as u64))?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+}
+
+impl<R: Read + Seek> Seek for RandomAccessReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::compressor::compressor::{compress_stream, decompress_stream, Progress};
+    use crate::compressor::options::CompressionOptions;
+    use crate::container::Stages;
+
+    const BLOCK: usize = 256;
+
+    /// Text with runs of one byte, where every fourth block repeats the
+    /// first so deduplication has something to refer to.
+    fn sample() -> Vec<u8> {
+        let words: [&[u8]; 5] = [b"abab", b"to be ", b"aaaaaaa", b"xyz\n", b"ababab "];
+        let mut seed = 11u32;
+        let mut text = Vec::new();
+        while text.len() < 16 * BLOCK {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            text.extend_from_slice(words[(seed >> 16) as usize % words.len()]);
+        }
+        let mut data = Vec::new();
+        for (number, block) in text.chunks_exact(BLOCK).enumerate() {
+            data.extend_from_slice(if number % 4 == 3 { &text[..BLOCK] } else { block });
+        }
+        data.extend_from_slice(b"aaaab");
+        data
+    }
+
+    fn compress(data: &[u8], options: &CompressionOptions) -> (Vec<u8>, Progress) {
+        let mut stream = Vec::new();
+        let stats = compress_stream(data, &mut stream, &options.clone().with_block_size(BLOCK)).unwrap();
+        let mut decoded = Vec::new();
+        decompress_stream(&stream[..], &mut decoded, &DecompressOptions::new()).unwrap();
+        assert_eq!(decoded, data);
+        (stream, stats)
+    }
+
+    /// Checks ranges and searches of `stream` against `data`.
+    fn check(stream: Vec<u8>, data: &[u8], options: &DecompressOptions) {
+        let mut reader = RandomAccessReader::with_options(Cursor::new(stream), options).unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+        assert!(reader.block_count() > 12);
+
+        let mut patterns: Vec<Vec<u8>> = vec![b"a".to_vec(), b"aa".to_vec(), b"aaab".to_vec(), b"abab".to_vec(), b"missing".to_vec()];
+        for block in 1..reader.block_count() {
+            let boundary = reader.block_range(block).start as usize;
+            for (before, after) in [(1, 1), (3, 5), (20, 300)] {
+                let range = boundary - before..(boundary + after).min(data.len());
+                assert_eq!(reader.read_range(range.start as u64..range.end as u64).unwrap(), &data[range]);
+            }
+            // Every search decodes the whole stream, so only some
+            // boundaries are searched across.
+            if block % 3 == 1 {
+                patterns.push(data[boundary - 3..boundary + 5].to_vec());
+            }
+        }
+        // Backwards, so every read misses the blocks decoded last.
+        for start in (0..data.len()).step_by(997).rev() {
+            assert_eq!(reader.read_range(start as u64..start as u64 + 1500).unwrap(), &data[start..(start + 1500).min(data.len())]);
+        }
+        for pattern in &patterns {
+            let naive: Vec<u64> = find_bytes(data, pattern).into_iter().map(|offset| offset as u64).collect();
+            assert_eq!(reader.find(pattern).unwrap(), naive, "pattern {:?}", String::from_utf8_lossy(pattern));
+        }
+
+        let mut all = Vec::new();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+    }
+
+    #[test]
+    fn ranges_and_matches_agree_with_a_full_decode() {
+        let data = sample();
+        let grammar = Stages { grammar: true, context_model: true };
+        for options in [
+            CompressionOptions::new(),
+            CompressionOptions::new().with_stages(grammar),
+            CompressionOptions::new().with_stages(grammar).with_prime_group(Some(4)),
+        ] {
+            let (stream, _) = compress(&data, &options);
+            check(stream, &data, &DecompressOptions::new());
+        }
+    }
+
+    #[test]
+    fn references_are_read_from_their_targets() {
+        let data = sample();
+        for options in [
+            CompressionOptions::new().with_dedup_window(Some(1 << 20)),
+            CompressionOptions::new().with_dedup_window(Some(1 << 20)).with_prime_group(Some(3)),
+        ] {
+            let (stream, stats) = compress(&data, &options);
+            assert_eq!(stats.duplicate_blocks, 4);
+            check(stream, &data, &DecompressOptions::new());
+        }
+    }
+
+    #[test]
+    fn find_continues_past_a_full_batch() {
+        // A memory limit that fits one block at a time ends every batch
+        // after its first record.
+        let data = sample();
+        let options = CompressionOptions::new().with_dedup_window(Some(1 << 20));
+        let (stream, _) = compress(&data, &options);
+        let limit = options.clone().with_block_size(BLOCK).estimated_block_memory() * 3 / 2;
+        check(stream, &data, &DecompressOptions::new().with_memory_limit(Some(limit)));
+    }
+}
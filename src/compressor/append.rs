@@ -0,0 +1,271 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::archive::append_to_archive;
+use crate::container::container::to_usize;
+use crate::container::{BlockRecord, Header};
+use crate::dictionary::Dictionary;
+use crate::recovery::recovery::{create_output, finish_output, protected_len, recovery_percent};
+use crate::volume::volume::volume_set_for;
+use super::compressor::{decompress_stored, encode_blocks, map_primed, Progress, StreamTail};
+use super::options::{CompressionOptions, DecompressOptions};
+
+/// Adds `inputs` to the `.bpc` file at `path`: as new entries if it is an
+/// archive (see [`append_to_archive`]), otherwise as new blocks continuing
+/// the stream with the inputs' contents, in order (see [`append_stream`]).
+///
+/// Every append copies the existing blocks into a new file, so it costs
+/// reading and writing the whole file, plus as much free disk space again,
+/// however little is added; that copy is what makes a failed append leave
+/// the file as it was. Batch many small additions into one call.
+pub fn append_files<P: AsRef<Path>, Q: AsRef<Path>>(path: P, inputs: &[Q], options: &CompressionOptions) -> io::Result<Progress> {
+    refuse_volume_set(path.as_ref(), options)?;
+    let header = Header::read(&mut BufReader::new(File::open(&path)?))?;
+    if header.is_archive() {
+        return append_to_archive(path, inputs, options);
+    }
+    let files = inputs.iter().map(File::open).collect::<io::Result<Vec<_>>>()?;
+    let mut total_in = 0;
+    for file in &files {
+        total_in += file.metadata()?.len();
+    }
+    let reader = files
+        .into_iter()
+        .fold(Box::new(io::empty()) as Box<dyn Read>, |chain, file| Box::new(chain.chain(BufReader::new(file))));
+    let mut stats = append_stream(path, reader, options)?;
+    stats.total_in = Some(total_in);
+    Ok(stats)
+}
+
+/// Appends everything readable from `reader` to the single-file stream at
+/// `path`, without touching the blocks already in it.
+///
+/// The new blocks are coded as the stream's header says (stages, model
+/// depth, checksum, priming and deduplication) and cut at its block size;
+/// `options` supply the threads plus the dictionary the stream was written
+/// with. A new block only deduplicates against other new blocks. The file
+/// is replaced atomically, so a failed append leaves it as it was, at the
+/// cost of copying the existing blocks to a new file each time. Recovery
+/// records, if the stream has them or `options` ask for them, are computed
+/// again for the whole file. Volume sets and encrypted streams cannot be
+/// appended to. The returned totals count only the new blocks.
+pub fn append_stream<P: AsRef<Path>, R: Read>(path: P, mut reader: R, options: &CompressionOptions) -> io::Result<Progress> {
+    let path = path.as_ref();
+    refuse_volume_set(path, options)?;
+    let mut file = BufReader::new(File::open(path)?);
+    let header = Header::read(&mut file)?;
+    if header.is_archive() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "stream is a multi-file archive; append entries to it instead"));
+    }
+    refuse_encrypted(&header)?;
+    let options = options.clone().with_block_size(to_usize(header.block_size)?).with_chunking(None);
+    options.validate()?;
+    Dictionary::for_header(&header, options.dictionary.as_deref())?;
+    let decode_options = DecompressOptions::new().with_dictionary(options.dictionary.clone()).with_memory_limit(options.memory_limit);
+
+    // Find the end of the last block, and where its priming group starts.
+    let mut blocks = 0u64;
+    let mut output_len = 0u64;
+    let mut offset = header.encoded_len() as u64;
+    let mut group_start = (0, offset);
+    while let Some((orig_len, framed_len)) = BlockRecord::skip_in(&mut file, &header)? {
+        if header.prime_group.is_some_and(|group| blocks.is_multiple_of(group as u64)) {
+            group_start = (blocks, offset);
+        }
+        blocks += 1;
+        output_len += orig_len as u64;
+        offset += framed_len as u64;
+    }
+    if offset != protected_len(&mut file)? {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block"));
+    }
+    header.check_decoded_len(output_len, true)?;
+    let recovery = match options.recovery {
+        Some(percent) => Some(percent),
+        None => recovery_percent(&mut file)?,
+    };
+
+    // New blocks finishing a primed group continue from the model its
+    // last block left behind, so the group so far is decoded again.
+    let mut primer = None;
+    if header.prime_group.is_some_and(|group| !blocks.is_multiple_of(group as u64)) {
+        let (first_block, mut record_offset) = group_start;
+        file.seek(SeekFrom::Start(record_offset))?;
+        let mut records = Vec::new();
+        while let Some(record) = decode_options.read_record(&mut file, &header, None, &mut record_offset)? {
+            records.push(record);
+        }
+        let dictionary = options.dictionary.as_deref();
+        let decoded = map_primed(records, first_block, header.prime_group, &mut primer, |record, primer| {
+            decompress_stored(record, &header, dictionary, usize::MAX, primer)
+        });
+        for block in decoded {
+            block?;
+        }
+    }
+
+    let (mut stats, recovery_bytes) = replace_file(path, offset, recovery, |writer| {
+        let mut stats = Progress::default();
+        let tail = StreamTail { header: &header, cipher: None, blocks, output_len, offset, primer };
+        encode_blocks(&mut reader, writer, &options, tail, &mut stats, |_| Ok(()))?;
+        // The header goes on recording the length of everything in the
+        // stream; it keeps its size, so only it is written again.
+        let header = Header { total_len: Some(output_len + stats.bytes_in), ..header };
+        writer.seek(SeekFrom::Start(0))?;
+        header.write(writer)?;
+        writer.seek(SeekFrom::End(0))?;
+        Ok(stats)
+    })?;
+    stats.add_recovery(recovery_bytes);
+    Ok(stats)
+}
+
+/// Fails if `path` names a volume set, which cannot be appended to, or if
+/// `options` ask for the result to be split into volumes.
+pub(crate) fn refuse_volume_set(path: &Path, options: &CompressionOptions) -> io::Result<()> {
+    if options.volume_size.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a file cannot be split into volumes while appending to it"));
+    }
+    match volume_set_for(path)? {
+        Some(set) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is split into volumes and cannot be appended to", set.display()))),
+        None => Ok(()),
+    }
+}
+
+/// Fails if `header` belongs to an encrypted file. Its records are sealed
+/// under nonces made from their offsets, and a copy of the file appended to
+/// separately would seal different records under the same ones.
+pub(crate) fn refuse_encrypted(header: &Header) -> io::Result<()> {
+    if header.is_encrypted() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "encrypted files cannot be appended to; compress them again instead"));
+    }
+    Ok(())
+}
+
+/// Replaces the file at `path` with its first `keep` bytes followed by what
+/// `write_tail` writes, and recovery records of `recovery` percent if set;
+/// returns what `write_tail` returned and the bytes of recovery records.
+/// The new version is written next to the old one and renamed over it, so
+/// readers see either the old file or the whole new one. Appending in place
+/// would have to overwrite the old index, trailer and recovery records
+/// before the new ones are complete, so the first `keep` bytes are copied
+/// instead: I/O and temporary disk space grow with the whole file.
+pub(crate) fn replace_file<T, F>(path: &Path, keep: u64, recovery: Option<u8>, write_tail: F) -> io::Result<(T, u64)>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<T>,
+{
+    let original = File::open(path)?;
+    let permissions = original.metadata()?.permissions();
+    let mut temp_name = OsString::from(path.as_os_str());
+    temp_name.push(".append");
+    let temp_path = PathBuf::from(temp_name);
+    let result = (|| {
+        let mut writer = BufWriter::new(create_output(&temp_path)?);
+        if io::copy(&mut original.take(keep), &mut writer)? != keep {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while appending"));
+        }
+        let value = write_tail(&mut writer)?;
+        let (file, recovery_bytes) = finish_output(writer, recovery)?;
+        file.sync_all()?;
+        fs::set_permissions(&temp_path, permissions)?;
+        fs::rename(&temp_path, path)?;
+        Ok((value, recovery_bytes))
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::archive::{create_archive, ArchiveReader};
+    use crate::compressor::compressor::{compress_file, decompress_file};
+    use crate::container::KdfParams;
+    use crate::encryption::Credentials;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockpiper-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample(len: u32, seed: u32) -> Vec<u8> {
+        (0..len).map(|i| ((i * i + seed) % 251) as u8).collect()
+    }
+
+    /// Compresses `first` with `options`, appends `second` and checks that
+    /// the stream decodes to both.
+    fn append_round_trips(name: &str, options: CompressionOptions) {
+        let dir = scratch_dir(name);
+        let (input, packed, output) = (dir.join("in"), dir.join("in.bpc"), dir.join("out"));
+        let (first, second) = (sample(2500, 1), sample(3100, 7));
+        fs::write(&input, &first).unwrap();
+        compress_file(&input, &packed, &options).unwrap();
+        let stats = append_stream(&packed, &second[..], &CompressionOptions::new()).unwrap();
+        assert_eq!(stats.bytes_in, second.len() as u64);
+        decompress_file(&packed, &output).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), [first, second].concat());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn plain_streams_round_trip() {
+        append_round_trips("append-plain", CompressionOptions::new().with_block_size(1024));
+    }
+
+    #[test]
+    fn primed_streams_round_trip() {
+        // The stream ends part way through a group, which the new blocks
+        // then finish.
+        append_round_trips("append-primed", CompressionOptions::new().with_block_size(1024).with_prime_group(Some(4)));
+    }
+
+    #[test]
+    fn archives_round_trip() {
+        for solid in [false, true] {
+            let dir = scratch_dir(if solid { "append-solid" } else { "append-archive" });
+            let (a, b, archive) = (dir.join("a"), dir.join("b"), dir.join("all.bpc"));
+            fs::write(&a, sample(2500, 1)).unwrap();
+            fs::write(&b, sample(3100, 7)).unwrap();
+            let options = CompressionOptions::new().with_block_size(1024).with_solid(solid);
+            create_archive(&[&a], &archive, &options).unwrap();
+            append_files(&archive, &[&b], &CompressionOptions::new()).unwrap();
+            let error = append_files(&archive, &[&a], &CompressionOptions::new()).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+
+            let mut reader = ArchiveReader::open(&archive).unwrap();
+            assert_eq!(reader.entries().iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+            for (index, source) in [&a, &b].into_iter().enumerate() {
+                let mut contents = Vec::new();
+                reader.read_entry(index, &mut contents).unwrap();
+                assert_eq!(contents, fs::read(source).unwrap());
+            }
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn encrypted_files_are_refused() {
+        let dir = scratch_dir("append-encrypted");
+        let (input, packed, archive) = (dir.join("in"), dir.join("in.bpc"), dir.join("all.bpc"));
+        fs::write(&input, sample(2000, 3)).unwrap();
+        let credentials = Some(Arc::new(Credentials::password("secret")));
+        let kdf = KdfParams { memory_kib: 64, iterations: 1, parallelism: 1 };
+        let options = CompressionOptions::new().with_encryption(credentials).with_kdf(kdf);
+        compress_file(&input, &packed, &options).unwrap();
+        create_archive(&[&input], &archive, &options).unwrap();
+        for path in [&packed, &archive] {
+            let before = fs::read(path).unwrap();
+            let error = append_files(path, &[&input], &options).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert_eq!(fs::read(path).unwrap(), before);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+//! Content-defined block boundaries.
+//!
+//! A Gear rolling hash runs over the input and a block ends where the hash's
+//! top bits are all zero. Boundaries therefore depend only on the bytes
+//! around them: an insertion or deletion moves the boundaries next to it,
+//! and every later block comes out the same as before.
+
+use std::io::{self, Read};
+
+use super::compressor::read_full_block;
+use super::options::CompressionOptions;
+
+/// Hash value mixed in for each byte, derived from a fixed seed so that
+/// every build cuts the same boundaries.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6a09_e667_f3bc_c908;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Sizes of content-defined blocks: none are shorter than `min` (except the
+/// last) or longer than `max`, and they average about `avg`.
+///
+/// ```
+/// use blockpiper::ChunkSizes;
+///
+/// let sizes = ChunkSizes::with_average(64 * 1024);
+/// assert_eq!((sizes.min, sizes.max), (16 * 1024, 256 * 1024));
+/// let data = vec![7u8; 100_000];
+/// assert!(sizes.cut(&data) >= sizes.min);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkSizes {
+    pub min: usize,
+    pub avg: usize,
+    pub max: usize,
+}
+
+impl ChunkSizes {
+    pub fn new(min: usize, avg: usize, max: usize) -> Self {
+        ChunkSizes { min, avg, max }
+    }
+
+    /// Blocks from a quarter to four times `avg`.
+    pub fn with_average(avg: usize) -> Self {
+        Self::new(avg / 4, avg, avg.saturating_mul(4))
+    }
+
+    /// Rejects sizes that are out of order or too small to cut on.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.min == 0 || self.avg < 2 || !(self.min <= self.avg && self.avg <= self.max) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("chunk sizes must satisfy 0 < min <= avg <= max, got {}/{}/{}", self.min, self.avg, self.max),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Length of the block that starts at `data[0]`. Unless `data` is the
+    /// end of the input it must hold at least `max` bytes, or the cut may
+    /// fall short of where it would otherwise be.
+    ///
+    /// Before `avg` bytes a boundary needs one more zero bit than after it,
+    /// which keeps block sizes close to the average.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min {
+            return data.len();
+        }
+        let bits = self.avg.ilog2();
+        let strict = top_bits(bits + 1);
+        let loose = top_bits(bits - 1);
+        let normal = data.len().min(self.avg);
+        let end = data.len().min(self.max);
+        let mut hash = 0u64;
+        for (i, &byte) in data.iter().enumerate().take(end).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if i < normal { strict } else { loose };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Mask of the `bits` most significant bits. Shifting the hash left for every
+/// byte means the top bits depend on the last 64 bytes, the low bits only on
+/// the last few.
+fn top_bits(bits: u32) -> u64 {
+    match bits {
+        0 => 0,
+        bits => !0u64 << (64 - bits.min(64)),
+    }
+}
+
+/// Cuts an input into the blocks `options` ask for: fixed-size, or at
+/// content-defined boundaries.
+pub(crate) struct BlockSplitter<R> {
+    reader: R,
+    block_size: usize,
+    chunking: Option<ChunkSizes>,
+    /// Input read but not yet handed out.
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> BlockSplitter<R> {
+    pub(crate) fn new(reader: R, options: &CompressionOptions) -> Self {
+        BlockSplitter { reader, block_size: options.block_size, chunking: options.chunking, buffer: Vec::new() }
+    }
+
+    /// The next block, or an empty one at the end of the input.
+    pub(crate) fn next_block(&mut self) -> io::Result<Vec<u8>> {
+        let Some(sizes) = self.chunking else {
+            return read_full_block(&mut self.reader, self.block_size);
+        };
+        if self.buffer.len() < sizes.max {
+            let want = sizes.max - self.buffer.len();
+            (&mut self.reader).take(want as u64).read_to_end(&mut self.buffer)?;
+        }
+        let rest = self.buffer.split_off(sizes.cut(&self.buffer));
+        Ok(std::mem::replace(&mut self.buffer, rest))
+    }
+}
+
+/// The blocks `options` cut from the front of `data`. Unless `at_end`, a
+/// final block that more input could still change is left out; the caller
+/// keeps those bytes for later.
+pub(crate) fn split_blocks<'a>(data: &'a [u8], options: &CompressionOptions, at_end: bool) -> Vec<&'a [u8]> {
+    let mut blocks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let len = match options.chunking {
+            Some(sizes) if at_end || rest.len() >= sizes.max => sizes.cut(rest),
+            None if at_end || rest.len() >= options.block_size => rest.len().min(options.block_size),
+            _ => break,
+        };
+        let (block, tail) = rest.split_at(len);
+        blocks.push(block);
+        rest = tail;
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Block boundaries `options` cut from `data`, as end offsets.
+    fn boundaries(data: &[u8], options: &CompressionOptions) -> Vec<usize> {
+        let mut splitter = BlockSplitter::new(data, options);
+        let mut ends = Vec::new();
+        let mut end = 0;
+        loop {
+            let block = splitter.next_block().unwrap();
+            if block.is_empty() {
+                break;
+            }
+            assert_eq!(block, &data[end..end + block.len()]);
+            end += block.len();
+            ends.push(end);
+        }
+        assert_eq!(split_blocks(data, options, true).iter().map(|block| block.len()).sum::<usize>(), data.len());
+        ends
+    }
+
+    #[test]
+    fn an_insertion_moves_only_nearby_boundaries() {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let data: Vec<u8> = (0..300_000)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                (seed >> 24) as u8
+            })
+            .collect();
+        let sizes = ChunkSizes::with_average(4096);
+        let options = CompressionOptions::new().with_chunking(Some(sizes));
+        let before = boundaries(&data, &options);
+        assert!(before.len() > 40);
+        let lens: Vec<usize> = before.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert!(lens.iter().all(|&len| (sizes.min..=sizes.max).contains(&len)));
+
+        let at = 150_000;
+        let mut edited = data.clone();
+        edited.insert(at, b'!');
+        let after = boundaries(&edited, &options);
+
+        // Boundaries before the insertion stay put, those after it move by
+        // one byte, and only the ones in the edited block or the next change.
+        let unchanged: Vec<usize> = before.iter().copied().filter(|&end| end <= at).collect();
+        assert_eq!(&after[..unchanged.len()], &unchanged[..]);
+        let shifted: Vec<usize> = before.iter().filter(|&&end| end > at).map(|end| end + 1).collect();
+        let moved = shifted.iter().filter(|end| !after.contains(end)).count();
+        assert!(moved <= 2, "{} boundaries moved", moved);
+        let resynced = shifted.iter().position(|end| after.contains(end)).unwrap();
+        assert!(shifted[resynced] - at <= 2 * sizes.max);
+        assert!(after.ends_with(&shifted[resynced..]));
+    }
+}
@@ -1,201 +1,1054 @@
-use std::fs::File;
-use std::io::{Read, Write, BufReader, BufWriter};
-use std::path::Path;
-use rayon::prelude::*;
-
-use crate::grammar::Grammar;
-use crate::grammar::grammar::Symbol;
-use crate::ctw::Ctw;
-use crate::arithmetic::{ArithmeticEncoder, ArithmeticDecoder};
-
-const DEFAULT_BLOCK_SIZE: usize = 256 * 1024; // 256 KB
-
-pub fn compress_file<P: AsRef<Path>>(input_path: P, output_path: P, block_size: Option<usize>) -> std::io::Result<()> {
-    let block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
-
-    let mut input_file = File::open(input_path)?;
-    let mut reader = BufReader::new(&mut input_file);
-
-    let mut output_file = File::create(output_path)?;
-    let mut writer = BufWriter::new(&mut output_file);
-
-    let mut blocks = Vec::new();
-    let mut buffer = vec![0u8; block_size];
-
-    loop {
-        let bytes_read = reader.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
-        }
-        blocks.push(buffer[..bytes_read].to_vec());
-    }
-
-    let compressed_blocks: Vec<(Vec<u8>, usize)> = blocks.par_iter().map(|block_data| {
-        // Stage 1: Grammar-Based Modeling
-        let mut grammar = Grammar::new();
-        grammar.infer_grammar(block_data);
-        let symbol_stream = serialize_grammar(&grammar);
-
-        // Stage 2 & 3: CTW and Arithmetic Coding
-        let mut ctw = Ctw::new();
-        let mut encoder = ArithmeticEncoder::new();
-
-        for &symbol in symbol_stream.iter() {
-            let (cum, total) = ctw.get_cumulative(symbol);
-            encoder.encode_symbol(symbol, (cum, total));
-            ctw.process_symbol(symbol);
-        }
-
-        (encoder.finish(), block_data.len())
-    }).collect();
-
-    // Write compressed_blocks to the output file, including block sizes and original sizes
-    for (compressed_block, orig_len) in compressed_blocks {
-        let block_len = compressed_block.len() as u32;
-        let orig_len = orig_len as u32;
-        writer.write_all(&block_len.to_le_bytes())?;
-        writer.write_all(&orig_len.to_le_bytes())?;
-        writer.write_all(&compressed_block)?;
-    }
-
-    Ok(())
-}
-
-pub fn decompress_file<P: AsRef<Path>>(input_path: P, output_path: P) -> std::io::Result<()> {
-    let mut input_file = File::open(input_path)?;
-    let mut reader = BufReader::new(&mut input_file);
-    let mut output_file = File::create(output_path)?;
-    let mut writer = BufWriter::new(&mut output_file);
-
-    let mut block_len_buf = [0u8; 4];
-    let mut orig_len_buf = [0u8; 4];
-    while reader.read_exact(&mut block_len_buf).is_ok() {
-        let block_len = u32::from_le_bytes(block_len_buf) as usize;
-        reader.read_exact(&mut orig_len_buf)?;
-        let orig_len = u32::from_le_bytes(orig_len_buf) as usize;
-        let mut compressed_block = vec![0u8; block_len];
-        reader.read_exact(&mut compressed_block)?;
-
-        // Stage 2 & 3: Arithmetic Decoding and CTW
-        let mut ctw = Ctw::new();
-        let mut decoder = ArithmeticDecoder::new(compressed_block);
-        let mut symbol_stream = Vec::new();
-        for _ in 0..(orig_len * 8) { // upper bound, should be enough for grammar serialization
-            let (cum, total) = ctw.get_cumulative(0);
-            let symbol = decoder.decode_symbol((cum, total));
-            symbol_stream.push(symbol);
-            ctw.process_symbol(symbol);
-            // Try to deserialize grammar and check if we have enough data
-            if let Some(original_block) = deserialize_grammar(&symbol_stream) {
-                if original_block.len() == orig_len {
-                    writer.write_all(&original_block)?;
-                    break;
-                }
-            }
-        }
-    }
-    Ok(())
-}
-
-pub fn serialize_grammar(grammar: &Grammar) -> Vec<u8> {
-    // Simple serialization: [num_rules][rule_id][rule_len][symbols...][sequence_len][sequence...]
-    let mut out = Vec::new();
-    out.extend(&(grammar.rules.len() as u32).to_le_bytes());
-    for (&rule_id, expansion) in &grammar.rules {
-        out.extend(&(rule_id as u32).to_le_bytes());
-        out.extend(&(expansion.len() as u32).to_le_bytes());
-        for symbol in expansion {
-            match symbol {
-                Symbol::Terminal(b) => {
-                    out.push(0); // tag for terminal
-                    out.push(*b);
-                }
-                Symbol::NonTerminal(id) => {
-                    out.push(1); // tag for nonterminal
-                    out.extend(&(*id as u32).to_le_bytes());
-                }
-            }
-        }
-    }
-    out.extend(&(grammar.sequence.len() as u32).to_le_bytes());
-    for symbol in &grammar.sequence {
-        match symbol {
-            Symbol::Terminal(b) => {
-                out.push(0);
-                out.push(*b);
-            }
-            Symbol::NonTerminal(id) => {
-                out.push(1);
-                out.extend(&(*id as u32).to_le_bytes());
-            }
-        }
-    }
-    out
-}
-
-fn deserialize_grammar(data: &[u8]) -> Option<Vec<u8>> {
-    use std::collections::HashMap;
-    let mut pos = 0;
-    let read_u32 = |data: &[u8], pos: &mut usize| {
-        if *pos + 4 > data.len() { return None; }
-        let val = u32::from_le_bytes([data[*pos], data[*pos+1], data[*pos+2], data[*pos+3]]);
-        *pos += 4;
-        Some(val)
-    };
-    let read_symbol = |data: &[u8], pos: &mut usize| -> Option<Symbol> {
-        if *pos >= data.len() { return None; }
-        let tag = data[*pos];
-        *pos += 1;
-        match tag {
-            0 => { // Terminal
-                if *pos >= data.len() { return None; }
-                let b = data[*pos];
-                *pos += 1;
-                Some(Symbol::Terminal(b))
-            }
-            1 => { // NonTerminal
-                if *pos + 4 > data.len() { return None; }
-                let id = u32::from_le_bytes([data[*pos], data[*pos+1], data[*pos+2], data[*pos+3]]) as usize;
-                *pos += 4;
-                Some(Symbol::NonTerminal(id))
-            }
-            _ => None,
-        }
-    };
-    // Read rules
-    let num_rules = read_u32(data, &mut pos)? as usize;
-    let mut rules = HashMap::new();
-    for _ in 0..num_rules {
-        let rule_id = read_u32(data, &mut pos)? as usize;
-        let rule_len = read_u32(data, &mut pos)? as usize;
-        let mut expansion = Vec::new();
-        for _ in 0..rule_len {
-            expansion.push(read_symbol(data, &mut pos)?);
-        }
-        rules.insert(rule_id, expansion);
-    }
-    // Read sequence
-    let seq_len = read_u32(data, &mut pos)? as usize;
-    let mut sequence = Vec::new();
-    for _ in 0..seq_len {
-        sequence.push(read_symbol(data, &mut pos)?);
-    }
-    // Expand the sequence using the rules
-    let mut output = Vec::new();
-    fn expand(symbol: &Symbol, rules: &HashMap<usize, Vec<Symbol>>, out: &mut Vec<u8>) {
-        match symbol {
-            Symbol::Terminal(b) => out.push(*b),
-            Symbol::NonTerminal(id) => {
-                if let Some(exp) = rules.get(id) {
-                    for s in exp {
-                        expand(s, rules, out);
-                    }
-                }
-            }
-        }
-    }
-    for s in &sequence {
-        expand(s, &rules, &mut output);
-    }
-    Some(output)
-} 
\ No newline at end of file
+use std::fs::{self, File};
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use rayon::prelude::*;
+
+use crate::grammar::Grammar;
+use crate::grammar::grammar::Symbol;
+use crate::ctw::Ctw;
+use crate::arithmetic::{ArithmeticEncoder, ArithmeticDecoder};
+use crate::container::{BlockRecord, Header, LimitExceeded, SyncMarker};
+use crate::dictionary::Dictionary;
+use crate::encryption::encryption::BlockCipher;
+use crate::recovery::recovery::{create_output, finish_output};
+use crate::volume::volume::{split_file, VolumeReader, VOLUME_HEADER_LEN};
+use super::chunking::BlockSplitter;
+use super::dedup::{Deduplicator, Resolver, StoredBlock};
+use super::options::{CompressionOptions, DecompressOptions};
+
+/// Running totals handed to progress callbacks after every batch of blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Size of the whole input, when it is known up front.
+    pub total_in: Option<u64>,
+    /// Blocks stored as references to an identical earlier block, and the
+    /// original bytes they cover.
+    pub duplicate_blocks: u64,
+    pub duplicate_bytes: u64,
+    /// Bytes of recovery records written after everything else; counted in
+    /// `bytes_out` too.
+    pub recovery_bytes: u64,
+    /// Volumes the output was split into, or 0 if it was not; their headers
+    /// are counted in `bytes_out`.
+    pub volumes: u32,
+}
+
+impl Progress {
+    /// Fraction of the input processed so far, in `0.0..=1.0`.
+    pub fn fraction(&self) -> f32 {
+        match self.total_in {
+            Some(0) => 1.0,
+            Some(total) => (self.bytes_in as f64 / total as f64).min(1.0) as f32,
+            None => 0.0,
+        }
+    }
+
+    pub(crate) fn add_recovery(&mut self, bytes: u64) {
+        self.recovery_bytes += bytes;
+        self.bytes_out += bytes;
+    }
+
+    /// Splits the finished output at `path` into volumes if `volume_size`
+    /// is set. Output that cannot be split is removed.
+    pub(crate) fn split_output(&mut self, path: &Path, volume_size: Option<u64>) -> io::Result<()> {
+        if let Some(size) = volume_size {
+            self.volumes = split_file(path, size).inspect_err(|_| {
+                let _ = fs::remove_file(path);
+            })?;
+            self.bytes_out += self.volumes as u64 * VOLUME_HEADER_LEN as u64;
+        }
+        Ok(())
+    }
+}
+
+/// What happened to one block during compression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockStats {
+    pub orig_len: usize,
+    /// Coded payload length, without the record framing.
+    pub compressed_len: usize,
+    /// Rules in the block's own grammar (seed rules not counted); zero when
+    /// the grammar stage is off.
+    pub rules: usize,
+    /// Length of the serialized grammar fed to the coder; zero when the
+    /// grammar stage is off.
+    pub grammar_len: usize,
+    /// Time spent in grammar inference and serialization.
+    pub grammar_time: Duration,
+    /// Time spent in the context model and arithmetic coder.
+    pub coding_time: Duration,
+    /// The earlier block this one duplicates, if it was stored as a
+    /// reference instead of being compressed.
+    pub duplicate_of: Option<u64>,
+}
+
+impl BlockStats {
+    /// Original over compressed length.
+    pub fn ratio(&self) -> f64 {
+        if self.compressed_len == 0 {
+            0.0
+        } else {
+            self.orig_len as f64 / self.compressed_len as f64
+        }
+    }
+}
+
+/// Runs block-parallel work on a dedicated pool when the options ask for a
+/// specific thread count, and on Rayon's global pool otherwise.
+pub(crate) struct Workers {
+    pool: Option<rayon::ThreadPool>,
+}
+
+impl Workers {
+    pub fn new(threads: usize) -> io::Result<Self> {
+        let pool = match threads {
+            0 => None,
+            n => Some(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(n)
+                    .build()
+                    .map_err(io::Error::other)?,
+            ),
+        };
+        Ok(Workers { pool })
+    }
+
+    pub fn threads(&self) -> usize {
+        match &self.pool {
+            Some(pool) => pool.current_num_threads(),
+            None => rayon::current_num_threads(),
+        }
+    }
+
+    pub fn install<T: Send>(&self, f: impl FnOnce() -> T + Send) -> T {
+        match &self.pool {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+}
+
+pub fn compress_file<P: AsRef<Path>>(input_path: P, output_path: P, options: &CompressionOptions) -> io::Result<()> {
+    compress_file_with_progress(input_path, output_path, options, |_| Ok(()))?;
+    Ok(())
+}
+
+/// Compresses `input_path` into `output_path`, calling `progress` after each
+/// batch of blocks. Returning an error from the callback aborts the job; a
+/// job that fails once the output was created removes it again.
+pub fn compress_file_with_progress<P, F>(input_path: P, output_path: P, options: &CompressionOptions, progress: F) -> io::Result<Progress>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Progress) -> io::Result<()>,
+{
+    compress_file_with_stats(input_path, output_path, options, progress).map(|(stats, _)| stats)
+}
+
+/// Like [`compress_file_with_progress`], but also returns statistics for
+/// every block, in file order.
+pub fn compress_file_with_stats<P, F>(input_path: P, output_path: P, options: &CompressionOptions, progress: F) -> io::Result<(Progress, Vec<BlockStats>)>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Progress) -> io::Result<()>,
+{
+    options.validate()?;
+    let input_file = File::open(input_path)?;
+    let total_in = input_size(&input_file);
+    let mut reader = BufReader::new(input_file);
+    let mut writer = BufWriter::new(create_output(&output_path)?);
+    let result: io::Result<(Progress, Vec<BlockStats>)> = (|| {
+        let (mut stats, block_stats) = compress_blocks(&mut reader, &mut writer, options, total_in, progress)?;
+        stats.add_recovery(finish_output(writer, options.recovery)?.1);
+        Ok((stats, block_stats))
+    })();
+    if result.is_err() {
+        // A half-written output is never useful.
+        let _ = fs::remove_file(&output_path);
+    }
+    let (mut stats, block_stats) = result?;
+    stats.split_output(output_path.as_ref(), options.volume_size)?;
+    Ok((stats, block_stats))
+}
+
+/// Length of `file` if it is a regular file, whose length is known up front.
+fn input_size(file: &File) -> Option<u64> {
+    file.metadata().ok().filter(|metadata| metadata.is_file()).map(|metadata| metadata.len())
+}
+
+/// Compresses everything readable from `reader` into `writer`.
+pub fn compress_stream<R: Read, W: Write>(mut reader: R, mut writer: W, options: &CompressionOptions) -> io::Result<Progress> {
+    options.validate()?;
+    if options.recovery.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "recovery records can only be added to a file"));
+    }
+    if options.volume_size.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "only output written to a file can be split into volumes"));
+    }
+    compress_blocks(&mut reader, &mut writer, options, None, |_| Ok(())).map(|(stats, _)| stats)
+}
+
+fn compress_blocks<R, W, F>(reader: &mut R, writer: &mut W, options: &CompressionOptions, total_in: Option<u64>, progress: F) -> io::Result<(Progress, Vec<BlockStats>)>
+where
+    R: Read,
+    W: Write,
+    F: FnMut(&Progress) -> io::Result<()>,
+{
+    let (header, cipher) = options.seal(Header { total_len: total_in, ..options.header() })?;
+    let mut stats = Progress { total_in, ..Progress::default() };
+    stats.bytes_out += header.write(writer)? as u64;
+    let tail = StreamTail { header: &header, cipher: cipher.as_ref(), blocks: 0, output_len: 0, offset: stats.bytes_out, primer: None };
+    let block_stats = encode_blocks(reader, writer, options, tail, &mut stats, progress)?;
+    if total_in.is_some_and(|total| total != stats.bytes_in) {
+        return Err(io::Error::other("input changed size while it was being compressed"));
+    }
+    Ok((stats, block_stats))
+}
+
+/// The stream [`encode_blocks`] adds to, and where its blocks end.
+pub(crate) struct StreamTail<'a> {
+    pub header: &'a Header,
+    pub cipher: Option<&'a BlockCipher>,
+    /// Blocks already in the stream, and the original bytes they hold.
+    pub blocks: u64,
+    pub output_len: u64,
+    /// Stream offset of the next record.
+    pub offset: u64,
+    /// In a primed stream, the model the last block left behind.
+    pub primer: Option<Ctw>,
+}
+
+/// Cuts everything readable from `reader` into blocks as `options` ask,
+/// codes them as the stream's header says and writes them to `writer` after
+/// `tail`. `stats` counts the input read and the bytes written.
+pub(crate) fn encode_blocks<R, W, F>(reader: &mut R, writer: &mut W, options: &CompressionOptions, tail: StreamTail, stats: &mut Progress, mut progress: F) -> io::Result<Vec<BlockStats>>
+where
+    R: Read,
+    W: Write,
+    F: FnMut(&Progress) -> io::Result<()>,
+{
+    let workers = Workers::new(options.threads)?;
+    let batch_len = options.batch_len(workers.threads());
+    let dictionary = options.dictionary.as_deref();
+    let mut block_stats = Vec::new();
+    let StreamTail { header, cipher, blocks: mut next_block, mut output_len, mut offset, mut primer } = tail;
+    let mut dedup = Deduplicator::for_header(header);
+    let mut splitter = BlockSplitter::new(reader, options);
+
+    loop {
+        // Read one batch of blocks, then compress the batch in parallel.
+        let mut blocks = Vec::with_capacity(batch_len);
+        while blocks.len() < batch_len {
+            let block = splitter.next_block()?;
+            if block.is_empty() {
+                break;
+            }
+            blocks.push(block);
+        }
+        if blocks.is_empty() {
+            break;
+        }
+
+        let first_block = next_block;
+        next_block += blocks.len() as u64;
+        let records = workers.install(|| {
+            let duplicates = match dedup.as_mut() {
+                Some(dedup) => dedup.check_batch(&blocks, first_block),
+                None => vec![None; blocks.len()],
+            };
+            let blocks: Vec<(Vec<u8>, Option<u64>)> = blocks.into_iter().zip(duplicates).collect();
+            map_primed(blocks, first_block, header.prime_group, &mut primer, |(block_data, duplicate_of), primer| {
+                let (record, record_stats, primer) = compress_stored(&block_data, duplicate_of, header, dictionary, primer);
+                Ok(((record, record_stats), primer))
+            })
+        });
+
+        for (number, result) in (first_block..).zip(records) {
+            let (record, record_stats) = result?;
+            stats.bytes_in += record.orig_len as u64;
+            if record_stats.duplicate_of.is_some() {
+                stats.duplicate_blocks += 1;
+                stats.duplicate_bytes += record.orig_len as u64;
+            }
+            let marker = header.has_sync_markers().then_some(SyncMarker { block: number, output_offset: output_len });
+            output_len += record.orig_len as u64;
+            let written = write_record(writer, record, cipher, offset, marker)? as u64;
+            offset += written;
+            stats.bytes_out += written;
+            block_stats.push(record_stats);
+        }
+        progress(stats)?;
+    }
+
+    Ok(block_stats)
+}
+
+/// Maps `f` over the consecutive blocks `items`, the first of which is
+/// block `first_block` of a stream whose priming groups hold `group` blocks.
+///
+/// Without priming every block is independent and all of them run in
+/// parallel. With priming, the blocks of one group run in order, each
+/// handed the context model the previous one left behind (`None` at the
+/// start of a group), while separate groups run in parallel. `carried`
+/// holds the model left by the block before `first_block` and receives the
+/// one left by the last block, so a group may span several calls. A group
+/// stops at its first error; results after an error may be missing, so
+/// callers must stop at the first `Err`.
+pub(crate) fn map_primed<T, U, F>(items: Vec<T>, first_block: u64, group: Option<u32>, carried: &mut Option<Ctw>, f: F) -> Vec<io::Result<U>>
+where
+    T: Send,
+    U: Send,
+    F: Fn(T, Option<Ctw>) -> io::Result<(U, Option<Ctw>)> + Sync,
+{
+    let Some(group) = group else {
+        return items.into_par_iter().map(|item| f(item, None).map(|(value, _)| value)).collect();
+    };
+    let starts_group = |block: u64| block.is_multiple_of(group as u64);
+    let mut runs: Vec<(Vec<T>, Option<Ctw>)> = Vec::new();
+    let mut first_primer = if starts_group(first_block) { None } else { carried.take() };
+    for (i, item) in items.into_iter().enumerate() {
+        if i == 0 || starts_group(first_block + i as u64) {
+            runs.push((Vec::new(), first_primer.take()));
+        }
+        runs.last_mut().unwrap().0.push(item);
+    }
+    let outputs: Vec<(Vec<io::Result<U>>, Option<Ctw>)> = runs
+        .into_par_iter()
+        .map(|(run, mut primer)| {
+            let mut results = Vec::with_capacity(run.len());
+            for item in run {
+                match f(item, primer.take()) {
+                    Ok((value, next)) => {
+                        results.push(Ok(value));
+                        primer = next;
+                    }
+                    Err(e) => {
+                        results.push(Err(e));
+                        break;
+                    }
+                }
+            }
+            (results, primer)
+        })
+        .collect();
+    let mut results = Vec::new();
+    for (run, primer) in outputs {
+        results.extend(run);
+        *carried = primer;
+    }
+    results
+}
+
+/// Writes `record` at stream offset `offset`, after `marker` if given and
+/// encrypted first when the stream is encrypted. Returns the number of bytes
+/// written.
+pub(crate) fn write_record<W: Write>(writer: &mut W, mut record: BlockRecord, cipher: Option<&BlockCipher>, offset: u64, marker: Option<SyncMarker>) -> io::Result<usize> {
+    if let Some(cipher) = cipher {
+        cipher.seal(&mut record, offset)?;
+    }
+    let marker_len = match marker {
+        Some(marker) => {
+            writer.write_all(&marker.encode())?;
+            SyncMarker::LEN
+        }
+        None => 0,
+    };
+    Ok(marker_len + record.write(writer)?)
+}
+
+/// Reads up to `block_size` bytes, only returning a short block at end of input.
+pub(crate) fn read_full_block<R: Read>(reader: &mut R, block_size: usize) -> io::Result<Vec<u8>> {
+    let mut block = Vec::with_capacity(block_size);
+    reader.take(block_size as u64).read_to_end(&mut block)?;
+    Ok(block)
+}
+
+/// Compresses one block and frames it with its length and checksum.
+pub(crate) fn compress_record(block_data: &[u8], header: &Header, dictionary: Option<&Dictionary>) -> BlockRecord {
+    compress_record_with_stats(block_data, header, dictionary, None).0
+}
+
+/// Compresses one block of a single-file stream: starts from the context
+/// model `primer` in a primed stream and returns the model the block leaves
+/// behind, and stores the block as a reference if it is `duplicate_of` an
+/// earlier one. A reference leaves the model as it was.
+pub(crate) fn compress_stored(
+    block_data: &[u8],
+    duplicate_of: Option<u64>,
+    header: &Header,
+    dictionary: Option<&Dictionary>,
+    primer: Option<Ctw>,
+) -> (BlockRecord, BlockStats, Option<Ctw>) {
+    let (stored, mut stats, primer) = match duplicate_of {
+        Some(target) => {
+            let stored = StoredBlock::Reference { target, orig_len: block_data.len(), checksum: header.checksum.compute(block_data) };
+            (stored, BlockStats { orig_len: block_data.len(), duplicate_of, ..BlockStats::default() }, primer)
+        }
+        None => {
+            let (record, stats, primer) = compress_record_with_stats(block_data, header, dictionary, primer);
+            (StoredBlock::Coded(record), stats, primer)
+        }
+    };
+    let record = stored.into_record(header);
+    if duplicate_of.is_some() {
+        stats.compressed_len = record.payload.len();
+    }
+    (record, stats, primer)
+}
+
+fn compress_record_with_stats(block_data: &[u8], header: &Header, dictionary: Option<&Dictionary>, primer: Option<Ctw>) -> (BlockRecord, BlockStats, Option<Ctw>) {
+    let (payload, stats, primer) = code_block(block_data, header, dictionary, primer);
+    let record = BlockRecord {
+        orig_len: block_data.len(),
+        checksum: header.checksum.compute(block_data),
+        payload,
+    };
+    (record, stats, primer)
+}
+
+/// Decodes one framed block and verifies its checksum.
+/// A grammar declaring more than `max_rules` rules is rejected.
+pub(crate) fn decompress_record(record: BlockRecord, header: &Header, dictionary: Option<&Dictionary>, max_rules: usize) -> io::Result<Vec<u8>> {
+    decompress_record_primed(record, header, dictionary, max_rules, None).map(|(block, _)| block)
+}
+
+/// [`decompress_record`] for a primed stream: starts from the context model
+/// `primer` and also returns the model the block leaves behind.
+fn decompress_record_primed(record: BlockRecord, header: &Header, dictionary: Option<&Dictionary>, max_rules: usize, primer: Option<Ctw>) -> io::Result<(Vec<u8>, Option<Ctw>)> {
+    let (original_block, primer) = decode_block(record.payload, record.orig_len, header, dictionary, max_rules, primer)?;
+    if header.checksum.compute(&original_block) != record.checksum {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "block checksum mismatch"));
+    }
+    Ok((original_block, primer))
+}
+
+/// Decodes one record of a single-file stream, starting from the context
+/// model `primer` in a primed stream. A reference is returned as it is, to
+/// be resolved in stream order, and leaves the model as it was.
+pub(crate) fn decompress_stored(
+    record: BlockRecord,
+    header: &Header,
+    dictionary: Option<&Dictionary>,
+    max_rules: usize,
+    primer: Option<Ctw>,
+) -> io::Result<(StoredBlock<Vec<u8>>, Option<Ctw>)> {
+    let mut primer = Some(primer);
+    let stored = StoredBlock::from_record(record, header)?.try_map(|record| {
+        let (original_block, next) = decompress_record_primed(record, header, dictionary, max_rules, primer.take().flatten())?;
+        primer = Some(next);
+        Ok(original_block)
+    })?;
+    Ok((stored, primer.flatten()))
+}
+
+/// Runs one block through the stages selected in `header` and returns the
+/// coded payload. A `dictionary` seeds both the grammar and the context
+/// model; the same one must be passed to [`decompress_block`].
+pub fn compress_block(block_data: &[u8], header: &Header, dictionary: Option<&Dictionary>) -> Vec<u8> {
+    compress_block_with_stats(block_data, header, dictionary).0
+}
+
+/// [`compress_block`] that also reports what each stage did.
+pub fn compress_block_with_stats(block_data: &[u8], header: &Header, dictionary: Option<&Dictionary>) -> (Vec<u8>, BlockStats) {
+    let (payload, stats, _) = code_block(block_data, header, dictionary, None);
+    (payload, stats)
+}
+
+fn code_block(block_data: &[u8], header: &Header, dictionary: Option<&Dictionary>, primer: Option<Ctw>) -> (Vec<u8>, BlockStats, Option<Ctw>) {
+    let mut stats = BlockStats { orig_len: block_data.len(), ..BlockStats::default() };
+
+    // Stage 1: Grammar-Based Modeling
+    let started = Instant::now();
+    let symbol_stream = if header.stages.grammar {
+        let grammar = match dictionary {
+            Some(dictionary) => {
+                let mut grammar = Grammar::with_seed(dictionary.seed());
+                grammar.infer_grammar_seeded(block_data, dictionary.seed());
+                grammar
+            }
+            None => {
+                let mut grammar = Grammar::new();
+                grammar.infer_grammar(block_data);
+                grammar
+            }
+        };
+        stats.rules = grammar.rules.len() - grammar.seed_rules;
+        let serialized = serialize_grammar(&grammar);
+        stats.grammar_len = serialized.len();
+        serialized
+    } else {
+        block_data.to_vec()
+    };
+    stats.grammar_time = started.elapsed();
+
+    // Stage 2 & 3: CTW and Arithmetic Coding
+    let started = Instant::now();
+    let mut model = SymbolModel::new(header, dictionary, primer);
+    let mut encoder = ArithmeticEncoder::new();
+
+    for &symbol in symbol_stream.iter() {
+        encoder.encode_symbol(symbol, &model.frequencies());
+        model.update(symbol);
+    }
+
+    let payload = encoder.finish();
+    stats.coding_time = started.elapsed();
+    stats.compressed_len = payload.len();
+    (payload, stats, model.into_primer(header))
+}
+
+/// Reverses [`compress_block`] for a block that expands to `orig_len` bytes.
+pub fn decompress_block(compressed_block: Vec<u8>, orig_len: usize, header: &Header, dictionary: Option<&Dictionary>) -> io::Result<Vec<u8>> {
+    decode_block(compressed_block, orig_len, header, dictionary, usize::MAX, None).map(|(block, _)| block)
+}
+
+fn decode_block(compressed_block: Vec<u8>, orig_len: usize, header: &Header, dictionary: Option<&Dictionary>, max_rules: usize, primer: Option<Ctw>) -> io::Result<(Vec<u8>, Option<Ctw>)> {
+    match decode_stages(compressed_block, orig_len, header, dictionary, max_rules, primer)? {
+        (DecodedBlock::Bytes(bytes), primer) => Ok((bytes, primer)),
+        (DecodedBlock::Grammar(grammar), primer) => {
+            let original_block = expand_grammar(&grammar, orig_len)?;
+            if original_block.len() != orig_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "block does not decode to its recorded length"));
+            }
+            Ok((original_block, primer))
+        }
+    }
+}
+
+/// A block's payload with the coding stages undone.
+pub(crate) enum DecodedBlock {
+    /// The original bytes, for streams without the grammar stage.
+    Bytes(Vec<u8>),
+    /// The block's grammar, including any dictionary rules, not yet expanded.
+    Grammar(Grammar),
+}
+
+/// Undoes arithmetic coding and the context model, stopping before grammar
+/// expansion. Also returns the model the block leaves behind, for the next
+/// block of a primed stream.
+pub(crate) fn decode_stages(compressed_block: Vec<u8>, orig_len: usize, header: &Header, dictionary: Option<&Dictionary>, max_rules: usize, primer: Option<Ctw>) -> io::Result<(DecodedBlock, Option<Ctw>)> {
+    // Stage 2 & 3: Arithmetic Decoding and CTW
+    let mut model = SymbolModel::new(header, dictionary, primer);
+    let mut decoder = ArithmeticDecoder::new(compressed_block)?;
+    // Decode symbols on demand; the grammar parser stops once it is complete,
+    // so the model sees exactly the symbols the encoder's did. A coder error
+    // ends the stream and is reported in place of whatever it cut short.
+    let mut failure = None;
+    let mut symbol_stream = std::iter::from_fn(|| match decoder.decode_symbol(&model.frequencies()) {
+        Ok(symbol) => {
+            model.update(symbol);
+            Some(symbol)
+        }
+        Err(e) => {
+            failure = Some(e);
+            None
+        }
+    });
+
+    let decoded = if !header.stages.grammar {
+        let bytes: Vec<u8> = symbol_stream.by_ref().take(orig_len).collect();
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        DecodedBlock::Bytes(bytes)
+    } else {
+        let mut symbol_stream = symbol_stream.by_ref().take(orig_len.saturating_mul(8).saturating_add(16)); // upper bound, should be enough for grammar serialization
+        let grammar = read_grammar(&mut symbol_stream, max_rules);
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        let mut grammar = grammar?;
+        if let Some(dictionary) = dictionary {
+            let seed = dictionary.seed().grammar();
+            for (&rule_id, expansion) in &seed.rules {
+                grammar.rules.entry(rule_id).or_insert_with(|| expansion.clone());
+            }
+        }
+        DecodedBlock::Grammar(grammar)
+    };
+    Ok((decoded, model.into_primer(header)))
+}
+
+/// Probability source for the coder: the context model, or a flat
+/// distribution when that stage is disabled.
+enum SymbolModel {
+    Context(Box<Ctw>),
+    Uniform,
+}
+
+impl SymbolModel {
+    /// Starts from `primer` if given, otherwise from the dictionary's model or
+    /// an empty one.
+    fn new(header: &Header, dictionary: Option<&Dictionary>, primer: Option<Ctw>) -> Self {
+        if header.stages.context_model {
+            let ctw = match (primer, dictionary) {
+                (Some(primer), _) => primer,
+                (None, Some(dictionary)) => dictionary.model().clone(),
+                (None, None) => Ctw::with_depth(header.model_depth as usize),
+            };
+            SymbolModel::Context(Box::new(ctw))
+        } else {
+            SymbolModel::Uniform
+        }
+    }
+
+    /// The model to start the next block of a primed stream from, or `None`
+    /// when the stream is not primed.
+    fn into_primer(self, header: &Header) -> Option<Ctw> {
+        match self {
+            SymbolModel::Context(mut ctw) if header.prime_group.is_some() => {
+                ctw.reset_context();
+                Some(*ctw)
+            }
+            _ => None,
+        }
+    }
+
+    fn frequencies(&self) -> [u32; 256] {
+        match self {
+            SymbolModel::Context(ctw) => ctw.frequencies(),
+            SymbolModel::Uniform => [1; 256],
+        }
+    }
+
+    fn update(&mut self, symbol: u8) {
+        if let SymbolModel::Context(ctw) = self {
+            ctw.process_symbol(symbol);
+        }
+    }
+}
+
+pub fn decompress_file<P: AsRef<Path>>(input_path: P, output_path: P) -> io::Result<()> {
+    decompress_file_with_progress(input_path, output_path, &DecompressOptions::default(), |_| Ok(()))?;
+    Ok(())
+}
+
+/// Decompresses `input_path` into `output_path`, calling `progress` after each
+/// batch of blocks. Returning an error from the callback aborts the job.
+///
+/// The header, dictionary and credentials are checked before anything is
+/// written. The output is then written next to its final name and renamed
+/// into place once complete, so a failed or aborted job leaves an existing
+/// file at `output_path` as it was.
+pub fn decompress_file_with_progress<P, F>(input_path: P, output_path: P, options: &DecompressOptions, progress: F) -> io::Result<Progress>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Progress) -> io::Result<()>,
+{
+    let input = VolumeReader::open(input_path)?;
+    let total_in = Some(input.len());
+    let mut reader = BufReader::new(input);
+    let header = Header::read(&mut reader)?;
+    let cipher = open_stream(&header, options)?;
+
+    let output_path = output_path.as_ref();
+    let mut temp_name = OsString::from(output_path.as_os_str());
+    temp_name.push(".partial");
+    let temp_path = PathBuf::from(temp_name);
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        let stats = decompress_blocks(&mut reader, &mut writer, &header, cipher.as_ref(), options, total_in, progress)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, output_path)?;
+        Ok(stats)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Decompresses a `.bpc` stream from `reader` into `writer`.
+pub fn decompress_stream<R: Read, W: Write>(mut reader: R, mut writer: W, options: &DecompressOptions) -> io::Result<Progress> {
+    let header = Header::read(&mut reader)?;
+    let cipher = open_stream(&header, options)?;
+    decompress_blocks(&mut reader, &mut writer, &header, cipher.as_ref(), options, None, |_| Ok(()))
+}
+
+/// Checks that the stream with `header` can be decoded with `options`: it
+/// is not an archive and the dictionary and credentials it needs are
+/// there. Returns its cipher if it is encrypted.
+fn open_stream(header: &Header, options: &DecompressOptions) -> io::Result<Option<BlockCipher>> {
+    if header.is_archive() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "stream is a multi-file archive; extract it instead"));
+    }
+    Dictionary::for_header(header, options.dictionary.as_deref())?;
+    options.cipher(header)
+}
+
+/// Decodes the blocks that follow `header` in `reader` into `writer`.
+fn decompress_blocks<R, W, F>(
+    reader: &mut R,
+    writer: &mut W,
+    header: &Header,
+    cipher: Option<&BlockCipher>,
+    options: &DecompressOptions,
+    total_in: Option<u64>,
+    mut progress: F,
+) -> io::Result<Progress>
+where
+    R: Read,
+    W: Write,
+    F: FnMut(&Progress) -> io::Result<()>,
+{
+    let header = *header;
+    let dictionary = Dictionary::for_header(&header, options.dictionary.as_deref())?;
+    let batch_len = rayon::current_num_threads().max(1).saturating_mul(header.prime_group.unwrap_or(1) as usize);
+    let mut stats = Progress { total_in, ..Progress::default() };
+    stats.bytes_in += header.encoded_len() as u64;
+    // Stream offset of the next record to read.
+    let mut offset = stats.bytes_in;
+    let mut batch_end = offset;
+    // A record read that did not fit in the previous batch's memory budget.
+    let mut pending = None;
+    let mut blocks_done = 0u64;
+    let mut primer = None;
+    let mut resolver = Resolver::for_header(&header, options.memory_limit)?;
+
+    loop {
+        let mut records = Vec::with_capacity(batch_len);
+        let mut batch_memory = 0;
+        while records.len() < batch_len {
+            let record = match pending.take() {
+                Some(record) => record,
+                None => match options.read_record(reader, &header, cipher, &mut offset)? {
+                    Some(record) => record,
+                    None => break,
+                },
+            };
+            match options.fit_in_batch(&header, batch_memory, &record) {
+                Some(total) => batch_memory = total,
+                None => {
+                    pending = Some(record);
+                    break;
+                }
+            }
+            records.push(record);
+            // `offset` is past the last record read, which is this one.
+            batch_end = offset;
+        }
+        if records.is_empty() {
+            break;
+        }
+
+        let batch_out: u64 = records.iter().map(|record| record.orig_len as u64).sum();
+        options.check_output(stats.bytes_out + batch_out)?;
+        header.check_decoded_len(stats.bytes_out + batch_out, false)?;
+        let max_rules = options.max_rules();
+        let first_block = blocks_done;
+        blocks_done += records.len() as u64;
+        let original_blocks = map_primed(records, first_block, header.prime_group, &mut primer, |record, primer| {
+            decompress_stored(record, &header, dictionary, max_rules, primer)
+        });
+
+        for (number, original_block) in (first_block..).zip(original_blocks) {
+            match original_block? {
+                StoredBlock::Coded(original_block) => {
+                    writer.write_all(&original_block)?;
+                    stats.bytes_out += original_block.len() as u64;
+                    if let Some(resolver) = resolver.as_mut() {
+                        resolver.coded(number, original_block);
+                    }
+                }
+                StoredBlock::Reference { target, orig_len, checksum } => {
+                    let resolver = resolver.as_mut().expect("references only occur in deduplicating streams");
+                    writer.write_all(resolver.resolve(&header, target, orig_len, &checksum)?)?;
+                    stats.bytes_out += orig_len as u64;
+                    stats.duplicate_blocks += 1;
+                    stats.duplicate_bytes += orig_len as u64;
+                }
+            }
+        }
+        stats.bytes_in = batch_end;
+        progress(&stats)?;
+    }
+
+    header.check_decoded_len(stats.bytes_out, true)?;
+    Ok(stats)
+}
+
+pub fn serialize_grammar(grammar: &Grammar) -> Vec<u8> {
+    // Simple serialization: [num_rules][rule_id][rule_len][symbols...][sequence_len][sequence...]
+    // Counts, lengths and rule ids are varints (see `write_varint`).
+    // Seed rules from a preset dictionary are known to the decoder already.
+    let mut own_rules: Vec<(&usize, &Vec<Symbol>)> = grammar.rules.iter().filter(|(&rule_id, _)| rule_id >= grammar.seed_rules).collect();
+    own_rules.sort_unstable_by_key(|(&rule_id, _)| rule_id);
+    let mut out = Vec::new();
+    write_varint(&mut out, own_rules.len() as u64);
+    for (&rule_id, expansion) in own_rules {
+        write_varint(&mut out, rule_id as u64);
+        write_varint(&mut out, expansion.len() as u64);
+        for symbol in expansion {
+            match symbol {
+                Symbol::Terminal(b) => {
+                    out.push(0); // tag for terminal
+                    out.push(*b);
+                }
+                Symbol::NonTerminal(id) => {
+                    out.push(1); // tag for nonterminal
+                    write_varint(&mut out, *id as u64);
+                }
+            }
+        }
+    }
+    write_varint(&mut out, grammar.sequence.len() as u64);
+    for symbol in &grammar.sequence {
+        match symbol {
+            Symbol::Terminal(b) => {
+                out.push(0);
+                out.push(*b);
+            }
+            Symbol::NonTerminal(id) => {
+                out.push(1);
+                write_varint(&mut out, *id as u64);
+            }
+        }
+    }
+    out
+}
+
+/// Appends `value` in seven-bit groups, lowest first, with the top bit of
+/// every byte but the last set.
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Parses the output of [`serialize_grammar`] back into a [`Grammar`].
+pub fn deserialize_grammar(data: &[u8]) -> Option<Grammar> {
+    read_grammar(&mut data.iter().copied(), usize::MAX).ok()
+}
+
+/// Parses a serialized grammar from a byte source, consuming exactly the
+/// bytes it needs, and fails with `InvalidData` if it is corrupt or declares
+/// more than `max_rules` rules.
+fn read_grammar<I: Iterator<Item = u8>>(bytes: &mut I, max_rules: usize) -> io::Result<Grammar> {
+    use std::collections::HashMap;
+    /// Reads a value written by `write_varint`; `None` if it is cut short
+    /// or does not fit in a `usize`.
+    fn read_varint<I: Iterator<Item = u8>>(bytes: &mut I) -> Option<usize> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = bytes.next()?;
+            let bits = (byte & 0x7f) as u64;
+            if bits << shift >> shift != bits {
+                return None;
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return usize::try_from(value).ok();
+            }
+        }
+        None
+    }
+    fn read_symbol<I: Iterator<Item = u8>>(bytes: &mut I) -> Option<Symbol> {
+        match bytes.next()? {
+            0 => Some(Symbol::Terminal(bytes.next()?)), // Terminal
+            1 => Some(Symbol::NonTerminal(read_varint(bytes)?)), // NonTerminal
+            _ => None,
+        }
+    }
+    fn corrupt_grammar() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "corrupt grammar in block")
+    }
+    // Read rules
+    let num_rules = read_varint(bytes).ok_or_else(corrupt_grammar)?;
+    if num_rules > max_rules {
+        return Err(LimitExceeded::GrammarRules { rules: num_rules as u64, limit: max_rules as u64 }.into());
+    }
+    let mut rules = HashMap::new();
+    let mut next_nonterminal_id = 0;
+    for _ in 0..num_rules {
+        let rule_id = read_varint(bytes).ok_or_else(corrupt_grammar)?;
+        let rule_len = read_varint(bytes).ok_or_else(corrupt_grammar)?;
+        let mut expansion = Vec::new();
+        for _ in 0..rule_len {
+            expansion.push(read_symbol(bytes).ok_or_else(corrupt_grammar)?);
+        }
+        rules.insert(rule_id, expansion);
+        next_nonterminal_id = next_nonterminal_id.max(rule_id.saturating_add(1));
+    }
+    // Read sequence
+    let seq_len = read_varint(bytes).ok_or_else(corrupt_grammar)?;
+    let mut sequence = Vec::new();
+    for _ in 0..seq_len {
+        sequence.push(read_symbol(bytes).ok_or_else(corrupt_grammar)?);
+    }
+    Ok(Grammar { rules, next_nonterminal_id, sequence, seed_rules: 0 })
+}
+
+/// Expands the start sequence of `grammar` into the bytes it derives,
+/// failing with `InvalidData` if it is malformed or longer than `limit`.
+/// The underlying [`ExpandError`](crate::grammar::ExpandError) is available
+/// through `get_ref`.
+pub fn expand_grammar(grammar: &Grammar, limit: usize) -> io::Result<Vec<u8>> {
+    grammar.expand(limit).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::options::{MAX_LEVEL, MIN_LEVEL};
+    use crate::container::Stages;
+
+    #[test]
+    fn grammar_is_parsed_once_from_a_stream() {
+        // The parser must stop right after the grammar, so a block's symbols
+        // can be decoded on demand instead of reparsing after every one.
+        let data = b"abcabcabcabd abcabcabcabd";
+        let mut grammar = Grammar::new();
+        grammar.infer_grammar(data);
+        let mut stream = serialize_grammar(&grammar).into_iter().chain([7, 8, 9]);
+        let parsed = read_grammar(&mut stream, usize::MAX).unwrap();
+        assert_eq!(expand_grammar(&parsed, data.len()).unwrap(), data);
+        assert_eq!(stream.collect::<Vec<u8>>(), [7, 8, 9]);
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let dir = std::env::temp_dir().join(format!("blockpiper-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, packed, output) = (dir.join("in"), dir.join("in.bpc"), dir.join("out"));
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * i % 251) as u8).chain([0xff; 64]).collect();
+        std::fs::write(&input, &data).unwrap();
+        compress_file(&input, &packed, &CompressionOptions::new().with_block_size(1024)).unwrap();
+        decompress_file(&packed, &output).unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_decompression_leaves_the_output_alone() {
+        let dir = std::env::temp_dir().join(format!("blockpiper-keep-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, packed, output) = (dir.join("in"), dir.join("in.bpc"), dir.join("keep.txt"));
+        std::fs::write(&input, b"compressed with a dictionary that is not at hand".repeat(20)).unwrap();
+        let dictionary = Dictionary::train(&[b"some sample text"], &CompressionOptions::new()).unwrap();
+        let options = CompressionOptions::new().with_dictionary(Some(std::sync::Arc::new(dictionary)));
+        compress_file(&input, &packed, &options).unwrap();
+        std::fs::write(&output, b"precious").unwrap();
+        assert!(decompress_file(&packed, &output).is_err());
+        assert!(decompress_file(&input, &output).is_err());
+        // A damaged block is only found part way through writing.
+        compress_file(&input, &packed, &CompressionOptions::new().with_block_size(256)).unwrap();
+        let mut stream = std::fs::read(&packed).unwrap();
+        let last = stream.len() - 20;
+        stream[last] ^= 0xff;
+        std::fs::write(&packed, stream).unwrap();
+        assert!(decompress_file(&packed, &output).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"precious");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn aborted_compression_removes_only_its_own_output() {
+        let dir = std::env::temp_dir().join(format!("blockpiper-abort-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("in"), dir.join("in.bpc"));
+        std::fs::write(&output, b"precious").unwrap();
+        assert!(compress_file(&input, &output, &CompressionOptions::new()).is_err());
+        assert_eq!(std::fs::read(&output).unwrap(), b"precious");
+
+        std::fs::write(&input, vec![7; 5000]).unwrap();
+        let options = CompressionOptions::new().with_block_size(1024);
+        let cancel = |_: &Progress| Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled"));
+        let error = compress_file_with_progress(&input, &output, &options, cancel).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Interrupted);
+        assert!(!output.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn higher_levels_never_compress_worse() {
+        // A log whose fields repeat at different distances, so every extra
+        // level of model depth has something to find.
+        let data: Vec<u8> = (0..400u32)
+            .flat_map(|i| {
+                let level = ["INFO", "WARN", "DEBUG", "ERROR"][(i * 7 % 11 % 4) as usize];
+                let path = ["/api/users", "/api/orders", "/health"][(i * i % 3) as usize];
+                format!("2024-06-12T10:{:02}:{:02}Z {} GET {}?id={} took={}ms\n", i / 60 % 60, i % 60, level, path, i * 37 % 1000, i * 13 % 97).into_bytes()
+            })
+            .collect();
+        let sizes: Vec<usize> = (MIN_LEVEL..=MAX_LEVEL)
+            .map(|level| {
+                let mut stream = Vec::new();
+                compress_stream(&data[..], &mut stream, &CompressionOptions::level(level)).unwrap();
+                stream.len()
+            })
+            .collect();
+        assert!(sizes.windows(2).all(|pair| pair[1] <= pair[0]), "sizes by level: {:?}", sizes);
+        assert!(sizes[MAX_LEVEL as usize - 1] < sizes[0], "sizes by level: {:?}", sizes);
+    }
+
+    /// Decodes `stream` with `options` and returns the limit it broke.
+    fn broken_limit(stream: &[u8], options: &DecompressOptions) -> LimitExceeded {
+        let error = decompress_stream(stream, io::sink(), options).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        *error.get_ref().and_then(|e| e.downcast_ref::<LimitExceeded>()).unwrap()
+    }
+
+    #[test]
+    fn decompression_limits_fire_before_allocating() {
+        let data = b"a small stream of repeated words, repeated words, repeated words. ".repeat(60);
+        let mut stream = Vec::new();
+        let stages = Stages { grammar: true, context_model: true };
+        let options = CompressionOptions::new().with_block_size(1024).with_stages(stages).with_sync_markers(false);
+        compress_stream(&data[..], &mut stream, &options).unwrap();
+        // The first record starts with its payload length and then the
+        // length of the block it decodes to.
+        let first = Header::read(&mut &stream[..]).unwrap().encoded_len();
+        let with_lengths = |block_len: u64, orig_len: u64| {
+            let mut stream = stream.clone();
+            stream[first..first + 8].copy_from_slice(&block_len.to_le_bytes());
+            stream[first + 8..first + 16].copy_from_slice(&orig_len.to_le_bytes());
+            stream
+        };
+        let payload_len = u64::from_le_bytes(stream[first..first + 8].try_into().unwrap());
+
+        let huge_block = with_lengths(payload_len, 1 << 40);
+        let options = DecompressOptions::new().with_max_block_size(Some(1 << 20));
+        assert_eq!(broken_limit(&huge_block, &options), LimitExceeded::BlockSize { size: 1 << 40, limit: 1 << 20 });
+
+        let huge_payload = with_lengths(1 << 40, 1024);
+        let options = DecompressOptions::new().with_memory_limit(Some(64 << 20));
+        assert_eq!(broken_limit(&huge_payload, &options), LimitExceeded::PayloadSize { size: 1 << 40, limit: 64 << 20 });
+
+        // The lengths alone are enough to refuse a block that would take too
+        // much memory to decode: the stream ends where its payload would start.
+        let huge_decode = &with_lengths(1024, 1 << 30)[..first + 16];
+        let options = DecompressOptions::new().with_memory_limit(Some(64 << 20));
+        assert!(matches!(broken_limit(huge_decode, &options), LimitExceeded::Memory { limit: 67108864, .. }));
+
+        // A rule count is refused before any rule is read or stored.
+        let mut huge_grammar = Vec::new();
+        write_varint(&mut huge_grammar, 1 << 40);
+        let error = read_grammar(&mut huge_grammar.into_iter(), 1000).unwrap_err();
+        let limit = error.get_ref().and_then(|e| e.downcast_ref::<LimitExceeded>());
+        assert_eq!(limit, Some(&LimitExceeded::GrammarRules { rules: 1 << 40, limit: 1000 }));
+    }
+
+    #[test]
+    fn decompression_limits_hold_on_intact_streams() {
+        let data = b"a small stream of repeated words, repeated words, repeated words. ".repeat(60);
+        let mut stream = Vec::new();
+        let stages = Stages { grammar: true, context_model: true };
+        let options = CompressionOptions::new().with_block_size(1024).with_stages(stages);
+        compress_stream(&data[..], &mut stream, &options).unwrap();
+
+        let options = DecompressOptions::new().with_memory_limit(Some(1 << 16));
+        assert!(matches!(broken_limit(&stream, &options), LimitExceeded::Memory { limit: 65536, .. }));
+
+        let options = DecompressOptions::new().with_max_output(Some(100));
+        assert_eq!(broken_limit(&stream, &options), LimitExceeded::TotalOutput { limit: 100 });
+
+        let options = DecompressOptions::new().with_max_grammar_rules(Some(1));
+        assert!(matches!(broken_limit(&stream, &options), LimitExceeded::GrammarRules { limit: 1, .. }));
+    }
+}
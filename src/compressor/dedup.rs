@@ -0,0 +1,279 @@
+//! Block-level deduplication.
+//!
+//! In a deduplicating stream every block's payload starts with a kind byte.
+//! A coded block carries its coded bytes as usual; a block whose contents
+//! hash the same as a recent coded block is stored as a reference to that
+//! block's number instead. Encoder and decoder keep the same window of
+//! recently used coded blocks, so a decoder never holds more than the
+//! window's worth of earlier output, even when reading a plain stream.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Digest};
+use rayon::prelude::*;
+
+use crate::container::{BlockRecord, Header, LimitExceeded};
+
+const KIND_CODED: u8 = 0;
+const KIND_REFERENCE: u8 = 1;
+
+/// Window used when deduplication is turned on without a size.
+pub const DEFAULT_DEDUP_WINDOW: u64 = 256 * 1024 * 1024;
+
+/// Content hash blocks are matched on.
+type BlockHash = [u8; 32];
+
+fn block_hash(data: &[u8]) -> BlockHash {
+    Blake2b::<U32>::digest(data).into()
+}
+
+/// A record of a deduplicating stream with its kind byte taken apart, or
+/// the same after decoding, with `T` the decoded block.
+pub(crate) enum StoredBlock<T = BlockRecord> {
+    Coded(T),
+    /// Same contents as coded block `target`.
+    Reference { target: u64, orig_len: usize, checksum: Vec<u8> },
+}
+
+impl<T> StoredBlock<T> {
+    /// Applies `f` to a coded block; a reference stays as it is.
+    pub(crate) fn try_map<U>(self, f: impl FnOnce(T) -> io::Result<U>) -> io::Result<StoredBlock<U>> {
+        Ok(match self {
+            StoredBlock::Coded(coded) => StoredBlock::Coded(f(coded)?),
+            StoredBlock::Reference { target, orig_len, checksum } => StoredBlock::Reference { target, orig_len, checksum },
+        })
+    }
+}
+
+impl StoredBlock {
+    /// Splits `record` if `header` says the stream deduplicates; any other
+    /// record is coded.
+    pub(crate) fn from_record(mut record: BlockRecord, header: &Header) -> io::Result<Self> {
+        if header.dedup_window.is_none() {
+            return Ok(StoredBlock::Coded(record));
+        }
+        match record.payload.first() {
+            Some(&KIND_CODED) => {
+                record.payload.remove(0);
+                Ok(StoredBlock::Coded(record))
+            }
+            Some(&KIND_REFERENCE) if record.payload.len() == 9 => Ok(StoredBlock::Reference {
+                target: u64::from_le_bytes(record.payload[1..].try_into().unwrap()),
+                orig_len: record.orig_len,
+                checksum: record.checksum,
+            }),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknown block kind")),
+        }
+    }
+
+    /// The record to write for this block in a stream with `header`.
+    pub(crate) fn into_record(self, header: &Header) -> BlockRecord {
+        match self {
+            StoredBlock::Coded(mut record) => {
+                if header.dedup_window.is_some() {
+                    record.payload.insert(0, KIND_CODED);
+                }
+                record
+            }
+            StoredBlock::Reference { target, orig_len, checksum } => {
+                let mut payload = vec![KIND_REFERENCE];
+                payload.extend_from_slice(&target.to_le_bytes());
+                BlockRecord { orig_len, checksum, payload }
+            }
+        }
+    }
+}
+
+/// The coded blocks a deduplicating stream may still refer to: the most
+/// recently used ones, holding at most `limit` bytes of original data.
+/// Encoder and decoder make the same calls, so they agree on its contents;
+/// the encoder keeps block hashes in it, the decoder block contents.
+pub(crate) struct DedupWindow<T> {
+    limit: u64,
+    used: u64,
+    clock: u64,
+    /// Block number to last use, length and value.
+    blocks: HashMap<u64, (u64, usize, T)>,
+    /// Last use to block number, oldest first.
+    order: BTreeMap<u64, u64>,
+}
+
+impl<T> DedupWindow<T> {
+    pub(crate) fn new(limit: u64) -> Self {
+        DedupWindow { limit, used: 0, clock: 0, blocks: HashMap::new(), order: BTreeMap::new() }
+    }
+
+    /// Adds coded block `block` of `len` bytes and returns the values it
+    /// pushed out. A block longer than the whole window is not kept.
+    pub(crate) fn insert(&mut self, block: u64, len: usize, value: T) -> Vec<T> {
+        let mut evicted = Vec::new();
+        if len as u64 > self.limit {
+            return evicted;
+        }
+        while self.used + len as u64 > self.limit {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, oldest_len, value)) = self.blocks.remove(&oldest) {
+                self.used -= oldest_len as u64;
+                evicted.push(value);
+            }
+        }
+        self.clock += 1;
+        self.order.insert(self.clock, block);
+        self.blocks.insert(block, (self.clock, len, value));
+        self.used += len as u64;
+        evicted
+    }
+
+    /// Marks `block` as used by a reference and returns its value, or `None`
+    /// if it is not in the window.
+    pub(crate) fn touch(&mut self, block: u64) -> Option<&T> {
+        let (last_use, _, value) = self.blocks.get_mut(&block)?;
+        self.order.remove(last_use);
+        self.clock += 1;
+        *last_use = self.clock;
+        self.order.insert(self.clock, block);
+        Some(value)
+    }
+}
+
+/// Encoder side: decides which blocks are duplicates.
+pub(crate) struct Deduplicator {
+    window: DedupWindow<BlockHash>,
+    by_hash: HashMap<BlockHash, u64>,
+}
+
+impl Deduplicator {
+    /// `None` for streams that do not deduplicate.
+    pub(crate) fn for_header(header: &Header) -> Option<Self> {
+        header.dedup_window.map(|limit| Deduplicator { window: DedupWindow::new(limit), by_hash: HashMap::new() })
+    }
+
+    /// For each of the consecutive blocks `blocks`, the first of which is
+    /// block `first_block`, the earlier coded block it duplicates, if any.
+    /// The blocks are hashed in parallel.
+    pub(crate) fn check_batch<B: AsRef<[u8]> + Sync>(&mut self, blocks: &[B], first_block: u64) -> Vec<Option<u64>> {
+        let hashes: Vec<BlockHash> = blocks.par_iter().map(|block| block_hash(block.as_ref())).collect();
+        hashes
+            .into_iter()
+            .zip(blocks)
+            .enumerate()
+            .map(|(i, (hash, block))| self.check(first_block + i as u64, block.as_ref().len(), hash))
+            .collect()
+    }
+
+    /// The earlier coded block that block `block`, of `len` bytes and hash
+    /// `hash`, duplicates, if any; otherwise records it as coded.
+    fn check(&mut self, block: u64, len: usize, hash: BlockHash) -> Option<u64> {
+        if let Some(&target) = self.by_hash.get(&hash) {
+            self.window.touch(target);
+            return Some(target);
+        }
+        for evicted in self.window.insert(block, len, hash) {
+            self.by_hash.remove(&evicted);
+        }
+        if self.window.blocks.contains_key(&block) {
+            self.by_hash.insert(hash, block);
+        }
+        None
+    }
+}
+
+/// Decoder side: the recent coded blocks references resolve to.
+pub(crate) struct Resolver {
+    window: DedupWindow<Vec<u8>>,
+}
+
+impl Resolver {
+    /// `None` for streams that do not deduplicate. Fails if holding the
+    /// stream's window would break the memory limit.
+    pub(crate) fn for_header(header: &Header, memory_limit: Option<usize>) -> io::Result<Option<Self>> {
+        let Some(limit) = header.dedup_window else {
+            return Ok(None);
+        };
+        if let Some(memory_limit) = memory_limit.filter(|&memory_limit| limit > memory_limit as u64) {
+            return Err(LimitExceeded::Memory { needed: limit, limit: memory_limit as u64 }.into());
+        }
+        Ok(Some(Resolver { window: DedupWindow::new(limit) }))
+    }
+
+    /// Keeps decoded block `block` for later references.
+    pub(crate) fn coded(&mut self, block: u64, data: Vec<u8>) {
+        self.window.insert(block, data.len(), data);
+    }
+
+    /// The contents of the block a reference points to, checked against the
+    /// reference's length and checksum.
+    pub(crate) fn resolve(&mut self, header: &Header, target: u64, orig_len: usize, checksum: &[u8]) -> io::Result<&[u8]> {
+        let data = self
+            .window
+            .touch(target)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("reference to block {} outside the dedup window", target)))?;
+        if data.len() != orig_len || header.checksum.compute(data) != checksum {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "block checksum mismatch"));
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::compressor::{compress_stream, decompress_stream};
+    use crate::compressor::{CompressionOptions, DecompressOptions};
+
+    const BLOCK: usize = 256;
+
+    /// Distinct block contents, one per letter.
+    fn block(letter: u8) -> Vec<u8> {
+        (0..BLOCK).map(|i| letter.wrapping_mul(31).wrapping_add((i * i % 253) as u8)).collect()
+    }
+
+    /// Compresses `letters` as one block each with a window of `window`
+    /// blocks and returns how many were stored as references, checking that
+    /// the stream decodes and reports the same.
+    fn duplicates(letters: &[u8], window: u64) -> u64 {
+        let data: Vec<u8> = letters.iter().flat_map(|&letter| block(letter)).collect();
+        let options = CompressionOptions::new().with_block_size(BLOCK).with_dedup_window(Some(window * BLOCK as u64));
+        let mut stream = Vec::new();
+        let written = compress_stream(&data[..], &mut stream, &options).unwrap();
+        let mut decoded = Vec::new();
+        let read = decompress_stream(&stream[..], &mut decoded, &DecompressOptions::new()).unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(read.duplicate_blocks, written.duplicate_blocks);
+        written.duplicate_blocks
+    }
+
+    #[test]
+    fn duplicates_are_referenced_while_in_the_window() {
+        assert_eq!(duplicates(b"abcabc", 8), 3);
+        // `a` leaves a two-block window when `c` arrives, so the second `a`
+        // is coded again and the third refers to the second.
+        assert_eq!(duplicates(b"abcaa", 2), 1);
+        // A reference counts as a use: `b` is evicted instead of `a`.
+        assert_eq!(duplicates(b"abacab", 2), 2);
+        // A block longer than the window is never kept.
+        assert_eq!(duplicates(b"aaa", 0), 0);
+    }
+
+    #[test]
+    fn references_outside_the_window_are_refused() {
+        let header = Header { dedup_window: Some(2 * BLOCK as u64), ..CompressionOptions::new().header() };
+        let mut resolver = Resolver::for_header(&header, None).unwrap().unwrap();
+        for (number, letter) in (0..).zip(b"abc") {
+            resolver.coded(number, block(*letter));
+        }
+        let checksum = header.checksum.compute(&block(b'a'));
+        let error = resolver.resolve(&header, 0, BLOCK, &checksum).unwrap_err();
+        assert!(error.to_string().contains("outside the dedup window"), "{}", error);
+        let checksum = header.checksum.compute(&block(b'c'));
+        assert_eq!(resolver.resolve(&header, 2, BLOCK, &checksum).unwrap(), block(b'c'));
+        assert!(resolver.resolve(&header, 1, BLOCK, &checksum).is_err());
+
+        let error = Resolver::for_header(&header, Some(BLOCK)).err().unwrap();
+        assert!(matches!(error.get_ref().and_then(|e| e.downcast_ref::<LimitExceeded>()), Some(LimitExceeded::Memory { .. })));
+    }
+}
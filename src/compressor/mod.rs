@@ -1 +1,22 @@
-pub mod compressor; 
\ No newline at end of file
+pub mod access;
+pub mod append;
+pub mod chunking;
+pub mod compressor;
+pub mod dedup;
+pub mod options;
+pub mod salvage;
+pub mod stream;
+
+pub use compressor::{
+    compress_block, compress_block_with_stats, compress_file, compress_file_with_progress,
+    compress_file_with_stats, compress_stream, decompress_block, decompress_file,
+    decompress_file_with_progress, decompress_stream, deserialize_grammar, expand_grammar,
+    serialize_grammar, BlockStats, Progress,
+};
+pub use access::RandomAccessReader;
+pub use append::{append_files, append_stream};
+pub use chunking::ChunkSizes;
+pub use dedup::DEFAULT_DEDUP_WINDOW;
+pub use options::{CompressionOptions, DecompressOptions, DEFAULT_BLOCK_SIZE, DEFAULT_LEVEL, MAX_LEVEL, MIN_LEVEL};
+pub use salvage::{salvage_file, salvage_stream, SalvageReport};
+pub use stream::{CompressWriter, DecompressReader};
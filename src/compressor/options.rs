@@ -0,0 +1,507 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use crate::container::{BlockRecord, ChecksumKind, Header, KdfParams, LimitExceeded, Stages, SyncMarker, FLAG_SYNC};
+use crate::ctw::ctw::MAX_CONTEXT_LEN;
+use crate::dictionary::Dictionary;
+use crate::encryption::encryption::BlockCipher;
+use crate::encryption::Credentials;
+use crate::recovery::recovery::validate_percent;
+use crate::volume::volume::validate_volume_size;
+use super::chunking::ChunkSizes;
+
+pub const DEFAULT_BLOCK_SIZE: usize = 256 * 1024; // 256 KB
+pub const DEFAULT_LEVEL: u32 = 6;
+pub const MIN_LEVEL: u32 = 1;
+pub const MAX_LEVEL: u32 = 9;
+
+/// Rough per-input-byte working set of one block in flight: the block
+/// itself, its symbol vectors and digram tables during grammar inference,
+/// and the serialized stream.
+const GRAMMAR_BYTES_PER_INPUT_BYTE: usize = 64;
+/// Rough per-input-byte cost of each context order kept by the model.
+const MODEL_BYTES_PER_INPUT_BYTE: usize = 24;
+
+/// Settings for the compression side of the pipeline.
+///
+/// Start from a preset and adjust individual settings:
+///
+/// ```
+/// use blockpiper::{ChecksumKind, CompressionOptions};
+///
+/// let options = CompressionOptions::level(9)
+///     .with_threads(4)
+///     .with_checksum(ChecksumKind::None);
+/// assert_eq!(options.threads, 4);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionOptions {
+    /// Size of the independent blocks the input is cut into.
+    pub block_size: usize,
+    /// Cut blocks at content-defined boundaries of these sizes instead of
+    /// every `block_size` bytes, so that an edit to the input only changes
+    /// the blocks around it. Archives always use `block_size`.
+    pub chunking: Option<ChunkSizes>,
+    /// Worker threads for block-parallel compression; `0` uses Rayon's
+    /// global pool (one thread per core).
+    pub threads: usize,
+    /// Number of previous symbols the context model conditions on.
+    pub model_depth: usize,
+    pub stages: Stages,
+    pub checksum: ChecksumKind,
+    /// Upper bound on working memory; limits how many blocks are compressed
+    /// at once. `None` means no limit.
+    pub memory_limit: Option<usize>,
+    /// Archives only: concatenate all files into shared blocks so that
+    /// small, similar files are modelled together.
+    pub solid: bool,
+    /// Preset dictionary every block is coded against. Its context model
+    /// replaces `model_depth`, and decoding needs the same dictionary.
+    pub dictionary: Option<Arc<Dictionary>>,
+    /// Start each block's context model from the one the previous block
+    /// left behind, in independent groups of this many blocks. Groups are
+    /// still compressed and decoded in parallel, but blocks within a group
+    /// are coded one after another. Archives do not use priming.
+    pub prime_group: Option<usize>,
+    /// Store a block whose contents match a recently used earlier block as
+    /// a reference to it. The window is how many bytes of blocks stay
+    /// available for reference; decoding holds that much in memory.
+    /// Archives do not deduplicate.
+    pub dedup_window: Option<u64>,
+    /// Encrypt every block with a key derived from these credentials.
+    pub encryption: Option<Arc<Credentials>>,
+    /// Cost of deriving the key; decoding pays the same cost.
+    pub kdf: KdfParams,
+    /// Put a [`SyncMarker`] in front of every
+    /// block, so that the intact blocks of a damaged stream can still be
+    /// found and decoded (see [`salvage_stream`](super::salvage::salvage_stream)).
+    /// Costs 24 bytes per block. Archives find their blocks through the
+    /// index and do not use markers.
+    pub sync_markers: bool,
+    /// Add recovery records of about this many percent of the output's
+    /// size, from which damaged parts of it can be rebuilt; see
+    /// [`crate::recovery`]. Only output written to a file can have them.
+    pub recovery: Option<u8>,
+    /// Split the finished output into volumes of at most this many bytes,
+    /// `name.bpc.001`, `name.bpc.002` and so on; see [`crate::volume`].
+    /// Only output written to a file can be split.
+    pub volume_size: Option<u64>,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self::level(DEFAULT_LEVEL)
+    }
+}
+
+impl CompressionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Preset for `level` (clamped to 1..=9). Each level uses blocks at
+    /// least as large and a model at least as deep as the one below it, so
+    /// a higher level never compresses worse. No preset runs grammar
+    /// inference: on text, logs and diffs it costs ratio as well as time
+    /// next to the context model alone, so it has to be asked for with
+    /// [`Stages::grammar`].
+    pub fn level(level: u32) -> Self {
+        let (block_size, model_depth) = match level.clamp(MIN_LEVEL, MAX_LEVEL) {
+            1 => (64 * 1024, 1),
+            2 => (64 * 1024, 2),
+            3 => (128 * 1024, 3),
+            4 => (256 * 1024, 3),
+            5 => (256 * 1024, 4),
+            6 => (DEFAULT_BLOCK_SIZE, 5),
+            7 => (1024 * 1024, 5),
+            8 => (1024 * 1024, MAX_CONTEXT_LEN),
+            _ => (4 * 1024 * 1024, MAX_CONTEXT_LEN),
+        };
+        CompressionOptions {
+            block_size,
+            chunking: None,
+            threads: 0,
+            model_depth,
+            stages: Stages {
+                grammar: false,
+                context_model: true,
+            },
+            checksum: ChecksumKind::default(),
+            memory_limit: None,
+            solid: false,
+            dictionary: None,
+            prime_group: None,
+            dedup_window: None,
+            encryption: None,
+            kdf: KdfParams::default(),
+            sync_markers: true,
+            recovery: None,
+            volume_size: None,
+        }
+    }
+
+    /// Fastest preset (level 1).
+    pub fn fast() -> Self {
+        Self::level(MIN_LEVEL)
+    }
+
+    /// Strongest preset (level 9).
+    pub fn max() -> Self {
+        Self::level(MAX_LEVEL)
+    }
+
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn with_chunking(mut self, chunking: Option<ChunkSizes>) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn with_model_depth(mut self, model_depth: usize) -> Self {
+        self.model_depth = model_depth;
+        self
+    }
+
+    pub fn with_stages(mut self, stages: Stages) -> Self {
+        self.stages = stages;
+        self
+    }
+
+    pub fn with_checksum(mut self, checksum: ChecksumKind) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn with_memory_limit(mut self, memory_limit: Option<usize>) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    pub fn with_solid(mut self, solid: bool) -> Self {
+        self.solid = solid;
+        self
+    }
+
+    pub fn with_dictionary(mut self, dictionary: Option<Arc<Dictionary>>) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+
+    pub fn with_prime_group(mut self, prime_group: Option<usize>) -> Self {
+        self.prime_group = prime_group;
+        self
+    }
+
+    /// Turns deduplication on with a window of `dedup_window` bytes; see
+    /// [`DEFAULT_DEDUP_WINDOW`](super::dedup::DEFAULT_DEDUP_WINDOW).
+    pub fn with_dedup_window(mut self, dedup_window: Option<u64>) -> Self {
+        self.dedup_window = dedup_window;
+        self
+    }
+
+    pub fn with_encryption(mut self, encryption: Option<Arc<Credentials>>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    pub fn with_kdf(mut self, kdf: KdfParams) -> Self {
+        self.kdf = kdf;
+        self
+    }
+
+    pub fn with_sync_markers(mut self, sync_markers: bool) -> Self {
+        self.sync_markers = sync_markers;
+        self
+    }
+
+    pub fn with_recovery(mut self, recovery: Option<u8>) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    pub fn with_volume_size(mut self, volume_size: Option<u64>) -> Self {
+        self.volume_size = volume_size;
+        self
+    }
+
+    /// Rejects settings the pipeline or the format cannot represent.
+    pub fn validate(&self) -> io::Result<()> {
+        if self.block_size == 0 {
+            return Err(invalid_input("block size must be non-zero".to_string()));
+        }
+        if let Some(chunking) = &self.chunking {
+            chunking.validate()?;
+        }
+        // Lengths are stored as 64-bit values, so the only bound is the
+        // memory a block's working set can take on this platform.
+        if self.estimated_block_memory() == usize::MAX {
+            return Err(invalid_input(format!("block size {} is too large for this platform", self.largest_block())));
+        }
+        match self.prime_group {
+            Some(0) => return Err(invalid_input("priming group must hold at least one block".to_string())),
+            Some(group) if group > u32::MAX as usize => {
+                return Err(invalid_input(format!("priming group of {} blocks does not fit the format", group)));
+            }
+            _ => {}
+        }
+        if let Some(percent) = self.recovery {
+            validate_percent(percent)?;
+        }
+        if let Some(size) = self.volume_size {
+            validate_volume_size(size)?;
+        }
+        if self.model_depth > MAX_CONTEXT_LEN {
+            return Err(invalid_input(format!("model depth must be at most {}", MAX_CONTEXT_LEN)));
+        }
+        if let Some(limit) = self.memory_limit {
+            if limit < self.estimated_block_memory() {
+                return Err(invalid_input(format!(
+                    "memory limit of {} bytes is below the ~{} bytes one {}-byte block needs",
+                    limit,
+                    self.estimated_block_memory(),
+                    self.largest_block()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rough working memory needed to compress one block.
+    pub fn estimated_block_memory(&self) -> usize {
+        block_memory(self.largest_block(), self.stages, self.model_depth)
+    }
+
+    /// Longest block these options cut: `block_size`, or the maximum
+    /// content-defined block size.
+    pub fn largest_block(&self) -> usize {
+        match &self.chunking {
+            Some(chunking) => chunking.max,
+            None => self.block_size,
+        }
+    }
+
+    /// How many blocks may be compressed at once with `threads` workers
+    /// without exceeding the memory limit.
+    pub(crate) fn blocks_in_flight(&self, threads: usize) -> usize {
+        let by_memory = match self.memory_limit {
+            Some(limit) => limit / self.estimated_block_memory().max(1),
+            None => usize::MAX,
+        };
+        threads.min(by_memory).max(1)
+    }
+
+    /// How many blocks to read per batch. With priming only whole groups run
+    /// in parallel, so a batch holds one group per block in flight.
+    pub(crate) fn batch_len(&self, threads: usize) -> usize {
+        self.blocks_in_flight(threads).saturating_mul(self.prime_group.unwrap_or(1))
+    }
+
+    /// Adds the encryption fields to `header` when encrypting, returning the
+    /// header to write and the cipher for its blocks. This runs the key
+    /// derivation, so call it once per stream.
+    pub(crate) fn seal(&self, mut header: Header) -> io::Result<(Header, Option<BlockCipher>)> {
+        match &self.encryption {
+            Some(credentials) => {
+                let cipher = BlockCipher::seal_header(&mut header, credentials, self.kdf)?;
+                Ok((header, Some(cipher)))
+            }
+            None => Ok((header, None)),
+        }
+    }
+
+    /// The stream header these options produce, without encryption fields;
+    /// see [`CompressionOptions::seal`].
+    pub fn header(&self) -> Header {
+        Header {
+            flags: if self.sync_markers { FLAG_SYNC } else { 0 },
+            stages: self.stages,
+            checksum: self.checksum,
+            model_depth: match &self.dictionary {
+                Some(dictionary) => dictionary.model().depth() as u8,
+                None => self.model_depth as u8,
+            },
+            block_size: self.largest_block() as u64,
+            total_len: None,
+            dictionary_id: self.dictionary.as_ref().map(|dictionary| dictionary.id()),
+            prime_group: self.prime_group.map(|group| group as u32),
+            dedup_window: self.dedup_window,
+            encryption: None,
+        }
+    }
+}
+
+/// Settings for the decompression side of the pipeline.
+///
+/// The limits make it safe to decode untrusted input: every size a stream
+/// declares is checked against them before memory is set aside for it, and
+/// a stream that asks for more fails with a [`LimitExceeded`] error. All
+/// limits default to `None`, meaning no limit.
+///
+/// ```
+/// use blockpiper::DecompressOptions;
+///
+/// let options = DecompressOptions::new()
+///     .with_max_block_size(Some(4 << 20))
+///     .with_max_output(Some(1 << 30))
+///     .with_memory_limit(Some(256 << 20));
+/// assert_eq!(options.max_grammar_rules, None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecompressOptions {
+    /// Dictionary for streams that were compressed with one. Streams that
+    /// need a different dictionary, or none is given, are rejected.
+    pub dictionary: Option<Arc<Dictionary>>,
+    /// Largest original length accepted for a single block.
+    pub max_block_size: Option<usize>,
+    /// Most bytes one stream or archive reader may produce in total.
+    pub max_output: Option<u64>,
+    /// Most grammar rules accepted in a single block.
+    pub max_grammar_rules: Option<usize>,
+    /// Upper bound on working memory; a block that needs more is rejected,
+    /// and fewer blocks are decoded at once to stay below it.
+    pub memory_limit: Option<usize>,
+    /// Password or key file for encrypted streams.
+    pub encryption: Option<Arc<Credentials>>,
+}
+
+impl DecompressOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_dictionary(mut self, dictionary: Option<Arc<Dictionary>>) -> Self {
+        self.dictionary = dictionary;
+        self
+    }
+
+    pub fn with_max_block_size(mut self, max_block_size: Option<usize>) -> Self {
+        self.max_block_size = max_block_size;
+        self
+    }
+
+    pub fn with_max_output(mut self, max_output: Option<u64>) -> Self {
+        self.max_output = max_output;
+        self
+    }
+
+    pub fn with_max_grammar_rules(mut self, max_grammar_rules: Option<usize>) -> Self {
+        self.max_grammar_rules = max_grammar_rules;
+        self
+    }
+
+    pub fn with_memory_limit(mut self, memory_limit: Option<usize>) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    pub fn with_encryption(mut self, encryption: Option<Arc<Credentials>>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// The cipher for `header`'s blocks, if the stream is encrypted. Fails
+    /// with `PermissionDenied` if no credentials were given or they are
+    /// wrong.
+    pub(crate) fn cipher(&self, header: &Header) -> io::Result<Option<BlockCipher>> {
+        if !header.is_encrypted() {
+            return Ok(None);
+        }
+        let credentials = self.encryption.as_deref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::PermissionDenied, "stream is encrypted; supply the password or key file")
+        })?;
+        BlockCipher::open_header(header, credentials, self.memory_limit).map(Some)
+    }
+
+    /// Reads the record at stream offset `*offset`, with its sync marker if
+    /// the stream has them, and advances `offset` past it. Checks the
+    /// record's declared lengths and its decoding cost before the payload is
+    /// read, and decrypts it if `cipher` is set.
+    pub(crate) fn read_record<R: Read>(&self, reader: &mut R, header: &Header, cipher: Option<&BlockCipher>, offset: &mut u64) -> io::Result<Option<BlockRecord>> {
+        let marker_len = if header.has_sync_markers() {
+            if SyncMarker::read(reader)?.is_none() {
+                return Ok(None);
+            }
+            SyncMarker::LEN
+        } else {
+            0
+        };
+        let max_orig_len = self.max_block_size.unwrap_or(usize::MAX);
+        let max_payload_len = self.memory_limit.unwrap_or(usize::MAX);
+        let check_memory = |block_len: u64, orig_len: u64| match self.memory_limit {
+            Some(limit) => {
+                let needed = length_memory(header, orig_len, block_len);
+                if needed > limit {
+                    return Err(LimitExceeded::Memory { needed: needed as u64, limit: limit as u64 }.into());
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        };
+        let Some(mut record) = BlockRecord::read_checked(reader, header.stored_checksum(), max_orig_len, max_payload_len, check_memory)? else {
+            if marker_len > 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "sync marker without a block"));
+            }
+            return Ok(None);
+        };
+        let record_offset = *offset;
+        *offset += (marker_len + record.framed_len()) as u64;
+        if let Some(cipher) = cipher {
+            cipher.open(&mut record, record_offset)?;
+        }
+        Ok(Some(record))
+    }
+
+    /// Whether `record` may join a batch whose records already need `used`
+    /// bytes; returns the new total if so. An empty batch takes any record
+    /// [`DecompressOptions::read_record`] let through.
+    pub(crate) fn fit_in_batch(&self, header: &Header, used: usize, record: &BlockRecord) -> Option<usize> {
+        let total = used.saturating_add(record_memory(header, record));
+        match self.memory_limit {
+            Some(limit) if used > 0 && total > limit => None,
+            _ => Some(total),
+        }
+    }
+
+    /// Fails if producing `total` bytes of output would break the limit.
+    pub(crate) fn check_output(&self, total: u64) -> io::Result<()> {
+        match self.max_output {
+            Some(limit) if total > limit => Err(LimitExceeded::TotalOutput { limit }.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn max_rules(&self) -> usize {
+        self.max_grammar_rules.unwrap_or(usize::MAX)
+    }
+}
+
+/// Rough working memory to code `block_size` bytes with the given stages.
+fn block_memory(block_size: usize, stages: Stages, model_depth: usize) -> usize {
+    let grammar = if stages.grammar { GRAMMAR_BYTES_PER_INPUT_BYTE } else { 2 };
+    let model = if stages.context_model { MODEL_BYTES_PER_INPUT_BYTE * (model_depth + 1) } else { 0 };
+    block_size.saturating_mul(grammar + model)
+}
+
+/// Rough working memory to decode `record`, including its payload.
+fn record_memory(header: &Header, record: &BlockRecord) -> usize {
+    length_memory(header, record.orig_len as u64, record.payload.len() as u64)
+}
+
+/// [`record_memory`] for a record with the given lengths, before it is read.
+fn length_memory(header: &Header, orig_len: u64, payload_len: u64) -> usize {
+    let orig_len = usize::try_from(orig_len).unwrap_or(usize::MAX);
+    let payload_len = usize::try_from(payload_len).unwrap_or(usize::MAX);
+    block_memory(orig_len, header.stages, header.model_depth as usize).saturating_add(payload_len)
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,427 @@
+//! Decoding what is left of a damaged stream.
+//!
+//! In a stream with sync markers every record starts with a marker naming
+//! its block and where the block's data belongs, so after a damaged record
+//! the decoder looks for the next intact marker and carries on from there.
+//! Without markers it can still pass over a block whose payload is damaged,
+//! but it has to trust every record's lengths, and it stops at the first
+//! record whose lengths make no sense.
+//!
+//! Blocks are only written once their checksum passes, so a stream written
+//! with `ChecksumKind::None` and not encrypted cannot tell a damaged block
+//! from an intact one.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::container::{BlockRecord, Header, SyncMarker, END_OF_BLOCKS, SYNC_MAGIC};
+use crate::ctw::Ctw;
+use crate::dictionary::Dictionary;
+use crate::encryption::encryption::BlockCipher;
+use crate::recovery::recovery::protected_len;
+use crate::volume::VolumeReader;
+use super::compressor::decompress_stored;
+use super::dedup::{Resolver, StoredBlock};
+use super::options::DecompressOptions;
+
+/// Bytes searched at a time for the next sync marker.
+const SCAN_CHUNK: usize = 1024 * 1024;
+
+/// What [`salvage_stream`] recovered.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// Blocks decoded and written, and the original bytes they hold.
+    pub blocks: u64,
+    pub recovered_bytes: u64,
+    /// Ranges of the original data that could not be recovered, in order.
+    pub lost: Vec<Range<u64>>,
+    /// Whether data after the last recovered block was lost too, in a
+    /// stream whose header does not record its length. How much is
+    /// unknown, so it is not part of `lost`; with the length known, a lost
+    /// tail is the last range in `lost`.
+    pub tail_lost: bool,
+}
+
+impl SalvageReport {
+    /// Whether the whole stream was recovered.
+    pub fn is_complete(&self) -> bool {
+        self.lost.is_empty() && !self.tail_lost
+    }
+
+    pub fn lost_bytes(&self) -> u64 {
+        self.lost.iter().map(|range| range.end - range.start).sum()
+    }
+}
+
+/// [`salvage_stream`] from one file into another.
+pub fn salvage_file<P: AsRef<Path>, Q: AsRef<Path>>(input_path: P, output_path: Q, options: &DecompressOptions, placeholder: Option<u8>) -> io::Result<SalvageReport> {
+    let reader = BufReader::new(VolumeReader::open(input_path)?);
+    let mut writer = BufWriter::new(File::create(output_path)?);
+    let report = salvage_stream(reader, &mut writer, options, placeholder)?;
+    writer.flush()?;
+    Ok(report)
+}
+
+/// Decodes every intact block of the possibly damaged stream in `reader`
+/// into `writer` and reports the ranges of original data that were lost.
+///
+/// A lost range is written as that many `placeholder` bytes, so the blocks
+/// after it keep their offsets, or left out if `placeholder` is `None`. A
+/// block is lost if its record is damaged, if it is a reference to a lost
+/// block, or, in a primed stream, if an earlier block of its group is
+/// lost. Only the stream header must be intact.
+pub fn salvage_stream<R: Read + Seek, W: Write>(mut reader: R, mut writer: W, options: &DecompressOptions, placeholder: Option<u8>) -> io::Result<SalvageReport> {
+    let header = Header::read(&mut reader)?;
+    if header.is_archive() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "stream is a multi-file archive; extract its entries instead"));
+    }
+    let dictionary = Dictionary::for_header(&header, options.dictionary.as_deref())?;
+    let cipher = options.cipher(&header)?;
+    let mut resolver = Resolver::for_header(&header, options.memory_limit)?;
+    let end = protected_len(&mut reader)?;
+    let mut scanner = Scanner {
+        reader,
+        header: &header,
+        cipher: cipher.as_ref(),
+        options,
+        pos: header.encoded_len() as u64,
+        end,
+        next_block: 0,
+        next_output: 0,
+        clean_end: false,
+    };
+
+    let group = header.prime_group.map_or(1, |group| group as u64);
+    let batch_len = rayon::current_num_threads().max(1) * group as usize;
+    let mut report = SalvageReport::default();
+    let mut written = 0u64;
+    let mut last_lost = false;
+    let mut next = scanner.next()?;
+    while next.is_some() {
+        // Batches end at a priming group boundary, so each group is decoded
+        // whole, in order, by one worker.
+        let mut batch: Vec<Found> = Vec::with_capacity(batch_len);
+        while let Some(found) = next.take() {
+            let boundary = batch.last().is_some_and(|last: &Found| last.block / group != found.block / group);
+            if batch.len() >= batch_len && boundary {
+                next = Some(found);
+                break;
+            }
+            batch.push(found);
+            next = scanner.next()?;
+        }
+
+        for (found, decoded) in decode_batch(batch, &header, dictionary, options.max_rules(), group) {
+            let data = match decoded {
+                Some(StoredBlock::Coded(data)) => Some(data),
+                Some(StoredBlock::Reference { target, orig_len, checksum }) => resolver
+                    .as_mut()
+                    .and_then(|resolver| resolver.resolve(&header, target, orig_len, &checksum).ok())
+                    .map(|data| data.to_vec()),
+                None => None,
+            };
+            let Some(data) = data else {
+                last_lost = true;
+                continue;
+            };
+            if found.output_offset < written {
+                // Overlaps what was already written; the marker cannot be
+                // trusted.
+                last_lost = true;
+                continue;
+            }
+            let gap = found.output_offset - written;
+            if gap > 0 {
+                report.lost.push(written..found.output_offset);
+            }
+            let output = match placeholder {
+                Some(_) => found.output_offset + data.len() as u64,
+                None => report.recovered_bytes + data.len() as u64,
+            };
+            options.check_output(output)?;
+            if let Some(byte) = placeholder {
+                io::copy(&mut io::repeat(byte).take(gap), &mut writer)?;
+            }
+            writer.write_all(&data)?;
+            written = found.output_offset + data.len() as u64;
+            report.blocks += 1;
+            report.recovered_bytes += data.len() as u64;
+            last_lost = false;
+            if let Some(resolver) = resolver.as_mut() {
+                resolver.coded(found.block, data);
+            }
+        }
+    }
+    match header.total_len {
+        Some(total) if written <= total => {
+            if written < total {
+                report.lost.push(written..total);
+                if let Some(byte) = placeholder {
+                    options.check_output(total)?;
+                    io::copy(&mut io::repeat(byte).take(total - written), &mut writer)?;
+                }
+            }
+        }
+        _ => report.tail_lost = last_lost || !scanner.clean_end,
+    }
+    writer.flush()?;
+    Ok(report)
+}
+
+/// A record found in the stream, decrypted; `None` if it could not be read
+/// or decrypted.
+struct Found {
+    block: u64,
+    output_offset: u64,
+    record: Option<BlockRecord>,
+}
+
+/// Walks the records of a damaged stream.
+struct Scanner<'a, R> {
+    reader: R,
+    header: &'a Header,
+    cipher: Option<&'a BlockCipher>,
+    options: &'a DecompressOptions,
+    /// Stream offset to continue from, and where the block records end.
+    pos: u64,
+    end: u64,
+    /// Markers for earlier blocks or data are stale or false and skipped.
+    next_block: u64,
+    next_output: u64,
+    /// Whether the walk reached the end of the records without damage.
+    clean_end: bool,
+}
+
+impl<R: Read + Seek> Scanner<'_, R> {
+    fn next(&mut self) -> io::Result<Option<Found>> {
+        if !self.header.has_sync_markers() {
+            return self.next_unmarked();
+        }
+        let Some((at, marker)) = self.find_marker(self.pos)? else {
+            self.clean_end = self.is_end(self.pos)?;
+            return Ok(None);
+        };
+        let record = self.read_record(at, at + SyncMarker::LEN as u64)?;
+        // A record that is not followed by the next marker or the end may
+        // have damaged lengths, so the search goes on from inside it.
+        self.pos = match &record {
+            Some((_, next)) if self.is_end(*next)? || self.marker_at(*next)?.is_some() => *next,
+            _ => at + 1,
+        };
+        self.clean_end = false;
+        self.next_block = marker.block + 1;
+        self.next_output = marker.output_offset;
+        let record = record.map(|(record, _)| record);
+        if let Some(record) = &record {
+            self.next_output += record.orig_len as u64;
+        }
+        Ok(Some(Found { block: marker.block, output_offset: marker.output_offset, record }))
+    }
+
+    /// Without markers, records are taken one after the other until one
+    /// cannot be read.
+    fn next_unmarked(&mut self) -> io::Result<Option<Found>> {
+        if self.is_end(self.pos)? {
+            self.clean_end = true;
+            return Ok(None);
+        }
+        let Some((record, next)) = self.read_record(self.pos, self.pos)? else {
+            return Ok(None);
+        };
+        let found = Found { block: self.next_block, output_offset: self.next_output, record: None };
+        self.pos = next;
+        self.next_block += 1;
+        self.next_output += record.orig_len as u64;
+        Ok(Some(Found { record: self.open(record, found.output_offset)?, ..found }))
+    }
+
+    /// Whether the block records end at `offset`.
+    fn is_end(&mut self, offset: u64) -> io::Result<bool> {
+        if offset >= self.end {
+            return Ok(true);
+        }
+        let mut buf = Vec::new();
+        self.reader.seek(SeekFrom::Start(offset))?;
+        (&mut self.reader).take(END_OF_BLOCKS.len() as u64).read_to_end(&mut buf)?;
+        Ok(buf == END_OF_BLOCKS)
+    }
+
+    /// The intact marker at `offset`, if there is one.
+    fn marker_at(&mut self, offset: u64) -> io::Result<Option<SyncMarker>> {
+        let mut buf = [0u8; SyncMarker::LEN];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        match self.reader.read_exact(&mut buf) {
+            Ok(()) => Ok(SyncMarker::decode(&buf)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The first intact marker at or after `from` that comes after the
+    /// blocks already found.
+    fn find_marker(&mut self, from: u64) -> io::Result<Option<(u64, SyncMarker)>> {
+        let mut start = from;
+        while start < self.end {
+            let mut buf = Vec::new();
+            self.reader.seek(SeekFrom::Start(start))?;
+            let want = (self.end - start).min((SCAN_CHUNK + SyncMarker::LEN - 1) as u64);
+            (&mut self.reader).take(want).read_to_end(&mut buf)?;
+            for pos in 0..buf.len().saturating_sub(SyncMarker::LEN - 1) {
+                if buf[pos..pos + SYNC_MAGIC.len()] != SYNC_MAGIC {
+                    continue;
+                }
+                let marker = SyncMarker::decode(buf[pos..pos + SyncMarker::LEN].try_into().unwrap());
+                if let Some(marker) = marker.filter(|marker| marker.block >= self.next_block && marker.output_offset >= self.next_output) {
+                    return Ok(Some((start + pos as u64, marker)));
+                }
+            }
+            if (buf.len() as u64) < want {
+                break;
+            }
+            start += SCAN_CHUNK as u64;
+        }
+        Ok(None)
+    }
+
+    /// The record at `offset` and the offset after it, if its lengths are
+    /// plausible and it lies within the block records. `marker_offset` is
+    /// where the record's framing starts, which is its encryption nonce.
+    fn read_record(&mut self, marker_offset: u64, offset: u64) -> io::Result<Option<(BlockRecord, u64)>> {
+        let block_size = usize::try_from(self.header.block_size).unwrap_or(usize::MAX);
+        let max_orig_len = self.options.max_block_size.unwrap_or(usize::MAX).min(block_size);
+        // Coded blocks can grow a little over their original length.
+        let max_payload_len = self.options.memory_limit.unwrap_or(usize::MAX).min(block_size.saturating_mul(2).saturating_add(64 * 1024));
+        self.reader.seek(SeekFrom::Start(offset))?;
+        let record = match BlockRecord::read_with_limits(&mut self.reader, self.header.stored_checksum(), max_orig_len, max_payload_len) {
+            Ok(Some(record)) => record,
+            Ok(None) => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData || e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let next = offset + record.framed_len() as u64;
+        if next > self.end {
+            return Ok(None);
+        }
+        Ok(self.open(record, marker_offset)?.map(|record| (record, next)))
+    }
+
+    /// Decrypts `record`, read at stream offset `offset`, if the stream is
+    /// encrypted; `None` if it does not authenticate.
+    fn open(&self, mut record: BlockRecord, offset: u64) -> io::Result<Option<BlockRecord>> {
+        match self.cipher {
+            Some(cipher) => Ok(cipher.open(&mut record, offset).ok().map(|()| record)),
+            None => Ok(Some(record)),
+        }
+    }
+}
+
+/// Decodes the records of `batch`, whose priming groups of `group` blocks
+/// are whole, a group per worker. A block whose record is missing or fails
+/// to decode comes back as `None`, and so do the blocks after it in its
+/// group, which needed the model it would have left behind.
+fn decode_batch(batch: Vec<Found>, header: &Header, dictionary: Option<&Dictionary>, max_rules: usize, group: u64) -> Vec<(Found, Option<StoredBlock<Vec<u8>>>)> {
+    let mut runs: Vec<Vec<Found>> = Vec::new();
+    for found in batch {
+        match runs.last_mut() {
+            Some(run) if header.prime_group.is_some() && run[0].block / group == found.block / group => run.push(found),
+            _ => runs.push(vec![found]),
+        }
+    }
+    runs.into_par_iter()
+        .flat_map_iter(|run| {
+            let mut primer: Option<Option<Ctw>> = None;
+            let mut expected = run[0].block - run[0].block % group;
+            run.into_iter()
+                .map(|mut found| {
+                    // The model the previous block left behind, if the chain
+                    // from the start of the group is unbroken.
+                    let carried = if found.block % group == 0 {
+                        Some(None)
+                    } else if found.block == expected {
+                        primer.take()
+                    } else {
+                        None
+                    };
+                    expected = found.block + 1;
+                    let decoded = match (carried, found.record.take()) {
+                        (Some(carried), Some(record)) => decompress_stored(record, header, dictionary, max_rules, carried).ok(),
+                        _ => None,
+                    };
+                    let decoded = decoded.map(|(block, next)| {
+                        primer = Some(next);
+                        block
+                    });
+                    (found, decoded)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::compressor::{compress_stream, CompressionOptions};
+
+    /// Eight blocks of 1 KiB, each different from the others.
+    fn sample() -> Vec<u8> {
+        (0..8)
+            .flat_map(|block| {
+                let text = format!("block {} says {}", block, "hello ".repeat(block + 1));
+                text.into_bytes().into_iter().cycle().take(1024)
+            })
+            .collect()
+    }
+
+    /// Compresses `data` in 1 KiB blocks and flips a bit in the payload of
+    /// block `damaged`.
+    fn damaged_stream(data: &[u8], options: CompressionOptions, damaged: usize) -> Vec<u8> {
+        let mut stream = Vec::new();
+        compress_stream(data, &mut stream, &options.with_block_size(1024)).unwrap();
+        let header = Header::read(&mut &stream[..]).unwrap();
+        let mut offset = header.encoded_len();
+        for _ in 0..damaged {
+            let lengths = offset + SyncMarker::LEN;
+            let payload_len = u64::from_le_bytes(stream[lengths..lengths + 8].try_into().unwrap()) as usize;
+            offset = lengths + BlockRecord::LENGTHS_LEN + header.checksum.len() + payload_len;
+        }
+        stream[offset + SyncMarker::LEN + BlockRecord::LENGTHS_LEN + header.checksum.len() + 1] ^= 1;
+        stream
+    }
+
+    fn salvage(stream: Vec<u8>, placeholder: Option<u8>) -> (SalvageReport, Vec<u8>) {
+        let mut out = Vec::new();
+        let report = salvage_stream(Cursor::new(stream), &mut out, &DecompressOptions::new(), placeholder).unwrap();
+        (report, out)
+    }
+
+    #[test]
+    fn lost_ranges_are_exact() {
+        let data = sample();
+        let stream = damaged_stream(&data, CompressionOptions::new(), 2);
+        let (report, out) = salvage(stream.clone(), Some(b'?'));
+        assert_eq!(report.lost, vec![Range { start: 2048, end: 3072 }]);
+        assert_eq!((report.blocks, report.recovered_bytes, report.tail_lost), (7, 7 * 1024, false));
+        let mut expected = data.clone();
+        expected[2048..3072].fill(b'?');
+        assert_eq!(out, expected);
+
+        let (_, out) = salvage(stream, None);
+        assert_eq!(out, [&data[..2048], &data[3072..]].concat());
+    }
+
+    #[test]
+    fn a_lost_block_takes_the_rest_of_its_primed_group() {
+        let data = sample();
+        let stream = damaged_stream(&data, CompressionOptions::new().with_prime_group(Some(4)), 1);
+        let (report, out) = salvage(stream, None);
+        assert_eq!(report.lost, vec![Range { start: 1024, end: 4096 }]);
+        assert_eq!(out, [&data[..1024], &data[4096..]].concat());
+    }
+}
@@ -0,0 +1,248 @@
+use std::io::{self, Read, Write};
+
+use crate::container::{Header, SyncMarker};
+use crate::ctw::Ctw;
+use crate::dictionary::Dictionary;
+use crate::encryption::encryption::BlockCipher;
+use super::chunking::split_blocks;
+use super::compressor::{compress_stored, decompress_stored, map_primed, write_record, Workers};
+use super::dedup::{Deduplicator, Resolver, StoredBlock};
+use super::options::{CompressionOptions, DecompressOptions};
+
+/// `Write` adapter that compresses everything written to it into `inner`.
+///
+/// Input is buffered until a batch of full blocks is available, which is then
+/// compressed in parallel. Call [`CompressWriter::finish`] to flush the final
+/// partial block and get the inner writer back; dropping the writer also
+/// flushes, but swallows any error.
+pub struct CompressWriter<W: Write> {
+    inner: Option<W>,
+    options: CompressionOptions,
+    header: Header,
+    header_written: bool,
+    cipher: Option<BlockCipher>,
+    /// Bytes written to `inner` so far, and input bytes compressed.
+    written: u64,
+    consumed: u64,
+    /// Blocks written so far, and the model the last one left behind in a
+    /// primed stream.
+    blocks: u64,
+    primer: Option<Ctw>,
+    dedup: Option<Deduplicator>,
+    workers: Workers,
+    pending: Vec<u8>,
+    batch_len: usize,
+}
+
+impl<W: Write> CompressWriter<W> {
+    /// Fails if `options` do not validate, ask for recovery records or
+    /// volumes, or the worker pool cannot start.
+    pub fn new(inner: W, options: CompressionOptions) -> io::Result<Self> {
+        options.validate()?;
+        if options.recovery.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "recovery records can only be added to a file"));
+        }
+        if options.volume_size.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "only output written to a file can be split into volumes"));
+        }
+        let workers = Workers::new(options.threads)?;
+        let batch_len = options.batch_len(workers.threads());
+        let (header, cipher) = options.seal(options.header())?;
+        Ok(CompressWriter {
+            inner: Some(inner),
+            header,
+            header_written: false,
+            cipher,
+            written: 0,
+            consumed: 0,
+            blocks: 0,
+            primer: None,
+            dedup: Deduplicator::for_header(&header),
+            workers,
+            pending: Vec::with_capacity(options.largest_block()),
+            options,
+            batch_len,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().expect("CompressWriter used after finish")
+    }
+
+    /// Compresses the buffered tail and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_blocks(true)?;
+        let mut inner = self.inner.take().expect("CompressWriter used after finish");
+        inner.flush()?;
+        Ok(inner)
+    }
+
+    fn flush_blocks(&mut self, include_partial: bool) -> io::Result<()> {
+        let inner = self.inner.as_mut().expect("CompressWriter used after finish");
+        if !self.header_written {
+            self.written += self.header.write(inner)? as u64;
+            self.header_written = true;
+        }
+        let blocks = split_blocks(&self.pending, &self.options, include_partial);
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let end: usize = blocks.iter().map(|block| block.len()).sum();
+        let header = &self.header;
+        let dictionary = self.options.dictionary.as_deref();
+        let first_block = self.blocks;
+        self.blocks += blocks.len() as u64;
+        let primer = &mut self.primer;
+        let dedup = &mut self.dedup;
+        let records = self.workers.install(|| {
+            let duplicates = match dedup.as_mut() {
+                Some(dedup) => dedup.check_batch(&blocks, first_block),
+                None => vec![None; blocks.len()],
+            };
+            let blocks: Vec<(&[u8], Option<u64>)> = blocks.into_iter().zip(duplicates).collect();
+            map_primed(blocks, first_block, header.prime_group, primer, |(block_data, duplicate_of), primer| {
+                let (record, _, primer) = compress_stored(block_data, duplicate_of, header, dictionary, primer);
+                Ok((record, primer))
+            })
+        });
+        for (number, record) in (first_block..).zip(records) {
+            let record = record?;
+            let marker = header.has_sync_markers().then_some(SyncMarker { block: number, output_offset: self.consumed });
+            self.consumed += record.orig_len as u64;
+            self.written += write_record(inner, record, self.cipher.as_ref(), self.written, marker)? as u64;
+        }
+        self.pending.drain(..end);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= self.options.largest_block() * self.batch_len {
+            self.flush_blocks(false)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Only flushes whole blocks; a partial block stays buffered until
+    /// [`CompressWriter::finish`] so block boundaries do not depend on how
+    /// often the caller flushes.
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_blocks(false)?;
+        self.inner.as_mut().expect("CompressWriter used after finish").flush()
+    }
+}
+
+impl<W: Write> Drop for CompressWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.flush_blocks(true);
+            if let Some(inner) = self.inner.as_mut() {
+                let _ = inner.flush();
+            }
+        }
+    }
+}
+
+/// `Read` adapter that yields the decompressed contents of a `.bpc` stream.
+pub struct DecompressReader<R: Read> {
+    inner: R,
+    header: Option<Header>,
+    options: DecompressOptions,
+    cipher: Option<BlockCipher>,
+    /// Stream offset of the next record.
+    offset: u64,
+    /// Blocks decoded so far, and the model the last one left behind in a
+    /// primed stream.
+    blocks: u64,
+    primer: Option<Ctw>,
+    /// Recent coded blocks, in a deduplicating stream.
+    resolver: Option<Resolver>,
+    block: Vec<u8>,
+    pos: usize,
+    /// Bytes decoded so far, checked against `options.max_output`.
+    produced: u64,
+}
+
+impl<R: Read> DecompressReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_options(inner, DecompressOptions::default())
+    }
+
+    pub fn with_options(inner: R, options: DecompressOptions) -> Self {
+        DecompressReader {
+            inner,
+            header: None,
+            options,
+            cipher: None,
+            offset: 0,
+            blocks: 0,
+            primer: None,
+            resolver: None,
+            block: Vec::new(),
+            pos: 0,
+            produced: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for DecompressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let header = match self.header {
+            Some(header) => header,
+            None => {
+                let header = Header::read(&mut self.inner)?;
+                if header.is_archive() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "stream is a multi-file archive; extract it instead"));
+                }
+                Dictionary::for_header(&header, self.options.dictionary.as_deref())?;
+                self.cipher = self.options.cipher(&header)?;
+                self.offset = header.encoded_len() as u64;
+                self.resolver = Resolver::for_header(&header, self.options.memory_limit)?;
+                *self.header.insert(header)
+            }
+        };
+        while self.pos == self.block.len() {
+            match self.options.read_record(&mut self.inner, &header, self.cipher.as_ref(), &mut self.offset)? {
+                Some(record) => {
+                    self.produced += record.orig_len as u64;
+                    self.options.check_output(self.produced)?;
+                    header.check_decoded_len(self.produced, false)?;
+                    let dictionary = self.options.dictionary.as_deref().filter(|_| header.dictionary_id.is_some());
+                    let primer = match header.prime_group {
+                        Some(group) if !self.blocks.is_multiple_of(group as u64) => self.primer.take(),
+                        _ => None,
+                    };
+                    let (block, primer) = decompress_stored(record, &header, dictionary, self.options.max_rules(), primer)?;
+                    self.block = match (block, self.resolver.as_mut()) {
+                        (StoredBlock::Coded(block), Some(resolver)) => {
+                            resolver.coded(self.blocks, block.clone());
+                            block
+                        }
+                        (StoredBlock::Coded(block), None) => block,
+                        (StoredBlock::Reference { target, orig_len, checksum }, Some(resolver)) => {
+                            resolver.resolve(&header, target, orig_len, &checksum)?.to_vec()
+                        }
+                        (StoredBlock::Reference { .. }, None) => unreachable!("references only occur in deduplicating streams"),
+                    };
+                    self.primer = primer;
+                    self.blocks += 1;
+                    self.pos = 0;
+                }
+                None => {
+                    header.check_decoded_len(self.produced, true)?;
+                    return Ok(0);
+                }
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
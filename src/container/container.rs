@@ -0,0 +1,656 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// First bytes of every `.bpc` stream.
+pub const MAGIC: [u8; 4] = *b"BPIP";
+pub const FORMAT_VERSION: u8 = 2;
+
+/// Header flag: the stream is a multi-file archive (see `crate::archive`).
+pub const FLAG_ARCHIVE: u8 = 1 << 0;
+/// Header flag: archive entries are concatenated into shared blocks.
+pub const FLAG_SOLID: u8 = 1 << 1;
+/// Header flag: blocks were coded against a preset dictionary whose id
+/// follows the fixed header fields.
+pub const FLAG_DICTIONARY: u8 = 1 << 2;
+/// Header flag: blocks are encrypted; the key derivation parameters and an
+/// authentication tag for the header follow the dictionary id.
+pub const FLAG_ENCRYPTED: u8 = 1 << 3;
+/// Header flag: each block's context model starts from the previous
+/// block's, in groups whose size follows the dictionary id.
+pub const FLAG_PRIMED: u8 = 1 << 4;
+/// Header flag: duplicate blocks are stored as references to earlier ones;
+/// the size of the window they may refer back into follows the priming
+/// group size.
+pub const FLAG_DEDUP: u8 = 1 << 5;
+/// Header flag: every block record of the stream is preceded by a
+/// [`SyncMarker`], so that a damaged stream can be salvaged.
+pub const FLAG_SYNC: u8 = 1 << 6;
+/// Payload length that ends the block records early: whatever follows is
+/// not a block (see `crate::recovery`). No real record is this long.
+pub const END_OF_BLOCKS: [u8; 8] = [0xff; 8];
+const KNOWN_FLAGS: u8 = FLAG_ARCHIVE | FLAG_SOLID | FLAG_DICTIONARY | FLAG_ENCRYPTED | FLAG_PRIMED | FLAG_DEDUP | FLAG_SYNC;
+
+const STAGE_GRAMMAR: u8 = 1 << 0;
+const STAGE_CONTEXT_MODEL: u8 = 1 << 1;
+
+/// Which pipeline stages a block passes through. With `grammar` off the raw
+/// bytes are coded directly; with `context_model` off every byte is coded
+/// under a flat distribution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stages {
+    pub grammar: bool,
+    pub context_model: bool,
+}
+
+impl Default for Stages {
+    fn default() -> Self {
+        Stages {
+            grammar: true,
+            context_model: true,
+        }
+    }
+}
+
+impl Stages {
+    fn to_bits(self) -> u8 {
+        let mut bits = 0;
+        if self.grammar {
+            bits |= STAGE_GRAMMAR;
+        }
+        if self.context_model {
+            bits |= STAGE_CONTEXT_MODEL;
+        }
+        bits
+    }
+
+    fn from_bits(bits: u8) -> io::Result<Self> {
+        if bits & !(STAGE_GRAMMAR | STAGE_CONTEXT_MODEL) != 0 {
+            return Err(invalid_data("unknown pipeline stage flags"));
+        }
+        Ok(Stages {
+            grammar: bits & STAGE_GRAMMAR != 0,
+            context_model: bits & STAGE_CONTEXT_MODEL != 0,
+        })
+    }
+}
+
+/// Integrity check stored with every block, computed over the original bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumKind {
+    None,
+    #[default]
+    Crc32,
+}
+
+impl ChecksumKind {
+    /// Number of checksum bytes stored per block.
+    pub fn len(self) -> usize {
+        match self {
+            ChecksumKind::None => 0,
+            ChecksumKind::Crc32 => 4,
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn compute(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ChecksumKind::None => Vec::new(),
+            ChecksumKind::Crc32 => crc32fast::hash(data).to_le_bytes().to_vec(),
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            ChecksumKind::None => 0,
+            ChecksumKind::Crc32 => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(ChecksumKind::None),
+            1 => Ok(ChecksumKind::Crc32),
+            _ => Err(invalid_data("unknown checksum kind")),
+        }
+    }
+}
+
+impl std::str::FromStr for ChecksumKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(ChecksumKind::None),
+            "crc32" => Ok(ChecksumKind::Crc32),
+            _ => Err(format!("unknown checksum kind: {}", s)),
+        }
+    }
+}
+
+/// Cost parameters of the Argon2id key derivation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory per derivation in KiB.
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// 64 MiB, three passes, one lane.
+    fn default() -> Self {
+        KdfParams { memory_kib: 64 * 1024, iterations: 3, parallelism: 1 }
+    }
+}
+
+/// Header fields of an encrypted stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncryptionHeader {
+    pub kdf: KdfParams,
+    /// Random per stream, so every stream gets its own key.
+    pub salt: [u8; 16],
+    /// AEAD tag over the rest of the header under the derived key; a
+    /// mismatch means the password or key file is wrong.
+    pub key_check: [u8; 16],
+}
+
+impl EncryptionHeader {
+    /// Length on disk: KDF parameters, salt, header CRC and key check.
+    const LEN: usize = 12 + 16 + 4 + 16;
+}
+
+/// Stream header: everything a decoder needs to know before the first block.
+///
+/// Layout: `MAGIC`, version `u8`, flags `u8`, stage flags `u8`, checksum
+/// kind `u8`, model depth `u8`, nominal block size `u64`, total length of
+/// the original data `u64` (`u64::MAX` if unknown), then the optional
+/// fields announced by the flags: dictionary id `u64`; priming group size
+/// `u32`; dedup window `u64`; Argon2 memory,
+/// iterations and parallelism `u32` each, salt `[u8; 16]`, CRC32 of the
+/// header so far `u32` and key check `[u8; 16]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// `FLAG_*` bits describing the stream layout.
+    pub flags: u8,
+    pub stages: Stages,
+    pub checksum: ChecksumKind,
+    pub model_depth: u8,
+    pub block_size: u64,
+    /// Length of all the original data, when it was known; checked once the
+    /// stream is decoded. Archives leave it unset: their index records the
+    /// size of every entry.
+    pub total_len: Option<u64>,
+    /// Id of the preset dictionary the blocks need, if any.
+    pub dictionary_id: Option<u64>,
+    /// Blocks per priming group: within a group every block's context model
+    /// starts from the one the previous block left behind, and each group
+    /// starts afresh.
+    pub prime_group: Option<u32>,
+    /// Bytes of recently used blocks a duplicate block may refer back to.
+    pub dedup_window: Option<u64>,
+    pub encryption: Option<EncryptionHeader>,
+}
+
+impl Header {
+    /// Length of the fields every header has.
+    pub const FIXED_LEN: usize = 25;
+
+    /// Length of this header on disk.
+    pub fn encoded_len(&self) -> usize {
+        Self::FIXED_LEN
+            + if self.dictionary_id.is_some() { 8 } else { 0 }
+            + if self.prime_group.is_some() { 4 } else { 0 }
+            + if self.dedup_window.is_some() { 8 } else { 0 }
+            + if self.encryption.is_some() { EncryptionHeader::LEN } else { 0 }
+    }
+
+    pub fn is_archive(&self) -> bool {
+        self.flags & FLAG_ARCHIVE != 0
+    }
+
+    pub fn is_solid(&self) -> bool {
+        self.flags & FLAG_SOLID != 0
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// Checksum stored in the clear in front of every record's payload. An
+    /// encrypted stream seals the checksum inside the payload instead.
+    pub fn stored_checksum(&self) -> ChecksumKind {
+        if self.is_encrypted() {
+            ChecksumKind::None
+        } else {
+            self.checksum
+        }
+    }
+
+    pub fn has_sync_markers(&self) -> bool {
+        self.flags & FLAG_SYNC != 0
+    }
+
+    /// Checks `decoded` bytes of output against `total_len`, if known: more
+    /// is always an error, fewer only once the blocks have `ended`.
+    pub fn check_decoded_len(&self, decoded: u64, ended: bool) -> io::Result<()> {
+        match self.total_len {
+            Some(total) if decoded > total => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("stream holds more than the {} bytes its header records", total),
+            )),
+            Some(total) if ended && decoded < total => {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("stream ends after {} of {} bytes", decoded, total)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<usize> {
+        let mut bytes = self.authenticated_bytes();
+        if let Some(encryption) = &self.encryption {
+            bytes.extend_from_slice(&encryption.key_check);
+        }
+        writer.write_all(&bytes)?;
+        Ok(bytes.len())
+    }
+
+    /// The encoded header up to, but not including, the key check. An
+    /// encrypted stream's key check authenticates exactly these bytes.
+    pub fn authenticated_bytes(&self) -> Vec<u8> {
+        let mut flags = self.flags & !(FLAG_DICTIONARY | FLAG_PRIMED | FLAG_DEDUP | FLAG_ENCRYPTED);
+        if self.dictionary_id.is_some() {
+            flags |= FLAG_DICTIONARY;
+        }
+        if self.prime_group.is_some() {
+            flags |= FLAG_PRIMED;
+        }
+        if self.dedup_window.is_some() {
+            flags |= FLAG_DEDUP;
+        }
+        if self.encryption.is_some() {
+            flags |= FLAG_ENCRYPTED;
+        }
+        let mut out = Vec::with_capacity(self.encoded_len());
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&[
+            FORMAT_VERSION,
+            flags,
+            self.stages.to_bits(),
+            self.checksum.to_byte(),
+            self.model_depth,
+        ]);
+        out.extend_from_slice(&self.block_size.to_le_bytes());
+        out.extend_from_slice(&self.total_len.unwrap_or(u64::MAX).to_le_bytes());
+        if let Some(id) = self.dictionary_id {
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        if let Some(group) = self.prime_group {
+            out.extend_from_slice(&group.to_le_bytes());
+        }
+        if let Some(window) = self.dedup_window {
+            out.extend_from_slice(&window.to_le_bytes());
+        }
+        if let Some(encryption) = &self.encryption {
+            out.extend_from_slice(&encryption.kdf.memory_kib.to_le_bytes());
+            out.extend_from_slice(&encryption.kdf.iterations.to_le_bytes());
+            out.extend_from_slice(&encryption.kdf.parallelism.to_le_bytes());
+            out.extend_from_slice(&encryption.salt);
+            let crc = crc32fast::hash(&out);
+            out.extend_from_slice(&crc.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut buf = [0u8; Self::FIXED_LEN];
+        reader.read_exact(&mut buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid_data("not a BlockPiper stream (too short)"),
+            _ => e,
+        })?;
+        if buf[..4] != MAGIC {
+            return Err(invalid_data("not a BlockPiper stream (bad magic)"));
+        }
+        if buf[4] != FORMAT_VERSION {
+            return Err(invalid_data(&format!("unsupported format version {} (this version reads {})", buf[4], FORMAT_VERSION)));
+        }
+        if buf[5] & !KNOWN_FLAGS != 0 {
+            return Err(invalid_data("unknown header flags"));
+        }
+        let dictionary_id = if buf[5] & FLAG_DICTIONARY != 0 {
+            let mut id = [0u8; 8];
+            reader.read_exact(&mut id)?;
+            Some(u64::from_le_bytes(id))
+        } else {
+            None
+        };
+        let prime_group = if buf[5] & FLAG_PRIMED != 0 {
+            let mut group = [0u8; 4];
+            reader.read_exact(&mut group)?;
+            match u32::from_le_bytes(group) {
+                0 => return Err(invalid_data("priming group size is zero")),
+                group => Some(group),
+            }
+        } else {
+            None
+        };
+        let dedup_window = if buf[5] & FLAG_DEDUP != 0 {
+            let mut window = [0u8; 8];
+            reader.read_exact(&mut window)?;
+            Some(u64::from_le_bytes(window))
+        } else {
+            None
+        };
+        let mut header = Header {
+            flags: buf[5],
+            stages: Stages::from_bits(buf[6])?,
+            checksum: ChecksumKind::from_byte(buf[7])?,
+            model_depth: buf[8],
+            block_size: u64::from_le_bytes(buf[9..17].try_into().unwrap()),
+            total_len: match u64::from_le_bytes(buf[17..25].try_into().unwrap()) {
+                u64::MAX => None,
+                len => Some(len),
+            },
+            dictionary_id,
+            prime_group,
+            dedup_window,
+            encryption: None,
+        };
+        if buf[5] & FLAG_ENCRYPTED != 0 {
+            let mut fields = [0u8; EncryptionHeader::LEN];
+            reader.read_exact(&mut fields)?;
+            let u32_at = |at: usize| u32::from_le_bytes(fields[at..at + 4].try_into().unwrap());
+            header.encryption = Some(EncryptionHeader {
+                kdf: KdfParams { memory_kib: u32_at(0), iterations: u32_at(4), parallelism: u32_at(8) },
+                salt: fields[12..28].try_into().unwrap(),
+                key_check: fields[32..48].try_into().unwrap(),
+            });
+            // The CRC tells a damaged header apart from a wrong password,
+            // which would otherwise both just fail the key check.
+            let authenticated = header.authenticated_bytes();
+            if authenticated[authenticated.len() - 4..] != fields[28..32] {
+                return Err(invalid_data("stream header is corrupt"));
+            }
+        }
+        Ok(header)
+    }
+}
+
+/// A stream asked for more than a decoder's configured limits allow.
+/// Returned inside an `InvalidData` [`io::Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// A block's original length is above the maximum block size.
+    BlockSize { size: u64, limit: u64 },
+    /// A block's stored payload is too large to hold in memory.
+    PayloadSize { size: u64, limit: u64 },
+    /// The decoded output would grow past the allowed total.
+    TotalOutput { limit: u64 },
+    /// A block's grammar declares more rules than allowed.
+    GrammarRules { rules: u64, limit: u64 },
+    /// Decoding a block would need more working memory than allowed.
+    Memory { needed: u64, limit: u64 },
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitExceeded::BlockSize { size, limit } => write!(f, "block of {} bytes exceeds the limit of {}", size, limit),
+            LimitExceeded::PayloadSize { size, limit } => write!(f, "stored block of {} bytes exceeds the limit of {}", size, limit),
+            LimitExceeded::TotalOutput { limit } => write!(f, "output would exceed the limit of {} bytes", limit),
+            LimitExceeded::GrammarRules { rules, limit } => write!(f, "grammar with {} rules exceeds the limit of {}", rules, limit),
+            LimitExceeded::Memory { needed, limit } => write!(f, "block needs ~{} bytes of memory, above the limit of {}", needed, limit),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl From<LimitExceeded> for io::Error {
+    fn from(e: LimitExceeded) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Marks the start of a block record in a stream with [`FLAG_SYNC`] and says
+/// where the block belongs, so that a reader that lost its place in a
+/// damaged stream can find the next record and put its block back in the
+/// right spot.
+///
+/// Layout: `SYNC_MAGIC`, block number `u64`, offset of the block in the
+/// original data `u64`, CRC32 of those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncMarker {
+    pub block: u64,
+    pub output_offset: u64,
+}
+
+/// First bytes of every [`SyncMarker`].
+pub const SYNC_MAGIC: [u8; 4] = *b"BPSY";
+
+impl SyncMarker {
+    pub const LEN: usize = 24;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        out[..4].copy_from_slice(&SYNC_MAGIC);
+        out[4..12].copy_from_slice(&self.block.to_le_bytes());
+        out[12..20].copy_from_slice(&self.output_offset.to_le_bytes());
+        let crc = crc32fast::hash(&out[..20]);
+        out[20..].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// The marker in `buf`, if it holds an intact one.
+    pub fn decode(buf: &[u8; Self::LEN]) -> Option<Self> {
+        if buf[..4] != SYNC_MAGIC || crc32fast::hash(&buf[..20]).to_le_bytes() != buf[20..] {
+            return None;
+        }
+        Some(SyncMarker {
+            block: u64::from_le_bytes(buf[4..12].try_into().unwrap()),
+            output_offset: u64::from_le_bytes(buf[12..20].try_into().unwrap()),
+        })
+    }
+
+    /// Reads the marker in front of a record, returning `None` at a clean
+    /// end of input or at [`END_OF_BLOCKS`].
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut buf = [0u8; Self::LEN];
+        if !read_exact_or_eof(reader, &mut buf[..END_OF_BLOCKS.len()])? || buf[..END_OF_BLOCKS.len()] == END_OF_BLOCKS {
+            return Ok(None);
+        }
+        reader.read_exact(&mut buf[END_OF_BLOCKS.len()..])?;
+        Self::decode(&buf).map(Some).ok_or_else(|| invalid_data("damaged sync marker"))
+    }
+}
+
+/// One framed block as stored in the stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockRecord {
+    pub orig_len: usize,
+    pub checksum: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+impl BlockRecord {
+    /// Length of the `[block_len u64][orig_len u64]` fields in front of
+    /// every record.
+    pub const LENGTHS_LEN: usize = 16;
+
+    /// Size of the record on disk.
+    pub fn framed_len(&self) -> usize {
+        Self::LENGTHS_LEN + self.checksum.len() + self.payload.len()
+    }
+
+    /// Reads only a record's lengths and seeks past its checksum and payload.
+    /// Returns `(orig_len, framed_len)`, or `None` at a clean end of input
+    /// or at [`END_OF_BLOCKS`]. Seeking does not notice a truncated payload;
+    /// callers compare the final position with the length of the input.
+    pub fn skip<R: Read + Seek>(reader: &mut R, checksum: ChecksumKind) -> io::Result<Option<(usize, usize)>> {
+        let Some((block_len, orig_len)) = read_lengths(reader)? else {
+            return Ok(None);
+        };
+        let rest = block_len
+            .checked_add(checksum.len() as u64)
+            .and_then(|rest| i64::try_from(rest).ok())
+            .ok_or_else(|| invalid_data("block length out of range"))?;
+        let framed_len = to_usize(rest as u64 + Self::LENGTHS_LEN as u64)?;
+        reader.seek(SeekFrom::Current(rest))?;
+        Ok(Some((to_usize(orig_len)?, framed_len)))
+    }
+
+    /// [`BlockRecord::skip`] for a record of the stream with `header`,
+    /// together with its [`SyncMarker`] if the stream has them; `framed_len`
+    /// counts the marker.
+    pub fn skip_in<R: Read + Seek>(reader: &mut R, header: &Header) -> io::Result<Option<(usize, usize)>> {
+        if !header.has_sync_markers() {
+            return Self::skip(reader, header.stored_checksum());
+        }
+        if SyncMarker::read(reader)?.is_none() {
+            return Ok(None);
+        }
+        let skipped = Self::skip(reader, header.stored_checksum())?.ok_or_else(|| invalid_data("sync marker without a block"))?;
+        Ok(Some((skipped.0, SyncMarker::LEN + skipped.1)))
+    }
+
+    /// Writes `[block_len u64][orig_len u64][checksum][payload]` and returns
+    /// the number of bytes written.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<usize> {
+        writer.write_all(&(self.payload.len() as u64).to_le_bytes())?;
+        writer.write_all(&(self.orig_len as u64).to_le_bytes())?;
+        writer.write_all(&self.checksum)?;
+        writer.write_all(&self.payload)?;
+        Ok(self.framed_len())
+    }
+
+    /// Reads one record, returning `None` at a clean end of input or at
+    /// [`END_OF_BLOCKS`].
+    pub fn read<R: Read>(reader: &mut R, checksum: ChecksumKind) -> io::Result<Option<Self>> {
+        Self::read_with_limits(reader, checksum, usize::MAX, usize::MAX)
+    }
+
+    /// Like [`BlockRecord::read`], but rejects records whose original length
+    /// exceeds `max_orig_len` or whose payload exceeds `max_payload_len`
+    /// before reading the payload.
+    pub fn read_with_limits<R: Read>(reader: &mut R, checksum: ChecksumKind, max_orig_len: usize, max_payload_len: usize) -> io::Result<Option<Self>> {
+        Self::read_checked(reader, checksum, max_orig_len, max_payload_len, |_, _| Ok(()))
+    }
+
+    /// Like [`BlockRecord::read_with_limits`], and also calls `check` with
+    /// the record's `(block_len, orig_len)` once they pass the limits, so a
+    /// caller can refuse the record before its payload is read.
+    pub(crate) fn read_checked<R, F>(reader: &mut R, checksum: ChecksumKind, max_orig_len: usize, max_payload_len: usize, check: F) -> io::Result<Option<Self>>
+    where
+        R: Read,
+        F: FnOnce(u64, u64) -> io::Result<()>,
+    {
+        let Some((block_len, orig_len)) = read_lengths(reader)? else {
+            return Ok(None);
+        };
+        if orig_len > max_orig_len as u64 {
+            return Err(LimitExceeded::BlockSize { size: orig_len, limit: max_orig_len as u64 }.into());
+        }
+        if block_len > max_payload_len as u64 {
+            return Err(LimitExceeded::PayloadSize { size: block_len, limit: max_payload_len as u64 }.into());
+        }
+        check(block_len, orig_len)?;
+        let mut checksum_buf = vec![0u8; checksum.len()];
+        reader.read_exact(&mut checksum_buf)?;
+        // Grow the buffer as data arrives, so a length field promising more
+        // than the input holds cannot force a large allocation.
+        let mut payload = Vec::new();
+        reader.take(block_len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != block_len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block"));
+        }
+        Ok(Some(BlockRecord {
+            orig_len: to_usize(orig_len)?,
+            checksum: checksum_buf,
+            payload,
+        }))
+    }
+}
+
+/// Reads a record's `(block_len, orig_len)`, or `None` at a clean end of
+/// input or at [`END_OF_BLOCKS`].
+fn read_lengths<R: Read>(reader: &mut R) -> io::Result<Option<(u64, u64)>> {
+    let mut lengths = [0u8; BlockRecord::LENGTHS_LEN];
+    if !read_exact_or_eof(reader, &mut lengths[..END_OF_BLOCKS.len()])? || lengths[..END_OF_BLOCKS.len()] == END_OF_BLOCKS {
+        return Ok(None);
+    }
+    reader.read_exact(&mut lengths[END_OF_BLOCKS.len()..]).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block header"),
+        _ => e,
+    })?;
+    Ok(Some((u64::from_le_bytes(lengths[..8].try_into().unwrap()), u64::from_le_bytes(lengths[8..].try_into().unwrap()))))
+}
+
+/// Converts a length read from a stream, failing if it does not fit in
+/// memory on this platform.
+pub(crate) fn to_usize(len: u64) -> io::Result<usize> {
+    usize::try_from(len).map_err(|_| invalid_data("block is too large for this platform"))
+}
+
+/// Like `read_exact`, but reports `false` instead of failing when the reader
+/// is already at end of input. A partially filled buffer is still an error.
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block header")),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::CompressionOptions;
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn lengths_above_four_gigabytes_survive_a_round_trip() {
+        let header = Header { block_size: 6 << 30, total_len: Some(5 << 40), ..CompressionOptions::new().header() };
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        assert_eq!(Header::read(&mut &bytes[..]).unwrap(), header);
+
+        // Only the lengths are large, so nothing big is allocated.
+        let orig_len = to_usize(5 << 32).unwrap();
+        let checksum = ChecksumKind::Crc32;
+        let record = BlockRecord { orig_len, checksum: checksum.compute(b"payload"), payload: b"payload".to_vec() };
+        let mut bytes = Vec::new();
+        record.write(&mut bytes).unwrap();
+        assert_eq!(BlockRecord::read(&mut &bytes[..], checksum).unwrap().unwrap().orig_len, orig_len);
+        let skipped = BlockRecord::skip(&mut io::Cursor::new(&bytes), checksum).unwrap().unwrap();
+        assert_eq!(skipped, (orig_len, bytes.len()));
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn oversize_blocks_are_refused() {
+        let record = BlockRecord { orig_len: to_usize(5 << 32).unwrap(), checksum: Vec::new(), payload: vec![0; 16] };
+        let mut bytes = Vec::new();
+        record.write(&mut bytes).unwrap();
+        let limited = |max_orig_len, max_payload_len| {
+            let error = BlockRecord::read_with_limits(&mut &bytes[..], ChecksumKind::None, max_orig_len, max_payload_len).unwrap_err();
+            error.get_ref().and_then(|e| e.downcast_ref::<LimitExceeded>()).copied()
+        };
+        assert_eq!(limited(1 << 32, usize::MAX), Some(LimitExceeded::BlockSize { size: 5 << 32, limit: 1 << 32 }));
+        assert_eq!(limited(usize::MAX, 8), Some(LimitExceeded::PayloadSize { size: 16, limit: 8 }));
+
+        // A block whose working set cannot be addressed is refused up front.
+        let error = CompressionOptions::new().with_block_size(usize::MAX).validate().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
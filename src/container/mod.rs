@@ -0,0 +1,6 @@
+pub mod container;
+pub use container::{
+    BlockRecord, ChecksumKind, EncryptionHeader, Header, KdfParams, LimitExceeded, Stages, SyncMarker, END_OF_BLOCKS,
+    FLAG_ARCHIVE, FLAG_DICTIONARY, FLAG_DEDUP, FLAG_ENCRYPTED, FLAG_PRIMED, FLAG_SOLID, FLAG_SYNC, FORMAT_VERSION, MAGIC,
+    SYNC_MAGIC,
+};
@@ -1,39 +1,184 @@
-use std::collections::HashMap;
-
-const CTW_CONTEXT_LEN: usize = 4;
-
-pub struct Ctw {
-    context: Vec<u8>,
-    tree: HashMap<Vec<u8>, [u32; 256]>,
-}
-
-impl Ctw {
-    pub fn new() -> Self {
-        Ctw {
-            context: Vec::with_capacity(CTW_CONTEXT_LEN),
-            tree: HashMap::new(),
-        }
-    }
-
-    pub fn process_symbol(&mut self, symbol: u8) {
-        let ctx = self.context.clone();
-        let entry = self.tree.entry(ctx).or_insert([0; 256]);
-        entry[symbol as usize] += 1;
-        self.context.push(symbol);
-        if self.context.len() > CTW_CONTEXT_LEN {
-            self.context.remove(0);
-        }
-    }
-
-    /// Returns (cumulative, total) for the symbol, for use with arithmetic coding
-    pub fn get_cumulative(&self, symbol: u8) -> (u32, u32) {
-        let ctx = &self.context;
-        let counts = self.tree.get(ctx).unwrap_or(&[1; 256]); // Laplace smoothing
-        let mut cumulative = 0;
-        for i in 0..(symbol as usize) {
-            cumulative += counts[i];
-        }
-        let total: u32 = counts.iter().sum();
-        (cumulative, total.max(1))
-    }
-} 
\ No newline at end of file
+use std::collections::HashMap;
+
+const CTW_CONTEXT_LEN: usize = 4;
+
+/// Deepest context the model can be configured to condition on.
+pub const MAX_CONTEXT_LEN: usize = 6;
+
+/// Per-context counts are halved once their total reaches this, which keeps
+/// the model adaptive and bounds the mixing arithmetic below.
+const RESCALE_LIMIT: u32 = 1 << 16;
+
+/// Symbol counts seen after one context, stored sparsely since most deep
+/// contexts are only ever followed by a handful of distinct symbols.
+#[derive(Debug, Clone, Default)]
+struct Node {
+    counts: Vec<(u8, u32)>,
+    total: u32,
+}
+
+impl Node {
+    fn add(&mut self, symbol: u8) {
+        match self.counts.iter_mut().find(|(s, _)| *s == symbol) {
+            Some((_, count)) => *count += 1,
+            None => self.counts.push((symbol, 1)),
+        }
+        self.total += 1;
+        if self.total >= RESCALE_LIMIT {
+            self.counts.retain_mut(|(_, count)| {
+                *count /= 2;
+                *count > 0
+            });
+            self.total = self.counts.iter().map(|(_, count)| count).sum();
+        }
+    }
+}
+
+/// Context model over the last `depth` symbols. Every suffix of the current
+/// context (orders `0..=depth`) keeps its own counts; predictions mix all
+/// orders, weighting longer contexts more heavily.
+#[derive(Debug, Clone)]
+pub struct Ctw {
+    depth: usize,
+    context: Vec<u8>,
+    tree: HashMap<Vec<u8>, Node>,
+}
+
+impl Default for Ctw {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ctw {
+    pub fn new() -> Self {
+        Self::with_depth(CTW_CONTEXT_LEN)
+    }
+
+    /// Creates a model conditioning on up to `depth` previous symbols
+    /// (clamped to [`MAX_CONTEXT_LEN`]).
+    pub fn with_depth(depth: usize) -> Self {
+        let depth = depth.min(MAX_CONTEXT_LEN);
+        Ctw {
+            depth,
+            context: Vec::with_capacity(depth),
+            tree: HashMap::new(),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Forgets the current context but keeps all counts, so that a trained
+    /// model can start on a fresh block.
+    pub fn reset_context(&mut self) {
+        self.context.clear();
+    }
+
+    /// Serializes the counts (not the current context). Layout: depth `u8`,
+    /// context count `u32`, then per context its length `u8`, its bytes,
+    /// the number of distinct symbols `u16` and `(symbol u8, count u32)`
+    /// pairs. Contexts are sorted so equal models give equal bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut contexts: Vec<&Vec<u8>> = self.tree.keys().collect();
+        contexts.sort();
+        let mut out = vec![self.depth as u8];
+        out.extend(&(contexts.len() as u32).to_le_bytes());
+        for ctx in contexts {
+            let node = &self.tree[ctx];
+            out.push(ctx.len() as u8);
+            out.extend(ctx);
+            out.extend(&(node.counts.len() as u16).to_le_bytes());
+            for &(symbol, count) in &node.counts {
+                out.push(symbol);
+                out.extend(&count.to_le_bytes());
+            }
+        }
+        out
+    }
+
+    /// Parses the output of [`Ctw::to_bytes`], returning the model and the
+    /// number of bytes consumed.
+    pub fn from_bytes(data: &[u8]) -> Option<(Self, usize)> {
+        let mut pos = 0;
+        let mut take = |n: usize| -> Option<&[u8]> {
+            let bytes = data.get(pos..pos + n)?;
+            pos += n;
+            Some(bytes)
+        };
+        let depth = take(1)?[0] as usize;
+        if depth > MAX_CONTEXT_LEN {
+            return None;
+        }
+        let mut ctw = Ctw::with_depth(depth);
+        let contexts = u32::from_le_bytes(take(4)?.try_into().ok()?);
+        for _ in 0..contexts {
+            let ctx_len = take(1)?[0] as usize;
+            if ctx_len > depth {
+                return None;
+            }
+            let ctx = take(ctx_len)?.to_vec();
+            let entries = u16::from_le_bytes(take(2)?.try_into().ok()?);
+            let mut node = Node::default();
+            for _ in 0..entries {
+                let entry = take(5)?;
+                node.counts.push((entry[0], u32::from_le_bytes(entry[1..5].try_into().ok()?)));
+            }
+            // A model built by `add` always stays below the rescale limit;
+            // anything larger could overflow the mixing arithmetic.
+            let total = node.counts.iter().try_fold(0u32, |sum, (_, count)| sum.checked_add(*count))?;
+            if total >= RESCALE_LIMIT {
+                return None;
+            }
+            node.total = total;
+            ctw.tree.insert(ctx, node);
+        }
+        Some((ctw, pos))
+    }
+
+    pub fn process_symbol(&mut self, symbol: u8) {
+        for order in 0..=self.context.len() {
+            let ctx = &self.context[self.context.len() - order..];
+            match self.tree.get_mut(ctx) {
+                Some(node) => node.add(symbol),
+                None => {
+                    let mut node = Node::default();
+                    node.add(symbol);
+                    self.tree.insert(ctx.to_vec(), node);
+                }
+            }
+        }
+        if self.depth == 0 {
+            return;
+        }
+        self.context.push(symbol);
+        if self.context.len() > self.depth {
+            self.context.remove(0);
+        }
+    }
+
+    /// Mixed symbol frequencies for the current context. Unseen symbols get
+    /// a small floor so that every symbol stays codable.
+    pub fn frequencies(&self) -> [u32; 256] {
+        let mut freqs = [1u32; 256];
+        for order in 0..=self.context.len() {
+            let ctx = &self.context[self.context.len() - order..];
+            if let Some(node) = self.tree.get(ctx) {
+                let weight = 1u32 << (2 * order);
+                for &(symbol, count) in &node.counts {
+                    freqs[symbol as usize] += count * weight;
+                }
+            }
+        }
+        freqs
+    }
+
+    /// Returns (cumulative, total) for the symbol, for use with arithmetic coding
+    pub fn get_cumulative(&self, symbol: u8) -> (u32, u32) {
+        let counts = self.frequencies();
+        let cumulative: u32 = counts[..symbol as usize].iter().sum();
+        let total: u32 = counts.iter().sum();
+        (cumulative, total.max(1))
+    }
+}
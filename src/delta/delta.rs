@@ -0,0 +1,339 @@
+//! Patches that rebuild a file from an earlier version of it.
+//!
+//! The new file is matched against the reference with a rolling hash: runs
+//! found in the reference become copy instructions and everything else is
+//! inserted literally. The instruction stream is then compressed like any
+//! other input, so new data still goes through the grammar and context
+//! model stages.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use blake2::digest::consts::U32;
+use blake2::{Blake2b, Digest};
+
+use crate::compressor::{compress_stream, CompressionOptions, DecompressOptions, DecompressReader};
+
+/// First bytes of a patch file.
+pub const PATCH_MAGIC: [u8; 4] = *b"BPDL";
+const PATCH_VERSION: u8 = 1;
+
+/// Magic, version, then length and hash of the reference and of the target.
+const PATCH_HEADER_LEN: usize = 5 + 2 * (8 + 32);
+
+/// Bytes hashed at a time when looking for matches. Matches shorter than
+/// this are not worth a copy instruction.
+const MATCH_WINDOW: usize = 32;
+
+/// Multiplier of the rolling hash.
+const HASH_BASE: u64 = 0x0000_0100_0000_01b3;
+
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+type FileHash = [u8; 32];
+
+/// What [`create_patch`] found: how much of the target was copied from the
+/// reference and how much had to be stored.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeltaStats {
+    pub reference_len: u64,
+    pub target_len: u64,
+    pub copied: u64,
+    pub inserted: u64,
+    /// Size of the patch, header included.
+    pub patch_len: u64,
+}
+
+/// Lengths and hashes a patch records for the files on either side of it.
+struct PatchHeader {
+    reference_len: u64,
+    reference_hash: FileHash,
+    target_len: u64,
+    target_hash: FileHash,
+}
+
+impl PatchHeader {
+    fn write<W: Write>(&self, writer: &mut W) -> io::Result<usize> {
+        let mut out = PATCH_MAGIC.to_vec();
+        out.push(PATCH_VERSION);
+        out.extend(&self.reference_len.to_le_bytes());
+        out.extend(&self.reference_hash);
+        out.extend(&self.target_len.to_le_bytes());
+        out.extend(&self.target_hash);
+        writer.write_all(&out)?;
+        Ok(out.len())
+    }
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let mut buf = [0u8; PATCH_HEADER_LEN];
+        reader.read_exact(&mut buf).map_err(|_| invalid("not a BlockPiper patch"))?;
+        if buf[..4] != PATCH_MAGIC {
+            return Err(invalid("not a BlockPiper patch"));
+        }
+        if buf[4] != PATCH_VERSION {
+            return Err(invalid("unsupported patch version"));
+        }
+        Ok(PatchHeader {
+            reference_len: u64::from_le_bytes(buf[5..13].try_into().unwrap()),
+            reference_hash: buf[13..45].try_into().unwrap(),
+            target_len: u64::from_le_bytes(buf[45..53].try_into().unwrap()),
+            target_hash: buf[53..85].try_into().unwrap(),
+        })
+    }
+}
+
+fn file_hash(data: &[u8]) -> FileHash {
+    Blake2b::<U32>::digest(data).into()
+}
+
+/// Hash of `window`, which is `MATCH_WINDOW` bytes long.
+fn window_hash(window: &[u8]) -> u64 {
+    window.iter().fold(0u64, |hash, &byte| hash.wrapping_mul(HASH_BASE).wrapping_add(byte as u64))
+}
+
+/// Copy and insert instructions that rebuild a target from a reference.
+///
+/// Layout: a copy is `OP_COPY`, reference offset `u64`, length `u64`; an
+/// insert is `OP_INSERT`, length `u64`, then that many bytes.
+struct Instructions {
+    bytes: Vec<u8>,
+    copied: u64,
+    inserted: u64,
+}
+
+impl Instructions {
+    fn copy(&mut self, offset: usize, len: usize) {
+        self.bytes.push(OP_COPY);
+        self.bytes.extend(&(offset as u64).to_le_bytes());
+        self.bytes.extend(&(len as u64).to_le_bytes());
+        self.copied += len as u64;
+    }
+
+    fn insert(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.bytes.push(OP_INSERT);
+        self.bytes.extend(&(data.len() as u64).to_le_bytes());
+        self.bytes.extend_from_slice(data);
+        self.inserted += data.len() as u64;
+    }
+}
+
+/// Finds the runs of `target` that also occur in `reference`.
+///
+/// Every `MATCH_WINDOW`-aligned window of the reference is indexed by hash,
+/// so any common run of at least twice the window length is found. A hit is
+/// checked byte by byte and then extended in both directions.
+fn diff(reference: &[u8], target: &[u8]) -> Instructions {
+    let mut index: HashMap<u64, usize> = HashMap::new();
+    for start in (0..reference.len().saturating_sub(MATCH_WINDOW - 1)).step_by(MATCH_WINDOW) {
+        index.entry(window_hash(&reference[start..start + MATCH_WINDOW])).or_insert(start);
+    }
+    // Weight of the byte leaving the window when the hash rolls forward.
+    let outgoing = (1..MATCH_WINDOW).fold(1u64, |power, _| power.wrapping_mul(HASH_BASE));
+
+    let mut instructions = Instructions { bytes: Vec::new(), copied: 0, inserted: 0 };
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut hash = None;
+    while pos + MATCH_WINDOW <= target.len() {
+        let current = match hash {
+            Some(hash) => hash,
+            None => window_hash(&target[pos..pos + MATCH_WINDOW]),
+        };
+        let hit = index
+            .get(&current)
+            .copied()
+            .filter(|&start| reference[start..start + MATCH_WINDOW] == target[pos..pos + MATCH_WINDOW]);
+        let Some(start) = hit else {
+            if pos + MATCH_WINDOW < target.len() {
+                let rolled = current.wrapping_sub((target[pos] as u64).wrapping_mul(outgoing));
+                hash = Some(rolled.wrapping_mul(HASH_BASE).wrapping_add(target[pos + MATCH_WINDOW] as u64));
+            }
+            pos += 1;
+            continue;
+        };
+        let back = target[literal_start..pos]
+            .iter()
+            .rev()
+            .zip(reference[..start].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let forward = target[pos..].iter().zip(&reference[start..]).take_while(|(a, b)| a == b).count();
+        instructions.insert(&target[literal_start..pos - back]);
+        instructions.copy(start - back, back + forward);
+        pos += forward;
+        literal_start = pos;
+        hash = None;
+    }
+    instructions.insert(&target[literal_start..]);
+    instructions
+}
+
+/// Writes to `writer` a patch that rebuilds `target` from `reference`. The
+/// instructions are compressed with `options`, which may include a
+/// dictionary or encryption.
+pub fn create_patch<W: Write>(reference: &[u8], target: &[u8], mut writer: W, options: &CompressionOptions) -> io::Result<DeltaStats> {
+    options.validate()?;
+    let header = PatchHeader {
+        reference_len: reference.len() as u64,
+        reference_hash: file_hash(reference),
+        target_len: target.len() as u64,
+        target_hash: file_hash(target),
+    };
+    let instructions = diff(reference, target);
+    let header_len = header.write(&mut writer)?;
+    let progress = compress_stream(&instructions.bytes[..], &mut writer, options)?;
+    Ok(DeltaStats {
+        reference_len: header.reference_len,
+        target_len: header.target_len,
+        copied: instructions.copied,
+        inserted: instructions.inserted,
+        patch_len: header_len as u64 + progress.bytes_out,
+    })
+}
+
+/// [`create_patch`] between two files.
+pub fn create_patch_file<P: AsRef<Path>>(reference_path: P, target_path: P, patch_path: P, options: &CompressionOptions) -> io::Result<DeltaStats> {
+    let reference = fs::read(reference_path)?;
+    let target = fs::read(target_path)?;
+    let mut writer = BufWriter::new(File::create(patch_path)?);
+    let stats = create_patch(&reference, &target, &mut writer, options)?;
+    writer.flush()?;
+    Ok(stats)
+}
+
+/// Rebuilds the target of the patch read from `patch` out of `reference`
+/// and writes it to `writer`. Returns its length.
+///
+/// Fails before writing anything if `reference` is not the file the patch
+/// was made from, and at the end if the output does not hash to what the
+/// patch recorded. `options` carry the dictionary, credentials and limits
+/// for the compressed instructions.
+pub fn apply_patch<R: Read, W: Write>(reference: &[u8], mut patch: R, mut writer: W, options: &DecompressOptions) -> io::Result<u64> {
+    let header = PatchHeader::read(&mut patch)?;
+    if reference.len() as u64 != header.reference_len || file_hash(reference) != header.reference_hash {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "reference file is not the one this patch was made from"));
+    }
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut instructions = DecompressReader::with_options(patch, options.clone());
+    let mut hasher = Blake2b::<U32>::new();
+    let mut written = 0u64;
+    let mut op = [0u8; 1];
+    while instructions.read(&mut op)? == 1 {
+        let (offset, len) = match op[0] {
+            OP_COPY => (Some(read_u64(&mut instructions)?), read_u64(&mut instructions)?),
+            OP_INSERT => (None, read_u64(&mut instructions)?),
+            _ => return Err(invalid("unknown patch instruction")),
+        };
+        if len > header.target_len - written {
+            return Err(invalid("patch output is longer than its recorded length"));
+        }
+        match offset {
+            Some(offset) => {
+                let run = offset
+                    .checked_add(len)
+                    .filter(|&end| end <= reference.len() as u64)
+                    .map(|end| &reference[offset as usize..end as usize])
+                    .ok_or_else(|| invalid("patch copies past the end of the reference"))?;
+                hasher.update(run);
+                writer.write_all(run)?;
+            }
+            None => {
+                let mut run = (&mut instructions).take(len);
+                let mut buf = vec![0u8; 64 * 1024];
+                loop {
+                    let n = run.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buf[..n]);
+                    writer.write_all(&buf[..n])?;
+                }
+                if run.limit() > 0 {
+                    return Err(invalid("patch instructions are truncated"));
+                }
+            }
+        }
+        written += len;
+    }
+    let hash: FileHash = hasher.finalize().into();
+    if written != header.target_len || hash != header.target_hash {
+        return Err(invalid("patched output does not match the hash recorded in the patch"));
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = [0u8; 8];
+    reader
+        .read_exact(&mut value)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "patch instructions are truncated"))?;
+    Ok(u64::from_le_bytes(value))
+}
+
+/// [`apply_patch`] between files. A failed patch removes its partial output.
+pub fn apply_patch_file<P: AsRef<Path>>(reference_path: P, patch_path: P, output_path: P, options: &DecompressOptions) -> io::Result<u64> {
+    let reference = fs::read(reference_path)?;
+    let patch = BufReader::new(File::open(patch_path)?);
+    let result = apply_patch(&reference, patch, BufWriter::new(File::create(&output_path)?), options);
+    if result.is_err() {
+        let _ = fs::remove_file(output_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions() -> (Vec<u8>, Vec<u8>) {
+        let reference: Vec<u8> = (0..20_000u32).flat_map(|i| format!("line {} value {}\n", i % 977, i * 7 % 1013).into_bytes()).collect();
+        // Edit the middle, drop a run, and move the head to the end.
+        let mut target = reference[5_000..].to_vec();
+        target.splice(40_000..40_010, *b"an edit in the middle");
+        target.drain(90_000..92_000);
+        target.extend_from_slice(&reference[..5_000]);
+        (reference, target)
+    }
+
+    #[test]
+    fn patches_rebuild_the_target() {
+        let (reference, target) = versions();
+        let mut patch = Vec::new();
+        let stats = create_patch(&reference, &target, &mut patch, &CompressionOptions::default()).unwrap();
+        assert_eq!(stats.copied + stats.inserted, target.len() as u64);
+        assert!(stats.inserted < 200, "{} bytes inserted", stats.inserted);
+        assert_eq!(stats.patch_len, patch.len() as u64);
+
+        let mut rebuilt = Vec::new();
+        assert_eq!(apply_patch(&reference, &patch[..], &mut rebuilt, &DecompressOptions::default()).unwrap(), target.len() as u64);
+        assert_eq!(rebuilt, target);
+    }
+
+    #[test]
+    fn wrong_references_and_tampered_hashes_are_refused() {
+        let (reference, target) = versions();
+        let mut patch = Vec::new();
+        create_patch(&reference, &target, &mut patch, &CompressionOptions::default()).unwrap();
+
+        let mut other = reference.clone();
+        other[100] ^= 1;
+        let mut rebuilt = Vec::new();
+        let error = apply_patch(&other, &patch[..], &mut rebuilt, &DecompressOptions::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(rebuilt.is_empty());
+
+        // The target hash sits at the end of the header.
+        patch[PATCH_HEADER_LEN - 1] ^= 1;
+        let error = apply_patch(&reference, &patch[..], io::sink(), &DecompressOptions::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("does not match the hash"), "{}", error);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod delta;
+pub use delta::{apply_patch, apply_patch_file, create_patch, create_patch_file, DeltaStats, PATCH_MAGIC};
@@ -0,0 +1,258 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::compressor::compressor::{deserialize_grammar, serialize_grammar};
+use crate::compressor::CompressionOptions;
+use crate::container::Header;
+use crate::ctw::Ctw;
+use crate::grammar::{Grammar, GrammarSeed};
+
+/// First bytes of a dictionary file.
+pub const DICTIONARY_MAGIC: [u8; 4] = *b"BPDC";
+const DICTIONARY_VERSION: u8 = 2;
+
+/// Training input beyond this is ignored: the samples are concatenated and
+/// run through grammar inference in one piece.
+pub const MAX_TRAINING_BYTES: usize = 4 * 1024 * 1024;
+
+/// Preset model shared by compressor and decompressor: seed rules for the
+/// grammar stage plus pre-warmed context model counts. Streams compressed
+/// with a dictionary record its id and cannot be decoded without it.
+#[derive(Clone)]
+pub struct Dictionary {
+    id: u64,
+    seed: GrammarSeed,
+    model: Ctw,
+}
+
+impl fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("id", &format_args!("{:016x}", self.id))
+            .field("rules", &self.seed.rule_count())
+            .field("model_depth", &self.model.depth())
+            .finish()
+    }
+}
+
+/// Dictionaries are identified by their id, which is a hash of their content.
+impl PartialEq for Dictionary {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Dictionary {}
+
+impl Dictionary {
+    /// Builds a dictionary from sample data. The seed rules come from
+    /// grammar inference over all samples together; the context model is
+    /// then warmed up on the symbol streams the samples produce when
+    /// compressed with those rules, using the depth and stages of `options`.
+    pub fn train<S: AsRef<[u8]>>(samples: &[S], options: &CompressionOptions) -> io::Result<Self> {
+        options.validate()?;
+        let mut training = Vec::new();
+        for sample in samples {
+            let room = MAX_TRAINING_BYTES - training.len();
+            let sample = sample.as_ref();
+            training.extend_from_slice(&sample[..sample.len().min(room)]);
+            if training.len() == MAX_TRAINING_BYTES {
+                break;
+            }
+        }
+        if training.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no training data"));
+        }
+
+        let mut grammar = Grammar::new();
+        if options.stages.grammar {
+            grammar.infer_grammar(&training);
+        }
+        let seed = GrammarSeed::new(&grammar);
+
+        // Feed the model exactly what a block starting with each sample
+        // would show it.
+        let mut model = Ctw::with_depth(options.model_depth);
+        let mut consumed = 0;
+        for sample in samples {
+            if consumed >= training.len() {
+                break;
+            }
+            let sample = sample.as_ref();
+            let sample = &sample[..sample.len().min(training.len() - consumed)];
+            consumed += sample.len();
+            for chunk in sample.chunks(options.block_size) {
+                let symbol_stream = if options.stages.grammar {
+                    let mut grammar = Grammar::with_seed(&seed);
+                    grammar.infer_grammar_seeded(chunk, &seed);
+                    serialize_grammar(&grammar)
+                } else {
+                    chunk.to_vec()
+                };
+                model.reset_context();
+                for &symbol in &symbol_stream {
+                    model.process_symbol(symbol);
+                }
+            }
+        }
+        model.reset_context();
+        Ok(Self::from_parts(seed, model))
+    }
+
+    /// Reads every file in `paths` and trains on their contents.
+    pub fn train_files<P: AsRef<Path>>(paths: &[P], options: &CompressionOptions) -> io::Result<Self> {
+        let samples = paths.iter().map(fs::read).collect::<io::Result<Vec<_>>>()?;
+        Self::train(&samples, options)
+    }
+
+    fn from_parts(seed: GrammarSeed, model: Ctw) -> Self {
+        let id = fnv1a64(&Self::body(&seed, &model));
+        Dictionary { id, seed, model }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn seed(&self) -> &GrammarSeed {
+        &self.seed
+    }
+
+    /// The pre-warmed context model every block starts from.
+    pub fn model(&self) -> &Ctw {
+        &self.model
+    }
+
+    /// Serialized seed grammar followed by the model counts.
+    fn body(seed: &GrammarSeed, model: &Ctw) -> Vec<u8> {
+        // Serialize the seed rules as ordinary rules of an empty grammar.
+        let rules = Grammar {
+            seed_rules: 0,
+            ..seed.grammar().clone()
+        };
+        let grammar_bytes = serialize_grammar(&rules);
+        let mut out = Vec::new();
+        out.extend(&(grammar_bytes.len() as u64).to_le_bytes());
+        out.extend(grammar_bytes);
+        out.extend(model.to_bytes());
+        out
+    }
+
+    /// File layout: `DICTIONARY_MAGIC`, version `u8`, id `u64`, then the
+    /// body the id is computed over.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = DICTIONARY_MAGIC.to_vec();
+        out.push(DICTIONARY_VERSION);
+        out.extend(&self.id.to_le_bytes());
+        out.extend(Self::body(&self.seed, &self.model));
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if data.len() < 21 || data[..4] != DICTIONARY_MAGIC {
+            return Err(invalid("not a BlockPiper dictionary"));
+        }
+        if data[4] != DICTIONARY_VERSION {
+            return Err(invalid("unsupported dictionary version"));
+        }
+        let id = u64::from_le_bytes(data[5..13].try_into().unwrap());
+        let body = &data[13..];
+        let grammar_end = usize::try_from(u64::from_le_bytes(body[..8].try_into().unwrap()))
+            .ok()
+            .and_then(|len| len.checked_add(8))
+            .filter(|&end| end <= body.len())
+            .ok_or_else(|| invalid("dictionary is truncated"))?;
+        let grammar = deserialize_grammar(&body[8..grammar_end]).ok_or_else(|| invalid("dictionary grammar is corrupt"))?;
+        // The seed is built from these rules before the id can be checked.
+        grammar
+            .check_rules()
+            .map_err(|e| invalid(&format!("dictionary grammar is corrupt: {}", e)))?;
+        let (model, used) = Ctw::from_bytes(&body[grammar_end..]).ok_or_else(|| invalid("dictionary model is corrupt"))?;
+        if grammar_end + used != body.len() {
+            return Err(invalid("trailing bytes after dictionary"));
+        }
+        let dictionary = Self::from_parts(GrammarSeed::new(&grammar), model);
+        if dictionary.id != id {
+            return Err(invalid("dictionary id does not match its content"));
+        }
+        Ok(dictionary)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Checks that `dictionary` is the one `header` was written with and
+    /// returns it, or `None` for streams that use no dictionary.
+    pub(crate) fn for_header<'a>(header: &Header, dictionary: Option<&'a Dictionary>) -> io::Result<Option<&'a Dictionary>> {
+        match (header.dictionary_id, dictionary) {
+            (None, _) => Ok(None),
+            (Some(id), None) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("stream was compressed with dictionary {:016x}; supply it to decompress", id),
+            )),
+            (Some(id), Some(dictionary)) if dictionary.id != id => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("wrong dictionary: stream needs {:016x}, got {:016x}", id, dictionary.id),
+            )),
+            (Some(_), Some(dictionary)) => Ok(Some(dictionary)),
+        }
+    }
+}
+
+/// 64-bit FNV-1a, used to derive dictionary ids from their content.
+fn fnv1a64(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::container::Stages;
+    use crate::grammar::Symbol;
+
+    /// A dictionary file holding `rules`, with a made-up id.
+    fn dictionary_bytes(rules: &[(usize, Vec<Symbol>)]) -> Vec<u8> {
+        let grammar = Grammar { rules: rules.iter().cloned().collect(), ..Grammar::new() };
+        let grammar_bytes = serialize_grammar(&grammar);
+        let mut out = DICTIONARY_MAGIC.to_vec();
+        out.push(DICTIONARY_VERSION);
+        out.extend(&0u64.to_le_bytes());
+        out.extend(&(grammar_bytes.len() as u64).to_le_bytes());
+        out.extend(grammar_bytes);
+        out.extend(Ctw::new().to_bytes());
+        out
+    }
+
+    #[test]
+    fn malformed_seed_rules_are_rejected() {
+        let undefined = vec![(0, vec![Symbol::NonTerminal(1), Symbol::Terminal(b'A')])];
+        let cycle = vec![(0, vec![Symbol::NonTerminal(0), Symbol::Terminal(b'A')])];
+        let short = vec![(0, vec![Symbol::Terminal(b'A')])];
+        for rules in [undefined, cycle, short] {
+            let error = Dictionary::from_bytes(&dictionary_bytes(&rules)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn trained_dictionaries_round_trip() {
+        let samples = [b"the quick brown fox, the quick brown dog; ".repeat(16)];
+        let options = CompressionOptions::new().with_stages(Stages { grammar: true, context_model: true });
+        let dictionary = Dictionary::train(&samples, &options).unwrap();
+        assert!(dictionary.seed().rule_count() > 0);
+        assert_eq!(Dictionary::from_bytes(&dictionary.to_bytes()).unwrap(), dictionary);
+    }
+}
@@ -0,0 +1,2 @@
+pub mod dictionary;
+pub use dictionary::{Dictionary, MAX_TRAINING_BYTES};
@@ -0,0 +1,299 @@
+//! Statistics and repeat discovery on top of an inferred grammar.
+
+use std::collections::{HashMap, HashSet};
+
+use super::grammar::{Grammar, Symbol};
+
+/// What the analysis knows about one rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleInfo {
+    pub id: usize,
+    /// Length of the rule's expansion in bytes.
+    pub length: usize,
+    /// References to the rule from the start sequence and other rules.
+    pub uses: usize,
+    /// Height of the rule's derivation tree: 1 for a rule made only of
+    /// terminals.
+    pub depth: usize,
+    /// Byte offsets in the derived data at which the expansion occurs.
+    pub occurrences: Vec<usize>,
+}
+
+/// Rough size of the grammar as a compressed representation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionPotential {
+    pub original_len: usize,
+    /// Symbols in the start sequence plus all rule bodies.
+    pub grammar_symbols: usize,
+    /// Zeroth-order entropy of those symbols, plus one length byte per rule.
+    pub estimated_bits: f64,
+}
+
+impl CompressionPotential {
+    /// Original bytes over estimated compressed bytes.
+    pub fn estimated_ratio(&self) -> f64 {
+        if self.estimated_bits == 0.0 {
+            0.0
+        } else {
+            self.original_len as f64 * 8.0 / self.estimated_bits
+        }
+    }
+
+    pub fn estimated_bits_per_byte(&self) -> f64 {
+        if self.original_len == 0 {
+            0.0
+        } else {
+            self.estimated_bits / self.original_len as f64
+        }
+    }
+}
+
+/// Analysis of a [`Grammar`]: per-rule lengths, depths and occurrence
+/// positions, derivation depth of the data, and the repeats the rules
+/// stand for.
+///
+/// ```
+/// use blockpiper::grammar::GrammarAnalysis;
+/// use blockpiper::Grammar;
+///
+/// let mut grammar = Grammar::new();
+/// grammar.infer_grammar(b"to be or not to be, to be or not to be");
+/// let analysis = GrammarAnalysis::new(&grammar);
+/// let longest = analysis.longest_repeats(1)[0];
+/// assert!(longest.length >= 5 && longest.occurrences.len() >= 2);
+/// assert_eq!(analysis.expanded_len(), 38);
+/// ```
+#[derive(Debug, Clone)]
+pub struct GrammarAnalysis {
+    /// In id order.
+    rules: Vec<RuleInfo>,
+    index: HashMap<usize, usize>,
+    expanded_len: usize,
+    max_depth: usize,
+    avg_depth: f64,
+    potential: CompressionPotential,
+}
+
+impl GrammarAnalysis {
+    /// Analyses `grammar`, which must be acyclic, as inferred grammars are.
+    pub fn new(grammar: &Grammar) -> Self {
+        let usage = grammar.rule_usage();
+        let mut occurrences = grammar.occurrences();
+
+        // Per rule: expansion length, derivation height, and the sum over
+        // its bytes of how many rules deep each one sits, counting the rule
+        // itself.
+        let mut length: HashMap<usize, usize> = HashMap::with_capacity(grammar.rules.len());
+        let mut depth: HashMap<usize, usize> = HashMap::with_capacity(grammar.rules.len());
+        let mut depth_sum: HashMap<usize, u64> = HashMap::with_capacity(grammar.rules.len());
+        let body_totals = |body: &[Symbol], length: &HashMap<usize, usize>, depth: &HashMap<usize, usize>, depth_sum: &HashMap<usize, u64>| {
+            let (mut len, mut height, mut sum) = (0usize, 0usize, 0u64);
+            for symbol in body {
+                match *symbol {
+                    Symbol::Terminal(_) => len += 1,
+                    Symbol::NonTerminal(id) => {
+                        len += length.get(&id).copied().unwrap_or(0);
+                        height = height.max(depth.get(&id).copied().unwrap_or(0));
+                        sum += depth_sum.get(&id).copied().unwrap_or(0);
+                    }
+                }
+            }
+            (len, height, sum)
+        };
+        for rid in postorder(grammar) {
+            let (len, height, sum) = body_totals(&grammar.rules[&rid], &length, &depth, &depth_sum);
+            length.insert(rid, len);
+            depth.insert(rid, height + 1);
+            depth_sum.insert(rid, sum + len as u64);
+        }
+        // The start sequence is not a rule, so it adds no level of its own.
+        let (expanded_len, max_depth, total_depth) = body_totals(&grammar.sequence, &length, &depth, &depth_sum);
+
+        let mut ids: Vec<usize> = grammar.rules.keys().copied().collect();
+        ids.sort_unstable();
+        let rules: Vec<RuleInfo> = ids
+            .iter()
+            .map(|&id| RuleInfo {
+                id,
+                length: length[&id],
+                uses: usage.get(&id).copied().unwrap_or(0),
+                depth: depth[&id],
+                occurrences: occurrences.remove(&id).unwrap_or_default(),
+            })
+            .collect();
+        let index = ids.iter().enumerate().map(|(n, &id)| (id, n)).collect();
+
+        GrammarAnalysis {
+            potential: potential(grammar, expanded_len),
+            avg_depth: if expanded_len == 0 { 0.0 } else { total_depth as f64 / expanded_len as f64 },
+            rules,
+            index,
+            expanded_len,
+            max_depth,
+        }
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Length of the data the grammar derives.
+    pub fn expanded_len(&self) -> usize {
+        self.expanded_len
+    }
+
+    /// Most rules any byte of the data is nested in.
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Average number of rules each byte of the data is nested in.
+    pub fn avg_depth(&self) -> f64 {
+        self.avg_depth
+    }
+
+    /// All rules, in id order.
+    pub fn rules(&self) -> &[RuleInfo] {
+        &self.rules
+    }
+
+    pub fn rule(&self, id: usize) -> Option<&RuleInfo> {
+        self.index.get(&id).map(|&n| &self.rules[n])
+    }
+
+    /// The `n` rules with the longest expansions; ties go to the rule that
+    /// occurs more often.
+    pub fn longest_repeats(&self, n: usize) -> Vec<&RuleInfo> {
+        self.top(n, |rule| (rule.length, rule.occurrences.len()))
+    }
+
+    /// The `n` rules whose expansions occur most often in the data; ties go
+    /// to the longer expansion.
+    pub fn most_frequent_repeats(&self, n: usize) -> Vec<&RuleInfo> {
+        self.top(n, |rule| (rule.occurrences.len(), rule.length))
+    }
+
+    fn top(&self, n: usize, key: impl Fn(&RuleInfo) -> (usize, usize)) -> Vec<&RuleInfo> {
+        let mut rules: Vec<&RuleInfo> = self.rules.iter().collect();
+        rules.sort_by(|a, b| key(b).cmp(&key(a)).then(a.id.cmp(&b.id)));
+        rules.truncate(n);
+        rules
+    }
+
+    pub fn compression_potential(&self) -> CompressionPotential {
+        self.potential
+    }
+}
+
+/// Rule ids ordered so that every rule comes after the rules it uses.
+/// Undefined rules are skipped and references closing a cycle ignored.
+fn postorder(grammar: &Grammar) -> Vec<usize> {
+    let mut ids: Vec<usize> = grammar.rules.keys().copied().collect();
+    ids.sort_unstable();
+    let mut order = Vec::with_capacity(ids.len());
+    let mut done = HashSet::with_capacity(ids.len());
+    let mut visiting = HashSet::new();
+    for root in ids {
+        let mut stack = vec![(root, false)];
+        while let Some((rid, children_done)) = stack.pop() {
+            if done.contains(&rid) {
+                continue;
+            }
+            if children_done {
+                visiting.remove(&rid);
+                done.insert(rid);
+                order.push(rid);
+            } else if visiting.insert(rid) {
+                stack.push((rid, true));
+                for symbol in &grammar.rules[&rid] {
+                    if let Symbol::NonTerminal(id) = *symbol {
+                        if grammar.rules.contains_key(&id) && !done.contains(&id) && !visiting.contains(&id) {
+                            stack.push((id, false));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    order
+}
+
+fn potential(grammar: &Grammar, original_len: usize) -> CompressionPotential {
+    let mut counts: HashMap<Symbol, usize> = HashMap::new();
+    for symbol in grammar.sequence.iter().chain(grammar.rules.values().flatten()) {
+        *counts.entry(*symbol).or_insert(0) += 1;
+    }
+    let grammar_symbols: usize = counts.values().sum();
+    let entropy_bits: f64 = counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / grammar_symbols as f64;
+            -(count as f64) * p.log2()
+        })
+        .sum();
+    CompressionPotential {
+        original_len,
+        grammar_symbols,
+        estimated_bits: entropy_bits + grammar.rules.len() as f64 * 8.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `S -> R2 "-" R2 R3 R1`, `R1 -> "ab"`, `R2 -> R1 "c" R1`,
+    /// `R3 -> "xyz"`, deriving `abcab-abcabxyzab`.
+    fn hand_built() -> Grammar {
+        let terminals = |bytes: &[u8]| bytes.iter().map(|&b| Symbol::Terminal(b)).collect::<Vec<_>>();
+        let mut grammar = Grammar::new();
+        grammar.rules.insert(1, terminals(b"ab"));
+        grammar.rules.insert(2, vec![Symbol::NonTerminal(1), Symbol::Terminal(b'c'), Symbol::NonTerminal(1)]);
+        grammar.rules.insert(3, terminals(b"xyz"));
+        grammar.sequence = vec![Symbol::NonTerminal(2), Symbol::Terminal(b'-'), Symbol::NonTerminal(2), Symbol::NonTerminal(3), Symbol::NonTerminal(1)];
+        grammar
+    }
+
+    #[test]
+    fn rules_report_length_uses_depth_and_occurrences() {
+        let analysis = GrammarAnalysis::new(&hand_built());
+        assert_eq!(analysis.rule_count(), 3);
+        assert_eq!(analysis.expanded_len(), 16);
+        let info = |id, length, uses, depth, occurrences: &[usize]| RuleInfo { id, length, uses, depth, occurrences: occurrences.to_vec() };
+        assert_eq!(
+            analysis.rules(),
+            [info(1, 2, 3, 1, &[0, 3, 6, 9, 14]), info(2, 5, 2, 2, &[0, 6]), info(3, 3, 1, 1, &[11])]
+        );
+        assert_eq!(analysis.rule(2), Some(&analysis.rules()[1]));
+        assert_eq!(analysis.rule(4), None);
+    }
+
+    #[test]
+    fn depths_count_the_rules_each_byte_sits_in() {
+        let analysis = GrammarAnalysis::new(&hand_built());
+        assert_eq!(analysis.max_depth(), 2);
+        // `abcab` twice: a and b sit in R1 inside R2, c only in R2; then
+        // `-` in none, `xyz` in R3 and `ab` in R1.
+        let total = 2 * (2 + 2 + 1 + 2 + 2) + 3 + 2;
+        assert_eq!(analysis.avg_depth(), total as f64 / 16.0);
+    }
+
+    #[test]
+    fn top_repeats_rank_by_length_or_frequency() {
+        let analysis = GrammarAnalysis::new(&hand_built());
+        let ids = |rules: Vec<&RuleInfo>| rules.iter().map(|rule| rule.id).collect::<Vec<_>>();
+        assert_eq!(ids(analysis.longest_repeats(2)), [2, 3]);
+        assert_eq!(ids(analysis.most_frequent_repeats(2)), [1, 2]);
+        assert_eq!(ids(analysis.longest_repeats(10)), [2, 3, 1]);
+        assert!(analysis.most_frequent_repeats(0).is_empty());
+
+        // Equal lengths go to the rule that occurs more often, then the
+        // lower id.
+        let mut grammar = hand_built();
+        grammar.rules.insert(4, vec![Symbol::Terminal(b'!'), Symbol::Terminal(b'?')]);
+        grammar.rules.insert(5, vec![Symbol::Terminal(b'%'), Symbol::Terminal(b'&')]);
+        grammar.sequence.extend([Symbol::NonTerminal(5), Symbol::NonTerminal(5), Symbol::NonTerminal(4)]);
+        let analysis = GrammarAnalysis::new(&grammar);
+        assert_eq!(ids(analysis.longest_repeats(5)), [2, 3, 1, 5, 4]);
+    }
+}
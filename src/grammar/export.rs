@@ -0,0 +1,248 @@
+//! Grammar exporters for analysis outside the compressor.
+
+use std::io::{self, Write};
+
+use super::grammar::{Grammar, Symbol};
+
+/// Expansion bytes shown in DOT node labels.
+const DOT_LABEL_LEN: usize = 24;
+
+/// Output formats understood by [`write_grammar`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExportFormat {
+    /// Sequitur-style listing, one rule per line.
+    #[default]
+    Text,
+    /// Graphviz digraph of which rules use which.
+    Dot,
+    /// Rules with their bodies, expansions and usage counts.
+    Json,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" | "txt" => Ok(ExportFormat::Text),
+            "dot" | "graphviz" => Ok(ExportFormat::Dot),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!("unknown grammar format: {}", s)),
+        }
+    }
+}
+
+pub fn write_grammar<W: Write>(grammar: &Grammar, format: ExportFormat, writer: &mut W) -> io::Result<()> {
+    match format {
+        ExportFormat::Text => write_text(grammar, writer),
+        ExportFormat::Dot => write_dot(grammar, writer),
+        ExportFormat::Json => write_json(grammar, writer),
+    }
+}
+
+fn sorted_rule_ids(grammar: &Grammar) -> Vec<usize> {
+    let mut ids: Vec<usize> = grammar.rules.keys().copied().collect();
+    ids.sort_unstable();
+    ids
+}
+
+/// Sequitur-style listing: the start rule `S` followed by every rule in id
+/// order. Runs of terminals are written as one quoted, escaped string, and
+/// each rule line ends with its expansion length and use count:
+///
+/// ```text
+/// S -> R0 " and " R0
+/// R0 -> "ab" R1    # 4 bytes, used 2x
+/// ```
+pub fn write_text<W: Write>(grammar: &Grammar, writer: &mut W) -> io::Result<()> {
+    let lengths = grammar.rule_lengths();
+    let usage = grammar.rule_usage();
+    writeln!(writer, "S -> {}", text_body(&grammar.sequence))?;
+    for rid in sorted_rule_ids(grammar) {
+        writeln!(
+            writer,
+            "R{} -> {}    # {} bytes, used {}x",
+            rid,
+            text_body(&grammar.rules[&rid]),
+            lengths.get(&rid).copied().unwrap_or(0),
+            usage.get(&rid).copied().unwrap_or(0)
+        )?;
+    }
+    Ok(())
+}
+
+fn text_body(symbols: &[Symbol]) -> String {
+    let mut parts = Vec::new();
+    let mut terminals = Vec::new();
+    for symbol in symbols {
+        match *symbol {
+            Symbol::Terminal(b) => terminals.push(b),
+            Symbol::NonTerminal(id) => {
+                if !terminals.is_empty() {
+                    parts.push(quoted(&terminals));
+                    terminals.clear();
+                }
+                parts.push(format!("R{}", id));
+            }
+        }
+    }
+    if !terminals.is_empty() {
+        parts.push(quoted(&terminals));
+    }
+    parts.join(" ")
+}
+
+fn quoted(bytes: &[u8]) -> String {
+    let escaped: String = bytes.iter().flat_map(|&b| std::ascii::escape_default(b)).map(char::from).collect();
+    format!("\"{}\"", escaped)
+}
+
+/// Graphviz digraph with one node per rule, labelled with the start of its
+/// expansion, and an edge from each rule to every rule its body uses. Edges
+/// are labelled with the number of uses when it is more than one. The
+/// start sequence is the node `S`.
+pub fn write_dot<W: Write>(grammar: &Grammar, writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "digraph grammar {{")?;
+    writeln!(writer, "    node [shape=box, fontname=\"monospace\"];")?;
+    writeln!(writer, "    S [label=\"S\\n{} symbols\"];", grammar.sequence.len())?;
+    for rid in sorted_rule_ids(grammar) {
+        let expansion = grammar.expand_rule(rid, DOT_LABEL_LEN + 1);
+        let mut label = quoted(&expansion[..expansion.len().min(DOT_LABEL_LEN)]);
+        if expansion.len() > DOT_LABEL_LEN {
+            label.push('…');
+        }
+        writeln!(writer, "    R{} [label=\"R{}\\n{}\"];", rid, rid, dot_escape(&label))?;
+    }
+    write_dot_edges(writer, "S", &grammar.sequence)?;
+    for rid in sorted_rule_ids(grammar) {
+        write_dot_edges(writer, &format!("R{}", rid), &grammar.rules[&rid])?;
+    }
+    writeln!(writer, "}}")
+}
+
+fn write_dot_edges<W: Write>(writer: &mut W, from: &str, body: &[Symbol]) -> io::Result<()> {
+    // (target, count) in order of first use
+    let mut targets: Vec<(usize, usize)> = Vec::new();
+    for symbol in body {
+        if let Symbol::NonTerminal(id) = *symbol {
+            match targets.iter_mut().find(|(target, _)| *target == id) {
+                Some((_, count)) => *count += 1,
+                None => targets.push((id, 1)),
+            }
+        }
+    }
+    for (target, count) in targets {
+        if count > 1 {
+            writeln!(writer, "    {} -> R{} [label=\"{}\"];", from, target, count)?;
+        } else {
+            writeln!(writer, "    {} -> R{};", from, target)?;
+        }
+    }
+    Ok(())
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// JSON object with the start sequence and every rule. Bodies list
+/// terminals as byte values and nonterminals as `"R<id>"` strings; each
+/// rule also carries its expansion (as lossy UTF-8 text and as hex), its
+/// expansion length and how often it is used.
+///
+/// ```json
+/// {"start": ["R0", 32, "R0"],
+///  "rules": [{"id": 0, "body": [97, 98], "expansion": "ab", "expansion_hex": "6162", "length": 2, "uses": 2}]}
+/// ```
+pub fn write_json<W: Write>(grammar: &Grammar, writer: &mut W) -> io::Result<()> {
+    let lengths = grammar.rule_lengths();
+    let usage = grammar.rule_usage();
+    writeln!(writer, "{{")?;
+    writeln!(writer, "  \"start\": {},", json_body(&grammar.sequence))?;
+    writeln!(writer, "  \"rules\": [")?;
+    let ids = sorted_rule_ids(grammar);
+    for (n, &rid) in ids.iter().enumerate() {
+        let expansion = grammar.expand_rule(rid, usize::MAX);
+        let hex: String = expansion.iter().map(|b| format!("{:02x}", b)).collect();
+        writeln!(
+            writer,
+            "    {{\"id\": {}, \"body\": {}, \"expansion\": {}, \"expansion_hex\": \"{}\", \"length\": {}, \"uses\": {}}}{}",
+            rid,
+            json_body(&grammar.rules[&rid]),
+            json_string(&String::from_utf8_lossy(&expansion)),
+            hex,
+            lengths.get(&rid).copied().unwrap_or(0),
+            usage.get(&rid).copied().unwrap_or(0),
+            if n + 1 < ids.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(writer, "  ]")?;
+    writeln!(writer, "}}")
+}
+
+fn json_body(symbols: &[Symbol]) -> String {
+    let items: Vec<String> = symbols
+        .iter()
+        .map(|symbol| match *symbol {
+            Symbol::Terminal(b) => b.to_string(),
+            Symbol::NonTerminal(id) => format!("\"R{}\"", id),
+        })
+        .collect();
+    format!("[{}]", items.join(", "))
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `R0 -> " \ 0x01 \n`, used twice, so its expansion holds everything
+    /// the exporters have to escape.
+    fn awkward_grammar() -> Grammar {
+        let mut grammar = Grammar::new();
+        grammar.rules.insert(0, b"\"\\\x01\n".iter().map(|&b| Symbol::Terminal(b)).collect());
+        grammar.sequence = vec![Symbol::NonTerminal(0), Symbol::Terminal(b' '), Symbol::NonTerminal(0)];
+        grammar
+    }
+
+    fn export(format: ExportFormat) -> String {
+        let mut out = Vec::new();
+        write_grammar(&awkward_grammar(), format, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.chars().any(|c| c.is_control() && c != '\n'), "raw control character in {:?}", out);
+        out
+    }
+
+    #[test]
+    fn dot_labels_escape_quotes_backslashes_and_control_bytes() {
+        let dot = export(ExportFormat::Dot);
+        assert!(dot.contains(r#"    R0 [label="R0\n\"\\\"\\\\\\x01\\n\""];"#), "{}", dot);
+        assert!(dot.contains("    S -> R0 [label=\"2\"];"), "{}", dot);
+    }
+
+    #[test]
+    fn json_strings_escape_quotes_backslashes_and_control_bytes() {
+        let json = export(ExportFormat::Json);
+        assert!(json.contains(r#"  "start": ["R0", 32, "R0"],"#), "{}", json);
+        let rule = r#"    {"id": 0, "body": [34, 92, 1, 10], "expansion": "\"\\\u0001\n", "expansion_hex": "225c010a", "length": 4, "uses": 2}"#;
+        assert!(json.lines().any(|line| line == rule), "{}", json);
+        assert_eq!(json_string("\t\r\u{1f}é"), r#""\t\r\u001fé""#);
+    }
+}
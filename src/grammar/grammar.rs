@@ -1,111 +1,582 @@
-use std::collections::{HashMap, HashSet};
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum Symbol {
-    Terminal(u8),
-    NonTerminal(usize),
-}
-
-type Digram = (Symbol, Symbol);
-
-#[derive(Debug, Clone)]
-pub struct Grammar {
-    pub rules: HashMap<usize, Vec<Symbol>>,
-    pub next_nonterminal_id: usize,
-    pub sequence: Vec<Symbol>,
-}
-
-impl Grammar {
-    pub fn new() -> Self {
-        Grammar {
-            rules: HashMap::new(),
-            next_nonterminal_id: 0,
-            sequence: Vec::new(),
-        }
-    }
-
-    /// Robust Sequitur implementation
-    pub fn infer_grammar(&mut self, data: &[u8]) {
-        self.sequence = data.iter().map(|&b| Symbol::Terminal(b)).collect();
-        let mut digram_map: HashMap<Digram, Vec<usize>> = HashMap::new();
-        let mut rule_usage: HashMap<usize, usize> = HashMap::new();
-        let mut i = 0;
-        while i + 1 < self.sequence.len() {
-            let digram = (self.sequence[i].clone(), self.sequence[i + 1].clone());
-            let entry = digram_map.entry(digram.clone()).or_insert_with(Vec::new);
-            entry.push(i);
-            if entry.len() == 2 {
-                // Digram repeats, create or use rule
-                let rule_id = self.find_or_create_rule(&digram, &mut rule_usage);
-                // Replace all occurrences of this digram in the sequence
-                self.replace_all_digrams(&digram, rule_id, &mut digram_map);
-                // Enforce rule utility
-                self.enforce_rule_utility(&mut rule_usage);
-                // Restart scan
-                i = 0;
-                digram_map.clear();
-                continue;
-            }
-            i += 1;
-        }
-    }
-
-    fn find_or_create_rule(&mut self, digram: &Digram, rule_usage: &mut HashMap<usize, usize>) -> usize {
-        // Check if a rule already exists for this digram
-        for (&rid, exp) in &self.rules {
-            if exp.len() == 2 && exp[0] == digram.0 && exp[1] == digram.1 {
-                return rid;
-            }
-        }
-        // Create new rule
-        let rule_id = self.next_nonterminal_id;
-        self.next_nonterminal_id += 1;
-        self.rules.insert(rule_id, vec![digram.0.clone(), digram.1.clone()]);
-        rule_usage.insert(rule_id, 0);
-        rule_id
-    }
-
-    fn replace_all_digrams(&mut self, digram: &Digram, rule_id: usize, digram_map: &mut HashMap<Digram, Vec<usize>>) {
-        let mut i = 0;
-        while i + 1 < self.sequence.len() {
-            if self.sequence[i] == digram.0 && self.sequence[i + 1] == digram.1 {
-                self.sequence.remove(i);
-                self.sequence[i] = Symbol::NonTerminal(rule_id);
-                // After replacement, don't increment i (check for overlapping digrams)
-            } else {
-                i += 1;
-            }
-        }
-    }
-
-    fn enforce_rule_utility(&mut self, rule_usage: &mut HashMap<usize, usize>) {
-        // Count rule usage in sequence
-        rule_usage.clear();
-        for s in &self.sequence {
-            if let Symbol::NonTerminal(id) = s {
-                *rule_usage.entry(*id).or_insert(0) += 1;
-            }
-        }
-        // Remove rules used only once
-        let to_remove: Vec<usize> = rule_usage.iter().filter(|(_, &count)| count == 1).map(|(&id, _)| id).collect();
-        for rid in to_remove {
-            if let Some(exp) = self.rules.remove(&rid) {
-                // Replace the single occurrence in the sequence
-                let mut i = 0;
-                while i < self.sequence.len() {
-                    if self.sequence[i] == Symbol::NonTerminal(rid) {
-                        self.sequence.remove(i);
-                        for (j, s) in exp.iter().enumerate() {
-                            self.sequence.insert(i + j, s.clone());
-                        }
-                        break;
-                    } else {
-                        i += 1;
-                    }
-                }
-            }
-        }
-    }
-}
-
-// (Keep only one set of tests at the end)
\ No newline at end of file
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Symbol {
+    Terminal(u8),
+    NonTerminal(usize),
+}
+
+type Digram = (Symbol, Symbol);
+
+/// Why [`Grammar::expand`] refused a grammar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpandError {
+    /// A symbol refers to a rule that is not defined.
+    UndefinedRule(usize),
+    /// The rule can derive itself.
+    Cycle(usize),
+    /// The rule has fewer than two symbols, which inference never produces.
+    ShortRule(usize),
+    /// The expansion would be longer than the allowed limit.
+    TooLong { limit: usize },
+}
+
+impl std::fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExpandError::UndefinedRule(id) => write!(f, "grammar uses undefined rule {}", id),
+            ExpandError::Cycle(id) => write!(f, "grammar rule {} derives itself", id),
+            ExpandError::ShortRule(id) => write!(f, "grammar rule {} has fewer than two symbols", id),
+            ExpandError::TooLong { limit } => write!(f, "grammar expands to more than {} bytes", limit),
+        }
+    }
+}
+
+impl std::error::Error for ExpandError {}
+
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    pub rules: HashMap<usize, Vec<Symbol>>,
+    pub next_nonterminal_id: usize,
+    pub sequence: Vec<Symbol>,
+    /// Rules with ids below this come from a preset dictionary: they are
+    /// never inlined and are not serialized with the block.
+    pub seed_rules: usize,
+}
+
+/// Longest dictionary rule expansion tried when matching input against a seed.
+const MAX_SEED_MATCH: usize = 256;
+/// Candidate rules kept per two-byte prefix, longest first.
+const MAX_SEED_CANDIDATES: usize = 64;
+
+/// Preset rules shared by every block compressed with a dictionary, with a
+/// lookup table for finding their expansions in new input.
+#[derive(Debug, Clone)]
+pub struct GrammarSeed {
+    grammar: Grammar,
+    by_prefix: HashMap<[u8; 2], Vec<(usize, Vec<u8>)>>,
+}
+
+impl GrammarSeed {
+    /// Turns the rules of `grammar` into a seed, renumbering them densely
+    /// from zero. The start sequence is dropped.
+    pub fn new(grammar: &Grammar) -> Self {
+        let mut ids: Vec<usize> = grammar.rules.keys().copied().collect();
+        ids.sort_unstable();
+        let renumber: HashMap<usize, usize> = ids.iter().enumerate().map(|(new, &old)| (old, new)).collect();
+        let rules: HashMap<usize, Vec<Symbol>> = ids
+            .iter()
+            .map(|old| {
+                let body = grammar.rules[old]
+                    .iter()
+                    .map(|s| match s {
+                        Symbol::NonTerminal(id) => Symbol::NonTerminal(renumber[id]),
+                        terminal => *terminal,
+                    })
+                    .collect();
+                (renumber[old], body)
+            })
+            .collect();
+        let seed = Grammar {
+            next_nonterminal_id: rules.len(),
+            seed_rules: rules.len(),
+            rules,
+            sequence: Vec::new(),
+        };
+
+        let mut by_prefix: HashMap<[u8; 2], Vec<(usize, Vec<u8>)>> = HashMap::new();
+        for &rid in seed.rules.keys() {
+            let expansion = seed.expand_rule(rid, MAX_SEED_MATCH + 1);
+            if expansion.len() >= 2 && expansion.len() <= MAX_SEED_MATCH {
+                by_prefix.entry([expansion[0], expansion[1]]).or_default().push((rid, expansion));
+            }
+        }
+        for candidates in by_prefix.values_mut() {
+            candidates.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(&b.0)));
+            candidates.truncate(MAX_SEED_CANDIDATES);
+        }
+        GrammarSeed { grammar: seed, by_prefix }
+    }
+
+    /// The preset rules (ids `0..rule_count()`).
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.grammar.seed_rules
+    }
+
+    /// Greedy longest-match parse of `data` into seed nonterminals and
+    /// terminals.
+    fn parse(&self, data: &[u8]) -> Vec<Symbol> {
+        let mut sequence = Vec::with_capacity(data.len());
+        let mut i = 0;
+        while i < data.len() {
+            let matched = data.get(i..i + 2).and_then(|prefix| {
+                self.by_prefix
+                    .get(&[prefix[0], prefix[1]])?
+                    .iter()
+                    .find(|(_, expansion)| data[i..].starts_with(expansion))
+            });
+            match matched {
+                Some((rid, expansion)) => {
+                    sequence.push(Symbol::NonTerminal(*rid));
+                    i += expansion.len();
+                }
+                None => {
+                    sequence.push(Symbol::Terminal(data[i]));
+                    i += 1;
+                }
+            }
+        }
+        sequence
+    }
+}
+
+impl Default for Grammar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Grammar {
+    pub fn new() -> Self {
+        Grammar {
+            rules: HashMap::new(),
+            next_nonterminal_id: 0,
+            sequence: Vec::new(),
+            seed_rules: 0,
+        }
+    }
+
+    /// Empty grammar that starts out with the rules of `seed`.
+    pub fn with_seed(seed: &GrammarSeed) -> Self {
+        seed.grammar.clone()
+    }
+
+    /// Expands rule `rid` to bytes, stopping early once `limit` bytes are
+    /// produced. Like [`Grammar::rule_lengths`], references to undefined
+    /// rules, and references that would close a cycle, expand to nothing.
+    pub fn expand_rule(&self, rid: usize, limit: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let Some(body) = self.rules.get(&rid) else {
+            return out;
+        };
+        // The rules being expanded, innermost last, with what is left of them.
+        let mut stack: Vec<(usize, &[Symbol])> = vec![(rid, body)];
+        let mut active = HashSet::from([rid]);
+        while out.len() < limit {
+            let Some((id, rest)) = stack.last_mut() else {
+                break;
+            };
+            let Some((&symbol, tail)) = rest.split_first() else {
+                active.remove(id);
+                stack.pop();
+                continue;
+            };
+            *rest = tail;
+            match symbol {
+                Symbol::Terminal(b) => out.push(b),
+                Symbol::NonTerminal(id) => {
+                    if let Some(body) = self.rules.get(&id) {
+                        if active.insert(id) {
+                            stack.push((id, body));
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Expands the start sequence into the bytes it derives, refusing
+    /// grammars that are malformed or would produce more than `limit` bytes.
+    ///
+    /// Rule lengths are checked before anything is expanded, so the work
+    /// and memory spent on a hostile grammar stay proportional to `limit`.
+    /// Only rules reachable from the start sequence are examined.
+    pub fn expand(&self, limit: usize) -> Result<Vec<u8>, ExpandError> {
+        let lengths = self.checked_lengths()?;
+        let total = self.sequence.iter().fold(0usize, |total, symbol| {
+            total.saturating_add(match symbol {
+                Symbol::Terminal(_) => 1,
+                Symbol::NonTerminal(id) => lengths[id],
+            })
+        });
+        if total > limit {
+            return Err(ExpandError::TooLong { limit });
+        }
+
+        // Every rule has at least two symbols, so the walk visits fewer
+        // rules than it emits bytes.
+        let mut out = Vec::with_capacity(total);
+        let mut stack: Vec<&[Symbol]> = vec![&self.sequence];
+        while let Some(top) = stack.last_mut() {
+            let Some((&symbol, rest)) = top.split_first() else {
+                stack.pop();
+                continue;
+            };
+            *top = rest;
+            match symbol {
+                Symbol::Terminal(b) => out.push(b),
+                Symbol::NonTerminal(id) => stack.push(&self.rules[&id]),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Expansion length of every rule reachable from the start sequence,
+    /// saturating at `usize::MAX`, after checking those rules are defined,
+    /// acyclic and at least two symbols long.
+    pub(crate) fn checked_lengths(&self) -> Result<HashMap<usize, usize>, ExpandError> {
+        let roots = self.sequence.iter().filter_map(|symbol| match *symbol {
+            Symbol::NonTerminal(id) => Some(id),
+            Symbol::Terminal(_) => None,
+        });
+        self.checked_lengths_from(roots)
+    }
+
+    /// Checks every rule, used or not, the way [`Grammar::expand`] checks
+    /// the rules it needs. For grammars that are only a set of rules, such
+    /// as a dictionary's.
+    pub(crate) fn check_rules(&self) -> Result<(), ExpandError> {
+        self.checked_lengths_from(self.rules.keys().copied()).map(|_| ())
+    }
+
+    fn checked_lengths_from(&self, roots: impl Iterator<Item = usize>) -> Result<HashMap<usize, usize>, ExpandError> {
+        let mut lengths: HashMap<usize, usize> = HashMap::new();
+        let mut visiting = HashSet::new();
+        for root in roots {
+            let mut stack = vec![(root, false)];
+            while let Some((rid, children_done)) = stack.pop() {
+                if lengths.contains_key(&rid) {
+                    continue;
+                }
+                let body = self.rules.get(&rid).ok_or(ExpandError::UndefinedRule(rid))?;
+                if body.len() < 2 {
+                    return Err(ExpandError::ShortRule(rid));
+                }
+                if children_done {
+                    visiting.remove(&rid);
+                    let len = body.iter().fold(0usize, |len, symbol| {
+                        len.saturating_add(match symbol {
+                            Symbol::Terminal(_) => 1,
+                            Symbol::NonTerminal(id) => lengths[id],
+                        })
+                    });
+                    lengths.insert(rid, len);
+                    continue;
+                }
+                if !visiting.insert(rid) {
+                    return Err(ExpandError::Cycle(rid));
+                }
+                stack.push((rid, true));
+                for symbol in body {
+                    if let Symbol::NonTerminal(id) = *symbol {
+                        if visiting.contains(&id) {
+                            return Err(ExpandError::Cycle(id));
+                        }
+                        if !lengths.contains_key(&id) {
+                            stack.push((id, false));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(lengths)
+    }
+
+    /// Number of bytes each rule expands to. References to undefined rules,
+    /// and references that would close a cycle, count as empty.
+    pub fn rule_lengths(&self) -> HashMap<usize, usize> {
+        let mut lengths: HashMap<usize, usize> = HashMap::with_capacity(self.rules.len());
+        let mut visiting = HashSet::new();
+        for &root in self.rules.keys() {
+            // Post-order walk: a rule's length is summed once all the rules
+            // it uses are known.
+            let mut stack = vec![(root, false)];
+            while let Some((rid, children_done)) = stack.pop() {
+                if lengths.contains_key(&rid) {
+                    continue;
+                }
+                let Some(body) = self.rules.get(&rid) else {
+                    lengths.insert(rid, 0);
+                    continue;
+                };
+                if children_done {
+                    visiting.remove(&rid);
+                    let len = body
+                        .iter()
+                        .map(|s| match s {
+                            Symbol::Terminal(_) => 1,
+                            Symbol::NonTerminal(id) => lengths.get(id).copied().unwrap_or(0),
+                        })
+                        .sum();
+                    lengths.insert(rid, len);
+                } else if visiting.insert(rid) {
+                    stack.push((rid, true));
+                    for s in body {
+                        if let Symbol::NonTerminal(id) = s {
+                            if !lengths.contains_key(id) && !visiting.contains(id) {
+                                stack.push((*id, false));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        lengths
+    }
+
+    /// Byte offsets in the expanded sequence at which each rule's
+    /// expansion starts, in increasing order. The grammar must be acyclic,
+    /// as inferred grammars are.
+    pub fn occurrences(&self) -> HashMap<usize, Vec<usize>> {
+        let lengths = self.rule_lengths();
+        let mut occurrences: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut offset = 0;
+        for symbol in &self.sequence {
+            let Symbol::NonTerminal(root) = *symbol else {
+                offset += 1;
+                continue;
+            };
+            let mut stack = vec![(root, offset)];
+            while let Some((rid, start)) = stack.pop() {
+                occurrences.entry(rid).or_default().push(start);
+                let Some(body) = self.rules.get(&rid) else {
+                    continue;
+                };
+                let mut child_start = start;
+                for s in body {
+                    match s {
+                        Symbol::Terminal(_) => child_start += 1,
+                        Symbol::NonTerminal(id) => {
+                            stack.push((*id, child_start));
+                            child_start += lengths.get(id).copied().unwrap_or(0);
+                        }
+                    }
+                }
+            }
+            offset += lengths.get(&root).copied().unwrap_or(0);
+        }
+        for positions in occurrences.values_mut() {
+            positions.sort_unstable();
+        }
+        occurrences
+    }
+
+    /// Sequitur-style inference enforcing the two Sequitur constraints in
+    /// rounds: every digram occurring twice in the sequence becomes a rule
+    /// (digram uniqueness), then rules referenced only once are inlined
+    /// again (rule utility). Each round is linear in the sequence length.
+    pub fn infer_grammar(&mut self, data: &[u8]) {
+        self.sequence = data.iter().map(|&b| Symbol::Terminal(b)).collect();
+        self.build_rules();
+    }
+
+    /// Like [`Grammar::infer_grammar`], but first replaces every match of a
+    /// seed rule's expansion in `data`, so that preset rules are used even
+    /// where they occur only once. The grammar should come from
+    /// [`Grammar::with_seed`] on the same seed.
+    pub fn infer_grammar_seeded(&mut self, data: &[u8], seed: &GrammarSeed) {
+        self.sequence = seed.parse(data);
+        self.build_rules();
+    }
+
+    fn build_rules(&mut self) {
+        let mut rule_index = self.digram_rule_index();
+        loop {
+            let repeated = self.repeated_digrams();
+            if repeated.is_empty() {
+                break;
+            }
+            let before = self.sequence.len();
+            self.replace_digrams(&repeated, &mut rule_index);
+            self.enforce_rule_utility();
+            rule_index = self.digram_rule_index();
+            // Every surviving rule is used at least twice, so a round that
+            // does not shrink the sequence found nothing worth keeping.
+            if self.sequence.len() >= before {
+                break;
+            }
+        }
+    }
+
+    /// Maps each two-symbol rule body to its rule, so repeats reuse it.
+    fn digram_rule_index(&self) -> HashMap<Digram, usize> {
+        self.rules
+            .iter()
+            .filter(|(_, exp)| exp.len() == 2)
+            .map(|(&rid, exp)| ((exp[0], exp[1]), rid))
+            .collect()
+    }
+
+    /// Digrams with at least two non-overlapping occurrences in the sequence.
+    fn repeated_digrams(&self) -> HashSet<Digram> {
+        // (count, first index a non-overlapping occurrence may start at)
+        let mut counts: HashMap<Digram, (usize, usize)> = HashMap::new();
+        for (i, pair) in self.sequence.windows(2).enumerate() {
+            let entry = counts.entry((pair[0], pair[1])).or_insert((0, 0));
+            if i >= entry.1 {
+                entry.0 += 1;
+                entry.1 = i + 2;
+            }
+        }
+        counts
+            .into_iter()
+            .filter(|(_, (count, _))| *count >= 2)
+            .map(|(digram, _)| digram)
+            .collect()
+    }
+
+    fn find_or_create_rule(&mut self, digram: &Digram, rule_index: &mut HashMap<Digram, usize>) -> usize {
+        if let Some(&rid) = rule_index.get(digram) {
+            return rid;
+        }
+        let rule_id = self.next_nonterminal_id;
+        self.next_nonterminal_id += 1;
+        self.rules.insert(rule_id, vec![digram.0, digram.1]);
+        rule_index.insert(*digram, rule_id);
+        rule_id
+    }
+
+    /// Replaces repeated digrams left to right in a single pass.
+    fn replace_digrams(&mut self, repeated: &HashSet<Digram>, rule_index: &mut HashMap<Digram, usize>) {
+        let sequence = std::mem::take(&mut self.sequence);
+        let mut out = Vec::with_capacity(sequence.len());
+        let mut i = 0;
+        while i < sequence.len() {
+            if i + 1 < sequence.len() {
+                let digram = (sequence[i], sequence[i + 1]);
+                if repeated.contains(&digram) {
+                    let rule_id = self.find_or_create_rule(&digram, rule_index);
+                    out.push(Symbol::NonTerminal(rule_id));
+                    i += 2;
+                    continue;
+                }
+            }
+            out.push(sequence[i]);
+            i += 1;
+        }
+        self.sequence = out;
+    }
+
+    /// Counts references to each rule from the sequence and from other rules.
+    pub fn rule_usage(&self) -> HashMap<usize, usize> {
+        let mut usage: HashMap<usize, usize> = self.rules.keys().map(|&rid| (rid, 0)).collect();
+        for s in self.sequence.iter().chain(self.rules.values().flatten()) {
+            if let Symbol::NonTerminal(id) = s {
+                *usage.entry(*id).or_insert(0) += 1;
+            }
+        }
+        usage
+    }
+
+    fn enforce_rule_utility(&mut self) {
+        // Remove rules used only once, splicing their bodies into the user
+        let single_use: HashSet<usize> = self
+            .rule_usage()
+            .into_iter()
+            .filter(|&(id, count)| count == 1 && id >= self.seed_rules)
+            .map(|(id, _)| id)
+            .collect();
+        if single_use.is_empty() {
+            return;
+        }
+        let removed: HashMap<usize, Vec<Symbol>> = single_use
+            .iter()
+            .filter_map(|rid| self.rules.remove(rid).map(|exp| (*rid, exp)))
+            .collect();
+
+        self.sequence = inline_rules(&self.sequence, &removed);
+        for exp in self.rules.values_mut() {
+            let uses_removed = exp
+                .iter()
+                .any(|s| matches!(s, Symbol::NonTerminal(id) if removed.contains_key(id)));
+            if uses_removed {
+                *exp = inline_rules(exp, &removed);
+            }
+        }
+    }
+}
+
+/// Copies `body`, replacing references to `removed` rules with their bodies.
+/// Removed rules may refer to each other; an explicit stack keeps deep
+/// chains of inlined rules off the call stack.
+fn inline_rules(body: &[Symbol], removed: &HashMap<usize, Vec<Symbol>>) -> Vec<Symbol> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut stack = vec![body.iter()];
+    while let Some(top) = stack.last_mut() {
+        match top.next() {
+            Some(Symbol::NonTerminal(id)) if removed.contains_key(id) => stack.push(removed[id].iter()),
+            Some(s) => out.push(*s),
+            None => {
+                stack.pop();
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(grammar: &Grammar) -> Vec<u8> {
+        fn expand_symbol(symbol: &Symbol, rules: &HashMap<usize, Vec<Symbol>>, out: &mut Vec<u8>) {
+            match symbol {
+                Symbol::Terminal(b) => out.push(*b),
+                Symbol::NonTerminal(id) => rules[id].iter().for_each(|s| expand_symbol(s, rules, out)),
+            }
+        }
+        let mut out = Vec::new();
+        grammar.sequence.iter().for_each(|s| expand_symbol(s, &grammar.rules, &mut out));
+        out
+    }
+
+    #[test]
+    fn overlapping_digrams_terminate_and_round_trip() {
+        // Runs of one byte are made of overlapping digrams, which used to
+        // make inference restart forever.
+        for data in [&b"aaaaaaaaaaaaaaaaa"[..], b"abababababab", b"aaabaaabaaab"] {
+            let mut grammar = Grammar::new();
+            grammar.infer_grammar(data);
+            assert_eq!(expand(&grammar), data);
+            assert!(grammar.rule_usage().values().all(|&uses| uses >= 2));
+        }
+    }
+
+    #[test]
+    fn expand_rule_skips_cycles_and_undefined_rules() {
+        let mut grammar = Grammar::new();
+        grammar.rules.insert(0, vec![Symbol::NonTerminal(0), Symbol::Terminal(b'A')]);
+        grammar.rules.insert(1, vec![Symbol::Terminal(b'B'), Symbol::NonTerminal(1), Symbol::NonTerminal(2)]);
+        assert_eq!(grammar.expand_rule(0, usize::MAX), b"A");
+        assert_eq!(grammar.expand_rule(1, usize::MAX), b"B");
+    }
+
+    #[test]
+    fn hostile_grammars_are_refused() {
+        let grammar = |rules: Vec<(usize, Vec<Symbol>)>, sequence: Vec<Symbol>| Grammar {
+            rules: rules.into_iter().collect(),
+            sequence,
+            ..Grammar::new()
+        };
+        let (a, r0, r1) = (Symbol::Terminal(b'a'), Symbol::NonTerminal(0), Symbol::NonTerminal(1));
+
+        let undefined = grammar(vec![(0, vec![a, r1])], vec![r0]);
+        assert_eq!(undefined.expand(usize::MAX), Err(ExpandError::UndefinedRule(1)));
+
+        let cycle = grammar(vec![(0, vec![a, r1]), (1, vec![r0, a])], vec![r1]);
+        assert!(matches!(cycle.expand(usize::MAX), Err(ExpandError::Cycle(0 | 1))));
+
+        let short = grammar(vec![(0, vec![a])], vec![r0, r0]);
+        assert_eq!(short.expand(usize::MAX), Err(ExpandError::ShortRule(0)));
+
+        // Forty rules that each double the one before derive a terabyte.
+        let rules = (0..40).map(|id| (id, vec![Symbol::NonTerminal(id + 1); 2])).chain([(40, vec![a, a])]);
+        let bomb = grammar(rules.collect(), vec![r0]);
+        assert_eq!(bomb.expand(1 << 20), Err(ExpandError::TooLong { limit: 1 << 20 }));
+    }
+}
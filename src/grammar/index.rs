@@ -0,0 +1,379 @@
+//! Random access into the bytes a grammar derives, without expanding it.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use super::grammar::{ExpandError, Grammar, Symbol};
+
+/// A checked grammar plus the expansion length of every rule, so that any
+/// byte range of its output can be produced by walking only the rules that
+/// overlap it.
+///
+/// ```
+/// use blockpiper::grammar::GrammarIndex;
+/// use blockpiper::Grammar;
+///
+/// let data = b"abcabcabcabc-xyzxyzxyz";
+/// let mut grammar = Grammar::new();
+/// grammar.infer_grammar(data);
+/// let index = GrammarIndex::new(grammar, data.len()).unwrap();
+/// assert_eq!(index.len(), data.len());
+/// assert_eq!(index.get(10..16), &data[10..16]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct GrammarIndex {
+    grammar: Grammar,
+    lengths: HashMap<usize, usize>,
+    /// Offset in the output at which each start-sequence symbol begins.
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl GrammarIndex {
+    /// Indexes `grammar`, rejecting it on the same grounds as
+    /// [`Grammar::expand`]: undefined rules, cycles, or an output longer
+    /// than `limit`.
+    pub fn new(grammar: Grammar, limit: usize) -> Result<Self, ExpandError> {
+        let lengths = grammar.checked_lengths()?;
+        let mut starts = Vec::with_capacity(grammar.sequence.len());
+        let mut len = 0usize;
+        for symbol in &grammar.sequence {
+            starts.push(len);
+            len = len.saturating_add(symbol_len(&lengths, symbol));
+        }
+        if len > limit {
+            return Err(ExpandError::TooLong { limit });
+        }
+        Ok(GrammarIndex { grammar, lengths, starts, len })
+    }
+
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    /// Length of the derived output in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Expansion length of rule `id`, if it is reachable from the start
+    /// sequence.
+    pub fn rule_len(&self, id: usize) -> Option<usize> {
+        self.lengths.get(&id).copied()
+    }
+
+    /// Bytes `range` of the output, clamped to its length.
+    pub fn get(&self, range: Range<usize>) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.read_into(range, &mut out);
+        out
+    }
+
+    /// Appends bytes `range` of the output, clamped to its length, to `out`.
+    /// Rules that end before the range are skipped by their length, so the
+    /// cost grows with the range and the grammar's depth rather than with
+    /// the size of the output.
+    pub fn read_into(&self, range: Range<usize>, out: &mut Vec<u8>) {
+        let (start, end) = (range.start, range.end.min(self.len));
+        if start >= end {
+            return;
+        }
+        out.reserve(end - start);
+        let first = self.starts.partition_point(|&offset| offset <= start) - 1;
+        let mut pos = self.starts[first];
+        let mut stack: Vec<&[Symbol]> = vec![&self.grammar.sequence[first..]];
+        while let Some(top) = stack.last_mut() {
+            let Some((symbol, rest)) = top.split_first() else {
+                stack.pop();
+                continue;
+            };
+            *top = rest;
+            if pos >= end {
+                break;
+            }
+            // Lengths only matter until the walk reaches `start`; from then
+            // on every terminal is emitted.
+            if pos < start {
+                let len = symbol_len(&self.lengths, symbol);
+                if pos + len <= start {
+                    pos += len;
+                    continue;
+                }
+            }
+            match *symbol {
+                Symbol::Terminal(b) => {
+                    out.push(b);
+                    pos += 1;
+                }
+                Symbol::NonTerminal(id) => stack.push(&self.grammar.rules[&id]),
+            }
+        }
+    }
+}
+
+/// The parts of a rule's expansion a search for one pattern needs: a match
+/// either lies inside one of the rule's symbols, or crosses between two and
+/// is then visible in the first and last `pattern.len() - 1` bytes.
+struct RuleSummary {
+    prefix: Vec<u8>,
+    suffix: Vec<u8>,
+    /// Matches lying wholly inside the expansion.
+    matches: usize,
+}
+
+impl GrammarIndex {
+    /// Offsets of every occurrence of `pattern` in the output, in increasing
+    /// order and including overlapping ones. An empty pattern matches
+    /// nothing.
+    ///
+    /// Each rule is summarised once: its first and last `pattern.len() - 1`
+    /// bytes and how many matches it contains. The walk from the start
+    /// sequence finds matches across symbol boundaries from those summaries
+    /// and only descends into rules that contain a match, so repeated text
+    /// is searched once rather than at every occurrence.
+    ///
+    /// ```
+    /// use blockpiper::grammar::GrammarIndex;
+    /// use blockpiper::Grammar;
+    ///
+    /// let data = b"to be or not to be, that is to be decided";
+    /// let mut grammar = Grammar::new();
+    /// grammar.infer_grammar(data);
+    /// let index = GrammarIndex::new(grammar, data.len()).unwrap();
+    /// assert_eq!(index.find(b"to be"), vec![0, 13, 28]);
+    /// ```
+    pub fn find(&self, pattern: &[u8]) -> Vec<usize> {
+        let mut found = Vec::new();
+        if pattern.is_empty() {
+            return found;
+        }
+        let summaries = self.summaries(pattern);
+        let keep = pattern.len() - 1;
+        let mut stack: Vec<(&[Symbol], PatternScan)> = vec![(&self.grammar.sequence, PatternScan::new(pattern, 0))];
+        while let Some((symbols, scan)) = stack.last_mut() {
+            let Some((symbol, rest)) = symbols.split_first() else {
+                stack.pop();
+                continue;
+            };
+            *symbols = rest;
+            let start = scan.pos;
+            match *symbol {
+                Symbol::Terminal(b) => {
+                    let piece = &[b][..keep.min(1)];
+                    scan.append(piece, piece, 1, |offset| found.push(offset as usize));
+                    if pattern == [b] {
+                        found.push(start as usize);
+                    }
+                }
+                Symbol::NonTerminal(id) => {
+                    let summary = &summaries[&id];
+                    scan.append(&summary.prefix, &summary.suffix, self.lengths[&id] as u64, |offset| found.push(offset as usize));
+                    if summary.matches > 0 {
+                        stack.push((&self.grammar.rules[&id], PatternScan::new(pattern, start)));
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// Summaries of every reachable rule, children before parents.
+    fn summaries(&self, pattern: &[u8]) -> HashMap<usize, RuleSummary> {
+        let keep = pattern.len() - 1;
+        let mut summaries: HashMap<usize, RuleSummary> = HashMap::with_capacity(self.lengths.len());
+        for &root in self.lengths.keys() {
+            let mut stack = vec![(root, false)];
+            while let Some((rid, children_done)) = stack.pop() {
+                if summaries.contains_key(&rid) {
+                    continue;
+                }
+                let body = &self.grammar.rules[&rid];
+                if !children_done {
+                    stack.push((rid, true));
+                    for symbol in body {
+                        if let Symbol::NonTerminal(id) = *symbol {
+                            if !summaries.contains_key(&id) {
+                                stack.push((id, false));
+                            }
+                        }
+                    }
+                    continue;
+                }
+                let mut scan = PatternScan::new(pattern, 0);
+                let mut matches = 0usize;
+                for symbol in body {
+                    match *symbol {
+                        Symbol::Terminal(b) => {
+                            let piece = &[b][..keep.min(1)];
+                            scan.append(piece, piece, 1, |_| matches += 1);
+                            matches += usize::from(pattern == [b]);
+                        }
+                        Symbol::NonTerminal(id) => {
+                            let child = &summaries[&id];
+                            scan.append(&child.prefix, &child.suffix, self.lengths[&id] as u64, |_| matches += 1);
+                            matches = matches.saturating_add(child.matches);
+                        }
+                    }
+                }
+                summaries.insert(rid, RuleSummary { prefix: scan.prefix, suffix: scan.tail, matches });
+            }
+        }
+        summaries
+    }
+}
+
+/// Running state of a search over a sequence of pieces of output, each
+/// known only by its length and its first and last `pattern.len() - 1`
+/// bytes. Finds the matches that cross from one piece into the next; the
+/// caller accounts for matches inside a piece.
+pub(crate) struct PatternScan<'p> {
+    pattern: &'p [u8],
+    /// Offset at which the next piece starts.
+    pub(crate) pos: u64,
+    /// First `pattern.len() - 1` bytes seen, or all of them if fewer.
+    prefix: Vec<u8>,
+    /// Last `pattern.len() - 1` bytes seen, or all of them if fewer.
+    tail: Vec<u8>,
+    window: Vec<u8>,
+}
+
+impl<'p> PatternScan<'p> {
+    pub(crate) fn new(pattern: &'p [u8], pos: u64) -> Self {
+        PatternScan { pattern, pos, prefix: Vec::new(), tail: Vec::new(), window: Vec::new() }
+    }
+
+    /// Appends a piece of `len` bytes whose first and last `pattern.len() - 1`
+    /// bytes (the whole piece if it is shorter) are `head` and `tail`, calling
+    /// `emit` with the offset of every match that starts before the piece
+    /// and ends inside it.
+    pub(crate) fn append(&mut self, head: &[u8], tail: &[u8], len: u64, mut emit: impl FnMut(u64)) {
+        let keep = self.pattern.len() - 1;
+        if !self.tail.is_empty() && !head.is_empty() {
+            self.window.clear();
+            self.window.extend_from_slice(&self.tail);
+            self.window.extend_from_slice(head);
+            let seen = self.tail.len();
+            let first = (seen + 1).saturating_sub(self.pattern.len());
+            for start in first..seen {
+                if self.window.get(start..start + self.pattern.len()) == Some(self.pattern) {
+                    emit(self.pos - seen as u64 + start as u64);
+                }
+            }
+        }
+        if self.prefix.len() < keep {
+            let take = (keep - self.prefix.len()).min(head.len());
+            self.prefix.extend_from_slice(&head[..take]);
+        }
+        if len >= keep as u64 {
+            self.tail.clear();
+            self.tail.extend_from_slice(tail);
+        } else {
+            self.tail.extend_from_slice(head);
+            let excess = self.tail.len().saturating_sub(keep);
+            self.tail.drain(..excess);
+        }
+        self.pos += len;
+    }
+}
+
+/// Offsets of every occurrence of `pattern` in `haystack`.
+pub(crate) fn find_bytes(haystack: &[u8], pattern: &[u8]) -> Vec<usize> {
+    if pattern.is_empty() {
+        return Vec::new();
+    }
+    haystack
+        .windows(pattern.len())
+        .enumerate()
+        .filter(|(_, window)| *window == pattern)
+        .map(|(offset, _)| offset)
+        .collect()
+}
+
+fn symbol_len(lengths: &HashMap<usize, usize>, symbol: &Symbol) -> usize {
+    match symbol {
+        Symbol::Terminal(_) => 1,
+        Symbol::NonTerminal(id) => lengths[id],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Repetitive text with runs of one byte, so the grammar has nested
+    /// rules and patterns overlap themselves.
+    fn sample() -> Vec<u8> {
+        let words: [&[u8]; 6] = [b"abab", b"to be ", b"aaaaaa", b"xyz", b"ababab ", b"\n"];
+        let mut seed = 7u32;
+        let mut data = Vec::new();
+        for _ in 0..300 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            data.extend_from_slice(words[(seed >> 16) as usize % words.len()]);
+        }
+        data
+    }
+
+    fn index_of(data: &[u8]) -> GrammarIndex {
+        let mut grammar = Grammar::new();
+        grammar.infer_grammar(data);
+        GrammarIndex::new(grammar, data.len()).unwrap()
+    }
+
+    #[test]
+    fn ranges_match_the_expansion() {
+        let data = sample();
+        let index = index_of(&data);
+        assert_eq!(index.len(), data.len());
+        assert!(!index.grammar().rules.is_empty());
+        for start in (0..data.len()).step_by(37) {
+            for len in [0, 1, 2, 5, 64, 333] {
+                let end = (start + len).min(data.len());
+                assert_eq!(index.get(start..start + len), &data[start..end], "range {}..{}", start, start + len);
+            }
+        }
+        assert_eq!(index.get(0..usize::MAX), data);
+        assert!(index.get(data.len()..data.len() + 10).is_empty());
+    }
+
+    #[test]
+    fn find_matches_a_naive_search() {
+        let data = sample();
+        let index = index_of(&data);
+        let mut patterns: Vec<Vec<u8>> = [&b"a"[..], b"aa", b"aaa", b"abab", b"babab", b"to be to", b"\n\n", b"missing"].iter().map(|p| p.to_vec()).collect();
+        // Substrings of every length up to a few rules' worth, wherever they
+        // fall relative to rule boundaries.
+        for start in (0..data.len() - 40).step_by(53) {
+            for len in [2, 3, 7, 16, 40] {
+                patterns.push(data[start..start + len].to_vec());
+            }
+        }
+        for pattern in &patterns {
+            assert_eq!(index.find(pattern), find_bytes(&data, pattern), "pattern {:?}", String::from_utf8_lossy(pattern));
+        }
+        assert!(index.find(b"").is_empty());
+    }
+
+    #[test]
+    fn pieces_stitch_matches_across_boundaries() {
+        let data = b"aaaabaaab";
+        let pattern = b"aab";
+        for cut in 0..=data.len() {
+            for second_cut in cut..=data.len() {
+                let mut found = Vec::new();
+                let mut scan = PatternScan::new(pattern, 0);
+                for piece in [&data[..cut], &data[cut..second_cut], &data[second_cut..]] {
+                    let keep = pattern.len() - 1;
+                    let start = scan.pos;
+                    scan.append(&piece[..keep.min(piece.len())], &piece[piece.len().saturating_sub(keep)..], piece.len() as u64, |offset| found.push(offset));
+                    found.extend(find_bytes(piece, pattern).into_iter().map(|offset| start + offset as u64));
+                }
+                found.sort_unstable();
+                assert_eq!(found, [2, 6], "cuts {} and {}", cut, second_cut);
+            }
+        }
+    }
+}
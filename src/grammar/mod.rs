@@ -1,2 +1,8 @@
-pub mod grammar;
-pub use grammar::Grammar; 
\ No newline at end of file
+pub mod analysis;
+pub mod export;
+pub mod grammar;
+pub mod index;
+pub use analysis::{CompressionPotential, GrammarAnalysis, RuleInfo};
+pub use export::{write_dot, write_grammar, write_json, write_text, ExportFormat};
+pub use grammar::{ExpandError, Grammar, GrammarSeed, Symbol};
+pub use index::GrammarIndex;
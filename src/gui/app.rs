@@ -0,0 +1,287 @@
+use eframe::{egui, App};
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::compressor::{CompressionOptions, DecompressOptions, DEFAULT_DEDUP_WINDOW, DEFAULT_LEVEL, MAX_LEVEL, MIN_LEVEL};
+use super::explorer::GrammarExplorer;
+use super::job::{Job, JobKind, JobState};
+use super::queue::JobQueue;
+use super::stats::{stats_panel, ChartMetric};
+
+pub struct BlockPiperApp {
+    input_path: String,
+    level: u32,
+    dedup: bool,
+    compress_job: Option<Job>,
+    decompress_input: String,
+    decompress_output: String,
+    decompress_job: Option<Job>,
+    queue: JobQueue,
+    queue_message: String,
+    /// Which of the two single-file jobs the results panel shows: the one
+    /// started last.
+    results_for: JobKind,
+    chart_metric: ChartMetric,
+    explorer: GrammarExplorer,
+}
+
+impl Default for BlockPiperApp {
+    fn default() -> Self {
+        Self {
+            input_path: String::new(),
+            level: DEFAULT_LEVEL,
+            dedup: false,
+            compress_job: None,
+            decompress_input: String::new(),
+            decompress_output: String::new(),
+            decompress_job: None,
+            queue: JobQueue::new(),
+            queue_message: String::new(),
+            results_for: JobKind::Compress,
+            chart_metric: ChartMetric::default(),
+            explorer: GrammarExplorer::default(),
+        }
+    }
+}
+
+fn is_running(job: &Option<Job>) -> bool {
+    job.as_ref().is_some_and(|job| !job.is_finished())
+}
+
+/// Pause/Resume and Cancel buttons for an active job.
+fn pause_cancel_buttons(ui: &mut egui::Ui, job: &Job) {
+    if job.state() == JobState::Paused {
+        if ui.button("Resume").clicked() {
+            job.resume();
+        }
+    } else if ui.button("Pause").clicked() {
+        job.pause();
+    }
+    if ui.button("Cancel").clicked() {
+        job.cancel();
+    }
+}
+
+/// Progress bar, Pause/Resume and Cancel buttons and a status line for `job`.
+fn job_controls(ui: &mut egui::Ui, job: &Option<Job>) {
+    let Some(job) = job else {
+        return;
+    };
+    if job.state().is_active() {
+        ui.add(egui::ProgressBar::new(job.progress().fraction()).show_percentage());
+        ui.horizontal(|ui| pause_cancel_buttons(ui, job));
+    }
+    ui.label(status_text(job));
+}
+
+fn status_text(job: &Job) -> String {
+    let verb = match job.kind() {
+        JobKind::Compress => "Compression",
+        JobKind::Decompress => "Decompression",
+    };
+    match job.state() {
+        JobState::Idle => format!("{} starting…", verb),
+        JobState::Running => format!("{} running…", verb),
+        JobState::Paused => format!("{} paused", verb),
+        JobState::Cancelled if !job.is_finished() => format!("{} cancelling…", verb),
+        JobState::Cancelled => format!("{} cancelled; partial output removed", verb),
+        JobState::Failed(e) => format!("{} failed: {}", verb, e),
+        JobState::Done => format!("{} complete! Output: {}", verb, job.output().display()),
+    }
+}
+
+impl App for BlockPiperApp {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.heading("BlockPiper File Compressor");
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    ui.label("Input file:");
+                    ui.text_edit_singleline(&mut self.input_path);
+                    if ui.button("Browse").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().pick_file() {
+                            self.input_path = path.display().to_string();
+                        }
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("Level:");
+                    ui.add(egui::Slider::new(&mut self.level, MIN_LEVEL..=MAX_LEVEL));
+                    ui.label(match self.level {
+                        1..=2 => "fast",
+                        7..=9 => "max",
+                        _ => "default",
+                    });
+                });
+                ui.checkbox(&mut self.dedup, "Deduplicate blocks");
+
+                let compressing = is_running(&self.compress_job);
+                if ui.add_enabled(!compressing, egui::Button::new("Compress")).clicked() {
+                    let input = PathBuf::from(&self.input_path);
+                    let output = PathBuf::from(format!("{}.bpc", self.input_path));
+                    let options = self.compression_options();
+                    self.compress_job = Some(Job::compress(input, output, options));
+                    self.results_for = JobKind::Compress;
+                }
+                job_controls(ui, &self.compress_job);
+
+                ui.separator();
+                ui.heading("Decompressor");
+
+                ui.horizontal(|ui| {
+                    ui.label("Compressed file:");
+                    ui.text_edit_singleline(&mut self.decompress_input);
+                    if ui.button("Browse").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().pick_file() {
+                            self.decompress_input = path.display().to_string();
+                        }
+                    }
+                });
+
+                ui.horizontal(|ui| {
+                    ui.label("Output file:");
+                    ui.text_edit_singleline(&mut self.decompress_output);
+                    if ui.button("Browse").clicked() {
+                        if let Some(path) = rfd::FileDialog::new().save_file() {
+                            self.decompress_output = path.display().to_string();
+                        }
+                    }
+                });
+
+                let decompressing = is_running(&self.decompress_job);
+                if ui.add_enabled(!decompressing, egui::Button::new("Decompress")).clicked() {
+                    let input = PathBuf::from(&self.decompress_input);
+                    let output = PathBuf::from(&self.decompress_output);
+                    self.decompress_job = Some(Job::decompress(input, output, DecompressOptions::default()));
+                    self.results_for = JobKind::Decompress;
+                }
+                job_controls(ui, &self.decompress_job);
+
+                let results = match self.results_for {
+                    JobKind::Compress => &self.compress_job,
+                    JobKind::Decompress => &self.decompress_job,
+                };
+                if let Some(job) = results.as_ref().filter(|job| job.state() == JobState::Done) {
+                    ui.separator();
+                    egui::CollapsingHeader::new("Results").default_open(true).show(ui, |ui| {
+                        stats_panel(ui, job, &mut self.chart_metric);
+                    });
+                }
+
+                ui.separator();
+                self.queue_panel(ui);
+
+                ui.separator();
+                egui::CollapsingHeader::new("Grammar explorer").show(ui, |ui| self.explorer.show(ui));
+            });
+        });
+
+        self.accept_dropped_files(ctx);
+        self.queue.poll(&self.compression_options());
+
+        // Workers do not touch the UI; poll while any job is in flight.
+        if is_running(&self.compress_job) || is_running(&self.decompress_job) || self.queue.is_busy() || self.explorer.is_busy() {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+    }
+}
+
+impl BlockPiperApp {
+    /// Options for compression jobs, from the level slider and dedup box.
+    fn compression_options(&self) -> CompressionOptions {
+        CompressionOptions::level(self.level).with_dedup_window(self.dedup.then_some(DEFAULT_DEDUP_WINDOW))
+    }
+
+    /// Queues files and folders dropped onto the window.
+    fn accept_dropped_files(&mut self, ctx: &egui::Context) {
+        let dropped: Vec<PathBuf> = ctx.input(|i| i.raw.dropped_files.iter().filter_map(|file| file.path.clone()).collect());
+        if dropped.is_empty() {
+            return;
+        }
+        let (mut queued, mut renamed, mut skipped) = (0, 0, 0);
+        self.queue_message.clear();
+        for path in dropped {
+            match self.queue.add_path(&path) {
+                Ok(added) => {
+                    queued += added.queued;
+                    renamed += added.renamed;
+                    skipped += added.skipped;
+                }
+                Err(e) => self.queue_message = format!("Could not queue {}: {}", path.display(), e),
+            }
+        }
+        if self.queue_message.is_empty() {
+            self.queue_message = format!("Queued {} file(s)", queued);
+            if renamed > 0 {
+                self.queue_message.push_str(&format!(", {} renamed to avoid overwriting", renamed));
+            }
+            if skipped > 0 {
+                self.queue_message.push_str(&format!(", skipped {} already queued or written by the queue", skipped));
+            }
+        }
+    }
+
+    fn queue_panel(&mut self, ui: &mut egui::Ui) {
+        ui.heading("Batch queue");
+        let hovered = ui.ctx().input(|i| i.raw.hovered_files.len());
+        if hovered > 0 {
+            ui.label(format!("Release to queue {} item(s)", hovered));
+        } else {
+            ui.label("Drop files or folders here. .bpc files are decompressed, everything else is compressed at the level above.");
+        }
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.queue.concurrent, "Run jobs concurrently");
+            if ui.button("Cancel all").clicked() {
+                self.queue.cancel_all();
+            }
+            if ui.button("Clear finished").clicked() {
+                self.queue.clear_finished();
+            }
+        });
+        if !self.queue_message.is_empty() {
+            ui.label(&self.queue_message);
+        }
+
+        let mut remove = None;
+        egui::ScrollArea::vertical().max_height(240.0).show(ui, |ui| {
+            for (index, entry) in self.queue.entries().iter().enumerate() {
+                ui.horizontal(|ui| {
+                    if ui.small_button("✖").on_hover_text("Remove from queue").clicked() {
+                        remove = Some(index);
+                    }
+                    ui.label(format!("{} → {}", entry.input.display(), entry.output.display()));
+                });
+                ui.horizontal(|ui| {
+                    let Some(job) = entry.job() else {
+                        ui.label("Waiting");
+                        return;
+                    };
+                    let fraction = if job.state() == JobState::Done { 1.0 } else { job.progress().fraction() };
+                    ui.add(egui::ProgressBar::new(fraction).desired_width(160.0).show_percentage());
+                    if let Some(ratio) = entry.ratio() {
+                        ui.label(format!("ratio {:.3}", ratio));
+                    }
+                    if job.state().is_active() {
+                        pause_cancel_buttons(ui, job);
+                    }
+                    ui.label(status_text(job));
+                });
+            }
+        });
+        if let Some(index) = remove {
+            self.queue.remove(index);
+        }
+    }
+}
+
+/// Opens the BlockPiper window and blocks until it is closed.
+pub fn run() -> eframe::Result<()> {
+    let options = eframe::NativeOptions::default();
+    eframe::run_native(
+        "BlockPiper Compressor",
+        options,
+        Box::new(|_cc| Box::new(BlockPiperApp::default())),
+    )
+}
@@ -0,0 +1,330 @@
+use eframe::egui;
+use eframe::egui::text::{LayoutJob, TextFormat};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::thread::{self, JoinHandle};
+
+use crate::compressor::DEFAULT_BLOCK_SIZE;
+use crate::grammar::{Grammar, Symbol};
+
+/// Largest region the explorer analyses, which keeps inference to seconds.
+const MAX_REGION: usize = 4 * 1024 * 1024;
+/// Bytes of an expansion shown inline in the tree.
+const PREVIEW_LEN: usize = 48;
+/// Top-level tree entries listed before the rest are summarised.
+const MAX_TOP_LEVEL: usize = 500;
+/// Bytes shown on each side of the selected occurrence in the data view.
+const CONTEXT_LEN: usize = 256;
+
+/// An inferred grammar plus the derived numbers the tree displays.
+struct Analysis {
+    data: Vec<u8>,
+    /// Offset of `data` in the file.
+    region_start: u64,
+    grammar: Grammar,
+    usage: HashMap<usize, usize>,
+    lengths: HashMap<usize, usize>,
+    occurrences: HashMap<usize, Vec<usize>>,
+    /// Rules referenced from the start sequence, in first-use order.
+    top_level: Vec<usize>,
+}
+
+impl Analysis {
+    /// Reads `len` bytes at `start` of the file at `path` and infers their
+    /// grammar.
+    fn load(path: &str, start: u64, len: usize) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut data = Vec::with_capacity(len);
+        file.take(len as u64).read_to_end(&mut data)?;
+        if data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "region is past the end of the file"));
+        }
+        Ok(Analysis::new(data, start))
+    }
+
+    fn new(data: Vec<u8>, region_start: u64) -> Self {
+        let mut grammar = Grammar::new();
+        grammar.infer_grammar(&data);
+        let mut top_level = Vec::new();
+        let mut seen = std::collections::HashSet::new();
+        for symbol in &grammar.sequence {
+            if let Symbol::NonTerminal(id) = symbol {
+                if seen.insert(*id) {
+                    top_level.push(*id);
+                }
+            }
+        }
+        Analysis {
+            usage: grammar.rule_usage(),
+            lengths: grammar.rule_lengths(),
+            occurrences: grammar.occurrences(),
+            top_level,
+            data,
+            region_start,
+            grammar,
+        }
+    }
+}
+
+/// Panel that runs grammar inference on one block or byte range of a file
+/// and shows the resulting rule hierarchy.
+pub struct GrammarExplorer {
+    path: String,
+    by_block: bool,
+    block: usize,
+    block_size: usize,
+    offset: u64,
+    length: usize,
+    analysis: Option<Analysis>,
+    /// Inference running on a worker thread, so the window stays
+    /// responsive.
+    pending: Option<JoinHandle<io::Result<Analysis>>>,
+    error: String,
+    /// Selected rule and which of its occurrences the data view shows.
+    selected: Option<(usize, usize)>,
+}
+
+impl Default for GrammarExplorer {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            by_block: true,
+            block: 0,
+            block_size: DEFAULT_BLOCK_SIZE,
+            offset: 0,
+            length: 64 * 1024,
+            analysis: None,
+            pending: None,
+            error: String::new(),
+            selected: None,
+        }
+    }
+}
+
+impl GrammarExplorer {
+    fn region(&self) -> (u64, usize) {
+        if self.by_block {
+            (self.block as u64 * self.block_size as u64, self.block_size)
+        } else {
+            (self.offset, self.length)
+        }
+    }
+
+    /// Whether an analysis is running; the window has to keep repainting
+    /// to pick up its result.
+    pub fn is_busy(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Starts analysing the selected region on a worker thread.
+    fn analyze(&mut self) {
+        let (start, len) = self.region();
+        if len > MAX_REGION {
+            self.error = format!("Analysis failed: region is limited to {} bytes", MAX_REGION);
+            return;
+        }
+        self.error.clear();
+        let path = self.path.clone();
+        self.pending = Some(thread::spawn(move || Analysis::load(&path, start, len)));
+    }
+
+    /// Takes the result of a finished analysis, if there is one.
+    fn poll(&mut self) {
+        if !self.pending.as_ref().is_some_and(JoinHandle::is_finished) {
+            return;
+        }
+        let result = self.pending.take().unwrap().join().unwrap_or_else(|_| Err(io::Error::other("grammar inference panicked")));
+        match result {
+            Ok(analysis) => {
+                self.analysis = Some(analysis);
+                self.selected = None;
+            }
+            Err(e) => self.error = format!("Analysis failed: {}", e),
+        }
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        self.poll();
+        ui.horizontal(|ui| {
+            ui.label("File:");
+            ui.text_edit_singleline(&mut self.path);
+            if ui.button("Browse").clicked() {
+                if let Some(path) = rfd::FileDialog::new().pick_file() {
+                    self.path = path.display().to_string();
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.by_block, true, "Block");
+            ui.add_enabled(self.by_block, egui::DragValue::new(&mut self.block));
+            ui.label("of size");
+            ui.add_enabled(self.by_block, egui::DragValue::new(&mut self.block_size).clamp_range(1..=MAX_REGION));
+            ui.radio_value(&mut self.by_block, false, "Region at");
+            ui.add_enabled(!self.by_block, egui::DragValue::new(&mut self.offset));
+            ui.label("length");
+            ui.add_enabled(!self.by_block, egui::DragValue::new(&mut self.length).clamp_range(1..=MAX_REGION));
+            if ui.add_enabled(!self.is_busy(), egui::Button::new("Analyze")).clicked() {
+                self.analyze();
+            }
+            if self.is_busy() {
+                ui.spinner();
+                ui.label("Inferring grammar…");
+            }
+        });
+        if !self.error.is_empty() {
+            ui.label(&self.error);
+        }
+
+        let Some(analysis) = &self.analysis else {
+            return;
+        };
+        ui.label(format!(
+            "{} bytes from offset {}: {} rules, start sequence of {} symbols",
+            analysis.data.len(),
+            analysis.region_start,
+            analysis.grammar.rules.len(),
+            analysis.grammar.sequence.len()
+        ));
+
+        let mut select = None;
+        egui::ScrollArea::vertical().id_source("rule tree").max_height(300.0).show(ui, |ui| {
+            for &rid in analysis.top_level.iter().take(MAX_TOP_LEVEL) {
+                rule_node(ui, analysis, rid, &mut select, &mut vec![rid]);
+            }
+            if analysis.top_level.len() > MAX_TOP_LEVEL {
+                ui.label(format!("… {} more rules used by the start sequence", analysis.top_level.len() - MAX_TOP_LEVEL));
+            }
+        });
+        if let Some(rid) = select {
+            self.selected = Some((rid, 0));
+        }
+
+        if let Some((rid, index)) = self.selected {
+            let positions = &analysis.occurrences[&rid];
+            let index = index.min(positions.len() - 1);
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label(format!(
+                    "R{}: occurrence {} of {} at offset {}",
+                    rid,
+                    index + 1,
+                    positions.len(),
+                    analysis.region_start + positions[index] as u64
+                ));
+                if ui.add_enabled(index > 0, egui::Button::new("◀")).clicked() {
+                    self.selected = Some((rid, index - 1));
+                }
+                if ui.add_enabled(index + 1 < positions.len(), egui::Button::new("▶")).clicked() {
+                    self.selected = Some((rid, index + 1));
+                }
+            });
+            data_view(ui, analysis, rid, positions[index]);
+        }
+    }
+}
+
+/// One rule as a collapsible node; its children are built only when open.
+/// `path` holds the rules on the way from the root and keeps ids unique.
+fn rule_node(ui: &mut egui::Ui, analysis: &Analysis, rid: usize, select: &mut Option<usize>, path: &mut Vec<usize>) {
+    let Some(body) = analysis.grammar.rules.get(&rid) else {
+        ui.label(format!("R{} (undefined)", rid));
+        return;
+    };
+    let expansion = analysis.grammar.expand_rule(rid, PREVIEW_LEN + 1);
+    let title = format!(
+        "R{}  ×{}  {} bytes  {}",
+        rid,
+        analysis.usage.get(&rid).copied().unwrap_or(0),
+        analysis.lengths.get(&rid).copied().unwrap_or(0),
+        preview_text(&expansion)
+    );
+    egui::CollapsingHeader::new(title).id_source(path.as_slice()).show(ui, |ui| {
+        ui.horizontal(|ui| {
+            ui.monospace(preview_hex(&expansion));
+            if ui.small_button("Highlight").clicked() {
+                *select = Some(rid);
+            }
+        });
+        for symbol in body {
+            match *symbol {
+                Symbol::Terminal(b) => {
+                    ui.monospace(format!("{}  0x{:02x}", preview_text(&[b]), b));
+                }
+                Symbol::NonTerminal(id) => {
+                    path.push(id);
+                    rule_node(ui, analysis, id, select, path);
+                    path.pop();
+                }
+            }
+        }
+    });
+}
+
+/// The bytes around one occurrence of `rid`, with every occurrence of it
+/// in view highlighted.
+fn data_view(ui: &mut egui::Ui, analysis: &Analysis, rid: usize, at: usize) {
+    let rule_len = analysis.lengths.get(&rid).copied().unwrap_or(0);
+    let start = at.saturating_sub(CONTEXT_LEN);
+    let end = (at + rule_len + CONTEXT_LEN).min(analysis.data.len());
+    let positions = &analysis.occurrences[&rid];
+    let first = positions.partition_point(|&p| p + rule_len <= start);
+
+    let plain = TextFormat {
+        font_id: egui::FontId::monospace(12.0),
+        color: ui.visuals().text_color(),
+        ..TextFormat::default()
+    };
+    let highlight = TextFormat {
+        background: ui.visuals().selection.bg_fill,
+        ..plain.clone()
+    };
+    let mut job = LayoutJob::default();
+    let mut pos = start;
+    for &occurrence in positions[first..].iter().take_while(|&&p| p < end) {
+        let from = occurrence.max(pos);
+        let to = (occurrence + rule_len).min(end);
+        if from > pos {
+            job.append(&display_text(&analysis.data[pos..from]), 0.0, plain.clone());
+        }
+        if to > from {
+            job.append(&display_text(&analysis.data[from..to]), 0.0, highlight.clone());
+        }
+        pos = pos.max(to);
+    }
+    if end > pos {
+        job.append(&display_text(&analysis.data[pos..end]), 0.0, plain);
+    }
+    job.wrap.max_width = ui.available_width();
+    egui::ScrollArea::vertical().id_source("data view").max_height(200.0).show(ui, |ui| {
+        ui.label(job);
+    });
+}
+
+/// Printable ASCII as is, newlines kept, everything else as `·`.
+fn display_text(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'\n' => '\n',
+            0x20..=0x7e => b as char,
+            _ => '·',
+        })
+        .collect()
+}
+
+/// Quoted, escaped text of at most `PREVIEW_LEN` bytes.
+fn preview_text(bytes: &[u8]) -> String {
+    let shown = &bytes[..bytes.len().min(PREVIEW_LEN)];
+    let escaped: String = shown.iter().flat_map(|&b| std::ascii::escape_default(b)).map(char::from).collect();
+    let ellipsis = if bytes.len() > PREVIEW_LEN { "…" } else { "" };
+    format!("\"{}\"{}", escaped, ellipsis)
+}
+
+fn preview_hex(bytes: &[u8]) -> String {
+    let shown = &bytes[..bytes.len().min(PREVIEW_LEN)];
+    let hex: Vec<String> = shown.iter().map(|b| format!("{:02x}", b)).collect();
+    let ellipsis = if bytes.len() > PREVIEW_LEN { " …" } else { "" };
+    format!("{}{}", hex.join(" "), ellipsis)
+}
@@ -0,0 +1,221 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::compressor::{compress_file_with_stats, decompress_file_with_progress, BlockStats, CompressionOptions, DecompressOptions, Progress};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobKind {
+    Compress,
+    Decompress,
+}
+
+/// Lifecycle of a background job. `Cancelled`, `Failed` and `Done` are
+/// final, though a cancelled worker may still be removing its output; see
+/// [`Job::is_finished`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobState {
+    Idle,
+    Running,
+    Paused,
+    Cancelled,
+    Failed(String),
+    Done,
+}
+
+impl JobState {
+    /// Whether the job can still be paused or cancelled.
+    pub fn is_active(&self) -> bool {
+        matches!(self, JobState::Idle | JobState::Running | JobState::Paused)
+    }
+}
+
+/// What a worker thread runs.
+enum Task {
+    Compress(CompressionOptions),
+    Decompress(DecompressOptions),
+}
+
+/// State shared between the UI and the worker thread. The worker checks it
+/// from its progress callback after every batch of blocks: it blocks while
+/// the job is paused and aborts once it is cancelled.
+struct Shared {
+    state: Mutex<JobState>,
+    changed: Condvar,
+    progress: Mutex<Progress>,
+    /// Per-block statistics of a finished compression.
+    block_stats: Mutex<Vec<BlockStats>>,
+    finished_at: Mutex<Option<Instant>>,
+}
+
+impl Shared {
+    fn checkpoint(&self, progress: &Progress) -> io::Result<()> {
+        *self.progress.lock().unwrap() = *progress;
+        let mut state = self.state.lock().unwrap();
+        while *state == JobState::Paused {
+            state = self.changed.wait(state).unwrap();
+        }
+        match *state {
+            JobState::Cancelled => Err(io::Error::new(io::ErrorKind::Interrupted, "cancelled")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// One compression or decompression running on its own thread.
+pub struct Job {
+    kind: JobKind,
+    input: PathBuf,
+    output: PathBuf,
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+    started_at: Instant,
+}
+
+impl Job {
+    pub fn compress(input: PathBuf, output: PathBuf, options: CompressionOptions) -> Self {
+        Self::start(input, output, Task::Compress(options))
+    }
+
+    pub fn decompress(input: PathBuf, output: PathBuf, options: DecompressOptions) -> Self {
+        Self::start(input, output, Task::Decompress(options))
+    }
+
+    fn start(input: PathBuf, output: PathBuf, task: Task) -> Self {
+        let kind = match task {
+            Task::Compress(_) => JobKind::Compress,
+            Task::Decompress(_) => JobKind::Decompress,
+        };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(JobState::Idle),
+            changed: Condvar::new(),
+            progress: Mutex::new(Progress::default()),
+            block_stats: Mutex::new(Vec::new()),
+            finished_at: Mutex::new(None),
+        });
+        let worker = {
+            let shared = shared.clone();
+            let (input, output) = (input.clone(), output.clone());
+            thread::spawn(move || run_job(&task, &input, &output, &shared))
+        };
+        Job {
+            kind,
+            input,
+            output,
+            shared,
+            handle: Some(worker),
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn kind(&self) -> JobKind {
+        self.kind
+    }
+
+    pub fn input(&self) -> &Path {
+        &self.input
+    }
+
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    pub fn state(&self) -> JobState {
+        self.shared.state.lock().unwrap().clone()
+    }
+
+    /// Whether the worker thread has exited, output cleanup included.
+    pub fn is_finished(&self) -> bool {
+        self.handle.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    pub fn progress(&self) -> Progress {
+        *self.shared.progress.lock().unwrap()
+    }
+
+    /// Statistics for every block, available once a compression is done.
+    pub fn block_stats(&self) -> Vec<BlockStats> {
+        self.shared.block_stats.lock().unwrap().clone()
+    }
+
+    /// Wall-clock time since the job started, pauses included, up to when
+    /// it finished.
+    pub fn elapsed(&self) -> Duration {
+        match *self.shared.finished_at.lock().unwrap() {
+            Some(finished_at) => finished_at - self.started_at,
+            None => self.started_at.elapsed(),
+        }
+    }
+
+    pub fn pause(&self) {
+        self.transition(|state| matches!(state, JobState::Idle | JobState::Running), JobState::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.transition(|state| *state == JobState::Paused, JobState::Running);
+    }
+
+    /// Asks the worker to stop at its next checkpoint; it then deletes the
+    /// partial output.
+    pub fn cancel(&self) {
+        self.transition(JobState::is_active, JobState::Cancelled);
+    }
+
+    fn transition(&self, allowed: impl Fn(&JobState) -> bool, next: JobState) {
+        let mut state = self.shared.state.lock().unwrap();
+        if allowed(&state) {
+            *state = next;
+            self.shared.changed.notify_all();
+        }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.cancel();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Runs `task` and records how it ended. The library functions remove
+/// whatever partial output a failed or cancelled job left, and never touch
+/// the output before they have started writing it, so the job itself
+/// deletes nothing.
+fn run_job(task: &Task, input: &Path, output: &Path, shared: &Shared) {
+    {
+        let mut state = shared.state.lock().unwrap();
+        match *state {
+            JobState::Idle => *state = JobState::Running,
+            JobState::Cancelled => return,
+            _ => {}
+        }
+    }
+    let checkpoint = |progress: &Progress| shared.checkpoint(progress);
+    let result = match task {
+        Task::Compress(options) => compress_file_with_stats(input, output, options, checkpoint).map(|(progress, block_stats)| {
+            *shared.block_stats.lock().unwrap() = block_stats;
+            progress
+        }),
+        Task::Decompress(options) => decompress_file_with_progress(input, output, options, checkpoint),
+    };
+
+    *shared.finished_at.lock().unwrap() = Some(Instant::now());
+    let mut state = shared.state.lock().unwrap();
+    match result {
+        // A cancel that arrives after the last checkpoint is too late: the
+        // output is complete, so it is kept.
+        Ok(progress) => {
+            *shared.progress.lock().unwrap() = progress;
+            *state = JobState::Done;
+        }
+        Err(e) => {
+            if *state != JobState::Cancelled {
+                *state = JobState::Failed(e.to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,10 @@
+pub mod app;
+pub mod explorer;
+pub mod job;
+pub mod queue;
+pub mod stats;
+pub use app::{run, BlockPiperApp};
+pub use explorer::GrammarExplorer;
+pub use job::{Job, JobKind, JobState};
+pub use queue::{Added, JobQueue, QueueEntry};
+pub use stats::ChartMetric;
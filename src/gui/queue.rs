@@ -0,0 +1,279 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::compressor::{CompressionOptions, DecompressOptions};
+use crate::volume::volume_set_path;
+use super::job::{Job, JobKind};
+
+/// One file in the batch queue.
+pub struct QueueEntry {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub kind: JobKind,
+    /// `None` until the queue starts the job.
+    job: Option<Job>,
+}
+
+impl QueueEntry {
+    /// Compressed files (`.bpc`) and volumes of one (`.bpc.001`) are
+    /// decompressed next to themselves; anything else is compressed to
+    /// `<input>.bpc`.
+    fn new(input: PathBuf) -> Self {
+        let (kind, output) = match compressed_file(&input) {
+            Some(compressed) => (JobKind::Decompress, compressed.with_extension("")),
+            None => {
+                let mut output = input.clone().into_os_string();
+                output.push(".bpc");
+                (JobKind::Compress, PathBuf::from(output))
+            }
+        };
+        QueueEntry { input, output, kind, job: None }
+    }
+
+    /// The file this entry reads: the volume set for a volume, otherwise
+    /// the input itself.
+    fn source(&self) -> PathBuf {
+        compressed_file(&self.input).unwrap_or_else(|| self.input.clone())
+    }
+
+    fn is_unfinished(&self) -> bool {
+        !self.job.as_ref().is_some_and(Job::is_finished)
+    }
+
+    pub fn job(&self) -> Option<&Job> {
+        self.job.as_ref()
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.job.is_none()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.job.as_ref().is_some_and(|job| !job.is_finished())
+    }
+
+    /// Original size over compressed size of the data processed so far.
+    pub fn ratio(&self) -> Option<f64> {
+        let progress = self.job.as_ref()?.progress();
+        let (original, compressed) = match self.kind {
+            JobKind::Compress => (progress.bytes_in, progress.bytes_out),
+            JobKind::Decompress => (progress.bytes_out, progress.bytes_in),
+        };
+        (compressed > 0 && original > 0).then(|| original as f64 / compressed as f64)
+    }
+}
+
+/// Files dropped onto the window, run one after another or all at once.
+/// Concurrent jobs share Rayon's global pool, so running several at once
+/// does not oversubscribe the CPU.
+#[derive(Default)]
+pub struct JobQueue {
+    entries: Vec<QueueEntry>,
+    pub concurrent: bool,
+}
+
+impl JobQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.entries
+    }
+
+    /// Queues `path`, or every file below it if it is a directory.
+    ///
+    /// Files that are already waiting or running, further volumes of a set
+    /// that is already queued, and files another waiting or running entry
+    /// writes are skipped. An output that already exists, or that another
+    /// entry reads or writes, is renamed to `name (1).ext`, `name (2).ext`
+    /// and so on, so no job overwrites a file.
+    pub fn add_path(&mut self, path: &Path) -> io::Result<Added> {
+        let mut files = Vec::new();
+        collect_files(path, &mut files)?;
+        let mut added = Added::default();
+        for file in files {
+            let mut entry = QueueEntry::new(file);
+            let source = entry.source();
+            let unfinished = || self.entries.iter().filter(|other| other.is_unfinished());
+            if unfinished().any(|other| other.source() == source || other.output == entry.input || other.output == source) {
+                added.skipped += 1;
+                continue;
+            }
+            let taken = |candidate: &Path| {
+                candidate.exists()
+                    || candidate == source
+                    || unfinished().any(|other| other.output == candidate || other.input == candidate || other.source() == candidate)
+            };
+            if taken(&entry.output) {
+                entry.output = (1..).map(|n| numbered_path(&entry.output, n)).find(|candidate| !taken(candidate)).expect("some name is free");
+                added.renamed += 1;
+            }
+            self.entries.push(entry);
+            added.queued += 1;
+        }
+        Ok(added)
+    }
+
+    /// Starts waiting jobs: all of them when running concurrently,
+    /// otherwise the next one once nothing else is running. New compression
+    /// jobs use `options`.
+    pub fn poll(&mut self, options: &CompressionOptions) {
+        let running = self.entries.iter().any(QueueEntry::is_running);
+        let slots = match (self.concurrent, running) {
+            (true, _) => usize::MAX,
+            (false, true) => 0,
+            (false, false) => 1,
+        };
+        for entry in self.entries.iter_mut().filter(|entry| entry.is_waiting()).take(slots) {
+            let (input, output) = (entry.input.clone(), entry.output.clone());
+            entry.job = Some(match entry.kind {
+                JobKind::Compress => Job::compress(input, output, options.clone().with_threads(0)),
+                JobKind::Decompress => {
+                    let decompress_options = DecompressOptions::new().with_dictionary(options.dictionary.clone());
+                    Job::decompress(input, output, decompress_options)
+                }
+            });
+        }
+    }
+
+    /// Whether any job is waiting or running.
+    pub fn is_busy(&self) -> bool {
+        self.entries.iter().any(|entry| entry.is_waiting() || entry.is_running())
+    }
+
+    /// Cancels running jobs and drops waiting ones.
+    pub fn cancel_all(&mut self) {
+        self.entries.retain(|entry| !entry.is_waiting());
+        for job in self.entries.iter().filter_map(QueueEntry::job) {
+            job.cancel();
+        }
+    }
+
+    /// Removes the entry at `index`, cancelling its job if it is running.
+    pub fn remove(&mut self, index: usize) {
+        if index < self.entries.len() {
+            // Dropping the job cancels it and waits for its cleanup.
+            self.entries.remove(index);
+        }
+    }
+
+    pub fn clear_finished(&mut self) {
+        self.entries.retain(|entry| entry.is_waiting() || entry.is_running());
+    }
+}
+
+/// What [`JobQueue::add_path`] did with the files it found.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Added {
+    pub queued: usize,
+    /// Entries whose output was renamed to avoid a clash.
+    pub renamed: usize,
+    /// Files left out because they are queued already or another entry
+    /// writes them.
+    pub skipped: usize,
+}
+
+/// The compressed file `path` is, or is a volume of, if any.
+fn compressed_file(path: &Path) -> Option<PathBuf> {
+    let file = volume_set_path(path).unwrap_or_else(|| path.to_path_buf());
+    file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("bpc")).then_some(file)
+}
+
+/// `path` with ` (n)` added to its file stem: `report (2).txt`.
+fn numbered_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(" ({})", n));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Appends `path` if it is a file, or the files below it in name order if
+/// it is a directory. Symlinked directories are not followed.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if !fs::symlink_metadata(path)?.is_dir() {
+        if path.is_file() {
+            files.push(path.to_path_buf());
+        }
+        return Ok(());
+    }
+    let mut children = fs::read_dir(path)?.map(|entry| entry.map(|e| e.path())).collect::<io::Result<Vec<_>>>()?;
+    children.sort();
+    for child in children {
+        collect_files(&child, files)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockpiper-queue-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn outputs(queue: &JobQueue) -> Vec<(PathBuf, PathBuf)> {
+        queue.entries().iter().map(|entry| (entry.input.clone(), entry.output.clone())).collect()
+    }
+
+    #[test]
+    fn clashing_outputs_are_renamed() {
+        let dir = scratch_dir("clash");
+        fs::write(dir.join("a"), b"plain").unwrap();
+        fs::write(dir.join("a.bpc"), b"compressed").unwrap();
+        fs::write(dir.join("report.txt"), b"old").unwrap();
+        fs::write(dir.join("report.txt.bpc"), b"compressed").unwrap();
+
+        let mut queue = JobQueue::new();
+        let added = queue.add_path(&dir).unwrap();
+        assert_eq!(added, Added { queued: 4, renamed: 4, skipped: 0 });
+        assert_eq!(
+            outputs(&queue),
+            vec![
+                (dir.join("a"), dir.join("a (1).bpc")),
+                (dir.join("a.bpc"), dir.join("a (1)")),
+                (dir.join("report.txt"), dir.join("report.txt (1).bpc")),
+                (dir.join("report.txt.bpc"), dir.join("report (1).txt")),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn outputs_of_queued_entries_are_not_queued_again() {
+        let dir = scratch_dir("outputs");
+        fs::write(dir.join("a"), b"plain").unwrap();
+        let mut queue = JobQueue::new();
+        assert_eq!(queue.add_path(&dir).unwrap().queued, 1);
+
+        // The compression job has started writing its output.
+        fs::write(dir.join("a.bpc"), b"partial").unwrap();
+        let added = queue.add_path(&dir).unwrap();
+        assert_eq!(added, Added { queued: 0, renamed: 0, skipped: 2 });
+        assert_eq!(outputs(&queue), vec![(dir.join("a"), dir.join("a.bpc"))]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn volumes_queue_one_decompression_per_set() {
+        let dir = scratch_dir("volumes");
+        for number in 1..=3 {
+            fs::write(dir.join(format!("x.bpc.{:03}", number)), b"volume").unwrap();
+        }
+        let mut queue = JobQueue::new();
+        let added = queue.add_path(&dir).unwrap();
+        assert_eq!(added, Added { queued: 1, renamed: 0, skipped: 2 });
+        let entry = &queue.entries()[0];
+        assert_eq!(entry.kind, JobKind::Decompress);
+        assert_eq!(entry.output, dir.join("x"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use eframe::egui;
+
+use crate::compressor::BlockStats;
+use super::job::{Job, JobKind, JobState};
+
+/// What the per-block chart plots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChartMetric {
+    #[default]
+    Ratio,
+    Rules,
+    GrammarLen,
+    /// Grammar inference and coding time, stacked.
+    Time,
+}
+
+const CHART_HEIGHT: f32 = 120.0;
+const GRAMMAR_COLOR: egui::Color32 = egui::Color32::from_rgb(90, 160, 230);
+const CODING_COLOR: egui::Color32 = egui::Color32::from_rgb(230, 150, 70);
+
+/// Totals for a finished job plus, for compression, a per-block chart.
+pub fn stats_panel(ui: &mut egui::Ui, job: &Job, metric: &mut ChartMetric) {
+    if job.state() != JobState::Done {
+        return;
+    }
+    let progress = job.progress();
+    let (original, compressed) = match job.kind() {
+        JobKind::Compress => (progress.bytes_in, progress.bytes_out),
+        JobKind::Decompress => (progress.bytes_out, progress.bytes_in),
+    };
+    let seconds = job.elapsed().as_secs_f64();
+
+    egui::Grid::new("totals").num_columns(2).striped(true).show(ui, |ui| {
+        ui.label("Input size");
+        ui.label(format_bytes(progress.bytes_in));
+        ui.end_row();
+        ui.label("Output size");
+        ui.label(format_bytes(progress.bytes_out));
+        ui.end_row();
+        ui.label("Ratio");
+        ui.label(if compressed > 0 { format!("{:.3}", original as f64 / compressed as f64) } else { "-".to_string() });
+        ui.end_row();
+        ui.label("Bits per byte");
+        ui.label(if original > 0 { format!("{:.3}", compressed as f64 * 8.0 / original as f64) } else { "-".to_string() });
+        ui.end_row();
+        ui.label("Throughput");
+        ui.label(if seconds > 0.0 { format!("{}/s", format_bytes((original as f64 / seconds) as u64)) } else { "-".to_string() });
+        ui.end_row();
+        ui.label("Elapsed");
+        ui.label(format!("{:.2} s", seconds));
+        ui.end_row();
+        if progress.duplicate_blocks > 0 {
+            ui.label("Deduplicated");
+            ui.label(format!("{} blocks, {}", progress.duplicate_blocks, format_bytes(progress.duplicate_bytes)));
+            ui.end_row();
+        }
+    });
+
+    let blocks = job.block_stats();
+    if blocks.is_empty() {
+        return;
+    }
+    ui.horizontal(|ui| {
+        ui.label("Per block:");
+        ui.selectable_value(metric, ChartMetric::Ratio, "Ratio");
+        ui.selectable_value(metric, ChartMetric::Rules, "Rules");
+        ui.selectable_value(metric, ChartMetric::GrammarLen, "Grammar length");
+        ui.selectable_value(metric, ChartMetric::Time, "Grammar vs coding time");
+    });
+    block_chart(ui, &blocks, *metric);
+    if *metric == ChartMetric::Time {
+        let grammar: f64 = blocks.iter().map(|b| b.grammar_time.as_secs_f64()).sum();
+        let coding: f64 = blocks.iter().map(|b| b.coding_time.as_secs_f64()).sum();
+        ui.horizontal(|ui| {
+            ui.colored_label(GRAMMAR_COLOR, format!("■ infer_grammar {:.2} s", grammar));
+            ui.colored_label(CODING_COLOR, format!("■ coding {:.2} s", coding));
+        });
+    }
+}
+
+/// Bar chart with one bar per block; hovering a bar shows all its numbers.
+fn block_chart(ui: &mut egui::Ui, blocks: &[BlockStats], metric: ChartMetric) {
+    let (rect, response) = ui.allocate_exact_size(egui::vec2(ui.available_width(), CHART_HEIGHT), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, ui.visuals().extreme_bg_color);
+
+    let segments = |block: &BlockStats| -> [f64; 2] {
+        match metric {
+            ChartMetric::Ratio => [block.ratio(), 0.0],
+            ChartMetric::Rules => [block.rules as f64, 0.0],
+            ChartMetric::GrammarLen => [block.grammar_len as f64, 0.0],
+            ChartMetric::Time => [block.grammar_time.as_secs_f64(), block.coding_time.as_secs_f64()],
+        }
+    };
+    let max = blocks.iter().map(|block| segments(block).iter().sum::<f64>()).fold(0.0, f64::max);
+    if max <= 0.0 {
+        return;
+    }
+    let bar_width = rect.width() / blocks.len() as f32;
+    let gap = if bar_width > 4.0 { 1.0 } else { 0.0 };
+    for (index, block) in blocks.iter().enumerate() {
+        let left = rect.left() + index as f32 * bar_width;
+        let mut bottom = rect.bottom();
+        for (value, color) in segments(block).into_iter().zip([GRAMMAR_COLOR, CODING_COLOR]) {
+            let height = (value / max) as f32 * rect.height();
+            let bar = egui::Rect::from_min_max(egui::pos2(left, bottom - height), egui::pos2(left + bar_width - gap, bottom));
+            painter.rect_filled(bar, 0.0, color);
+            bottom -= height;
+        }
+    }
+
+    if let Some(pos) = response.hover_pos() {
+        let index = (((pos.x - rect.left()) / bar_width) as usize).min(blocks.len() - 1);
+        let block = blocks[index];
+        response.on_hover_ui_at_pointer(|ui| {
+            ui.label(format!("Block {}", index));
+            ui.label(format!("{} -> {} (ratio {:.3})", format_bytes(block.orig_len as u64), format_bytes(block.compressed_len as u64), block.ratio()));
+            ui.label(format!("{} rules, grammar {}", block.rules, format_bytes(block.grammar_len as u64)));
+            ui.label(format!(
+                "infer_grammar {:.1} ms, coding {:.1} ms",
+                block.grammar_time.as_secs_f64() * 1000.0,
+                block.coding_time.as_secs_f64() * 1000.0
+            ));
+        });
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
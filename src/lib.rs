@@ -0,0 +1,49 @@
+//! BlockPiper: lossless block-based compression built from Sequitur grammar
+//! inference, context modelling (CTW) and arithmetic coding.
+//!
+//! The top-level functions cover the common cases; the stage modules are
+//! public for callers that want to drive the pipeline themselves.
+//!
+//! ```no_run
+//! use blockpiper::{compress_file, decompress_file, CompressionOptions};
+//!
+//! compress_file("data.bin", "data.bin.bpc", &CompressionOptions::level(9))?;
+//! decompress_file("data.bin.bpc", "data.bin")?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+// Stage modules follow the `foo/foo.rs` layout.
+#![allow(clippy::module_inception)]
+
+pub mod archive;
+pub mod arithmetic;
+pub mod cli;
+pub mod compressor;
+pub mod container;
+pub mod ctw;
+pub mod delta;
+pub mod dictionary;
+pub mod encryption;
+pub mod grammar;
+#[cfg(feature = "gui")]
+pub mod gui;
+pub mod recovery;
+pub mod volume;
+
+pub use compressor::{
+    append_files, append_stream, compress_block, compress_block_with_stats, compress_file,
+    compress_file_with_progress, compress_file_with_stats, compress_stream, decompress_block, decompress_file,
+    decompress_file_with_progress, decompress_stream, BlockStats, ChunkSizes, CompressWriter,
+    CompressionOptions, DecompressOptions, DecompressReader, Progress, RandomAccessReader,
+    salvage_file, salvage_stream, SalvageReport,
+    DEFAULT_BLOCK_SIZE, DEFAULT_DEDUP_WINDOW, DEFAULT_LEVEL, MAX_LEVEL, MIN_LEVEL,
+};
+pub use archive::{append_to_archive, create_archive, ArchiveEntry, ArchiveReader, EntryKind};
+pub use container::{ChecksumKind, Header, KdfParams, LimitExceeded, Stages};
+pub use ctw::Ctw;
+pub use delta::{apply_patch, create_patch, DeltaStats};
+pub use dictionary::Dictionary;
+pub use encryption::Credentials;
+pub use grammar::{ExportFormat, Grammar, Symbol};
+pub use recovery::{add_recovery, check_recovery, repair_file, RecoveryReport};
+pub use volume::{split_file, VolumeReader, MIN_VOLUME_SIZE};
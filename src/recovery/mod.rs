@@ -0,0 +1,2 @@
+pub mod recovery;
+pub use recovery::{add_recovery, check_recovery, repair_file, RecoveryReport};
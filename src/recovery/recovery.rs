@@ -0,0 +1,600 @@
+//! Recovery records: Reed–Solomon parity stored after a finished `.bpc`
+//! file, from which damaged or missing parts of it can be rebuilt.
+//!
+//! The protected bytes (the file as it was written: header, block records
+//! and, in an archive, the index and its trailer) are cut into shards of
+//! equal size. Shards are dealt round-robin into groups, so that a run of
+//! damaged bytes is spread thinly over many groups, and every group gets
+//! its own parity shards. A CRC of every shard tells which ones are
+//! damaged; a group can be rebuilt as long as no more of its shards are
+//! damaged than it has parity shards.
+//!
+//! Layout of the section after the protected bytes:
+//!
+//! - [`END_OF_BLOCKS`], so that readers going through the block records
+//!   stop before the section
+//! - `SECTION_MAGIC`, version `u8`, percent `u8`, shard size `u64`, groups
+//!   `u32`, data shards per group `u16`, parity shards per group `u16`,
+//!   protected length `u64`
+//! - CRC32 of every data shard, of every parity shard, then of the section
+//!   so far
+//! - the parity shards, group by group
+//! - trailer: protected length `u64`, `TRAILER_MAGIC`
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::container::END_OF_BLOCKS;
+use crate::volume::volume::volume_set_for;
+use crate::volume::VolumeReader;
+
+const SECTION_MAGIC: [u8; 4] = *b"BPRS";
+const SECTION_VERSION: u8 = 2;
+/// Fixed part of the section, from `END_OF_BLOCKS` to the protected length.
+const SECTION_HEADER_LEN: usize = 38;
+/// Marks the trailer at the very end of a file with recovery records.
+const TRAILER_MAGIC: [u8; 4] = *b"BPRC";
+/// Trailer layout: protected length `u64`, `TRAILER_MAGIC`.
+const TRAILER_LEN: u64 = 12;
+
+/// Most data shards in one group. Together with as many parity shards this
+/// is the most a Reed–Solomon code over GF(2^8) allows.
+const MAX_DATA_SHARDS: usize = 128;
+/// Shard sizes are a multiple of this, so shards line up with disk sectors.
+const SHARD_ALIGN: u64 = 512;
+/// Roughly how many shards a file is cut into, whatever its size.
+const TARGET_SHARDS: u64 = 8192;
+/// Data held in memory at once while computing parity.
+const BATCH_BYTES: usize = 64 * 1024 * 1024;
+
+/// How a file's protected bytes are cut into shards and groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Layout {
+    percent: u8,
+    shard_size: usize,
+    groups: u64,
+    /// Data shards per group; the last shards of some groups lie past the
+    /// end of the file and count as zeros.
+    data_shards: usize,
+    parity_shards: usize,
+    protected_len: u64,
+}
+
+impl Layout {
+    fn new(protected_len: u64, percent: u8) -> Self {
+        let shard_size = protected_len.div_ceil(TARGET_SHARDS).div_ceil(SHARD_ALIGN).max(1) * SHARD_ALIGN;
+        let shards = protected_len.div_ceil(shard_size).max(1);
+        let groups = shards.div_ceil(MAX_DATA_SHARDS as u64);
+        let data_shards = shards.div_ceil(groups) as usize;
+        let parity_shards = (data_shards * percent as usize).div_ceil(100).max(1);
+        Layout { percent, shard_size: shard_size as usize, groups, data_shards, parity_shards, protected_len }
+    }
+
+    /// Data shards that hold bytes of the file.
+    fn shards(&self) -> u64 {
+        self.protected_len.div_ceil(self.shard_size as u64)
+    }
+
+    /// Number of data shard `index` of group `group`.
+    fn shard(&self, group: u64, index: usize) -> u64 {
+        index as u64 * self.groups + group
+    }
+
+    /// File range of data shard `shard`, cut short at the protected length.
+    fn shard_range(&self, shard: u64) -> Range<u64> {
+        let start = (shard * self.shard_size as u64).min(self.protected_len);
+        start..(start + self.shard_size as u64).min(self.protected_len)
+    }
+
+    fn parity_count(&self) -> u64 {
+        self.groups * self.parity_shards as u64
+    }
+
+    /// Bytes of the CRC tables, the section CRC included.
+    fn crc_len(&self) -> u64 {
+        4 * (self.shards() + self.parity_count()) + 4
+    }
+
+    /// File offset of parity shard `index` of group `group`.
+    fn parity_offset(&self, group: u64, index: usize) -> u64 {
+        let first = self.protected_len + SECTION_HEADER_LEN as u64 + self.crc_len();
+        first + (group * self.parity_shards as u64 + index as u64) * self.shard_size as u64
+    }
+
+    fn section_len(&self) -> u64 {
+        self.parity_offset(self.groups, 0) - self.protected_len + TRAILER_LEN
+    }
+
+    fn codec(&self) -> io::Result<ReedSolomon> {
+        ReedSolomon::new(self.data_shards, self.parity_shards).map_err(|e| io::Error::other(format!("recovery records: {:?}", e)))
+    }
+
+    fn encode(&self) -> [u8; SECTION_HEADER_LEN] {
+        let mut out = END_OF_BLOCKS.to_vec();
+        out.extend(&SECTION_MAGIC);
+        out.push(SECTION_VERSION);
+        out.push(self.percent);
+        out.extend(&(self.shard_size as u64).to_le_bytes());
+        out.extend(&(self.groups as u32).to_le_bytes());
+        out.extend(&(self.data_shards as u16).to_le_bytes());
+        out.extend(&(self.parity_shards as u16).to_le_bytes());
+        out.extend(&self.protected_len.to_le_bytes());
+        out.try_into().unwrap()
+    }
+
+    /// The layout `buf` describes, if it is one this version writes.
+    fn decode(buf: &[u8; SECTION_HEADER_LEN]) -> Option<Self> {
+        let percent = buf[13];
+        let protected_len = u64::from_le_bytes(buf[30..].try_into().unwrap());
+        if !(1..=100).contains(&percent) {
+            return None;
+        }
+        let layout = Layout::new(protected_len, percent);
+        (layout.encode() == *buf).then_some(layout)
+    }
+}
+
+/// A section's layout and CRC tables.
+struct Section {
+    layout: Layout,
+    data_crcs: Vec<u32>,
+    parity_crcs: Vec<u32>,
+    /// Whether the trailer pointed at the section and ended the file.
+    trailer_intact: bool,
+}
+
+/// What [`check_recovery`] or [`repair_file`] found in a file with recovery
+/// records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// Parity size, in percent of the protected bytes.
+    pub percent: u8,
+    /// Bytes the recovery records protect: the file as it was written.
+    pub protected_len: u64,
+    /// Bytes of recovery records after them.
+    pub recovery_len: u64,
+    /// Byte ranges of the protected part that are damaged, in file order.
+    pub damaged: Vec<Range<u64>>,
+    /// Parity shards that are damaged.
+    pub damaged_parity: u64,
+    /// Whether the trailer at the end of the file is damaged or missing.
+    pub damaged_trailer: bool,
+    /// Whether everything damaged can be rebuilt.
+    pub recoverable: bool,
+}
+
+impl RecoveryReport {
+    pub fn is_intact(&self) -> bool {
+        self.damaged.is_empty() && self.damaged_parity == 0 && !self.damaged_trailer
+    }
+
+    /// Damaged bytes of the protected part.
+    pub fn damaged_bytes(&self) -> u64 {
+        self.damaged.iter().map(|range| range.end - range.start).sum()
+    }
+}
+
+/// Damaged shards found by [`check`], by number, in ascending order.
+struct Damage {
+    data: Vec<u64>,
+    parity: Vec<u64>,
+}
+
+pub(crate) fn validate_percent(percent: u8) -> io::Result<()> {
+    if !(1..=100).contains(&percent) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "recovery records must be 1 to 100 percent"));
+    }
+    Ok(())
+}
+
+/// Adds recovery records of about `percent` percent of its size to the
+/// `.bpc` file at `path`, replacing any it already has. Returns the bytes
+/// they take. The records are computed from the file as it is, so check a
+/// file that may be damaged before protecting it again.
+pub fn add_recovery<P: AsRef<Path>>(path: P, percent: u8) -> io::Result<u64> {
+    validate_percent(percent)?;
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let protected_len = protected_len(&mut file)?;
+    file.set_len(protected_len)?;
+    write_section(&mut file, &Layout::new(protected_len, percent))
+}
+
+/// Checks the file at `path` against its recovery records. `None` if it has
+/// none.
+pub fn check_recovery<P: AsRef<Path>>(path: P) -> io::Result<Option<RecoveryReport>> {
+    let mut reader = BufReader::new(VolumeReader::open(path)?);
+    let Some(section) = find_section(&mut reader)? else {
+        return Ok(None);
+    };
+    check(&mut reader, &section).map(|(report, _)| Some(report))
+}
+
+/// Rebuilds the damaged parts of the file at `path` from its recovery
+/// records and writes the repaired file, with fresh recovery records, to
+/// `output`, which may be `path` itself. The output is written next to its
+/// final name and renamed into place, so a failed repair leaves no partial
+/// file. Returns what was found before repairing; fails if the file has no
+/// recovery records or is damaged beyond what they can rebuild. An intact
+/// file repaired in place is left alone. A volume set is read whole and
+/// repaired into a single file, which cannot be `path` itself.
+pub fn repair_file<P: AsRef<Path>, Q: AsRef<Path>>(path: P, output: Q) -> io::Result<RecoveryReport> {
+    let (path, output) = (path.as_ref(), output.as_ref());
+    if output == path && volume_set_for(path)?.is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "a volume set cannot be repaired in place; give an output file"));
+    }
+    let original = VolumeReader::open(path)?;
+    let permissions = original.permissions()?;
+    let mut reader = BufReader::new(original);
+    let section = find_section(&mut reader)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "file has no recovery records"))?;
+    let (report, damage) = check(&mut reader, &section)?;
+    if report.is_intact() && output == path {
+        return Ok(report);
+    }
+    if !report.recoverable {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("damaged beyond repair: {} bytes in {} ranges", report.damaged_bytes(), report.damaged.len()),
+        ));
+    }
+
+    let mut temp_name = OsString::from(output.as_os_str());
+    temp_name.push(".repair");
+    let temp_path = PathBuf::from(temp_name);
+    let result = (|| {
+        let layout = &section.layout;
+        let mut file = create_output(&temp_path)?;
+        reader.seek(SeekFrom::Start(0))?;
+        io::copy(&mut (&mut reader).take(layout.protected_len), &mut file)?;
+        file.set_len(layout.protected_len)?;
+        rebuild(&mut reader, &mut file, &section, &damage)?;
+        write_section(&mut file, layout)?;
+        file.sync_all()?;
+        fs::set_permissions(&temp_path, permissions)?;
+        fs::rename(&temp_path, output)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result.map(|()| report)
+}
+
+/// Length of the file behind `reader` without its recovery records, if it
+/// has any. Only the trailer is looked at.
+pub(crate) fn protected_len<R: Read + Seek>(reader: &mut R) -> io::Result<u64> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    Ok(trailer_offset(reader, end)?.unwrap_or(end))
+}
+
+/// The size in percent of the recovery records of the file behind `reader`,
+/// if it has intact ones.
+pub(crate) fn recovery_percent<R: Read + Seek>(reader: &mut R) -> io::Result<Option<u8>> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    match trailer_offset(reader, end)? {
+        Some(offset) => Ok(read_section_at(reader, offset)?.map(|layout| layout.percent)),
+        None => Ok(None),
+    }
+}
+
+/// Opens `path` to write a `.bpc` file to. Recovery records are added by
+/// reading the file back, so it is opened for reading too.
+pub(crate) fn create_output<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)
+}
+
+/// Flushes a file opened with [`create_output`] and, with `percent` set,
+/// adds recovery records to it. Returns the bytes they take.
+pub(crate) fn finish_output(writer: BufWriter<File>, percent: Option<u8>) -> io::Result<(File, u64)> {
+    let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+    let Some(percent) = percent else {
+        return Ok((file, 0));
+    };
+    let protected_len = file.metadata()?.len();
+    let added = write_section(&mut file, &Layout::new(protected_len, percent))?;
+    Ok((file, added))
+}
+
+/// Where the trailer at `end` says the section starts, if there is one.
+fn trailer_offset<R: Read + Seek>(reader: &mut R, end: u64) -> io::Result<Option<u64>> {
+    if end < TRAILER_LEN {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(end - TRAILER_LEN))?;
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    reader.read_exact(&mut trailer)?;
+    let offset = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    Ok((trailer[8..] == TRAILER_MAGIC && offset < end - TRAILER_LEN).then_some(offset))
+}
+
+/// Reads into `buf` until it is full or the input ends; returns the bytes
+/// read.
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// The layout of a section starting at `offset`, if its header is valid.
+fn read_section_at<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<Option<Layout>> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut buf = [0u8; SECTION_HEADER_LEN];
+    if read_up_to(reader, &mut buf)? < SECTION_HEADER_LEN {
+        return Ok(None);
+    }
+    Ok(Layout::decode(&buf).filter(|layout| layout.protected_len == offset))
+}
+
+/// The section starting at `offset`, if its header and CRC tables are
+/// intact.
+fn read_section<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<Option<Section>> {
+    let Some(layout) = read_section_at(reader, offset)? else {
+        return Ok(None);
+    };
+    let mut tables = vec![0u8; layout.crc_len() as usize];
+    if read_up_to(reader, &mut tables)? < tables.len() {
+        return Ok(None);
+    }
+    let (tables, section_crc) = tables.split_at(tables.len() - 4);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&layout.encode());
+    hasher.update(tables);
+    if hasher.finalize().to_le_bytes() != section_crc {
+        return Ok(None);
+    }
+    let mut crcs = tables.chunks_exact(4).map(|crc| u32::from_le_bytes(crc.try_into().unwrap()));
+    let data_crcs = crcs.by_ref().take(layout.shards() as usize).collect();
+    let parity_crcs = crcs.collect();
+    Ok(Some(Section { layout, data_crcs, parity_crcs, trailer_intact: false }))
+}
+
+/// Finds the recovery section of the file behind `reader`: where its
+/// trailer says, or else by looking through the whole file for one whose
+/// header and CRC tables are intact.
+fn find_section<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Section>> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    if let Some(offset) = trailer_offset(reader, end)? {
+        if let Some(mut section) = read_section(reader, offset)? {
+            section.trailer_intact = section.layout.protected_len + section.layout.section_len() == end;
+            return Ok(Some(section));
+        }
+    }
+
+    let mut marker = END_OF_BLOCKS.to_vec();
+    marker.extend(&SECTION_MAGIC);
+    let chunk_len = 1024 * 1024;
+    let mut buf = vec![0u8; chunk_len + marker.len() - 1];
+    let mut chunk_start = 0;
+    while chunk_start < end {
+        reader.seek(SeekFrom::Start(chunk_start))?;
+        let filled = read_up_to(reader, &mut buf)?;
+        let candidates: Vec<u64> = buf[..filled]
+            .windows(marker.len())
+            .enumerate()
+            .filter(|(_, window)| *window == marker)
+            .map(|(pos, _)| chunk_start + pos as u64)
+            .collect();
+        for offset in candidates {
+            if let Some(section) = read_section(reader, offset)? {
+                return Ok(Some(section));
+            }
+        }
+        chunk_start += chunk_len as u64;
+    }
+    Ok(None)
+}
+
+/// Reads data shard `shard` of the protected bytes, padded with zeros. A
+/// file that ends early reads as zeros too, which the shard's CRC catches.
+fn read_data_shard<R: Read + Seek>(reader: &mut R, layout: &Layout, shard: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; layout.shard_size];
+    let range = layout.shard_range(shard);
+    if !range.is_empty() {
+        reader.seek(SeekFrom::Start(range.start))?;
+        read_up_to(reader, &mut buf[..(range.end - range.start) as usize])?;
+    }
+    Ok(buf)
+}
+
+fn read_parity_shard<R: Read + Seek>(reader: &mut R, layout: &Layout, group: u64, index: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; layout.shard_size];
+    reader.seek(SeekFrom::Start(layout.parity_offset(group, index)))?;
+    read_up_to(reader, &mut buf)?;
+    Ok(buf)
+}
+
+/// Data shards of group `group`, padded with zero shards past the end.
+fn read_group<R: Read + Seek>(reader: &mut R, layout: &Layout, group: u64) -> io::Result<Vec<Vec<u8>>> {
+    (0..layout.data_shards).map(|index| read_data_shard(reader, layout, layout.shard(group, index))).collect()
+}
+
+/// Writes the recovery section for the first `layout.protected_len` bytes
+/// of `file`, which must hold nothing after them. Returns its length.
+fn write_section(file: &mut File, layout: &Layout) -> io::Result<u64> {
+    let codec = layout.codec()?;
+    let mut data_crcs = vec![0u32; layout.shards() as usize];
+    let mut parity_crcs = Vec::with_capacity(layout.parity_count() as usize);
+    let batch = (BATCH_BYTES / (layout.data_shards * layout.shard_size)).max(1) as u64;
+    let mut group = 0;
+    while group < layout.groups {
+        let groups = group..(group + batch).min(layout.groups);
+        let mut data = Vec::new();
+        for group in groups.clone() {
+            let shards = read_group(file, layout, group)?;
+            for (index, shard) in shards.iter().enumerate() {
+                if let Some(crc) = data_crcs.get_mut(layout.shard(group, index) as usize) {
+                    *crc = crc32fast::hash(shard);
+                }
+            }
+            data.push(shards);
+        }
+        let parity = data
+            .par_iter()
+            .map(|shards| {
+                let mut parity = vec![vec![0u8; layout.shard_size]; layout.parity_shards];
+                codec.encode_sep(shards, &mut parity).map(|()| parity)
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::other(format!("recovery records: {:?}", e)))?;
+        file.seek(SeekFrom::Start(layout.parity_offset(group, 0)))?;
+        for shard in parity.iter().flatten() {
+            parity_crcs.push(crc32fast::hash(shard));
+            file.write_all(shard)?;
+        }
+        group = groups.end;
+    }
+
+    let mut section = layout.encode().to_vec();
+    for crc in data_crcs.iter().chain(&parity_crcs) {
+        section.extend(&crc.to_le_bytes());
+    }
+    section.extend(&crc32fast::hash(&section).to_le_bytes());
+    file.seek(SeekFrom::Start(layout.protected_len))?;
+    file.write_all(&section)?;
+    file.seek(SeekFrom::Start(layout.parity_offset(layout.groups, 0)))?;
+    file.write_all(&layout.protected_len.to_le_bytes())?;
+    file.write_all(&TRAILER_MAGIC)?;
+    Ok(layout.section_len())
+}
+
+/// Compares every shard with its CRC.
+fn check<R: Read + Seek>(reader: &mut R, section: &Section) -> io::Result<(RecoveryReport, Damage)> {
+    let layout = &section.layout;
+    let mut damage = Damage { data: Vec::new(), parity: Vec::new() };
+    for (shard, &crc) in section.data_crcs.iter().enumerate() {
+        if crc32fast::hash(&read_data_shard(reader, layout, shard as u64)?) != crc {
+            damage.data.push(shard as u64);
+        }
+    }
+    for (shard, &crc) in section.parity_crcs.iter().enumerate() {
+        let (group, index) = (shard as u64 / layout.parity_shards as u64, shard % layout.parity_shards);
+        if crc32fast::hash(&read_parity_shard(reader, layout, group, index)?) != crc {
+            damage.parity.push(shard as u64);
+        }
+    }
+
+    let mut lost = vec![0usize; layout.groups as usize];
+    for &shard in &damage.data {
+        lost[(shard % layout.groups) as usize] += 1;
+    }
+    for &shard in &damage.parity {
+        lost[(shard / layout.parity_shards as u64) as usize] += 1;
+    }
+    let mut damaged: Vec<Range<u64>> = Vec::new();
+    for &shard in &damage.data {
+        let range = layout.shard_range(shard);
+        match damaged.last_mut() {
+            Some(last) if last.end == range.start => last.end = range.end,
+            _ => damaged.push(range),
+        }
+    }
+    let report = RecoveryReport {
+        percent: layout.percent,
+        protected_len: layout.protected_len,
+        recovery_len: layout.section_len(),
+        damaged,
+        damaged_parity: damage.parity.len() as u64,
+        damaged_trailer: !section.trailer_intact,
+        recoverable: lost.iter().all(|&lost| lost <= layout.parity_shards),
+    };
+    Ok((report, damage))
+}
+
+/// Rebuilds the damaged data shards found by [`check`] from the intact
+/// shards of their groups, read from `reader`, and writes them to `file`.
+fn rebuild<R: Read + Seek>(reader: &mut R, file: &mut File, section: &Section, damage: &Damage) -> io::Result<()> {
+    let layout = &section.layout;
+    let codec = layout.codec()?;
+    let mut groups: Vec<u64> = damage.data.iter().map(|&shard| shard % layout.groups).collect();
+    groups.sort_unstable();
+    groups.dedup();
+    for group in groups {
+        let mut shards = Vec::with_capacity(layout.data_shards + layout.parity_shards);
+        for index in 0..layout.data_shards {
+            let shard = layout.shard(group, index);
+            let intact = damage.data.binary_search(&shard).is_err();
+            shards.push(if intact { Some(read_data_shard(reader, layout, shard)?) } else { None });
+        }
+        for index in 0..layout.parity_shards {
+            let shard = group * layout.parity_shards as u64 + index as u64;
+            let intact = damage.parity.binary_search(&shard).is_err();
+            shards.push(if intact { Some(read_parity_shard(reader, layout, group, index)?) } else { None });
+        }
+        codec
+            .reconstruct_data(&mut shards)
+            .map_err(|e| io::Error::other(format!("recovery records: {:?}", e)))?;
+        for (index, data) in shards.iter().take(layout.data_shards).enumerate() {
+            let shard = layout.shard(group, index);
+            if damage.data.binary_search(&shard).is_err() {
+                continue;
+            }
+            let data = data.as_ref().expect("reconstructed");
+            if crc32fast::hash(data) != section.data_crcs[shard as usize] {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "rebuilt data does not match its recovery records"));
+            }
+            let range = layout.shard_range(shard);
+            file.seek(SeekFrom::Start(range.start))?;
+            file.write_all(&data[..(range.end - range.start) as usize])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::{compress_file, decompress_file, CompressionOptions};
+    use crate::container::Stages;
+
+    #[test]
+    fn damaged_regions_are_found_and_repaired() {
+        let dir = std::env::temp_dir().join(format!("blockpiper-recovery-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (input, packed, fixed, output) = (dir.join("in"), dir.join("in.bpc"), dir.join("fixed.bpc"), dir.join("out"));
+        // Data stored as it is, so the file is large enough to be cut into
+        // many shards.
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..100_000).map(|_| {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 24) as u8
+        }).collect();
+        fs::write(&input, &data).unwrap();
+        let stages = Stages { grammar: false, context_model: false };
+        compress_file(&input, &packed, &CompressionOptions::new().with_stages(stages).with_recovery(Some(10))).unwrap();
+        let intact = fs::read(&packed).unwrap();
+
+        let damaged = 50_000..52_000u64;
+        let mut bytes = intact.clone();
+        bytes[damaged.start as usize..damaged.end as usize].iter_mut().for_each(|byte| *byte ^= 0x5a);
+        fs::write(&packed, &bytes).unwrap();
+        let report = check_recovery(&packed).unwrap().unwrap();
+        assert!(report.recoverable && report.damaged_parity == 0 && !report.damaged_trailer);
+        // Damage is found whole shards at a time.
+        assert!(report.damaged.first().unwrap().start <= damaged.start && report.damaged.last().unwrap().end >= damaged.end);
+        assert!(report.damaged_bytes() < 2 * (damaged.end - damaged.start));
+
+        assert_eq!(repair_file(&packed, &fixed).unwrap(), report);
+        assert_eq!(fs::read(&fixed).unwrap(), intact);
+        decompress_file(&fixed, &output).unwrap();
+        assert_eq!(fs::read(&output).unwrap(), data);
+
+        // Far more damage than the parity covers is reported, not repaired.
+        bytes[10_000..60_000].iter_mut().for_each(|byte| *byte ^= 0x5a);
+        fs::write(&packed, &bytes).unwrap();
+        assert!(!check_recovery(&packed).unwrap().unwrap().recoverable);
+        assert!(repair_file(&packed, &fixed).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,2 @@
+pub mod volume;
+pub use volume::{split_file, volume_path, volume_set_path, VolumeReader, MIN_VOLUME_SIZE, VOLUME_HEADER_LEN};
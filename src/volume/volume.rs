@@ -0,0 +1,450 @@
+//! Volume sets: a finished `.bpc` file split into numbered pieces of at most
+//! a given size, `name.bpc.001`, `name.bpc.002` and so on, for media and
+//! transfers with a size cap.
+//!
+//! Volumes are cut between block records, so every volume but the first
+//! starts with a block (or a sync marker), and no record is split across two
+//! volumes; only what follows the records (an archive's index, recovery
+//! records) may be cut anywhere. Each volume starts with a header:
+//!
+//! - `VOLUME_MAGIC`, version `u8`
+//! - archive ID: 16 random bytes shared by all volumes of the set
+//! - volume number `u32`, counting from 1, and the number of volumes `u32`
+//! - length `u64` of the data after the header
+//! - CRC32 of the header so far
+//!
+//! The data of the volumes, in order, is the original file. Recovery
+//! records, if the file has them, were computed for the whole file before it
+//! was split, so they protect the data of every volume.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::archive::archive::index_offset;
+use crate::container::{BlockRecord, Header};
+
+const VOLUME_MAGIC: [u8; 4] = *b"BPVL";
+const VOLUME_VERSION: u8 = 1;
+/// Bytes of the header at the start of every volume.
+pub const VOLUME_HEADER_LEN: usize = 41;
+/// Smallest volume size accepted; volumes also have to hold the largest
+/// block record.
+pub const MIN_VOLUME_SIZE: u64 = 64 * 1024;
+
+/// Header at the start of every volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct VolumeHeader {
+    archive_id: [u8; 16],
+    number: u32,
+    count: u32,
+    data_len: u64,
+}
+
+impl VolumeHeader {
+    fn encode(&self) -> [u8; VOLUME_HEADER_LEN] {
+        let mut bytes = [0u8; VOLUME_HEADER_LEN];
+        bytes[..4].copy_from_slice(&VOLUME_MAGIC);
+        bytes[4] = VOLUME_VERSION;
+        bytes[5..21].copy_from_slice(&self.archive_id);
+        bytes[21..25].copy_from_slice(&self.number.to_le_bytes());
+        bytes[25..29].copy_from_slice(&self.count.to_le_bytes());
+        bytes[29..37].copy_from_slice(&self.data_len.to_le_bytes());
+        let crc = crc32fast::hash(&bytes[..37]);
+        bytes[37..].copy_from_slice(&crc.to_le_bytes());
+        bytes
+    }
+
+    /// Reads the header at the start of `reader`; `name` is how errors
+    /// refer to the volume.
+    fn read(reader: &mut impl Read, name: &Path) -> io::Result<Self> {
+        let mut bytes = [0u8; VOLUME_HEADER_LEN];
+        reader.read_exact(&mut bytes).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid_data(format!("{} is too short to be a volume", name.display())),
+            _ => e,
+        })?;
+        if bytes[..4] != VOLUME_MAGIC {
+            return Err(invalid_data(format!("{} is not a volume of a BlockPiper file", name.display())));
+        }
+        if bytes[4] != VOLUME_VERSION {
+            return Err(invalid_data(format!("{} has unsupported volume version {}", name.display(), bytes[4])));
+        }
+        if crc32fast::hash(&bytes[..37]).to_le_bytes() != bytes[37..] {
+            return Err(invalid_data(format!("volume header of {} is damaged", name.display())));
+        }
+        let header = VolumeHeader {
+            archive_id: bytes[5..21].try_into().unwrap(),
+            number: u32::from_le_bytes(bytes[21..25].try_into().unwrap()),
+            count: u32::from_le_bytes(bytes[25..29].try_into().unwrap()),
+            data_len: u64::from_le_bytes(bytes[29..37].try_into().unwrap()),
+        };
+        if header.number == 0 || header.number > header.count {
+            return Err(invalid_data(format!("volume header of {} is damaged", name.display())));
+        }
+        Ok(header)
+    }
+}
+
+/// The name of volume `number` of the set for the file `path`:
+/// `path.001`, `path.002` and so on.
+pub fn volume_path<P: AsRef<Path>>(path: P, number: u32) -> PathBuf {
+    let mut name = path.as_ref().as_os_str().to_owned();
+    name.push(format!(".{:03}", number));
+    PathBuf::from(name)
+}
+
+/// The file a volume set stands for, if `path` is named like one of its
+/// volumes: `name.bpc` for `name.bpc.002`.
+pub fn volume_set_path<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let extension = path.extension()?.to_str()?;
+    let numbered = extension.len() >= 3 && extension.bytes().all(|byte| byte.is_ascii_digit());
+    numbered.then(|| path.with_extension(""))
+}
+
+/// Splits the finished `.bpc` file at `path` into volumes of at most
+/// `volume_size` bytes each, headers included, named as [`volume_path`]
+/// says, and removes it. Returns the number of volumes.
+///
+/// Fails, leaving the file as it was, if a block record does not fit in a
+/// volume.
+pub fn split_file<P: AsRef<Path>>(path: P, volume_size: u64) -> io::Result<u32> {
+    let path = path.as_ref();
+    validate_volume_size(volume_size)?;
+    let mut reader = BufReader::new(File::open(path)?);
+    let cuts = cut_points(&mut reader, volume_size - VOLUME_HEADER_LEN as u64)?;
+    let count = u32::try_from(cuts.len() - 1).map_err(|_| invalid_input("too many volumes; use a larger volume size".to_string()))?;
+    let mut archive_id = [0u8; 16];
+    getrandom::getrandom(&mut archive_id).map_err(|e| io::Error::other(e.to_string()))?;
+
+    let mut written = Vec::new();
+    let result = (|| {
+        reader.seek(SeekFrom::Start(0))?;
+        for (number, range) in (1..=count).zip(cuts.windows(2)) {
+            let volume = volume_path(path, number);
+            let mut writer = BufWriter::new(File::create(&volume)?);
+            written.push(volume);
+            let data_len = range[1] - range[0];
+            writer.write_all(&VolumeHeader { archive_id, number, count, data_len }.encode())?;
+            if io::copy(&mut (&mut reader).take(data_len), &mut writer)? != data_len {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while splitting it"));
+            }
+            writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+        fs::remove_file(path)
+    })();
+    if result.is_err() {
+        for volume in written {
+            let _ = fs::remove_file(volume);
+        }
+    }
+    result.map(|()| count)
+}
+
+pub(crate) fn validate_volume_size(volume_size: u64) -> io::Result<()> {
+    if volume_size < MIN_VOLUME_SIZE {
+        return Err(invalid_input(format!("volume size must be at least {} bytes", MIN_VOLUME_SIZE)));
+    }
+    Ok(())
+}
+
+/// Offsets at which the file behind `reader` is cut into pieces of at most
+/// `capacity` bytes, from 0 to its length: as late as possible, but between
+/// block records until they end.
+fn cut_points<R: Read + Seek>(reader: &mut R, capacity: u64) -> io::Result<Vec<u64>> {
+    let header = Header::read(reader)?;
+    // Where the header and every record end; a volume may end at any of
+    // them, or anywhere after the records.
+    let mut boundaries = vec![header.encoded_len() as u64];
+    let records_end = if header.is_archive() {
+        let index_offset = index_offset(reader, &header)?;
+        reader.seek(SeekFrom::Start(boundaries[0]))?;
+        while *boundaries.last().unwrap() < index_offset {
+            let (_, framed_len) = BlockRecord::skip_in(reader, &header)?.ok_or_else(|| invalid_data("truncated archive".to_string()))?;
+            boundaries.push(boundaries.last().unwrap() + framed_len as u64);
+        }
+        index_offset
+    } else {
+        while let Some((_, framed_len)) = BlockRecord::skip_in(reader, &header)? {
+            boundaries.push(boundaries.last().unwrap() + framed_len as u64);
+        }
+        *boundaries.last().unwrap()
+    };
+    let len = reader.seek(SeekFrom::End(0))?;
+    if records_end > len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block"));
+    }
+
+    let mut cuts = vec![0];
+    let mut start = 0;
+    while start < len {
+        let limit = start + capacity;
+        let cut = if limit >= len {
+            len
+        } else if limit >= records_end {
+            limit
+        } else {
+            let fits = boundaries.partition_point(|&boundary| boundary <= limit);
+            match boundaries[..fits].last() {
+                Some(&boundary) if boundary > start => boundary,
+                _ => {
+                    let record_len = boundaries[fits] - start;
+                    return Err(invalid_input(format!(
+                        "a block record of {} bytes does not fit in a volume of {} bytes; use a smaller block size or larger volumes",
+                        record_len,
+                        capacity + VOLUME_HEADER_LEN as u64
+                    )));
+                }
+            }
+        };
+        cuts.push(cut);
+        start = cut;
+    }
+    if len == 0 {
+        cuts.push(0);
+    }
+    Ok(cuts)
+}
+
+/// Reads a volume set as the one file it was split from. Opened on a plain
+/// `.bpc` file, it reads that file, so code that takes a path to decode
+/// handles both.
+#[derive(Debug)]
+pub struct VolumeReader {
+    /// Every volume's path, where its data starts in it, and where it starts
+    /// in the whole.
+    volumes: Vec<(PathBuf, u64, u64)>,
+    len: u64,
+    /// The volume open at the moment and whether its file position is
+    /// `pos`.
+    current: Option<(usize, File, bool)>,
+    pos: u64,
+}
+
+impl VolumeReader {
+    /// Opens the file at `path`. If it is named like a volume, or does not
+    /// exist but `path.001` does, it opens the whole volume set instead, and
+    /// fails with a list of the missing volumes if any are missing.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let Some(set) = volume_set_for(path)? else {
+            let file = File::open(path)?;
+            let len = file.metadata()?.len();
+            return Ok(VolumeReader { volumes: vec![(path.to_path_buf(), 0, 0)], len, current: Some((0, file, false)), pos: 0 });
+        };
+
+        let first_path = volume_path(&set, 1);
+        let first = match File::open(&first_path) {
+            Ok(mut file) => VolumeHeader::read(&mut file, &first_path)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("volume set {} is incomplete: {} is missing", set.display(), first_path.display()),
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        let mut volumes = Vec::with_capacity(first.count as usize);
+        let mut missing = Vec::new();
+        let mut len = 0u64;
+        for number in 1..=first.count {
+            let volume = volume_path(&set, number);
+            let mut file = match File::open(&volume) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    missing.push(volume.display().to_string());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let header = VolumeHeader::read(&mut file, &volume)?;
+            if header.archive_id != first.archive_id || header.count != first.count {
+                return Err(invalid_data(format!("{} belongs to a different volume set", volume.display())));
+            }
+            if header.number != number {
+                return Err(invalid_data(format!("{} is volume {} of the set, not volume {}", volume.display(), header.number, number)));
+            }
+            let actual = file.metadata()?.len().saturating_sub(VOLUME_HEADER_LEN as u64);
+            if actual < header.data_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{} is truncated: {} of {} bytes", volume.display(), actual, header.data_len),
+                ));
+            }
+            volumes.push((volume, VOLUME_HEADER_LEN as u64, len));
+            len += header.data_len;
+        }
+        if !missing.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("volume set {} is incomplete: {} of {} volumes missing: {}", set.display(), missing.len(), first.count, missing.join(", ")),
+            ));
+        }
+        Ok(VolumeReader { volumes, len, current: None, pos: 0 })
+    }
+
+    /// Number of volumes; 1 for a plain file.
+    pub fn volumes(&self) -> usize {
+        self.volumes.len()
+    }
+
+    /// Length of the whole file, without volume headers.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Permissions of the file, or of the first volume.
+    pub(crate) fn permissions(&self) -> io::Result<fs::Permissions> {
+        Ok(fs::metadata(&self.volumes[0].0)?.permissions())
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+        let index = self.volumes.partition_point(|volume| volume.2 <= self.pos) - 1;
+        let (path, data_start, start) = &self.volumes[index];
+        let end = self.volumes.get(index + 1).map_or(self.len, |volume| volume.2);
+        if self.current.as_ref().is_none_or(|current| current.0 != index) {
+            self.current = Some((index, File::open(path)?, false));
+        }
+        let (_, file, positioned) = self.current.as_mut().unwrap();
+        if !*positioned {
+            file.seek(SeekFrom::Start(data_start + self.pos - start))?;
+            *positioned = true;
+        }
+        let want = buf.len().min((end - self.pos) as usize);
+        let read = file.read(&mut buf[..want])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} shrank while reading it", path.display())));
+        }
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        let target = target.ok_or_else(|| invalid_input("seek to a negative or overflowing position".to_string()))?;
+        if target != self.pos {
+            if let Some(current) = self.current.as_mut() {
+                current.2 = false;
+            }
+            self.pos = target;
+        }
+        Ok(self.pos)
+    }
+}
+
+/// The volume set `path` stands for, if it names one: either one of its
+/// volumes, or the file it was split from when that no longer exists.
+pub(crate) fn volume_set_for(path: &Path) -> io::Result<Option<PathBuf>> {
+    match volume_set_path(path) {
+        Some(set) if path.exists() && is_volume(path)? => Ok(Some(set)),
+        Some(set) if !path.exists() && volume_path(&set, 1).exists() => Ok(Some(set)),
+        _ if !path.exists() && volume_path(path, 1).exists() => Ok(Some(path.to_path_buf())),
+        _ => Ok(None),
+    }
+}
+
+/// Whether the file at `path` starts like a volume.
+fn is_volume(path: &Path) -> io::Result<bool> {
+    let mut magic = [0u8; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == VOLUME_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compressor::{compress_file, decompress_file, CompressionOptions};
+    use crate::container::Stages;
+
+    /// Compresses `data` to `dir/name.bpc` and splits copies of it into
+    /// volume sets of at most `MIN_VOLUME_SIZE` bytes, one per name in
+    /// `sets`, returning the names the sets stand for.
+    fn split_sets(dir: &Path, data: &[u8], sets: &[&str]) -> Vec<PathBuf> {
+        let input = dir.join("data");
+        fs::write(&input, data).unwrap();
+        let packed = dir.join("data.bpc");
+        // Stored blocks keep the volume count independent of the coder.
+        let options = CompressionOptions::new().with_block_size(16 * 1024).with_stages(Stages { grammar: false, context_model: false });
+        compress_file(&input, &packed, &options).unwrap();
+        let sets: Vec<PathBuf> = sets.iter().map(|name| dir.join(format!("{}.bpc", name))).collect();
+        for set in &sets {
+            fs::copy(&packed, set).unwrap();
+            assert!(split_file(set, MIN_VOLUME_SIZE).unwrap() >= 3);
+        }
+        sets
+    }
+
+    fn sample() -> Vec<u8> {
+        (0..140_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect()
+    }
+
+    #[test]
+    fn split_sets_read_as_the_original_file() {
+        let dir = std::env::temp_dir().join(format!("blockpiper-volumes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = sample();
+        let packed = split_sets(&dir, &data, &["set"]).remove(0);
+        assert!(!packed.exists());
+        let volumes: Vec<PathBuf> = (1..).map(|number| volume_path(&packed, number)).take_while(|volume| volume.exists()).collect();
+        assert!(volumes.len() >= 3);
+        assert!(volumes.iter().all(|volume| fs::metadata(volume).unwrap().len() <= MIN_VOLUME_SIZE));
+        assert_eq!(volume_set_path(&volumes[1]), Some(packed.clone()));
+
+        // Any volume, or the name the set was split from, opens the set.
+        for name in [&packed, &volumes[2]] {
+            let output = dir.join("out");
+            decompress_file(name, &output).unwrap();
+            assert_eq!(fs::read(&output).unwrap(), data);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_and_foreign_volumes_are_named() {
+        let dir = std::env::temp_dir().join(format!("blockpiper-bad-volumes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data = sample();
+        let [packed, other] = <[PathBuf; 2]>::try_from(split_sets(&dir, &data, &["set", "other"])).unwrap();
+
+        fs::rename(volume_path(&packed, 2), dir.join("aside")).unwrap();
+        let error = VolumeReader::open(&packed).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(error.to_string().contains(&volume_path(&packed, 2).display().to_string()), "{}", error);
+
+        // Same contents, but split separately: the archive IDs differ.
+        fs::copy(volume_path(&other, 2), volume_path(&packed, 2)).unwrap();
+        let error = VolumeReader::open(&packed).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("belongs to a different volume set"), "{}", error);
+
+        fs::rename(dir.join("aside"), volume_path(&packed, 2)).unwrap();
+        assert_eq!(VolumeReader::open(&packed).unwrap().len(), VolumeReader::open(&other).unwrap().len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}